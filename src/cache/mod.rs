@@ -0,0 +1,950 @@
+use crate::cgi::CgiResponse;
+use crate::config::CacheZoneConfig;
+use crate::http::response::{BodyPart, FileRegion};
+use crate::http::{HttpMethod, HttpRequest, HttpResponse};
+use crate::utils::hash::fnv1a;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Statuses cacheable without being marked so (RFC 9111 section 4.2.2), limited to
+/// those `StatusCode` can represent.
+const CACHEABLE_STATUSES: &[u16] = &[200, 204, 301, 308, 404, 405, 414, 501];
+
+/// Response headers that are not stored: they describe one connection or are
+/// regenerated when the entry is served.
+const UNSTORED_HEADERS: &[&str] = &[
+    "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding",
+    "upgrade", "content-length", "date", "server", "age", "x-cache", "status",
+];
+
+/// A cache file holds the body, then the head (this line, then one field per line up to
+/// a blank one), then the body length as 8 big-endian bytes. The head goes last because it
+/// is only complete once the body has been captured.
+const FILE_MAGIC: &str = "WEBSERV-CACHE 2";
+
+/// Heads longer than this mark a file that is not a cache entry.
+const MAX_HEAD_LEN: u64 = 1024 * 1024;
+
+/// The key of a request before Vary is applied: method, host and URI.
+pub fn primary_key(request: &HttpRequest) -> String {
+    let host = request.host().map(|h| h.trim().to_ascii_lowercase()).unwrap_or_default();
+    match &request.query_string {
+        Some(query) => format!("{} {}{}?{}", request.method, host, request.uri, query),
+        None => format!("{} {}{}", request.method, host, request.uri),
+    }
+}
+
+/// Only plain GETs are cached; credentials or `no-store` keep a request out of the cache.
+pub fn is_cacheable_request(request: &HttpRequest) -> bool {
+    request.method == HttpMethod::GET
+        && !request.headers.contains_key("authorization")
+        && !cache_control(request.headers.get("cache-control")).iter().any(|(name, _)| name == "no-store")
+}
+
+/// The client asked for a response fresh from the backend (a forced reload).
+pub fn wants_fresh(request: &HttpRequest) -> bool {
+    let directives = cache_control(request.headers.get("cache-control"));
+    directives.iter().any(|(name, value)| name == "no-cache" || (name == "max-age" && value.as_deref() == Some("0")))
+        || request.headers.get("pragma").is_some_and(|v| v.to_ascii_lowercase().contains("no-cache"))
+}
+
+/// Split a Cache-Control value into lowercase directive names and their values.
+fn cache_control(value: Option<&String>) -> Vec<(String, Option<String>)> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .map(|d| match d.split_once('=') {
+                    Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+                    None => (d.to_ascii_lowercase(), None),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// How long a response stays fresh, and how long after that it may still be served
+/// while a refresh runs. `None` if it may not be stored. Explicit freshness (s-maxage,
+/// max-age, Expires) wins over `default_ttl`, which applies to responses without any.
+fn freshness(
+    status: u16,
+    headers: &HashMap<String, String>,
+    default_ttl: Option<Duration>,
+    default_stale: Duration,
+    now: SystemTime,
+) -> Option<(Duration, Duration)> {
+    // Personalised responses are never shared
+    if !CACHEABLE_STATUSES.contains(&status) || headers.contains_key("set-cookie") {
+        return None;
+    }
+    let directives = cache_control(headers.get("cache-control"));
+    let has = |wanted: &str| directives.iter().any(|(name, _)| name == wanted);
+    let seconds = |wanted: &str| {
+        directives.iter()
+            .find(|(name, _)| name == wanted)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+            .map(Duration::from_secs)
+    };
+    if has("no-store") || has("private") || has("no-cache") {
+        return None;
+    }
+
+    let age = headers.get("age").and_then(|v| v.trim().parse().ok()).map(Duration::from_secs).unwrap_or_default();
+    let fresh = if let Some(max_age) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+        max_age.saturating_sub(age)
+    } else if let Some(expires) = headers.get("expires") {
+        let date = headers.get("date").and_then(|d| httpdate::parse_http_date(d).ok()).unwrap_or(now);
+        // An invalid Expires means already expired (RFC 9111 section 5.3)
+        httpdate::parse_http_date(expires).ok()
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default()
+    } else {
+        default_ttl?
+    };
+    let stale = if has("must-revalidate") || has("proxy-revalidate") {
+        Duration::ZERO
+    } else {
+        seconds("stale-while-revalidate").unwrap_or(default_stale)
+    };
+    if fresh.is_zero() && stale.is_zero() {
+        return None;
+    }
+    Some((fresh, stale))
+}
+
+/// The key of one stored variant: the primary key plus the request's values for the
+/// headers named in the response's Vary.
+fn variant_key(primary: &str, vary: &[(String, String)]) -> String {
+    let mut key = primary.to_string();
+    for (name, value) in vary {
+        key.push_str(&format!("\n{}: {}", name, value));
+    }
+    key
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The body of a cached response: in memory, or left in the entry's cache file when it
+/// is too large for the zone's memory.
+#[derive(Debug, Clone)]
+enum CachedBody {
+    Bytes(Vec<u8>),
+    File(Arc<File>, u64), // The first bytes of the file, and how many
+}
+
+/// A response as held in a cache zone.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    primary: String,
+    vary: Vec<(String, String)>,
+    status: u16,
+    headers: HashMap<String, String>,
+    body: CachedBody,
+    stored_at: SystemTime,
+    fresh_until: SystemTime,
+    stale_until: SystemTime, // Served while a refresh runs until then
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        now < self.fresh_until
+    }
+
+    /// Whether the entry may still be served, fresh or stale.
+    pub fn is_usable(&self, now: SystemTime) -> bool {
+        now < self.stale_until
+    }
+
+    /// The response for a client, with `Age` and `X-Cache` set.
+    pub fn to_response(&self, cache_status: &str, now: SystemTime) -> HttpResponse {
+        let mut response = HttpResponse::from_cgi_response(CgiResponse {
+            status: self.status,
+            headers: self.headers.clone(),
+            body: Vec::new(),
+        });
+        match &self.body {
+            CachedBody::Bytes(bytes) => response.set_body(bytes),
+            CachedBody::File(file, len) => {
+                let region = FileRegion { file: file.clone(), offset: 0, len: *len };
+                response.set_body_parts(vec![BodyPart::File(region)]);
+            }
+        }
+        let age = now.duration_since(self.stored_at).unwrap_or_default().as_secs();
+        response.set_header("age", &age.to_string());
+        response.set_header("x-cache", cache_status);
+        response
+    }
+
+    fn key(&self) -> String {
+        variant_key(&self.primary, &self.vary)
+    }
+
+    /// Bytes held in memory: the body unless it stayed on disk, and the headers.
+    fn size(&self) -> usize {
+        let body = match &self.body {
+            CachedBody::Bytes(bytes) => bytes.len(),
+            CachedBody::File(..) => 0,
+        };
+        body + self.headers.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>()
+    }
+
+    /// The head of the entry's cache file.
+    fn head(&self) -> String {
+        let mut head = format!("{}\nprimary: {}\nstatus: {}\nstored: {}\nfresh: {}\nstale: {}\n",
+            FILE_MAGIC, self.primary, self.status,
+            unix_secs(self.stored_at), unix_secs(self.fresh_until), unix_secs(self.stale_until));
+        for (name, value) in &self.vary {
+            head.push_str(&format!("vary: {}: {}\n", name, value));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("header: {}: {}\n", name, value));
+        }
+        head.push('\n');
+        head
+    }
+
+    /// Read an entry from its cache file. A body of up to `max_in_memory` bytes is read
+    /// into memory; a larger one is served from the file.
+    fn read_from(path: &Path, max_in_memory: u64) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), what));
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut trailer = [0u8; 8];
+        if file_len < trailer.len() as u64 {
+            return Err(invalid("truncated"));
+        }
+        file.read_exact_at(&mut trailer, file_len - 8)?;
+        let body_len = u64::from_be_bytes(trailer);
+        let head_len = (file_len - 8).checked_sub(body_len).filter(|&len| len <= MAX_HEAD_LEN).ok_or_else(|| invalid("bad length"))?;
+        let mut head = vec![0u8; head_len as usize];
+        file.read_exact_at(&mut head, body_len)?;
+        let head = String::from_utf8(head).map_err(|_| invalid("not a cache file"))?;
+
+        let mut lines = head.lines();
+        if lines.next() != Some(FILE_MAGIC) {
+            return Err(invalid("not a cache file"));
+        }
+        let mut entry = CachedResponse {
+            primary: String::new(),
+            vary: Vec::new(),
+            status: 0,
+            headers: HashMap::new(),
+            body: CachedBody::Bytes(Vec::new()),
+            stored_at: UNIX_EPOCH,
+            fresh_until: UNIX_EPOCH,
+            stale_until: UNIX_EPOCH,
+        };
+        let time = |secs: &str| secs.parse::<u64>().map(|s| UNIX_EPOCH + Duration::from_secs(s)).map_err(|_| invalid("bad time"));
+        for field in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = field.split_once(": ").ok_or_else(|| invalid("bad line"))?;
+            match name {
+                "primary" => entry.primary = value.to_string(),
+                "status" => entry.status = value.parse().map_err(|_| invalid("bad status"))?,
+                "stored" => entry.stored_at = time(value)?,
+                "fresh" => entry.fresh_until = time(value)?,
+                "stale" => entry.stale_until = time(value)?,
+                "vary" | "header" => {
+                    let (header, header_value) = value.split_once(':').ok_or_else(|| invalid("bad header"))?;
+                    let pair = (header.to_string(), header_value.trim_start().to_string());
+                    if name == "vary" {
+                        entry.vary.push(pair);
+                    } else {
+                        entry.headers.insert(pair.0, pair.1);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        entry.body = if body_len <= max_in_memory {
+            let mut body = vec![0u8; body_len as usize];
+            file.read_exact_at(&mut body, 0)?;
+            CachedBody::Bytes(body)
+        } else {
+            CachedBody::File(Arc::new(file), body_len)
+        };
+        Ok(entry)
+    }
+}
+
+/// A captured body on its way to a zone's disk, in a temporary file in the zone directory.
+/// The file is removed unless `persist` moves it into place.
+#[derive(Debug)]
+struct Spool {
+    file: File,
+    path: Option<PathBuf>, // Until persisted
+    len: u64,
+}
+
+impl Spool {
+    fn create(dir: &Path) -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}.part", std::process::id(), n));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(Self { file, path: Some(path), len: 0 }),
+                // Left behind by an earlier run with the same pid
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Complete the file with `head` and rename it to `path`, replacing the entry there
+    /// in one step. Returns the size of the file.
+    fn persist(mut self, head: &str, path: &Path) -> io::Result<u64> {
+        self.file.write_all(head.as_bytes())?;
+        self.file.write_all(&self.len.to_be_bytes())?;
+        let temp = match &self.path {
+            Some(temp) => temp,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        fs::rename(temp, path)?;
+        self.path = None;
+        Ok(self.len + head.len() as u64 + 8)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Collects a backend response on its way to the client, to be stored once it is complete.
+/// For a zone with a path the body goes straight to a file in its directory; otherwise it
+/// is kept in memory, up to the zone's memory budget.
+#[derive(Debug)]
+pub struct CacheCapture {
+    zone: String,
+    primary: String,
+    request_headers: HashMap<String, String>, // For the Vary values
+    default_ttl: Option<Duration>,
+    default_stale: Duration,
+    max_size: usize,
+    dir: Option<PathBuf>,
+    status: Option<u16>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    spool: Option<Spool>, // Created with the first body bytes
+    abandoned: bool,
+}
+
+impl CacheCapture {
+    pub fn new(zone: &CacheZone, request: &HttpRequest, default_ttl: Option<Duration>, default_stale: Duration) -> Self {
+        Self {
+            zone: zone.name.clone(),
+            primary: primary_key(request),
+            request_headers: request.headers.clone(),
+            default_ttl,
+            default_stale,
+            max_size: zone.max_entry_size,
+            dir: zone.dir.clone(),
+            status: None,
+            headers: HashMap::new(),
+            body: Vec::new(),
+            spool: None,
+            abandoned: false,
+        }
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    pub fn primary_key(&self) -> &str {
+        &self.primary
+    }
+
+    /// Record the status and headers; repeated headers are joined with commas.
+    pub fn headers<'a>(&mut self, status: u16, headers: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        self.status = Some(status);
+        for (name, value) in headers {
+            let name = name.to_ascii_lowercase();
+            if UNSTORED_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            self.headers.entry(name)
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+
+    pub fn body(&mut self, data: &[u8]) {
+        if self.abandoned || data.is_empty() {
+            return;
+        }
+        if self.body_len() + data.len() as u64 > self.max_size as u64 {
+            self.abandon();
+            return;
+        }
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => {
+                self.body.extend_from_slice(data);
+                return;
+            }
+        };
+        let written = match &mut self.spool {
+            Some(spool) => spool.write(data),
+            None => Spool::create(dir).and_then(|spool| self.spool.insert(spool).write(data)),
+        };
+        if let Err(e) = written {
+            log::error!("Cache zone '{}': failed to spool {}: {}", self.zone, self.primary, e);
+            self.abandon();
+        }
+    }
+
+    fn body_len(&self) -> u64 {
+        match &self.spool {
+            Some(spool) => spool.len,
+            None => self.body.len() as u64,
+        }
+    }
+
+    /// The response turned out not to be storable (too large, raw nph output, a redirect).
+    pub fn abandon(&mut self) {
+        self.abandoned = true;
+        self.body = Vec::new();
+        self.spool = None;
+    }
+
+    /// The entry to store, and the file its body was spooled to, if any.
+    fn into_entry(self, now: SystemTime) -> Option<(CachedResponse, Option<Spool>)> {
+        if self.abandoned {
+            return None;
+        }
+        let status = self.status?;
+        let (fresh, stale) = freshness(status, &self.headers, self.default_ttl, self.default_stale, now)?;
+
+        let mut vary = Vec::new();
+        if let Some(names) = self.headers.get("vary") {
+            for name in names.split(',').map(|n| n.trim().to_ascii_lowercase()).filter(|n| !n.is_empty()) {
+                if name == "*" {
+                    return None;
+                }
+                let value = self.request_headers.get(&name).cloned().unwrap_or_default();
+                vary.push((name, value));
+            }
+        }
+
+        let entry = CachedResponse {
+            primary: self.primary,
+            vary,
+            status,
+            headers: self.headers,
+            body: CachedBody::Bytes(self.body),
+            stored_at: now,
+            fresh_until: now + fresh,
+            stale_until: now + fresh + stale,
+        };
+        Some((entry, self.spool))
+    }
+}
+
+/// Least recently used eviction within a byte budget.
+#[derive(Debug)]
+struct Lru<V> {
+    entries: HashMap<String, (V, usize, u64)>, // Value, size, last use
+    order: BTreeMap<u64, String>, // Last use -> key, oldest first
+    tick: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl<V> Lru<V> {
+    fn new(max_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let (value, _, last_use) = self.entries.get_mut(key)?;
+        self.order.remove(last_use);
+        *last_use = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(value)
+    }
+
+    /// Insert `value`, evicting the least recently used entries to make room. Returns
+    /// the evicted keys. A value larger than the whole budget is not kept.
+    fn insert(&mut self, key: String, value: V, size: usize) -> Vec<String> {
+        self.remove(&key);
+        if size > self.max_size {
+            return Vec::new();
+        }
+        let mut evicted = Vec::new();
+        while self.size + size > self.max_size {
+            let (_, oldest) = match self.order.pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some((_, oldest_size, _)) = self.entries.remove(&oldest) {
+                self.size -= oldest_size;
+            }
+            evicted.push(oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.size += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, size, last_use) = self.entries.remove(key)?;
+        self.order.remove(&last_use);
+        self.size -= size;
+        Some(value)
+    }
+}
+
+/// A `cache_zone`: recently used entries in memory, and every entry on disk when the
+/// zone has a path, so the cache survives restarts and can outgrow memory.
+#[derive(Debug)]
+pub struct CacheZone {
+    name: String,
+    dir: Option<PathBuf>,
+    memory: Lru<CachedResponse>,
+    disk: Lru<()>, // Variant keys of the entries on disk
+    vary: HashMap<String, (Vec<String>, usize)>, // Primary key -> headers its response varies on, and its stored variants
+    updating: HashMap<String, Instant>, // Primary keys being refreshed in the background
+    max_entry_size: usize,
+}
+
+impl CacheZone {
+    /// A refresh that has not stored anything after this long is assumed to have failed.
+    const UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new(config: &CacheZoneConfig) -> io::Result<Self> {
+        let mut zone = Self {
+            name: config.name.clone(),
+            dir: config.path.clone(),
+            memory: Lru::new(config.memory),
+            disk: Lru::new(config.max_size),
+            vary: HashMap::new(),
+            updating: HashMap::new(),
+            max_entry_size: if config.path.is_some() { config.max_size } else { config.memory },
+        };
+        if let Some(dir) = &config.path {
+            fs::create_dir_all(dir)?;
+            zone.load_index(dir)?;
+        }
+        Ok(zone)
+    }
+
+    /// Index the entries a previous run left on disk, dropping expired ones and the
+    /// captures it did not finish.
+    fn load_index(&mut self, dir: &Path) -> io::Result<()> {
+        let now = SystemTime::now();
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("cache") => {}
+                Some("part") => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            match CachedResponse::read_from(&path, 0) {
+                Ok(entry) if entry.is_usable(now) => {
+                    let size = fs::metadata(&path).map(|m| m.len() as usize).unwrap_or(0);
+                    let key = entry.key();
+                    let was_stored = self.is_stored(&key);
+                    self.set_vary(entry.primary.clone(), &entry.vary);
+                    for evicted in self.disk.insert(key.clone(), (), size) {
+                        self.remove_file(&evicted);
+                        self.recount(&evicted, true);
+                    }
+                    self.recount(&key, was_stored);
+                }
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        log::info!("Cache zone '{}': {} entries on disk", self.name, self.disk.entries.len());
+        Ok(())
+    }
+
+    fn file_path(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{:016x}.cache", fnv1a(key.as_bytes()))))
+    }
+
+    fn is_stored(&self, key: &str) -> bool {
+        self.memory.entries.contains_key(key) || self.disk.entries.contains_key(key)
+    }
+
+    /// Note the headers the primary key's responses vary on, keeping its variant count.
+    fn set_vary(&mut self, primary: String, vary: &[(String, String)]) {
+        let names = vary.iter().map(|(name, _)| name.clone()).collect();
+        self.vary.entry(primary).or_insert_with(|| (Vec::new(), 0)).0 = names;
+    }
+
+    /// Update the variant count of `key`'s primary key after it was added to or dropped
+    /// from memory or disk. A primary key is forgotten with its last stored variant.
+    fn recount(&mut self, key: &str, was_stored: bool) {
+        let stored = self.is_stored(key);
+        // Primary keys hold no newline; the Vary values follow the first one
+        let primary = key.split('\n').next().unwrap_or(key);
+        if let Some((_, count)) = self.vary.get_mut(primary) {
+            match (was_stored, stored) {
+                (false, true) => *count += 1,
+                (true, false) => *count = count.saturating_sub(1),
+                _ => {}
+            }
+            if *count == 0 {
+                self.vary.remove(primary);
+            }
+        }
+    }
+
+    fn remove_file(&self, key: &str) {
+        if let Some(path) = self.file_path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Find the stored variant matching the request's headers, fresh or not.
+    pub fn lookup(&mut self, primary: &str, request_headers: &HashMap<String, String>) -> Option<CachedResponse> {
+        let (names, _) = self.vary.get(primary)?;
+        let vary: Vec<(String, String)> = names.iter()
+            .map(|name| (name.clone(), request_headers.get(name).cloned().unwrap_or_default()))
+            .collect();
+        let key = variant_key(primary, &vary);
+
+        if let Some(entry) = self.memory.get(&key) {
+            return Some(entry.clone());
+        }
+        self.disk.get(&key)?;
+        let path = self.file_path(&key)?;
+        match CachedResponse::read_from(&path, self.memory.max_size as u64) {
+            // Different keys can share a file name; the key inside tells them apart
+            Ok(entry) if entry.key() == key => {
+                // A body too large for memory is served from the file each time
+                if let CachedBody::Bytes(_) = entry.body {
+                    for evicted in self.memory.insert(key, entry.clone(), entry.size()) {
+                        self.recount(&evicted, true);
+                    }
+                }
+                Some(entry)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("Cache zone '{}': dropping unreadable entry: {}", self.name, e);
+                self.disk.remove(&key);
+                let _ = fs::remove_file(&path);
+                self.recount(&key, true);
+                None
+            }
+        }
+    }
+
+    /// Claim the background refresh of a stale entry; false if one is already running.
+    pub fn begin_update(&mut self, primary: &str) -> bool {
+        let now = Instant::now();
+        match self.updating.get(primary) {
+            Some(started) if now.duration_since(*started) < Self::UPDATE_TIMEOUT => false,
+            _ => {
+                self.updating.insert(primary.to_string(), now);
+                true
+            }
+        }
+    }
+
+    /// Store a captured response if its headers allow it.
+    pub fn store(&mut self, capture: CacheCapture) {
+        self.updating.remove(&capture.primary);
+        let primary = capture.primary.clone();
+        let (entry, spool) = match capture.into_entry(SystemTime::now()) {
+            Some(stored) => stored,
+            None => {
+                log::debug!("Cache zone '{}': not storing {}", self.name, primary);
+                return;
+            }
+        };
+
+        let key = entry.key();
+        let was_stored = self.is_stored(&key);
+        self.set_vary(primary, &entry.vary);
+        match (self.file_path(&key), &self.dir) {
+            (Some(path), Some(dir)) => {
+                // The next lookup reads the new entry from disk
+                self.memory.remove(&key);
+                let spool = match spool {
+                    Some(spool) => Ok(spool),
+                    None => Spool::create(dir), // No body was captured
+                };
+                match spool.and_then(|spool| spool.persist(&entry.head(), &path)) {
+                    Ok(size) => {
+                        log::debug!("Cache zone '{}': stored {} ({} bytes)", self.name, key.replace('\n', " | "), size);
+                        for evicted in self.disk.insert(key.clone(), (), size as usize) {
+                            self.remove_file(&evicted);
+                            self.recount(&evicted, true);
+                        }
+                    }
+                    Err(e) => log::error!("Cache zone '{}': failed to write {}: {}", self.name, path.display(), e),
+                }
+            }
+            _ => {
+                let size = entry.size();
+                log::debug!("Cache zone '{}': stored {} ({} bytes)", self.name, key.replace('\n', " | "), size);
+                for evicted in self.memory.insert(key.clone(), entry, size) {
+                    self.recount(&evicted, true);
+                }
+            }
+        }
+        self.recount(&key, was_stored);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn fresh_for(pairs: &[(&str, &str)]) -> Option<Duration> {
+        freshness(200, &headers(pairs), None, Duration::ZERO, SystemTime::now()).map(|(fresh, _)| fresh)
+    }
+
+    fn memory_zone(memory: usize) -> CacheZone {
+        CacheZone::new(&CacheZoneConfig { name: "test".to_string(), path: None, memory, max_size: memory }).unwrap()
+    }
+
+    fn request(uri: &str, pairs: &[(&str, &str)]) -> HttpRequest {
+        let mut request = HttpRequest::new();
+        request.uri = uri.to_string();
+        request.headers = headers(pairs);
+        request.headers.insert("host".to_string(), "example.com".to_string());
+        request
+    }
+
+    /// Capture and store a response to `request` the way a backend relay does.
+    fn store(zone: &mut CacheZone, request: &HttpRequest, response_headers: &[(&str, &str)], body: &[u8]) {
+        let mut capture = CacheCapture::new(zone, request, None, Duration::ZERO);
+        capture.headers(200, response_headers.iter().copied());
+        capture.body(body);
+        zone.store(capture);
+    }
+
+    fn lookup(zone: &mut CacheZone, request: &HttpRequest) -> Option<CachedResponse> {
+        zone.lookup(&primary_key(request), &request.headers)
+    }
+
+    fn body(entry: &CachedResponse) -> Vec<u8> {
+        match &entry.body {
+            CachedBody::Bytes(bytes) => bytes.clone(),
+            CachedBody::File(file, len) => {
+                let mut body = vec![0u8; *len as usize];
+                file.read_exact_at(&mut body, 0).unwrap();
+                body
+            }
+        }
+    }
+
+    #[test]
+    fn freshness_prefers_s_maxage_then_max_age_then_expires() {
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let expires = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_700_000_300));
+        let all = [("cache-control", "max-age=120, s-maxage=60"), ("expires", &expires[..]), ("date", &date[..])];
+        assert_eq!(fresh_for(&all), Some(MINUTE));
+        assert_eq!(fresh_for(&all[1..]), Some(Duration::from_secs(300)));
+        assert_eq!(fresh_for(&[("cache-control", "max-age=120"), ("expires", &expires), ("date", &date)]), Some(2 * MINUTE));
+
+        // Time already spent in other caches counts against it
+        assert_eq!(fresh_for(&[("cache-control", "s-maxage=60"), ("age", "20")]), Some(Duration::from_secs(40)));
+        assert_eq!(fresh_for(&[("cache-control", "max-age=60"), ("age", "90")]), None);
+        // An invalid Expires is already expired
+        assert_eq!(fresh_for(&[("expires", "0")]), None);
+
+        // cache_valid only covers responses that state no freshness
+        let ttl = Some(5 * MINUTE);
+        assert_eq!(freshness(200, &headers(&[]), ttl, Duration::ZERO, SystemTime::now()), Some((5 * MINUTE, Duration::ZERO)));
+        let explicit = headers(&[("cache-control", "max-age=60")]);
+        assert_eq!(freshness(200, &explicit, ttl, Duration::ZERO, SystemTime::now()), Some((MINUTE, Duration::ZERO)));
+        assert_eq!(freshness(500, &headers(&[]), ttl, Duration::ZERO, SystemTime::now()), None);
+    }
+
+    #[test]
+    fn private_and_no_store_responses_are_not_stored() {
+        for cache_control in ["no-store", "private", "private, max-age=60", "max-age=60, no-store", "no-cache"] {
+            assert_eq!(fresh_for(&[("cache-control", cache_control)]), None, "{}", cache_control);
+        }
+        assert_eq!(fresh_for(&[("cache-control", "max-age=60"), ("set-cookie", "id=1")]), None);
+
+        let mut zone = memory_zone(1024);
+        let page = request("/private", &[]);
+        store(&mut zone, &page, &[("Cache-Control", "private, max-age=60")], b"secret");
+        assert!(lookup(&mut zone, &page).is_none());
+        store(&mut zone, &page, &[("Cache-Control", "max-age=60")], b"public");
+        assert_eq!(body(&lookup(&mut zone, &page).unwrap()), b"public");
+    }
+
+    #[test]
+    fn variants_are_keyed_by_the_vary_headers() {
+        let mut zone = memory_zone(1024);
+        let english = request("/greeting", &[("accept-language", "en"), ("user-agent", "a")]);
+        let french = request("/greeting", &[("accept-language", "fr"), ("user-agent", "a")]);
+        let response = [("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")];
+
+        store(&mut zone, &english, &response, b"hello");
+        assert_eq!(body(&lookup(&mut zone, &english).unwrap()), b"hello");
+        assert!(lookup(&mut zone, &french).is_none());
+
+        store(&mut zone, &french, &response, b"bonjour");
+        assert_eq!(body(&lookup(&mut zone, &french).unwrap()), b"bonjour");
+        assert_eq!(body(&lookup(&mut zone, &english).unwrap()), b"hello");
+        // Headers outside Vary don't matter
+        let other_agent = request("/greeting", &[("accept-language", "en"), ("user-agent", "b")]);
+        assert_eq!(body(&lookup(&mut zone, &other_agent).unwrap()), b"hello");
+
+        // Vary: * can never be matched
+        let star = request("/star", &[]);
+        store(&mut zone, &star, &[("Cache-Control", "max-age=60"), ("Vary", "*")], b"x");
+        assert!(lookup(&mut zone, &star).is_none());
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_first() {
+        let mut lru = Lru::new(30);
+        assert!(lru.insert("a".to_string(), 1, 10).is_empty());
+        assert!(lru.insert("b".to_string(), 2, 10).is_empty());
+        assert!(lru.insert("c".to_string(), 3, 10).is_empty());
+        assert_eq!(lru.get("a"), Some(&1));
+
+        assert_eq!(lru.insert("d".to_string(), 4, 10), vec!["b".to_string()]);
+        assert_eq!(lru.insert("e".to_string(), 5, 20), vec!["c".to_string(), "a".to_string()]);
+        assert_eq!(lru.size, 30);
+        // Larger than the whole budget: not kept, and nothing else is evicted for it
+        assert!(lru.insert("f".to_string(), 6, 31).is_empty());
+        assert!(lru.get("f").is_none());
+        assert_eq!(lru.get("d"), Some(&4));
+    }
+
+    #[test]
+    fn evicted_keys_are_forgotten_with_their_last_variant() {
+        let mut zone = memory_zone(100);
+        let response = [("Cache-Control", "max-age=60"), ("Vary", "Accept")];
+        let first = request("/first", &[("accept", "a")]);
+        let first_b = request("/first", &[("accept", "b")]);
+        store(&mut zone, &first, &response, &[0; 10]);
+        store(&mut zone, &first_b, &response, &[0; 10]);
+        assert_eq!(zone.vary[&primary_key(&first)].1, 2);
+
+        // Entries take 30 to 45 bytes with their headers, so the pages push out the rest
+        for i in 0..10 {
+            store(&mut zone, &request(&format!("/page{}", i), &[]), &[("Cache-Control", "max-age=60")], &[0; 10]);
+        }
+        assert!(!zone.vary.contains_key(&primary_key(&first)));
+        assert!(zone.vary.len() <= zone.memory.entries.len());
+    }
+
+    #[test]
+    fn stale_entries_are_served_within_the_stale_window() {
+        let mut zone = memory_zone(1024);
+        let page = request("/news", &[]);
+        let mut capture = CacheCapture::new(&zone, &page, Some(MINUTE), 30 * MINUTE);
+        capture.headers(200, [("Content-Type", "text/html"), ("Cache-Control", "max-age=60, stale-while-revalidate=30")]);
+        capture.body(b"news");
+        let stored_at = SystemTime::now();
+        let (entry, _) = capture.into_entry(stored_at).unwrap();
+
+        let fresh = stored_at + Duration::from_secs(59);
+        assert!(entry.is_fresh(fresh));
+        let response = entry.to_response("HIT", fresh);
+        assert_eq!(response.get_header("x-cache").unwrap(), "HIT");
+        assert_eq!(response.get_header("age").unwrap(), "59");
+
+        // stale-while-revalidate overrides cache_stale
+        let stale = stored_at + Duration::from_secs(80);
+        assert!(!entry.is_fresh(stale) && entry.is_usable(stale));
+        let response = entry.to_response("STALE", stale);
+        assert_eq!(response.get_header("x-cache").unwrap(), "STALE");
+        assert_eq!(response.body, b"news");
+        // Only one refresh runs at a time
+        assert!(zone.begin_update(&entry.primary));
+        assert!(!zone.begin_update(&entry.primary));
+
+        // Past the window the entry has to be fetched again
+        assert!(!entry.is_usable(stored_at + Duration::from_secs(90)));
+
+        // A stored response ends the refresh
+        store(&mut zone, &page, &[("Cache-Control", "max-age=60")], b"newer");
+        assert!(zone.begin_update(&entry.primary));
+    }
+
+    #[test]
+    fn disk_zones_spool_captures_and_serve_large_bodies_from_the_file() {
+        let dir = std::env::temp_dir().join(format!("webserv-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = CacheZoneConfig { name: "disk".to_string(), path: Some(dir.clone()), memory: 64, max_size: 1 << 20 };
+        let mut zone = CacheZone::new(&config).unwrap();
+        let large = request("/large", &[]);
+        let small = request("/small", &[]);
+        let large_body: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut capture = CacheCapture::new(&zone, &large, None, Duration::ZERO);
+        capture.headers(200, [("Cache-Control", "max-age=60")]);
+        for chunk in large_body.chunks(1000) {
+            capture.body(chunk);
+        }
+        // The body is on disk, not in the capture
+        assert!(capture.body.is_empty());
+        assert!(fs::read_dir(&dir).unwrap().any(|e| e.unwrap().path().extension().is_some_and(|ext| ext == "part")));
+        zone.store(capture);
+        store(&mut zone, &small, &[("Cache-Control", "max-age=60")], b"small");
+
+        let entry = lookup(&mut zone, &large).unwrap();
+        assert!(matches!(entry.body, CachedBody::File(..)));
+        assert_eq!(body(&entry), large_body);
+        let response = entry.to_response("HIT", SystemTime::now());
+        assert_eq!(response.get_header("content-length").unwrap(), "10000");
+        assert_eq!(response.body_parts.len(), 1);
+        assert!(matches!(lookup(&mut zone, &small).unwrap().body, CachedBody::Bytes(_)));
+
+        // An abandoned capture leaves nothing behind, and a restart finds both entries
+        let mut abandoned = CacheCapture::new(&zone, &request("/abandoned", &[]), None, Duration::ZERO);
+        abandoned.body(b"partial");
+        abandoned.abandon();
+        drop(zone);
+        let names: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert!(names.iter().all(|path| path.extension().is_some_and(|ext| ext == "cache")), "{:?}", names);
+        let mut zone = CacheZone::new(&config).unwrap();
+        assert_eq!(body(&lookup(&mut zone, &large).unwrap()), large_body);
+        assert_eq!(body(&lookup(&mut zone, &small).unwrap()), b"small");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
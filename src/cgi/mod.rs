@@ -1,26 +1,48 @@
+use crate::http::BodyFile;
+use crate::utils::net::{read_available, read_up_to};
 use std::collections::HashMap;
 use std::process::{Command, Stdio};
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::sync::Arc;
+use std::time::Duration;
 use libc::{fcntl, F_SETFL, O_NONBLOCK};
 
 pub struct CgiHandler {
-    pub timeout_seconds: u64,
+    pub timeout: Duration,
 }
 
 #[derive(Debug, Clone)]
 pub struct CgiRequest {
-    pub script_path: String,
+    pub script_path: String,  // Absolute filesystem path (SCRIPT_FILENAME)
+    pub script_name: String,  // URI path of the script, e.g. "/cgi-bin/app.py"
+    pub path_info: String,    // URI remainder after the script, e.g. "/users/42"
+    pub document_root: String,
     pub method: String,
     pub uri: String,
     pub query_string: String,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    pub body_file: Option<Arc<BodyFile>>, // Spooled body, given to the script as its stdin
+    pub content_length: u64, // May exceed `body` while the body is still being streamed in
+    pub server_name: String,
+    pub server_port: String,
     pub remote_addr: String,
+    pub remote_port: Option<u16>,
     pub cgi_pass: Option<String>,
 }
 
+/// Where a CGI URI landed on disk: `/cgi-bin/app.py/users/42` resolves to the
+/// script `<root>/cgi-bin/app.py` with PATH_INFO `/users/42`.
+#[derive(Debug)]
+pub struct ScriptLocation {
+    pub script_path: PathBuf,
+    pub script_name: String,
+    pub path_info: String,
+}
+
 #[derive(Debug)]
 pub struct CgiResponse {
     pub status: u16,
@@ -39,89 +61,59 @@ pub struct CgiProcess {
 impl CgiHandler {
     pub fn new() -> Self {
         Self {
-            timeout_seconds: 30,
+            timeout: Duration::from_secs(30),
         }
     }
 
-    pub fn execute(&self, request: CgiRequest) -> Result<CgiResponse, Box<dyn std::error::Error>> {
-        if !Path::new(&request.script_path).exists() {
-            return Err("CGI script not found".into());
+    pub fn with_timeout(timeout: Option<Duration>) -> Self {
+        let mut handler = Self::new();
+        if let Some(timeout) = timeout {
+            handler.timeout = timeout;
         }
+        handler
+    }
 
-        let env_vars = self.build_environment(&request);
-        
-        let mut command = if let Some(interpreter) = &request.cgi_pass {
-            let mut cmd = Command::new(interpreter);
-            cmd.arg(&request.script_path);
-            cmd
-        } else {
-            Command::new(&request.script_path)
-        };
-
-        let mut child = command
-            .envs(&env_vars)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+    /// Find the script for `uri` under `root`: the shortest path prefix (on a `/` boundary)
+    /// that is a regular file, and matches `extension` when one is configured.
+    /// The script must stay inside `root` after resolving symlinks and `..`.
+    pub fn resolve_script(root: &Path, uri: &str, extension: Option<&str>) -> Option<ScriptLocation> {
+        let canonical_root = root.canonicalize().ok()?;
+        let boundaries = uri.match_indices('/').map(|(i, _)| i).skip(1).chain(std::iter::once(uri.len()));
 
-        // --- NON-BLOCKING CGI I/O SUGGESTION ---
-        // To be fully non-blocking and epoll-compliant:
-        // 1. Set child.stdin, child.stdout, and child.stderr to non-blocking mode using libc::fcntl.
-        //    Example:
-        //    use std::os::unix::io::AsRawFd;
-        //    use libc::{fcntl, F_SETFL, O_NONBLOCK};
-        //    let fd = child.stdout.as_ref().unwrap().as_raw_fd();
-        //    unsafe { fcntl(fd, F_SETFL, O_NONBLOCK); }
-        // 2. Register these fds with your epoll manager.
-        // 3. Integrate CGI I/O into your event loop, reading/writing only when epoll signals readiness.
-        // 4. Avoid wait_with_output (which is blocking); instead, poll for process completion and I/O readiness.
-        //
-        // For now, the following is blocking and should be refactored for full compliance:
-
-        // Write request body to stdin if present
-        if !request.body.is_empty() {
-            if let Some(stdin) = child.stdin.as_mut() {
-                match stdin.write_all(&request.body) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        log::error!("Failed to write to CGI script stdin: {}", e);
-                        return Err(format!("Failed to write to CGI script stdin: {}", e).into());
-                    }
+        for end in boundaries {
+            let script_name = &uri[..end];
+            if let Some(ext) = extension {
+                if !script_name.ends_with(ext) {
+                    continue;
                 }
             }
-        }
-
-        // Wait for the process to complete (blocking!)
-        let output = match child.wait_with_output() {
-            Ok(out) => out,
-            Err(e) => {
-                log::error!("Failed to wait for CGI script output: {}", e);
-                return Err(format!("Failed to wait for CGI script output: {}", e).into());
+            let candidate = root.join(script_name.trim_start_matches('/'));
+            if !candidate.is_file() {
+                continue;
             }
-        };
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            log::error!("CGI script failed: {}", stderr);
-            return Err(format!("CGI script failed: {}", stderr).into());
-        }
-
-        match self.parse_cgi_output(&output.stdout) {
-            Ok(resp) => Ok(resp),
-            Err(e) => {
-                log::error!("Failed to parse CGI output: {}", e);
-                Err(e)
+            let script_path = candidate.canonicalize().ok()?;
+            if !script_path.starts_with(&canonical_root) {
+                return None;
             }
+            return Some(ScriptLocation {
+                script_path,
+                script_name: script_name.to_string(),
+                path_info: uri[end..].to_string(),
+            });
         }
+        None
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
     }
 
-    pub fn start_nonblocking(&self, request: CgiRequest) -> Result<CgiProcess, Box<dyn std::error::Error>> {
+    pub fn start_nonblocking(&self, request: &CgiRequest) -> Result<CgiProcess, Box<dyn std::error::Error>> {
         if !Path::new(&request.script_path).exists() {
             return Err("CGI script not found".into());
         }
 
-        let env_vars = self.build_environment(&request);
+        let env_vars = self.build_environment(request);
         
         let mut command = if let Some(interpreter) = &request.cgi_pass {
             let mut cmd = Command::new(interpreter);
@@ -131,11 +123,18 @@ impl CgiHandler {
             Command::new(&request.script_path)
         };
 
-        let mut child = command
+        // Run the script in its own process group so a timeout can kill
+        // everything it spawned, not just the interpreter
+        let stdin = match &request.body_file {
+            Some(body) => Stdio::from(body.open()?),
+            None => Stdio::piped(),
+        };
+        let child = command
             .envs(&env_vars)
-            .stdin(Stdio::piped())
+            .stdin(stdin)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .process_group(0)
             .spawn()?;
 
         // Set pipes to non-blocking
@@ -160,18 +159,33 @@ impl CgiHandler {
         })
     }
 
-    fn build_environment(&self, request: &CgiRequest) -> HashMap<String, String> {
+    pub fn build_environment(&self, request: &CgiRequest) -> HashMap<String, String> {
         let mut env = HashMap::new();
 
         // Standard CGI environment variables
         env.insert("REQUEST_METHOD".to_string(), request.method.clone());
         env.insert("REQUEST_URI".to_string(), request.uri.clone());
         env.insert("QUERY_STRING".to_string(), request.query_string.clone());
-        env.insert("CONTENT_LENGTH".to_string(), request.body.len().to_string());
+        env.insert("CONTENT_LENGTH".to_string(), request.content_length.to_string());
         env.insert("REMOTE_ADDR".to_string(), request.remote_addr.clone());
+        if let Some(port) = request.remote_port {
+            env.insert("REMOTE_PORT".to_string(), port.to_string());
+        }
         env.insert("SERVER_SOFTWARE".to_string(), "webserv/1.0".to_string());
         env.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
         env.insert("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string());
+        env.insert("SERVER_NAME".to_string(), request.server_name.clone());
+        env.insert("SERVER_PORT".to_string(), request.server_port.clone());
+
+        // Script location (RFC 3875 4.1.5, 4.1.6, 4.1.13)
+        env.insert("SCRIPT_NAME".to_string(), request.script_name.clone());
+        env.insert("SCRIPT_FILENAME".to_string(), request.script_path.clone());
+        env.insert("DOCUMENT_ROOT".to_string(), request.document_root.clone());
+        env.insert("PATH_INFO".to_string(), request.path_info.clone());
+        if !request.path_info.is_empty() {
+            let translated = Path::new(&request.document_root).join(request.path_info.trim_start_matches('/'));
+            env.insert("PATH_TRANSLATED".to_string(), translated.to_string_lossy().into_owned());
+        }
 
         // Add HTTP headers as environment variables
         for (name, value) in &request.headers {
@@ -187,47 +201,226 @@ impl CgiHandler {
         env
     }
 
-    pub fn parse_cgi_output(&self, output: &[u8]) -> Result<CgiResponse, Box<dyn std::error::Error>> {
-        let output_str = String::from_utf8_lossy(output);
-        
-        // Find the separator between headers and body
-        if let Some(separator_pos) = output_str.find("\r\n\r\n") {
-            let headers_part = &output_str[..separator_pos];
-            let body_part = &output_str[separator_pos + 4..];
-            
-            let mut headers = HashMap::new();
-            let mut status = 200;
-            
-            for line in headers_part.lines() {
-                if let Some(colon_pos) = line.find(':') {
-                    let name = line[..colon_pos].trim().to_lowercase();
-                    let value = line[colon_pos + 1..].trim().to_string();
-                    
-                    if name == "status" {
-                        if let Some(space_pos) = value.find(' ') {
-                            if let Ok(status_code) = value[..space_pos].parse::<u16>() {
-                                status = status_code;
-                            }
-                        }
+    /// Parse a CGI header block (without the terminating blank line, RFC 3875 section 6.2).
+    /// The `Status` header sets the response status and is not passed on. A `Location`
+    /// holding a local path without `Status` is a local redirect; an absolute URL
+    /// without `Status` becomes a 302.
+    pub fn parse_cgi_headers(header_block: &[u8]) -> CgiOutput {
+        let headers_part = String::from_utf8_lossy(header_block);
+        let mut headers = HashMap::new();
+        let mut status = 200;
+        let mut status_given = false;
+
+        for line in headers_part.lines() {
+            if let Some(colon_pos) = line.find(':') {
+                let name = line[..colon_pos].trim().to_lowercase();
+                let value = line[colon_pos + 1..].trim().to_string();
+
+                if name == "status" {
+                    // "Status: 404 Not Found" or just "Status: 404"
+                    if let Some(Ok(status_code)) = value.split_whitespace().next().map(str::parse::<u16>) {
+                        status = status_code;
+                        status_given = true;
+                    }
+                } else {
+                    headers.insert(name, value);
+                }
+            }
+        }
+
+        if !status_given {
+            match headers.get("location") {
+                Some(location) if location.starts_with('/') && !location.starts_with("//") => {
+                    return CgiOutput::LocalRedirect(location.clone());
+                }
+                Some(_) => status = 302,
+                None => {}
+            }
+        }
+
+        CgiOutput::Headers(CgiResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    /// Non-Parsed-Header scripts ("nph-" prefix) write the complete HTTP response themselves.
+    pub fn is_nph_script(script_path: &str) -> bool {
+        Path::new(script_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("nph-"))
+    }
+}
+
+/// A piece of CGI output, ready to be relayed to the client.
+#[derive(Debug)]
+pub enum CgiOutput {
+    /// The parsed header block (the body is always empty)
+    Headers(CgiResponse),
+    Body(Vec<u8>),
+    /// Output of an nph- script, passed to the client untouched
+    Raw(Vec<u8>),
+    /// The script asked the server to serve this local path instead
+    LocalRedirect(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum OutputState {
+    Headers,
+    Body,
+    Raw,
+    /// After a local redirect; the script must not send a body, anything it does is dropped
+    Discard,
+}
+
+/// Incrementally splits a script's stdout into its header block and body, so
+/// headers can be sent as soon as they are complete and the body streamed.
+#[derive(Debug)]
+pub struct CgiOutputParser {
+    state: OutputState,
+    header_buffer: Vec<u8>,
+}
+
+impl CgiOutputParser {
+    const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+    pub fn new(nph: bool) -> Self {
+        Self {
+            state: if nph { OutputState::Raw } else { OutputState::Headers },
+            header_buffer: Vec::new(),
+        }
+    }
+
+    pub fn headers_done(&self) -> bool {
+        self.state != OutputState::Headers
+    }
+
+    /// Feed the next bytes of stdout.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<CgiOutput>, Box<dyn std::error::Error>> {
+        let mut output = Vec::new();
+        if data.is_empty() {
+            return Ok(output);
+        }
+        match self.state {
+            OutputState::Raw => output.push(CgiOutput::Raw(data.to_vec())),
+            OutputState::Body => output.push(CgiOutput::Body(data.to_vec())),
+            OutputState::Discard => {}
+            OutputState::Headers => {
+                self.header_buffer.extend_from_slice(data);
+                if let Some((header_len, body_start)) = Self::find_header_end(&self.header_buffer) {
+                    let headers = CgiHandler::parse_cgi_headers(&self.header_buffer[..header_len]);
+                    if let CgiOutput::LocalRedirect(_) = headers {
+                        self.state = OutputState::Discard;
+                        output.push(headers);
                     } else {
-                        headers.insert(name, value);
+                        output.push(headers);
+                        if body_start < self.header_buffer.len() {
+                            output.push(CgiOutput::Body(self.header_buffer[body_start..].to_vec()));
+                        }
+                        self.state = OutputState::Body;
                     }
+                    self.header_buffer = Vec::new();
+                } else if self.header_buffer.len() > Self::MAX_HEADER_SIZE {
+                    return Err("CGI header block too large".into());
                 }
             }
-            
-            Ok(CgiResponse {
-                status,
-                headers,
-                body: body_part.as_bytes().to_vec(),
-            })
-        } else {
-            // No headers separator found, treat entire output as body
-            Ok(CgiResponse {
+        }
+        Ok(output)
+    }
+
+    /// Stdout reached EOF. Output that never completed its header block is sent as the body.
+    pub fn finish(&mut self) -> Vec<CgiOutput> {
+        let mut output = Vec::new();
+        if self.state == OutputState::Headers {
+            output.push(CgiOutput::Headers(CgiResponse {
                 status: 200,
                 headers: HashMap::new(),
-                body: output.to_vec(),
-            })
+                body: Vec::new(),
+            }));
+            if !self.header_buffer.is_empty() {
+                output.push(CgiOutput::Body(std::mem::take(&mut self.header_buffer)));
+            }
+            self.state = OutputState::Body;
+        }
+        output
+    }
+
+    /// Find the blank line ending the header block; RFC 3875 allows bare LF line endings.
+    /// Returns (length of the header lines, offset of the body).
+    fn find_header_end(buffer: &[u8]) -> Option<(usize, usize)> {
+        let mut line_start = 0;
+        while let Some(newline) = buffer[line_start..].iter().position(|&b| b == b'\n') {
+            let line_end = line_start + newline;
+            let line = &buffer[line_start..line_end];
+            if line.is_empty() || line == b"\r" {
+                return Some((line_start, line_end + 1));
+            }
+            line_start = line_end + 1;
+        }
+        None
+    }
+}
+
+impl CgiProcess {
+    /// Write as much of `data` to the script's stdin as the pipe accepts right now.
+    pub fn write_stdin(&mut self, data: &[u8]) -> io::Result<usize> {
+        let stdin = self.child.stdin.as_mut().ok_or(io::ErrorKind::BrokenPipe)?;
+        let mut written = 0;
+        while written < data.len() {
+            match stdin.write(&data[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(written)
+    }
+
+    /// Read the script's stdout into `buf`, up to `limit` bytes. Returns `Ok(true)` once
+    /// the script closed it.
+    pub fn read_stdout(&mut self, buf: &mut Vec<u8>, limit: usize) -> io::Result<bool> {
+        match self.child.stdout.as_mut() {
+            Some(stdout) => read_up_to(stdout, buf, limit),
+            None => Ok(true),
+        }
+    }
+
+    /// Drain the script's stderr into `buf`. Returns `Ok(true)` once the script closed it.
+    pub fn read_stderr(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        match self.child.stderr.as_mut() {
+            // Pipes are registered edge-triggered, so read until the pipe would block
+            Some(stderr) => read_available(stderr, buf),
+            None => Ok(true),
+        }
+    }
+
+    /// Close one of the child's pipes (the caller deregisters it from epoll first).
+    pub fn close_pipe(&mut self, fd: RawFd) {
+        if self.stdin_fd == Some(fd) {
+            drop(self.child.stdin.take());
+            self.stdin_fd = None;
+        } else if self.stdout_fd == Some(fd) {
+            drop(self.child.stdout.take());
+            self.stdout_fd = None;
+        } else if self.stderr_fd == Some(fd) {
+            drop(self.child.stderr.take());
+            self.stderr_fd = None;
+        }
+    }
+
+    /// Send `signal` to the script's whole process group.
+    pub fn signal_group(&self, signal: libc::c_int) {
+        let pgid = self.child.id() as libc::pid_t;
+        unsafe { libc::kill(-pgid, signal); }
+    }
+
+    /// File descriptors of the pipes that are still open.
+    pub fn open_fds(&self) -> Vec<RawFd> {
+        [self.stdin_fd, self.stdout_fd, self.stderr_fd].into_iter().flatten().collect()
     }
 }
 
@@ -0,0 +1,406 @@
+use crate::config::CompressionConfig;
+use crate::http::response::BodyPart;
+use crate::http::{HttpResponse, StatusCode};
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+
+/// Types compressed when a location lists none.
+const DEFAULT_TYPES: [&str; 7] = [
+    "text/html", "text/plain", "text/css", "text/javascript",
+    "application/javascript", "application/json", "image/svg+xml",
+];
+
+/// Bodies shorter than this (in bytes) gain too little to be worth compressing.
+const DEFAULT_MIN_LENGTH: usize = 256;
+
+/// File bodies are read into memory to be compressed; larger files keep being sent
+/// as they are, with sendfile.
+const MAX_FILE_LENGTH: u64 = 8 * 1024 * 1024;
+
+/// A content coding the server can produce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// In order of preference when a client rates several of them equally.
+    pub const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The suffix of a file precompressed with this coding, e.g. `app.js.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+/// The coding of those `available` to use for a client's `Accept-Encoding`: the one with
+/// the highest q-value, or none if the client accepts none of them (RFC 9110 section 12.5.3).
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut codings = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q=").or_else(|| param.trim().strip_prefix("Q=")))
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .next()
+            .unwrap_or(1.0);
+        codings.push((coding, q));
+    }
+    let q_of = |encoding: Encoding| {
+        let named = codings.iter().find(|(coding, _)| {
+            coding == encoding.token() || (encoding == Encoding::Gzip && coding == "x-gzip")
+        });
+        named.or_else(|| codings.iter().find(|(coding, _)| coding == "*")).map_or(0.0, |(_, q)| *q)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in Encoding::PREFERRED.iter().filter(|encoding| available.contains(encoding)) {
+        let q = q_of(encoding);
+        match best {
+            Some((_, best_q)) if best_q >= q => {}
+            _ if q > 0.0 => best = Some((encoding, q)),
+            _ => {}
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether `config` lets the response be compressed: a status with a full body, a
+/// listed type, no coding of its own and at least `compression_min_length` bytes
+/// (a streamed body of unknown length qualifies).
+fn is_compressible(config: &CompressionConfig, response: &HttpResponse) -> bool {
+    if !config.enabled.unwrap_or(false) {
+        return false;
+    }
+    let code = response.status as u16;
+    if code < 200 || matches!(response.status, StatusCode::NoContent | StatusCode::PartialContent | StatusCode::NotModified) {
+        return false;
+    }
+    if response.get_header("content-encoding").is_some()
+        || response.get_header("cache-control").is_some_and(|v| v.to_ascii_lowercase().contains("no-transform"))
+    {
+        return false;
+    }
+    let content_type = match response.get_header("content-type") {
+        Some(value) => value.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+        None => return false,
+    };
+    let listed = match &config.types {
+        Some(types) => types.iter().any(|t| t == "*" || *t == content_type),
+        None => DEFAULT_TYPES.contains(&content_type.as_str()),
+    };
+    let min_length = config.min_length.unwrap_or(DEFAULT_MIN_LENGTH);
+    listed && response.get_header("content-length").and_then(|v| v.parse::<usize>().ok()).is_none_or(|len| len >= min_length)
+}
+
+/// The coding for a response to a client sending `accept_encoding`, if `config` lets it
+/// be compressed. Such a response varies by `Accept-Encoding` whatever the client sent,
+/// so caches keep the encoded and plain versions apart.
+pub fn choose(accept_encoding: Option<&str>, config: &CompressionConfig, response: &mut HttpResponse) -> Option<Encoding> {
+    if !is_compressible(config, response) {
+        return None;
+    }
+    vary_by_encoding(response);
+    negotiate(accept_encoding?, &Encoding::PREFERRED)
+}
+
+/// Add `Accept-Encoding` to the response's `Vary` header.
+pub fn vary_by_encoding(response: &mut HttpResponse) {
+    let vary = match response.get_header("vary") {
+        Some(vary) if vary.split(',').any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding")) => return,
+        Some(vary) => format!("{}, Accept-Encoding", vary),
+        None => "Accept-Encoding".to_string(),
+    };
+    response.set_header("Vary", &vary);
+}
+
+/// Label a response as encoded: its length changes, and its entity tag now names a
+/// representation that is equivalent to the file but not byte-identical.
+pub fn mark_encoded(response: &mut HttpResponse, encoding: Encoding) {
+    response.set_header("Content-Encoding", encoding.token());
+    response.headers.remove("content-length");
+    if let Some(etag) = response.get_header("etag").filter(|etag| !etag.starts_with("W/")) {
+        let weak = format!("W/{}", etag);
+        response.set_header("ETag", &weak);
+    }
+}
+
+/// Compress a complete response for a client sending `accept_encoding`, if `config`
+/// allows it.
+pub fn compress_response(accept_encoding: Option<&str>, config: &CompressionConfig, response: &mut HttpResponse) {
+    if response.body_parts.iter().map(BodyPart::len).sum::<u64>() > MAX_FILE_LENGTH {
+        return;
+    }
+    let encoding = match choose(accept_encoding, config, response) {
+        Some(encoding) => encoding,
+        None => return,
+    };
+    match encode_body(response, encoding) {
+        Ok(body) => {
+            mark_encoded(response, encoding);
+            response.set_body(&body);
+        }
+        Err(e) => log::warn!("Failed to compress response with {}: {}", encoding.token(), e),
+    }
+}
+
+fn encode_body(response: &HttpResponse, encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding)?;
+    let mut out = encoder.write(&response.body, false)?;
+    for part in &response.body_parts {
+        match part {
+            BodyPart::Bytes(bytes) => out.extend_from_slice(&encoder.write(bytes, false)?),
+            BodyPart::File(region) => {
+                let mut chunk = vec![0u8; 64 * 1024];
+                let mut offset = region.offset;
+                let end = region.offset + region.len;
+                while offset < end {
+                    let want = chunk.len().min((end - offset) as usize);
+                    let n = region.file.read_at(&mut chunk[..want], offset)?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ended before its length"));
+                    }
+                    out.extend_from_slice(&encoder.write(&chunk[..n], false)?);
+                    offset += n as u64;
+                }
+            }
+        }
+    }
+    out.extend_from_slice(&encoder.finish()?);
+    Ok(out)
+}
+
+/// A streaming compressor. Output is handed back as it is produced.
+pub enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl std::fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoding = match self {
+            Encoder::Brotli(_) => Encoding::Brotli,
+            Encoder::Zstd(_) => Encoding::Zstd,
+            Encoder::Gzip(_) => Encoding::Gzip,
+        };
+        write!(f, "Encoder({})", encoding.token())
+    }
+}
+
+impl Encoder {
+    /// Levels suited to compressing on the fly rather than the smallest output.
+    pub fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        })
+    }
+
+    /// Compress `data` and return the output produced so far. With `flush` that output
+    /// decodes to everything written, for bodies relayed as they are produced.
+    pub fn write(&mut self, data: &[u8], flush: bool) -> io::Result<Vec<u8>> {
+        let writer: &mut dyn Write = match self {
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Gzip(encoder) => encoder,
+        };
+        writer.write_all(data)?;
+        if flush {
+            writer.flush()?;
+        }
+        let output = match self {
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+        };
+        Ok(std::mem::take(output))
+    }
+
+    /// End the stream and return the rest of the output.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const ALL: [Encoding; 3] = Encoding::PREFERRED;
+
+    /// Something done to a response before it is offered for compression.
+    type Change = fn(&mut HttpResponse);
+
+    fn enabled() -> CompressionConfig {
+        CompressionConfig { enabled: Some(true), ..CompressionConfig::default() }
+    }
+
+    /// A compressible 200 response with a strong entity tag.
+    fn html_response() -> HttpResponse {
+        let mut response = HttpResponse::ok();
+        response.set_header("Content-Type", "text/html; charset=utf-8");
+        response.set_header("ETag", "\"abc\"");
+        response.set_body("<p>hello, hello, hello</p>\n".repeat(40).as_bytes());
+        response
+    }
+
+    /// Everything a decoder yields from `data` before it runs out of input.
+    fn decode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut reader: Box<dyn Read + '_> = match encoding {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(data).unwrap()),
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+        };
+        let mut out = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn negotiate_takes_the_highest_q_value() {
+        assert_eq!(negotiate("gzip, br", &ALL), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5", &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.2, zstd;q=0.8, gzip;q=0.5", &ALL), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip, br", &[Encoding::Gzip]), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZIP; Q=0.5, br;Q=0.1", &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, identity", &ALL), None);
+        assert_eq!(negotiate("", &ALL), None);
+    }
+
+    #[test]
+    fn negotiate_refuses_codings_rated_zero() {
+        assert_eq!(negotiate("br;q=0, gzip", &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, zstd;q=0, gzip;q=0", &ALL), None);
+        assert_eq!(negotiate("gzip;q=0.000", &ALL), None);
+        assert_eq!(negotiate("gzip;q=bogus", &ALL), None);
+    }
+
+    #[test]
+    fn negotiate_understands_the_wildcard_and_x_gzip() {
+        assert_eq!(negotiate("*", &ALL), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*", &[Encoding::Gzip]), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *;q=0.5", &ALL), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=0.1, *;q=0.5", &[Encoding::Gzip]), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0", &ALL), None);
+        assert_eq!(negotiate("x-gzip", &ALL), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn compress_response_encodes_and_weakens_the_entity_tag() {
+        let mut response = html_response();
+        let plain = response.body.clone();
+        compress_response(Some("gzip"), &enabled(), &mut response);
+
+        assert_eq!(response.get_header("content-encoding").map(String::as_str), Some("gzip"));
+        assert_eq!(response.get_header("etag").map(String::as_str), Some("W/\"abc\""));
+        assert_eq!(response.get_header("vary").map(String::as_str), Some("Accept-Encoding"));
+        assert_eq!(response.get_header("content-length"), Some(&response.body.len().to_string()));
+        assert!(response.body.len() < plain.len());
+        assert_eq!(decode(Encoding::Gzip, &response.body), plain);
+
+        // An entity tag that is already weak stays as it is
+        let mut response = html_response();
+        response.set_header("ETag", "W/\"abc\"");
+        compress_response(Some("br"), &enabled(), &mut response);
+        assert_eq!(response.get_header("etag").map(String::as_str), Some("W/\"abc\""));
+    }
+
+    #[test]
+    fn compress_response_leaves_responses_it_must_not_touch() {
+        let cases: [(&str, Change); 6] = [
+            ("disabled", |_| {}),
+            ("206", |r| r.status = StatusCode::PartialContent),
+            ("304", |r| r.status = StatusCode::NotModified),
+            ("no-transform", |r| r.set_header("Cache-Control", "public, No-Transform")),
+            ("already encoded", |r| r.set_header("Content-Encoding", "br")),
+            ("unlisted type", |r| r.set_header("Content-Type", "image/png")),
+        ];
+        for (name, change) in cases {
+            let mut response = html_response();
+            change(&mut response);
+            let before = response.body.clone();
+            let config = if name == "disabled" { CompressionConfig::default() } else { enabled() };
+            compress_response(Some("gzip, br, zstd"), &config, &mut response);
+
+            assert_eq!(response.body, before, "{}", name);
+            assert_eq!(response.get_header("etag").map(String::as_str), Some("\"abc\""), "{}", name);
+            assert!(response.get_header("vary").is_none(), "{}", name);
+            assert_ne!(response.get_header("content-encoding").map(String::as_str), Some("gzip"), "{}", name);
+        }
+
+        // Too short to be worth it
+        let mut response = html_response();
+        response.set_body(b"<p>hi</p>");
+        compress_response(Some("gzip"), &enabled(), &mut response);
+        assert_eq!(response.body, b"<p>hi</p>");
+    }
+
+    #[test]
+    fn compressible_responses_vary_even_when_sent_plain() {
+        let mut response = html_response();
+        response.set_header("Vary", "Origin");
+        compress_response(Some("identity"), &enabled(), &mut response);
+        assert!(response.get_header("content-encoding").is_none());
+        assert_eq!(response.get_header("vary").map(String::as_str), Some("Origin, Accept-Encoding"));
+
+        let mut response = html_response();
+        compress_response(None, &enabled(), &mut response);
+        assert_eq!(response.get_header("vary").map(String::as_str), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn encoders_round_trip_and_flush_what_was_written() {
+        let chunks: [&[u8]; 3] = [b"first chunk, ", &[0u8, 255, 10, 13], b" and the last one"];
+        for encoding in ALL {
+            let mut encoder = Encoder::new(encoding).unwrap();
+            let mut out = Vec::new();
+            let mut written = Vec::new();
+            for chunk in chunks {
+                out.extend_from_slice(&encoder.write(chunk, true).unwrap());
+                written.extend_from_slice(chunk);
+                assert_eq!(decode(encoding, &out), written, "{} after a flush", encoding.token());
+            }
+            out.extend_from_slice(&encoder.finish().unwrap());
+            assert_eq!(decode(encoding, &out), written, "{}", encoding.token());
+
+            // Without flushing, the whole body still comes out at the end
+            let mut encoder = Encoder::new(encoding).unwrap();
+            let body = "abc".repeat(10_000);
+            let mut out = encoder.write(body.as_bytes(), false).unwrap();
+            out.extend_from_slice(&encoder.finish().unwrap());
+            assert_eq!(decode(encoding, &out), body.as_bytes(), "{}", encoding.token());
+        }
+    }
+}
@@ -1,21 +1,279 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub servers: Vec<ServerConfig>,
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    pub cache_zones: HashMap<String, CacheZoneConfig>,
+}
+
+/// A top-level `cache_zone` line: `cache_zone reports path=/var/cache/webserv memory=16M max_size=1G;`
+#[derive(Debug, Clone)]
+pub struct CacheZoneConfig {
+    pub name: String,
+    pub path: Option<PathBuf>, // Without a path the zone lives in memory only
+    pub memory: usize, // Bytes of entries kept in memory
+    pub max_size: usize, // Bytes of entries kept on disk
+}
+
+/// How an upstream group picks a server for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceMethod {
+    RoundRobin, // Weighted round-robin, the default
+    LeastConn,
+    IpHash,
+}
+
+/// What happens to an upload whose filename is already taken (`upload_conflict`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadConflict {
+    Rename, // Store it as name-1.ext, name-2.ext, ...; the default
+    Overwrite,
+    Reject, // Fail the upload with 409 Conflict
+}
+
+/// One `server` line of an `upstream` block.
+#[derive(Debug, Clone)]
+pub struct UpstreamServer {
+    pub addr: ListenAddr,
+    pub weight: u32,
+    pub max_fails: u32, // Failures within fail_timeout before the server is marked down; 0 disables
+    pub fail_timeout: Duration, // Both the failure window and how long the server stays down
+}
+
+/// Active checks of an upstream group's servers, from its `health_check` line.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub uri: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub statuses: Vec<u16>, // Expected status codes; any 2xx or 3xx when empty
+    pub rise: u32, // Consecutive passes before a failing server takes traffic again
+    pub fall: u32, // Consecutive failures before a server is taken out
+}
+
+impl HealthCheck {
+    pub fn accepts(&self, status: u16) -> bool {
+        if self.statuses.is_empty() {
+            (200..400).contains(&status)
+        } else {
+            self.statuses.contains(&status)
+        }
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            uri: "/".to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            statuses: Vec::new(),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// A named group of interchangeable backends: `upstream backend { server 127.0.0.1:3000; }`.
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub method: BalanceMethod,
+    pub servers: Vec<UpstreamServer>,
+    pub health_check: Option<HealthCheck>,
+}
+
+impl UpstreamConfig {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            method: BalanceMethod::RoundRobin,
+            servers: Vec::new(),
+            health_check: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-    pub listen: u16,
-    pub server_name: String,
+    pub listen: Vec<Listen>,
+    pub server_names: Vec<String>,
     pub client_max_body_size: usize,
     pub error_pages: HashMap<u16, String>,
+    pub compression: CompressionConfig, // Inherited by locations that don't set their own
     pub routes: Vec<RouteConfig>,
 }
 
+/// `compression`, `compression_types`, `compression_min_length` and `compression_static`,
+/// in a server block or a location. Unset values fall back to the server's, then to the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionConfig {
+    pub enabled: Option<bool>,
+    pub types: Option<Vec<String>>, // MIME types to compress, `*` for any
+    pub min_length: Option<usize>,
+    pub static_files: Option<bool>, // Serve precompressed siblings of static files (app.js.br)
+}
+
+impl CompressionConfig {
+    fn inherit(&mut self, outer: &CompressionConfig) {
+        self.enabled = self.enabled.or(outer.enabled);
+        self.types = self.types.take().or_else(|| outer.types.clone());
+        self.min_length = self.min_length.or(outer.min_length);
+        self.static_files = self.static_files.or(outer.static_files);
+    }
+}
+
+/// Address a server block accepts connections on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// One `listen` directive.
+#[derive(Debug, Clone)]
+pub struct Listen {
+    pub addr: ListenAddr,
+    pub default_server: bool,
+}
+
+impl ListenAddr {
+    /// Parse the address part of a `listen` directive:
+    /// `8080`, `127.0.0.1`, `0.0.0.0:8080`, `*:8080`, `[::]:8080`, `localhost:8080`
+    /// or `unix:/run/webserv.sock`. A bare port binds only the loopback address;
+    /// `*:8080` or `0.0.0.0:8080` binds every IPv4 address.
+    /// Host names may resolve to several addresses, hence the Vec.
+    pub fn parse_all(value: &str) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        const DEFAULT_PORT: u16 = 80;
+
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("listen: empty unix socket path".into());
+            }
+            return Ok(vec![ListenAddr::Unix(PathBuf::from(path))]);
+        }
+        if let Ok(port) = value.parse::<u16>() {
+            return Ok(vec![ListenAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))]);
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(vec![ListenAddr::Tcp(addr)]);
+        }
+        if let Ok(ip) = value.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(vec![ListenAddr::Tcp(SocketAddr::new(ip, DEFAULT_PORT))]);
+        }
+
+        // "*:8080", "localhost:8080" or a bare host name
+        let (host, port) = match value.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("listen: invalid port in '{}'", value))?),
+            None => (value, DEFAULT_PORT),
+        };
+        if host == "*" {
+            return Ok(vec![ListenAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))]);
+        }
+        let mut addrs: Vec<ListenAddr> = Vec::new();
+        for addr in (host, port).to_socket_addrs()? {
+            let addr = ListenAddr::Tcp(addr);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        if addrs.is_empty() {
+            return Err(format!("listen: '{}' did not resolve to any address", value).into());
+        }
+        Ok(addrs)
+    }
+}
+
+impl ListenAddr {
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(addr.port()),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+/// Where a `proxy_pass` sends requests.
+#[derive(Debug, Clone)]
+pub enum ProxyTarget {
+    Addr(ListenAddr),
+    Upstream(String), // Name of an `upstream` block
+}
+
+/// Target of a `proxy_pass` directive, e.g. `http://127.0.0.1:3000/api/` or `http://backend`.
+#[derive(Debug, Clone)]
+pub struct ProxyPass {
+    pub target: ProxyTarget,
+    pub host: String,         // Sent as the upstream Host header
+    pub path: Option<String>, // Replaces the location prefix when present
+}
+
+impl ProxyPass {
+    /// The host is kept as a possible upstream name here; `resolve` decides once
+    /// every `upstream` block has been read, since they may come after the server.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let rest = value.strip_prefix("http://")
+            .ok_or_else(|| format!("proxy_pass: only http:// upstreams are supported, got '{}'", value))?;
+        let (host, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], Some(rest[pos..].to_string())),
+            None => (rest, None),
+        };
+        if host.is_empty() {
+            return Err(format!("proxy_pass: missing host in '{}'", value).into());
+        }
+        Ok(Self {
+            target: ProxyTarget::Upstream(host.to_string()),
+            host: host.to_string(),
+            path,
+        })
+    }
+
+    /// Turn a host that names no upstream block into an address.
+    fn resolve(&mut self, upstreams: &HashMap<String, UpstreamConfig>) -> Result<(), Box<dyn std::error::Error>> {
+        if let ProxyTarget::Upstream(name) = &self.target {
+            if !upstreams.contains_key(name) {
+                let addr = ListenAddr::parse_all(name)
+                    .map_err(|e| format!("proxy_pass '{}': {}", name, e))?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format!("proxy_pass: '{}' did not resolve", name))?;
+                self.target = ProxyTarget::Addr(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// The upstream request path for `uri`, which matched the location `prefix`.
+    pub fn upstream_path(&self, prefix: &str, uri: &str) -> String {
+        match &self.path {
+            Some(path) => {
+                let rest = uri.strip_prefix(prefix).unwrap_or(uri);
+                if path.ends_with('/') {
+                    format!("{}{}", path, rest.trim_start_matches('/'))
+                } else {
+                    format!("{}{}", path, rest)
+                }
+            }
+            None => uri.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerLocation {
     pub path: String,
@@ -41,20 +299,40 @@ pub struct RouteConfig {
     pub redirect: Option<(u16, String)>, // (status code, url)
     pub cgi_pass: Option<String>,
     pub cgi_extension: Option<String>,
+    pub cgi_timeout: Option<Duration>,
+    pub fastcgi_pass: Option<ListenAddr>, // FastCGI application address, e.g. 127.0.0.1:9000 or unix:/run/app.sock
+    pub proxy_pass: Option<ProxyPass>,
+    pub proxy_timeout: Option<Duration>,
+    pub upstream_status: bool, // Serve the upstream health report instead of files
+    pub proxy_cache: Option<String>, // Cache zone for proxied responses
+    pub cgi_cache: Option<String>, // Cache zone for CGI and FastCGI responses
+    pub cache_valid: Option<Duration>, // Lifetime of responses that don't state one
+    pub cache_stale: Option<Duration>, // How long an expired entry is served while it is refreshed
     pub upload_store: Option<String>,
+    pub upload_conflict: UploadConflict,
+    pub upload_uuid_names: bool, // Store uploads under generated names, keeping the extension
+    pub tus: bool, // Accept resumable uploads (tus protocol) into upload_store
+    pub dav: bool, // Share upload_store over WebDAV
+    pub compression: CompressionConfig,
     pub default_file: Option<String>,
 }
 
 impl RouteConfig {
     pub fn is_cgi_request(&self, uri: &str) -> bool {
-        if self.cgi_pass.is_some() {
-            if let Some(ext) = &self.cgi_extension {
-                return uri.ends_with(ext);
-            }
-            // If cgi_pass is set but no extension, any request to this route is CGI
-            return true;
+        self.cgi_pass.is_some() && self.matches_cgi_extension(uri)
+    }
+
+    pub fn is_fastcgi_request(&self, uri: &str) -> bool {
+        self.fastcgi_pass.is_some() && self.matches_cgi_extension(uri)
+    }
+
+    fn matches_cgi_extension(&self, uri: &str) -> bool {
+        if let Some(ext) = &self.cgi_extension {
+            // Any path segment may be the script: "/cgi-bin/app.py/users/42"
+            return uri.split('/').any(|segment| segment.ends_with(ext.as_str()));
         }
-        false
+        // Without an extension, any request to this route is handled by the script
+        true
     }
 }
 
@@ -69,7 +347,21 @@ impl RouteConfig {
             redirect: None,
             cgi_pass: None,
             cgi_extension: None,
+            cgi_timeout: None,
+            fastcgi_pass: None,
+            proxy_pass: None,
+            proxy_timeout: None,
+            upstream_status: false,
+            proxy_cache: None,
+            cgi_cache: None,
+            cache_valid: None,
+            cache_stale: None,
             upload_store: None,
+            upload_conflict: UploadConflict::Rename,
+            upload_uuid_names: false,
+            tus: false,
+            dav: false,
+            compression: CompressionConfig::default(),
             default_file: None,
         }
     }
@@ -83,8 +375,11 @@ impl Config {
 
     fn parse(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let mut servers = Vec::new();
+        let mut upstreams = HashMap::new();
+        let mut cache_zones = HashMap::new();
         let mut current_server: Option<ServerConfig> = None;
         let mut current_route: Option<RouteConfig> = None;
+        let mut current_upstream: Option<UpstreamConfig> = None;
         let mut brace_level = 0;
 
         for line in content.lines() {
@@ -100,6 +395,21 @@ impl Config {
                 continue;
             }
 
+            if line.starts_with("upstream ") && line.ends_with(" {") && brace_level == 0 {
+                // "upstream backend {"
+                if let Some(name) = line.split_whitespace().nth(1) {
+                    current_upstream = Some(UpstreamConfig::new(name.to_string()));
+                    brace_level = 1;
+                }
+                continue;
+            }
+
+            if line.starts_with("cache_zone ") && brace_level == 0 {
+                let zone = Self::parse_cache_zone(line)?;
+                cache_zones.insert(zone.name.clone(), zone);
+                continue;
+            }
+
             if line.starts_with("location ") && line.ends_with(" {") {
                 if let Some(path) = Self::extract_location_path(line) {
                     current_route = Some(RouteConfig::new(path));
@@ -118,14 +428,24 @@ impl Config {
                 } else if brace_level == 0 && current_server.is_some() {
                     // End of server block
                     if let Some(server) = current_server.take() {
-                        servers.push(server);
+                        servers.push(server.with_default_listen());
+                    }
+                } else if brace_level == 0 {
+                    // End of upstream block
+                    if let Some(upstream) = current_upstream.take() {
+                        if upstream.servers.is_empty() {
+                            return Err(format!("upstream '{}' has no servers", upstream.name).into());
+                        }
+                        upstreams.insert(upstream.name.clone(), upstream);
                     }
                 }
                 continue;
             }
 
             // Parse directives
-            if let Some(ref mut server) = current_server {
+            if let Some(ref mut upstream) = current_upstream {
+                Self::parse_upstream_directive(upstream, line)?;
+            } else if let Some(ref mut server) = current_server {
                 if brace_level == 1 {
                     // Server-level directive
                     Self::parse_server_directive(server, line)?;
@@ -140,10 +460,46 @@ impl Config {
 
         // Add any remaining server
         if let Some(server) = current_server {
-            servers.push(server);
+            servers.push(server.with_default_listen());
         }
 
-        Ok(Config { servers })
+        for server in servers.iter_mut() {
+            for route in server.routes.iter_mut() {
+                route.compression.inherit(&server.compression);
+            }
+        }
+        for route in servers.iter_mut().flat_map(|server| server.routes.iter_mut()) {
+            if let Some(proxy_pass) = &mut route.proxy_pass {
+                proxy_pass.resolve(&upstreams)?;
+            }
+            for zone in route.proxy_cache.iter().chain(route.cgi_cache.iter()) {
+                if !cache_zones.contains_key(zone) {
+                    return Err(format!("location {}: unknown cache zone '{}'", route.path, zone).into());
+                }
+            }
+        }
+
+        Ok(Config { servers, upstreams, cache_zones })
+    }
+
+    fn parse_cache_zone(line: &str) -> Result<CacheZoneConfig, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = line.split_whitespace().map(|s| s.trim_end_matches(';')).collect();
+        let name = parts.get(1).filter(|name| !name.contains('=')).ok_or("cache_zone: missing zone name")?;
+        let mut zone = CacheZoneConfig {
+            name: name.to_string(),
+            path: None,
+            memory: 16 * 1024 * 1024,
+            max_size: 256 * 1024 * 1024,
+        };
+        for param in &parts[2..] {
+            match param.split_once('=') {
+                Some(("path", value)) => zone.path = Some(PathBuf::from(value)),
+                Some(("memory", value)) => zone.memory = Self::parse_size(value)?,
+                Some(("max_size", value)) => zone.max_size = Self::parse_size(value)?,
+                _ => return Err(format!("cache_zone {}: unknown parameter '{}'", zone.name, param).into()),
+            }
+        }
+        Ok(zone)
     }
 
     fn extract_location_path(line: &str) -> Option<String> {
@@ -164,21 +520,92 @@ impl Config {
 
         match parts[0] {
             "listen" => {
-                let port_str = parts[1].trim_end_matches(';');
-                server.listen = port_str.parse()?;
+                let addr_str = parts[1].trim_end_matches(';');
+                // Optional flags after the address, e.g. "listen 8080 default_server;"
+                let default_server = parts[2..].iter().any(|flag| flag.trim_end_matches(';') == "default_server");
+                for addr in ListenAddr::parse_all(addr_str)? {
+                    server.listen.push(Listen { addr, default_server });
+                }
             }
             "server_name" => {
-                server.server_name = parts[1].trim_end_matches(';').to_string();
+                server.server_names = parts[1..]
+                    .iter()
+                    .map(|s| s.trim_end_matches(';').to_lowercase())
+                    .filter(|s| !s.is_empty())
+                    .collect();
             }
             "client_max_body_size" => {
                 let size_str = parts[1].trim_end_matches(';');
                 server.client_max_body_size = Self::parse_size(size_str)?;
             }
-            "error_page" => {
-                if parts.len() >= 3 {
-                    let status: u16 = parts[1].parse()?;
-                    let page = parts[2].trim_end_matches(';').to_string();
-                    server.error_pages.insert(status, page);
+            "error_page" if parts.len() >= 3 => {
+                let status: u16 = parts[1].parse()?;
+                let page = parts[2].trim_end_matches(';').to_string();
+                server.error_pages.insert(status, page);
+            }
+            "compression" | "compression_types" | "compression_min_length" | "compression_static" => {
+                Self::parse_compression_directive(&mut server.compression, &parts)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn parse_upstream_directive(upstream: &mut UpstreamConfig, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = line.split_whitespace().map(|s| s.trim_end_matches(';')).collect();
+        if parts.is_empty() {
+            return Ok(());
+        }
+
+        match parts[0] {
+            "least_conn" => upstream.method = BalanceMethod::LeastConn,
+            "health_check" => {
+                // e.g. "health_check uri=/healthz interval=5s timeout=2s status=200,204 rise=2 fall=3;"
+                let mut check = HealthCheck::default();
+                for param in &parts[1..] {
+                    match param.split_once('=') {
+                        Some(("uri", value)) if value.starts_with('/') => check.uri = value.to_string(),
+                        Some(("interval", value)) => check.interval = Self::parse_duration(value)?,
+                        Some(("timeout", value)) => check.timeout = Self::parse_duration(value)?,
+                        Some(("status", value)) => {
+                            check.statuses = value.split(',').map(|code| code.parse()).collect::<Result<_, _>>()?;
+                        }
+                        Some(("rise", value)) => check.rise = value.parse()?,
+                        Some(("fall", value)) => check.fall = value.parse()?,
+                        _ => return Err(format!("upstream '{}': invalid health_check parameter '{}'", upstream.name, param).into()),
+                    }
+                }
+                if check.interval.is_zero() || check.rise == 0 || check.fall == 0 {
+                    return Err(format!("upstream '{}': health_check interval, rise and fall must be positive", upstream.name).into());
+                }
+                upstream.health_check = Some(check);
+            }
+            "ip_hash" => upstream.method = BalanceMethod::IpHash,
+            "server" => {
+                if parts.len() < 2 {
+                    return Err(format!("upstream '{}': server needs an address", upstream.name).into());
+                }
+                let mut weight = 1;
+                let mut max_fails = 1;
+                let mut fail_timeout = Duration::from_secs(10);
+                // Optional parameters, e.g. "server 127.0.0.1:3001 weight=3 max_fails=2 fail_timeout=30s;"
+                for param in &parts[2..] {
+                    match param.split_once('=') {
+                        Some(("weight", value)) => weight = value.parse()?,
+                        Some(("max_fails", value)) => max_fails = value.parse()?,
+                        Some(("fail_timeout", value)) => fail_timeout = Self::parse_duration(value)?,
+                        _ => return Err(format!("upstream '{}': unknown server parameter '{}'", upstream.name, param).into()),
+                    }
+                }
+                if weight == 0 {
+                    return Err(format!("upstream '{}': weight must be at least 1", upstream.name).into());
+                }
+                let addrs = ListenAddr::parse_all(parts[1])
+                    .map_err(|e| format!("upstream '{}' server '{}': {}", upstream.name, parts[1], e))?;
+                // A name resolving to several addresses adds each of them
+                for addr in addrs {
+                    upstream.servers.push(UpstreamServer { addr, weight, max_fails, fail_timeout });
                 }
             }
             _ => {}
@@ -201,44 +628,84 @@ impl Config {
                     .collect();
                 route.methods = methods;
             },
-            "root" => {
-                if parts.len() >= 2 {
-                    route.root = Some(parts[1].trim_end_matches(';').to_string());
-                }
+            "root" if parts.len() >= 2 => {
+                route.root = Some(parts[1].trim_end_matches(';').to_string());
             },
-            "index" => {
-                if parts.len() >= 2 {
-                    route.index = Some(parts[1].trim_end_matches(';').to_string());
-                }
+            "index" if parts.len() >= 2 => {
+                route.index = Some(parts[1].trim_end_matches(';').to_string());
             },
-            "autoindex" => {
-                if parts.len() >= 2 {
-                    route.autoindex = parts[1].trim_end_matches(';') == "on";
-                }
+            "autoindex" if parts.len() >= 2 => {
+                route.autoindex = parts[1].trim_end_matches(';') == "on";
             },
-            "return" => {
-                if parts.len() >= 3 {
-                    // Parse status code and URL
-                    if let Ok(code) = parts[1].parse::<u16>() {
-                        let url = parts[2].trim_end_matches(';').to_string();
-                        route.redirect = Some((code, url));
-                    }
+            "return" if parts.len() >= 3 => {
+                // Parse status code and URL
+                if let Ok(code) = parts[1].parse::<u16>() {
+                    let url = parts[2].trim_end_matches(';').to_string();
+                    route.redirect = Some((code, url));
                 }
             },
-            "cgi_pass" => {
-                if parts.len() >= 2 {
-                    route.cgi_pass = Some(parts[1].trim_end_matches(';').to_string());
-                }
+            "cgi_pass" if parts.len() >= 2 => {
+                route.cgi_pass = Some(parts[1].trim_end_matches(';').to_string());
             },
-            "cgi_extension" => {
-                if parts.len() >= 2 {
-                    route.cgi_extension = Some(parts[1].trim_end_matches(';').to_string());
-                }
+            "cgi_extension" if parts.len() >= 2 => {
+                route.cgi_extension = Some(parts[1].trim_end_matches(';').to_string());
             },
-            "upload_store" => {
-                if parts.len() >= 2 {
-                    route.upload_store = Some(parts[1].trim_end_matches(';').to_string());
-                }
+            "cgi_timeout" if parts.len() >= 2 => {
+                route.cgi_timeout = Some(Self::parse_duration(parts[1].trim_end_matches(';'))?);
+            },
+            "fastcgi_pass" if parts.len() >= 2 => {
+                let value = parts[1].trim_end_matches(';');
+                let addrs = ListenAddr::parse_all(value)
+                    .map_err(|e| format!("fastcgi_pass '{}': {}", value, e))?;
+                route.fastcgi_pass = addrs.into_iter().next();
+            },
+            "proxy_pass" if parts.len() >= 2 => {
+                route.proxy_pass = Some(ProxyPass::parse(parts[1].trim_end_matches(';'))?);
+            },
+            "proxy_timeout" if parts.len() >= 2 => {
+                route.proxy_timeout = Some(Self::parse_duration(parts[1].trim_end_matches(';'))?);
+            },
+            "proxy_cache" if parts.len() >= 2 => {
+                route.proxy_cache = Some(parts[1].trim_end_matches(';').to_string());
+            },
+            "cgi_cache" if parts.len() >= 2 => {
+                route.cgi_cache = Some(parts[1].trim_end_matches(';').to_string());
+            },
+            "cache_valid" if parts.len() >= 2 => {
+                route.cache_valid = Some(Self::parse_duration(parts[1].trim_end_matches(';'))?);
+            },
+            "cache_stale" if parts.len() >= 2 => {
+                route.cache_stale = Some(Self::parse_duration(parts[1].trim_end_matches(';'))?);
+            },
+            "upstream_status" if parts.len() >= 2 => {
+                route.upstream_status = parts[1].trim_end_matches(';') == "on";
+            },
+            "upload_store" if parts.len() >= 2 => {
+                route.upload_store = Some(parts[1].trim_end_matches(';').to_string());
+            },
+            "upload_conflict" if parts.len() >= 2 => {
+                route.upload_conflict = match parts[1].trim_end_matches(';') {
+                    "rename" => UploadConflict::Rename,
+                    "overwrite" => UploadConflict::Overwrite,
+                    "reject" => UploadConflict::Reject,
+                    other => return Err(format!("upload_conflict: expected rename, overwrite or reject, got '{}'", other).into()),
+                };
+            },
+            "upload_names" if parts.len() >= 2 => {
+                route.upload_uuid_names = match parts[1].trim_end_matches(';') {
+                    "original" => false,
+                    "uuid" => true,
+                    other => return Err(format!("upload_names: expected original or uuid, got '{}'", other).into()),
+                };
+            },
+            "tus" if parts.len() >= 2 => {
+                route.tus = parts[1].trim_end_matches(';') == "on";
+            },
+            "dav" if parts.len() >= 2 => {
+                route.dav = parts[1].trim_end_matches(';') == "on";
+            },
+            "compression" | "compression_types" | "compression_min_length" | "compression_static" => {
+                Self::parse_compression_directive(&mut route.compression, &parts)?;
             },
             _ => {},
         }
@@ -246,6 +713,25 @@ impl Config {
         Ok(())
     }
 
+    /// e.g. "compression on;", "compression_types text/html application/json;",
+    /// "compression_min_length 1K;", "compression_static on;"
+    fn parse_compression_directive(compression: &mut CompressionConfig, parts: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let values: Vec<&str> = parts[1..].iter().map(|s| s.trim_end_matches(';')).filter(|s| !s.is_empty()).collect();
+        let value = values.first().ok_or_else(|| format!("{}: missing value", parts[0]))?;
+        let switch = || match *value {
+            "on" => Ok(Some(true)),
+            "off" => Ok(Some(false)),
+            other => Err(format!("{}: expected on or off, got '{}'", parts[0], other)),
+        };
+        match parts[0] {
+            "compression" => compression.enabled = switch()?,
+            "compression_static" => compression.static_files = switch()?,
+            "compression_types" => compression.types = Some(values.iter().map(|t| t.to_lowercase()).collect()),
+            _ => compression.min_length = Some(Self::parse_size(value)?),
+        }
+        Ok(())
+    }
+
     fn parse_size(size_str: &str) -> Result<usize, Box<dyn std::error::Error>> {
         let size_str = size_str.to_uppercase();
         
@@ -260,22 +746,80 @@ impl Config {
             Ok(size_str.parse::<usize>()?)
         }
     }
+
+    /// Parse a duration such as `30`, `30s`, `500ms`, `5m` or `1h` (bare numbers are seconds).
+    fn parse_duration(value: &str) -> Result<Duration, Box<dyn std::error::Error>> {
+        let value = value.to_lowercase();
+        if let Some(num) = value.strip_suffix("ms") {
+            Ok(Duration::from_millis(num.parse()?))
+        } else if let Some(num) = value.strip_suffix('s') {
+            Ok(Duration::from_secs(num.parse()?))
+        } else if let Some(num) = value.strip_suffix('m') {
+            Ok(Duration::from_secs(num.parse::<u64>()? * 60))
+        } else if let Some(num) = value.strip_suffix('h') {
+            Ok(Duration::from_secs(num.parse::<u64>()? * 3600))
+        } else {
+            Ok(Duration::from_secs(value.parse()?))
+        }
+    }
+}
+
+/// How well a `server_name` entry matched the request host. Variants are
+/// ordered so that a better match compares greater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HostMatch {
+    /// `*.example.com` style match; carries the suffix length so the longest wildcard wins
+    Wildcard(usize),
+    Exact,
 }
 
 impl ServerConfig {
-    pub fn socket_addr(&self) -> Result<SocketAddr, Box<dyn std::error::Error>> {
-        let addr = format!("127.0.0.1:{}", self.listen);
-        Ok(addr.parse()?)
+    /// Match a normalized (lowercase, port-less) host against this server's names.
+    pub fn match_host(&self, host: &str) -> Option<HostMatch> {
+        self.server_names
+            .iter()
+            .filter_map(|name| {
+                if name == host {
+                    Some(HostMatch::Exact)
+                } else if let Some(suffix) = name.strip_prefix('*') {
+                    // "*.example.com" matches "a.example.com" but not "example.com"
+                    if suffix.starts_with('.') && host.len() > suffix.len() && host.ends_with(suffix) {
+                        Some(HostMatch::Wildcard(suffix.len()))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            })
+            .max()
+    }
+
+    /// Whether `default_server` was given on the `listen` line for `addr`.
+    pub fn is_default_for(&self, addr: &ListenAddr) -> bool {
+        self.listen.iter().any(|l| &l.addr == addr && l.default_server)
+    }
+
+    /// A server block without any `listen` directive listens on 127.0.0.1:80.
+    fn with_default_listen(mut self) -> Self {
+        if self.listen.is_empty() {
+            self.listen.push(Listen {
+                addr: ListenAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 80)),
+                default_server: false,
+            });
+        }
+        self
     }
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            listen: 80,
-            server_name: "localhost".to_string(),
+            listen: Vec::new(),
+            server_names: vec!["localhost".to_string()],
             client_max_body_size: 1024 * 1024, // 1MB default
             error_pages: HashMap::new(),
+            compression: CompressionConfig::default(),
             routes: Vec::new(),
         }
     }
@@ -0,0 +1,870 @@
+use crate::config::RouteConfig;
+use crate::http::conditional::EntityTag;
+use crate::http::{HttpMethod, HttpRequest, HttpResponse, StatusCode};
+use crate::upload_handler;
+use crate::utils::html;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What a DAV location answers, for `Allow` in OPTIONS.
+const DAV_METHODS: &str = "OPTIONS, GET, HEAD, POST, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// The longest a lock is held without a refresh; also what clients get by default.
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The largest PROPFIND or LOCK body read; bigger ones are refused with 413.
+const MAX_XML_BODY: u64 = 1024 * 1024;
+
+/// A write lock (RFC 4918 section 6). Locks are kept in memory and end with the process.
+#[derive(Debug, Clone)]
+struct DavLock {
+    token: String,
+    path: PathBuf, // The locked resource
+    href: String,
+    shared: bool,
+    deep: bool, // Depth: infinity, covering everything below a collection
+    owner: Option<String>, // The <owner> content as the client sent it
+    timeout: Duration,
+    expires: Instant,
+}
+
+impl DavLock {
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.deep && path.starts_with(&self.path))
+    }
+}
+
+lazy_static! {
+    static ref LOCKS: Mutex<Vec<DavLock>> = Mutex::new(Vec::new());
+}
+
+/// The locks still in force, with expired ones dropped.
+fn active_locks() -> std::sync::MutexGuard<'static, Vec<DavLock>> {
+    let mut locks = LOCKS.lock().unwrap();
+    let now = Instant::now();
+    locks.retain(|lock| lock.expires > now);
+    locks
+}
+
+/// Forget the locks on `path` and below, after it was deleted or moved away.
+fn drop_locks(path: &Path) {
+    active_locks().retain(|lock| !lock.path.starts_with(path));
+}
+
+/// The lock tokens a request submits in `If` (or `Lock-Token`). Only whether a token is
+/// present is checked; the conditions of an `If` list are not evaluated.
+fn submitted_tokens(request: &HttpRequest) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for header in ["if", "lock-token"] {
+        let mut rest = request.get_header(header).map(|v| v.as_str()).unwrap_or("");
+        while let Some(start) = rest.find('<') {
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end,
+                None => break,
+            };
+            let token = &rest[start + 1..end];
+            if token.starts_with("opaquelocktoken:") {
+                tokens.push(token.to_string());
+            }
+            rest = &rest[end + 1..];
+        }
+    }
+    tokens
+}
+
+/// Refuse to change `path` (and, for a collection being deleted or moved, anything below
+/// it) while someone else's lock covers it: the request must submit every such token.
+pub fn check_locks(path: &Path, request: &HttpRequest, with_descendants: bool) -> Result<(), HttpResponse> {
+    let tokens = submitted_tokens(request);
+    let blocked = active_locks().iter().any(|lock| {
+        (lock.covers(path) || (with_descendants && lock.path.starts_with(path))) && !tokens.contains(&lock.token)
+    });
+    if blocked {
+        let mut resp = HttpResponse::new(StatusCode::Locked);
+        resp.set_body(b"The resource is locked");
+        return Err(resp);
+    }
+    Ok(())
+}
+
+/// The file under `upload_store` that a request path in a DAV location names. Segments are
+/// percent-decoded; `..` and hidden names (which include the server's temporary files) are
+/// refused.
+pub fn resolve(location: &RouteConfig, uri_path: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from(location.upload_store.as_ref()?);
+    let rest = uri_path.strip_prefix(location.path.trim_end_matches('/'))?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode(segment)?;
+        if segment.starts_with('.') || segment.contains(['/', '\0']) {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn percent_encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for &b in segment.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// One element of an XML body: its namespace URI and local name, and where the tag sits.
+#[derive(Debug)]
+struct XmlTag {
+    namespace: String,
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    start: usize, // Offset of '<'
+    end: usize, // Offset just past '>'
+}
+
+/// Scan the tags of a small XML body such as a PROPFIND or LOCK request. Namespace
+/// declarations are collected for the whole document rather than per element, which is
+/// enough for what clients send. Returns the tags and the declared prefixes.
+fn xml_tags(body: &str) -> (Vec<XmlTag>, HashMap<String, String>) {
+    let mut raw = Vec::new();
+    let mut namespaces = HashMap::new();
+    let mut pos = 0;
+    while let Some(offset) = body[pos..].find('<') {
+        let start = pos + offset;
+        let end = match body[start..].find('>') {
+            Some(offset) => start + offset + 1,
+            None => break,
+        };
+        pos = end;
+        let inner = &body[start + 1..end - 1];
+        if inner.starts_with(['?', '!']) {
+            continue;
+        }
+        let closing = inner.starts_with('/');
+        let self_closing = inner.ends_with('/');
+        let inner = inner.trim_start_matches('/').trim_end_matches('/');
+        let qname = inner.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+        // xmlns="uri" or xmlns:prefix="uri"
+        let mut attrs = &inner[qname.len()..];
+        while let Some(at) = attrs.find("xmlns") {
+            attrs = &attrs[at + 5..];
+            let (prefix, after) = match attrs.strip_prefix(':') {
+                Some(after) => after.split_at(after.find('=').unwrap_or(after.len())),
+                None => ("", attrs),
+            };
+            let after = after.trim_start().trim_start_matches('=').trim_start();
+            let quote = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => continue,
+            };
+            if let Some(len) = after[1..].find(quote) {
+                namespaces.insert(prefix.trim().to_string(), after[1..1 + len].to_string());
+                attrs = &after[1 + len..];
+            }
+        }
+        raw.push((qname.to_string(), closing, self_closing, start, end));
+    }
+
+    let tags = raw.into_iter()
+        .map(|(qname, closing, self_closing, start, end)| {
+            let (prefix, name) = qname.split_once(':').unwrap_or(("", &qname));
+            XmlTag {
+                namespace: namespaces.get(prefix).cloned().unwrap_or_default(),
+                name: name.to_string(),
+                closing,
+                self_closing,
+                start,
+                end,
+            }
+        })
+        .collect();
+    (tags, namespaces)
+}
+
+/// What a PROPFIND asks for.
+#[derive(Debug)]
+enum PropFind {
+    AllProp,
+    PropName,
+    Props(Vec<(String, String)>), // (namespace, name)
+}
+
+fn parse_propfind(body: &str) -> PropFind {
+    let (tags, _) = xml_tags(body);
+    let mut props = Vec::new();
+    let mut in_prop = false;
+    let mut depth = 0;
+    for tag in &tags {
+        let is_dav = tag.namespace == "DAV:";
+        match (tag.name.as_str(), in_prop) {
+            ("propname", false) if is_dav => return PropFind::PropName,
+            ("prop", false) if is_dav && !tag.closing && !tag.self_closing => in_prop = true,
+            ("prop", true) if is_dav && tag.closing && depth == 0 => break,
+            (_, true) => {
+                if tag.closing {
+                    depth -= 1;
+                } else {
+                    if depth == 0 {
+                        props.push((tag.namespace.clone(), tag.name.clone()));
+                    }
+                    if !tag.self_closing {
+                        depth += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // An empty body, or one without <prop>, asks for everything
+    if props.is_empty() {
+        PropFind::AllProp
+    } else {
+        PropFind::Props(props)
+    }
+}
+
+/// Properties every resource has, in the order they are listed.
+const LIVE_PROPS: [&str; 9] = [
+    "creationdate", "displayname", "getcontentlength", "getcontenttype", "getetag",
+    "getlastmodified", "resourcetype", "supportedlock", "lockdiscovery",
+];
+
+/// A request for a DAV location, answered from its upload_store.
+pub struct DavRequest<'a> {
+    request: &'a HttpRequest,
+    location: &'a RouteConfig,
+    root: PathBuf,
+}
+
+impl<'a> DavRequest<'a> {
+    pub fn new(request: &'a HttpRequest, location: &'a RouteConfig) -> Self {
+        let root = PathBuf::from(location.upload_store.as_deref().unwrap_or_default());
+        Self { request, location, root }
+    }
+
+    /// Answer everything but GET, HEAD, POST and PUT, which the static handler serves.
+    pub fn handle(&self, path: &str) -> HttpResponse {
+        if self.request.method == HttpMethod::OPTIONS {
+            let mut resp = HttpResponse::ok();
+            resp.set_header("DAV", "1, 2");
+            resp.set_header("Allow", DAV_METHODS);
+            resp.set_header("MS-Author-Via", "DAV");
+            resp.set_body(b"");
+            return resp;
+        }
+        let target = match resolve(self.location, path) {
+            Some(target) => target,
+            None => return HttpResponse::forbidden(),
+        };
+        // The shared collection itself always exists
+        if let Err(e) = fs::create_dir_all(&self.root) {
+            log::error!("Cannot create {}: {}", self.root.display(), e);
+            return HttpResponse::internal_server_error();
+        }
+
+        let result = match self.request.method {
+            HttpMethod::PROPFIND => self.propfind(&target),
+            HttpMethod::MKCOL => self.mkcol(&target),
+            HttpMethod::DELETE => self.delete(&target),
+            HttpMethod::COPY => self.copy_or_move(&target, false),
+            HttpMethod::MOVE => self.copy_or_move(&target, true),
+            HttpMethod::LOCK => self.lock(&target),
+            HttpMethod::UNLOCK => self.unlock(&target),
+            _ => Ok(HttpResponse::method_not_allowed_custom(None)),
+        };
+        result.unwrap_or_else(|resp| resp)
+    }
+
+    fn propfind(&self, target: &Path) -> Result<HttpResponse, HttpResponse> {
+        let metadata = fs::metadata(target).map_err(|_| HttpResponse::not_found())?;
+        let depth = match self.request.get_header("depth").map(|v| v.trim()) {
+            Some("0") => 0,
+            Some("1") => 1,
+            // Listing a whole tree in one response is refused (RFC 4918 section 9.1)
+            _ => {
+                let mut resp = HttpResponse::forbidden();
+                resp.set_header("Content-Type", "application/xml; charset=utf-8");
+                resp.set_body_string("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>");
+                return Err(resp);
+            }
+        };
+        let find = parse_propfind(&self.xml_body()?);
+
+        let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">");
+        body.push_str(&self.prop_response(target, &metadata, &find));
+        if depth == 1 && metadata.is_dir() {
+            let mut children: Vec<_> = fs::read_dir(target)
+                .map_err(|_| HttpResponse::internal_server_error())?
+                .filter_map(Result::ok)
+                .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                .collect();
+            children.sort_by_key(|entry| entry.file_name());
+            for entry in children {
+                if let Ok(metadata) = entry.metadata() {
+                    body.push_str(&self.prop_response(&entry.path(), &metadata, &find));
+                }
+            }
+        }
+        body.push_str("</D:multistatus>");
+        Ok(multistatus(body))
+    }
+
+    /// The request's XML body, read back from its spool file if it was too large to keep
+    /// in memory.
+    fn xml_body(&self) -> Result<String, HttpResponse> {
+        let body_file = match &self.request.body_file {
+            Some(body_file) => body_file,
+            None => return Ok(String::from_utf8_lossy(&self.request.body).into_owned()),
+        };
+        if body_file.len() > MAX_XML_BODY {
+            return Err(HttpResponse::payload_too_large());
+        }
+        let mut body = Vec::new();
+        body_file.open().and_then(|mut file| file.read_to_end(&mut body)).map_err(io_error)?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// The <response> for one resource: the properties found, and those asked for that it lacks.
+    fn prop_response(&self, path: &Path, metadata: &fs::Metadata, find: &PropFind) -> String {
+        let mut found = String::new();
+        let mut missing = String::new();
+        match find {
+            PropFind::AllProp => {
+                for name in LIVE_PROPS {
+                    found.push_str(&self.live_prop(name, path, metadata).unwrap_or_default());
+                }
+            }
+            PropFind::PropName => {
+                for name in LIVE_PROPS {
+                    if self.live_prop(name, path, metadata).is_some() {
+                        found.push_str(&format!("<D:{}/>", name));
+                    }
+                }
+            }
+            PropFind::Props(props) => {
+                for (namespace, name) in props {
+                    let value = if namespace == "DAV:" { self.live_prop(name, path, metadata) } else { None };
+                    match value {
+                        Some(value) => found.push_str(&value),
+                        None if namespace == "DAV:" => missing.push_str(&format!("<D:{}/>", name)),
+                        None => missing.push_str(&format!("<x:{} xmlns:x=\"{}\"/>", name, html::escape(namespace))),
+                    }
+                }
+            }
+        }
+
+        let mut out = format!("<D:response><D:href>{}</D:href>", html::escape(&self.href(path, metadata.is_dir())));
+        for (props, status) in [(found, "200 OK"), (missing, "404 Not Found")] {
+            if !props.is_empty() {
+                out.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>", props, status));
+            }
+        }
+        out.push_str("</D:response>");
+        out
+    }
+
+    fn live_prop(&self, name: &str, path: &Path, metadata: &fs::Metadata) -> Option<String> {
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let value = match name {
+            "creationdate" => {
+                let created = chrono::DateTime::<chrono::Utc>::from(metadata.created().unwrap_or(modified));
+                created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            }
+            "displayname" => html::escape(&path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()),
+            "getcontentlength" if metadata.is_file() => metadata.len().to_string(),
+            "getcontenttype" if metadata.is_file() => {
+                let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+                HttpResponse::content_type_from_extension(extension).to_string()
+            }
+            "getetag" if metadata.is_file() => html::escape(&EntityTag::for_file(metadata).to_string()),
+            "getlastmodified" => httpdate::fmt_http_date(modified),
+            "resourcetype" if metadata.is_dir() => "<D:collection/>".to_string(),
+            "resourcetype" => String::new(),
+            "supportedlock" => {
+                let entry = |scope: &str| format!("<D:lockentry><D:lockscope><D:{}/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>", scope);
+                format!("{}{}", entry("exclusive"), entry("shared"))
+            }
+            "lockdiscovery" => active_locks().iter().filter(|lock| lock.covers(path)).map(active_lock_xml).collect(),
+            _ => return None,
+        };
+        Some(format!("<D:{}>{}</D:{}>", name, value, name))
+    }
+
+    /// The URL of a file under the upload_store, collections ending in a slash.
+    fn href(&self, path: &Path, is_dir: bool) -> String {
+        let relative = path.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        let mut href = self.location.path.trim_end_matches('/').to_string();
+        for segment in relative.iter() {
+            href.push('/');
+            href.push_str(&percent_encode(&segment.to_string_lossy()));
+        }
+        if is_dir || href.is_empty() {
+            href.push('/');
+        }
+        href
+    }
+
+    fn mkcol(&self, target: &Path) -> Result<HttpResponse, HttpResponse> {
+        if self.request.body_len() > 0 {
+            return Err(HttpResponse::new(StatusCode::UnsupportedMediaType));
+        }
+        if fs::symlink_metadata(target).is_ok() {
+            return Err(HttpResponse::method_not_allowed_custom(None));
+        }
+        check_parent(target)?;
+        check_locks(target, self.request, false)?;
+        fs::create_dir(target).map_err(io_error)?;
+        Ok(created())
+    }
+
+    fn delete(&self, target: &Path) -> Result<HttpResponse, HttpResponse> {
+        if target == self.root {
+            return Err(HttpResponse::forbidden());
+        }
+        let metadata = fs::symlink_metadata(target).map_err(|_| HttpResponse::not_found())?;
+        check_locks(target, self.request, true)?;
+        remove(target, &metadata).map_err(io_error)?;
+        drop_locks(target);
+        Ok(HttpResponse::new(StatusCode::NoContent))
+    }
+
+    fn copy_or_move(&self, source: &Path, is_move: bool) -> Result<HttpResponse, HttpResponse> {
+        fs::symlink_metadata(source).map_err(|_| HttpResponse::not_found())?;
+        let destination = self.request.get_header("destination").ok_or_else(HttpResponse::bad_request)?;
+        // An absolute URL or a path; only this location is reachable
+        let dest_path = match destination.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|slash| &rest[slash..]).unwrap_or("/"),
+            None => destination.as_str(),
+        };
+        let dest_path = dest_path.split('?').next().unwrap_or("");
+        let dest = resolve(self.location, dest_path).ok_or_else(HttpResponse::bad_gateway)?;
+        if dest == *source || dest.starts_with(source) || source == self.root || dest == self.root {
+            return Err(HttpResponse::forbidden());
+        }
+        let depth = self.request.get_header("depth").map(|v| v.trim().to_ascii_lowercase());
+        let deep = match depth.as_deref() {
+            None | Some("infinity") => true,
+            Some("0") if !is_move => false,
+            _ => return Err(HttpResponse::bad_request()),
+        };
+
+        check_parent(&dest)?;
+        let existing = fs::symlink_metadata(&dest).ok();
+        if existing.is_some() && self.request.get_header("overwrite").is_some_and(|v| v.trim().eq_ignore_ascii_case("F")) {
+            return Err(HttpResponse::new(StatusCode::PreconditionFailed));
+        }
+        if is_move {
+            check_locks(source, self.request, true)?;
+        }
+        check_locks(&dest, self.request, true)?;
+
+        if let Some(existing) = &existing {
+            remove(&dest, existing).map_err(io_error)?;
+            drop_locks(&dest);
+        }
+        if is_move {
+            fs::rename(source, &dest).map_err(io_error)?;
+            // Locks stay with the URL, not the resource (RFC 4918 section 7.7)
+            drop_locks(source);
+        } else {
+            copy_tree(source, &dest, deep).map_err(io_error)?;
+        }
+        Ok(if existing.is_some() { HttpResponse::new(StatusCode::NoContent) } else { created() })
+    }
+
+    fn lock(&self, target: &Path) -> Result<HttpResponse, HttpResponse> {
+        let timeout = self.request.get_header("timeout")
+            .and_then(|v| v.split(',').find_map(|t| t.trim().strip_prefix("Second-")?.parse().ok()))
+            .map(Duration::from_secs)
+            .map_or(MAX_LOCK_TIMEOUT, |t| t.min(MAX_LOCK_TIMEOUT));
+
+        // Without a body, LOCK refreshes a lock named in If
+        if self.request.body_len() == 0 {
+            let tokens = submitted_tokens(self.request);
+            let mut locks = active_locks();
+            let lock = locks.iter_mut()
+                .find(|lock| lock.covers(target) && tokens.contains(&lock.token))
+                .ok_or_else(|| HttpResponse::new(StatusCode::PreconditionFailed))?;
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + timeout;
+            return Ok(lock_response(StatusCode::Ok, lock));
+        }
+
+        let body = self.xml_body()?;
+        let (tags, namespaces) = xml_tags(&body);
+        let is_dav = |tag: &&XmlTag, name: &str| tag.namespace == "DAV:" && tag.name == name;
+        let shared = tags.iter().any(|tag| is_dav(&tag, "shared"));
+        let owner = tags.iter().position(|tag| is_dav(&tag, "owner") && !tag.closing && !tag.self_closing)
+            .and_then(|open| {
+                let close = tags[open..].iter().find(|tag| is_dav(tag, "owner") && tag.closing)?;
+                // Declare the client's prefixes again, as the response uses its own
+                let declarations: String = namespaces.iter()
+                    .filter(|(prefix, _)| !prefix.is_empty() && prefix.as_str() != "D")
+                    .map(|(prefix, uri)| format!(" xmlns:{}=\"{}\"", prefix, html::escape(uri)))
+                    .collect();
+                Some(format!("<D:owner{}>{}</D:owner>", declarations, &body[tags[open].end..close.start]))
+            });
+        let deep = match self.request.get_header("depth").map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("infinity") => true,
+            Some("0") => false,
+            _ => return Err(HttpResponse::bad_request()),
+        };
+
+        let mut locks = active_locks();
+        let conflict = locks.iter().any(|lock| {
+            (lock.covers(target) || (deep && lock.path.starts_with(target))) && !(shared && lock.shared)
+        });
+        if conflict {
+            return Err(HttpResponse::new(StatusCode::Locked));
+        }
+        // Locking an unmapped URL creates an empty file (RFC 4918 section 7.3)
+        let status = if fs::symlink_metadata(target).is_err() {
+            check_parent(target)?;
+            fs::File::create(target).map_err(io_error)?;
+            StatusCode::Created
+        } else {
+            StatusCode::Ok
+        };
+        let is_dir = target.is_dir();
+        let lock = DavLock {
+            token: format!("opaquelocktoken:{}", upload_handler::uuid_name("")),
+            path: target.to_path_buf(),
+            href: self.href(target, is_dir),
+            shared,
+            deep: deep && is_dir,
+            owner,
+            timeout,
+            expires: Instant::now() + timeout,
+        };
+        let mut resp = lock_response(status, &lock);
+        resp.set_header("Lock-Token", &format!("<{}>", lock.token));
+        locks.push(lock);
+        Ok(resp)
+    }
+
+    fn unlock(&self, target: &Path) -> Result<HttpResponse, HttpResponse> {
+        let token = self.request.get_header("lock-token")
+            .map(|v| v.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+            .ok_or_else(HttpResponse::bad_request)?;
+        let mut locks = active_locks();
+        let index = locks.iter().position(|lock| lock.token == token && lock.covers(target))
+            .ok_or_else(HttpResponse::conflict)?;
+        locks.remove(index);
+        Ok(HttpResponse::new(StatusCode::NoContent))
+    }
+}
+
+fn active_lock_xml(lock: &DavLock) -> String {
+    let timeout = lock.expires.saturating_duration_since(Instant::now()).as_secs();
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.shared { "shared" } else { "exclusive" },
+        if lock.deep { "infinity" } else { "0" },
+        lock.owner.as_deref().unwrap_or(""),
+        timeout.max(1),
+        lock.token,
+        html::escape(&lock.href),
+    )
+}
+
+fn lock_response(status: StatusCode, lock: &DavLock) -> HttpResponse {
+    let mut resp = HttpResponse::new(status);
+    resp.set_header("Content-Type", "application/xml; charset=utf-8");
+    resp.set_body_string(&format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        active_lock_xml(lock),
+    ));
+    resp
+}
+
+fn multistatus(body: String) -> HttpResponse {
+    let mut resp = HttpResponse::new(StatusCode::MultiStatus);
+    resp.set_header("Content-Type", "application/xml; charset=utf-8");
+    resp.set_body_string(&body);
+    resp
+}
+
+fn created() -> HttpResponse {
+    let mut resp = HttpResponse::new(StatusCode::Created);
+    resp.set_body(b"");
+    resp
+}
+
+/// A new member needs an existing collection to go in (RFC 4918 section 9.3.1).
+pub fn check_parent(target: &Path) -> Result<(), HttpResponse> {
+    match target.parent().map(Path::is_dir) {
+        Some(true) => Ok(()),
+        _ => Err(HttpResponse::conflict()),
+    }
+}
+
+fn io_error(e: io::Error) -> HttpResponse {
+    log::error!("WebDAV request failed: {}", e);
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => HttpResponse::forbidden(),
+        _ => HttpResponse::internal_server_error(),
+    }
+}
+
+fn remove(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copy a file, or a collection with (if `deep`) everything in it but hidden files.
+fn copy_tree(source: &Path, dest: &Path, deep: bool) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, dest).map(|_| ());
+    }
+    fs::create_dir(dest)?;
+    if deep {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                copy_tree(&entry.path(), &dest.join(entry.file_name()), true)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::BodyFile;
+    use std::sync::Arc;
+
+    const PROPS: &str = "<D:propfind xmlns:D=\"DAV:\"><D:prop><D:getcontentlength/><x:color xmlns:x=\"urn:x\"/></D:prop></D:propfind>";
+    const LOCKINFO: &str = "<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype><D:owner>tester</D:owner></D:lockinfo>";
+
+    /// A DAV location at /dav/ sharing a new, empty directory.
+    fn location(name: &str) -> RouteConfig {
+        let dir = std::env::temp_dir().join(format!("webserv-dav-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut location = RouteConfig::new("/dav/".to_string());
+        location.upload_store = Some(dir.to_string_lossy().into_owned());
+        location.dav = true;
+        location
+    }
+
+    fn store(location: &RouteConfig) -> PathBuf {
+        PathBuf::from(location.upload_store.as_ref().unwrap())
+    }
+
+    fn request(method: HttpMethod, headers: &[(&str, &str)], body: &str) -> HttpRequest {
+        let mut request = HttpRequest::new();
+        request.method = method;
+        for (name, value) in headers {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    /// Move the body to a spool file, as the server does with bodies over 64 KB.
+    fn spool(mut request: HttpRequest) -> HttpRequest {
+        let mut file = BodyFile::create(&std::env::temp_dir()).unwrap();
+        file.write_all(&request.body).unwrap();
+        request.body = Vec::new();
+        request.body_file = Some(Arc::new(file));
+        request
+    }
+
+    fn send(location: &RouteConfig, request: &HttpRequest, path: &str) -> HttpResponse {
+        DavRequest::new(request, location).handle(path)
+    }
+
+    fn body(resp: &HttpResponse) -> String {
+        String::from_utf8_lossy(&resp.body).into_owned()
+    }
+
+    #[test]
+    fn propfind_lists_a_collection_to_depth_one() {
+        let location = location("propfind");
+        fs::create_dir(store(&location).join("dir")).unwrap();
+        fs::write(store(&location).join("dir/a b.txt"), "hello").unwrap();
+        fs::write(store(&location).join("dir/.hidden"), "").unwrap();
+
+        let resp = send(&location, &request(HttpMethod::PROPFIND, &[("depth", "0")], ""), "/dav/dir");
+        assert_eq!(resp.status, StatusCode::MultiStatus);
+        let xml = body(&resp);
+        assert_eq!(xml.matches("<D:response>").count(), 1);
+        assert!(xml.contains("<D:href>/dav/dir/</D:href>"), "{}", xml);
+        assert!(xml.contains("<D:resourcetype><D:collection/></D:resourcetype>"), "{}", xml);
+
+        let xml = body(&send(&location, &request(HttpMethod::PROPFIND, &[("depth", "1")], ""), "/dav/dir"));
+        assert_eq!(xml.matches("<D:response>").count(), 2);
+        assert!(xml.contains("<D:href>/dav/dir/a%20b.txt</D:href>"), "{}", xml);
+        assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>"), "{}", xml);
+        assert!(!xml.contains(".hidden"), "{}", xml);
+
+        // Whole trees are not listed, and nothing is there to list for a missing path
+        let resp = send(&location, &request(HttpMethod::PROPFIND, &[("depth", "infinity")], ""), "/dav/dir");
+        assert_eq!(resp.status, StatusCode::Forbidden);
+        assert!(body(&resp).contains("propfind-finite-depth"));
+        let resp = send(&location, &request(HttpMethod::PROPFIND, &[("depth", "0")], ""), "/dav/missing");
+        assert_eq!(resp.status, StatusCode::NotFound);
+        fs::remove_dir_all(store(&location)).unwrap();
+    }
+
+    #[test]
+    fn propfind_answers_the_named_properties_even_from_a_spooled_body() {
+        let location = location("props");
+        fs::write(store(&location).join("a.txt"), "hello").unwrap();
+        let padded = format!("<?xml version=\"1.0\"?>{}{}", " ".repeat(70 * 1024), PROPS);
+
+        for req in [request(HttpMethod::PROPFIND, &[("depth", "0")], PROPS), spool(request(HttpMethod::PROPFIND, &[("depth", "0")], &padded))] {
+            let xml = body(&send(&location, &req, "/dav/a.txt"));
+            assert!(xml.contains("<D:prop><D:getcontentlength>5</D:getcontentlength></D:prop><D:status>HTTP/1.1 200 OK"), "{}", xml);
+            assert!(xml.contains("<x:color xmlns:x=\"urn:x\"/></D:prop><D:status>HTTP/1.1 404 Not Found"), "{}", xml);
+            // Not mistaken for allprop
+            assert!(!xml.contains("getlastmodified"), "{}", xml);
+        }
+
+        let huge = format!("{}{}", " ".repeat(MAX_XML_BODY as usize), PROPS);
+        let resp = send(&location, &spool(request(HttpMethod::PROPFIND, &[("depth", "0")], &huge)), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::PayloadTooLarge);
+        fs::remove_dir_all(store(&location)).unwrap();
+    }
+
+    #[test]
+    fn copy_and_move_follow_destination_and_overwrite() {
+        let location = location("copy");
+        let dir = store(&location);
+        fs::write(dir.join("a.txt"), "one").unwrap();
+        fs::write(dir.join("b.txt"), "two").unwrap();
+        let copy = |headers: &[(&str, &str)]| send(&location, &request(HttpMethod::COPY, headers, ""), "/dav/a.txt");
+
+        // Overwrite: F keeps an existing destination
+        let resp = copy(&[("destination", "/dav/b.txt"), ("overwrite", "F")]);
+        assert_eq!(resp.status, StatusCode::PreconditionFailed);
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "two");
+        let resp = copy(&[("destination", "http://localhost/dav/b.txt"), ("overwrite", "T")]);
+        assert_eq!(resp.status, StatusCode::NoContent);
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "one");
+        let resp = copy(&[("destination", "http://localhost/dav/c.txt")]);
+        assert_eq!(resp.status, StatusCode::Created);
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).unwrap(), "one");
+
+        // Destinations must be somewhere else in this location
+        assert_eq!(copy(&[]).status, StatusCode::BadRequest);
+        assert_eq!(copy(&[("destination", "/other/a.txt")]).status, StatusCode::BadGateway);
+        assert_eq!(copy(&[("destination", "/dav/a.txt")]).status, StatusCode::Forbidden);
+        assert_eq!(copy(&[("destination", "/dav/.a.txt")]).status, StatusCode::BadGateway);
+        assert_eq!(copy(&[("destination", "/dav/missing/a.txt")]).status, StatusCode::Conflict);
+
+        let moved = |headers: &[(&str, &str)]| send(&location, &request(HttpMethod::MOVE, headers, ""), "/dav/a.txt");
+        assert_eq!(moved(&[("destination", "/dav/d.txt"), ("depth", "0")]).status, StatusCode::BadRequest);
+        assert_eq!(moved(&[("destination", "/dav/c.txt"), ("overwrite", "F")]).status, StatusCode::PreconditionFailed);
+        assert_eq!(moved(&[("destination", "/dav/d.txt")]).status, StatusCode::Created);
+        assert!(!dir.join("a.txt").exists());
+        assert_eq!(fs::read_to_string(dir.join("d.txt")).unwrap(), "one");
+        assert_eq!(moved(&[("destination", "/dav/e.txt")]).status, StatusCode::NotFound);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copy_of_a_collection_skips_hidden_files_and_stops_at_depth_zero() {
+        let location = location("tree");
+        let dir = store(&location);
+        fs::create_dir_all(dir.join("src/sub")).unwrap();
+        fs::write(dir.join("src/sub/a.txt"), "a").unwrap();
+        fs::write(dir.join("src/.tmp"), "").unwrap();
+
+        let resp = send(&location, &request(HttpMethod::COPY, &[("destination", "/dav/deep/")], ""), "/dav/src/");
+        assert_eq!(resp.status, StatusCode::Created);
+        assert_eq!(fs::read_to_string(dir.join("deep/sub/a.txt")).unwrap(), "a");
+        assert!(!dir.join("deep/.tmp").exists());
+        let resp = send(&location, &request(HttpMethod::COPY, &[("destination", "/dav/shallow/"), ("depth", "0")], ""), "/dav/src/");
+        assert_eq!(resp.status, StatusCode::Created);
+        assert_eq!(fs::read_dir(dir.join("shallow")).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lock_tokens_guard_changes_until_unlocked() {
+        let location = location("lock");
+        let dir = store(&location);
+
+        // Locking an unmapped URL creates it
+        let resp = send(&location, &request(HttpMethod::LOCK, &[("timeout", "Second-60")], LOCKINFO), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::Created);
+        assert!(dir.join("a.txt").is_file());
+        let header = resp.get_header("Lock-Token").unwrap().clone();
+        let token = header.trim_matches(|c| c == '<' || c == '>').to_string();
+        assert!(token.starts_with("opaquelocktoken:"), "{}", token);
+        let xml = body(&resp);
+        assert!(xml.contains("<D:owner>tester</D:owner>") && xml.contains("<D:timeout>Second-"), "{}", xml);
+
+        // Others can neither lock nor change it
+        let resp = send(&location, &request(HttpMethod::LOCK, &[], LOCKINFO), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::Locked);
+        let path = dir.join("a.txt");
+        assert_eq!(check_locks(&path, &request(HttpMethod::PUT, &[], ""), false).unwrap_err().status, StatusCode::Locked);
+        let submitted = format!("(<{}>)", token);
+        assert!(check_locks(&path, &request(HttpMethod::PUT, &[("if", &submitted)], ""), false).is_ok());
+        let resp = send(&location, &request(HttpMethod::DELETE, &[], ""), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::Locked);
+
+        // A LOCK without a body refreshes the lock whose token it submits
+        let resp = send(&location, &request(HttpMethod::LOCK, &[], ""), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::PreconditionFailed);
+        let resp = send(&location, &request(HttpMethod::LOCK, &[("if", &submitted)], ""), "/dav/a.txt");
+        assert_eq!(resp.status, StatusCode::Ok);
+
+        let unlock = |token: &str| send(&location, &request(HttpMethod::UNLOCK, &[("lock-token", &format!("<{}>", token))], ""), "/dav/a.txt");
+        assert_eq!(unlock("opaquelocktoken:other").status, StatusCode::Conflict);
+        assert_eq!(unlock(&token).status, StatusCode::NoContent);
+        assert!(check_locks(&path, &request(HttpMethod::PUT, &[], ""), false).is_ok());
+        assert_eq!(send(&location, &request(HttpMethod::DELETE, &[], ""), "/dav/a.txt").status, StatusCode::NoContent);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lock_with_a_spooled_body_takes_a_new_lock() {
+        let location = location("spooled-lock");
+        let owner = "x".repeat(70 * 1024);
+        let lockinfo = LOCKINFO.replace("tester", &owner);
+        let resp = send(&location, &spool(request(HttpMethod::LOCK, &[], &lockinfo)), "/dav/big.txt");
+        // Not taken for a refresh of a lock that doesn't exist
+        assert_eq!(resp.status, StatusCode::Created);
+        assert!(body(&resp).contains(&owner));
+        let token = resp.get_header("Lock-Token").unwrap().trim_matches(|c| c == '<' || c == '>').to_string();
+        let resp = send(&location, &request(HttpMethod::UNLOCK, &[("lock-token", &format!("<{}>", token))], ""), "/dav/big.txt");
+        assert_eq!(resp.status, StatusCode::NoContent);
+        fs::remove_dir_all(store(&location)).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+// FastCGI 1.0 wire protocol, client (web server) side.
+// See https://fastcgi-archives.github.io/FastCGI_Specification.html
+
+pub const FCGI_VERSION_1: u8 = 1;
+const HEADER_LEN: usize = 8;
+pub const MAX_CONTENT_LEN: usize = 65535;
+
+// Record types
+pub const FCGI_BEGIN_REQUEST: u8 = 1;
+pub const FCGI_ABORT_REQUEST: u8 = 2;
+pub const FCGI_END_REQUEST: u8 = 3;
+pub const FCGI_PARAMS: u8 = 4;
+pub const FCGI_STDIN: u8 = 5;
+pub const FCGI_STDOUT: u8 = 6;
+pub const FCGI_STDERR: u8 = 7;
+pub const FCGI_GET_VALUES: u8 = 9;
+pub const FCGI_GET_VALUES_RESULT: u8 = 10;
+pub const FCGI_UNKNOWN_TYPE: u8 = 11;
+
+// Management records (GET_VALUES and its answer) use request id 0
+pub const FCGI_NULL_REQUEST_ID: u16 = 0;
+
+// Roles, flags and protocol status
+pub const FCGI_RESPONDER: u16 = 1;
+pub const FCGI_KEEP_CONN: u8 = 1;
+pub const FCGI_REQUEST_COMPLETE: u8 = 0;
+pub const FCGI_CANT_MPX_CONN: u8 = 1;
+
+// Variables an application can be asked about with GET_VALUES
+pub const FCGI_MAX_REQS: &str = "FCGI_MAX_REQS";
+pub const FCGI_MPXS_CONNS: &str = "FCGI_MPXS_CONNS";
+
+#[derive(Debug)]
+pub struct Record {
+    pub record_type: u8,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+/// Body of an FCGI_END_REQUEST record.
+#[derive(Debug, Clone, Copy)]
+pub struct EndRequest {
+    pub app_status: u32,
+    pub protocol_status: u8,
+}
+
+impl EndRequest {
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        if content.len() < 5 {
+            return None;
+        }
+        Some(Self {
+            app_status: u32::from_be_bytes([content[0], content[1], content[2], content[3]]),
+            protocol_status: content[4],
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.protocol_status == FCGI_REQUEST_COMPLETE && self.app_status == 0
+    }
+}
+
+/// Encode a single record; `content` must fit in one record (64K - 1 bytes).
+pub fn encode_record(record_type: u8, request_id: u16, content: &[u8]) -> Vec<u8> {
+    debug_assert!(content.len() <= MAX_CONTENT_LEN);
+    // Pad records to a multiple of 8 bytes, as the spec recommends
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(HEADER_LEN + content.len() + padding);
+    record.push(FCGI_VERSION_1);
+    record.push(record_type);
+    record.extend_from_slice(&request_id.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.push(padding as u8);
+    record.push(0); // reserved
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding, 0);
+    record
+}
+
+/// Encode a stream (PARAMS, STDIN): as many records as needed, then the empty record ending it.
+pub fn encode_stream(record_type: u8, request_id: u16, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(MAX_CONTENT_LEN) {
+        out.extend_from_slice(&encode_record(record_type, request_id, chunk));
+    }
+    out.extend_from_slice(&encode_record(record_type, request_id, &[]));
+    out
+}
+
+/// Encode name-value pairs: lengths below 128 take one byte, longer ones four
+/// with the high bit set.
+pub fn encode_params(params: &HashMap<String, String>) -> Vec<u8> {
+    encode_pairs(params.iter().map(|(name, value)| (name.as_str(), value.as_str())))
+}
+
+fn encode_pairs<'a>(pairs: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    fn push_length(out: &mut Vec<u8>, len: usize) {
+        if len < 128 {
+            out.push(len as u8);
+        } else {
+            out.extend_from_slice(&((len as u32) | 0x8000_0000).to_be_bytes());
+        }
+    }
+
+    let mut out = Vec::new();
+    for (name, value) in pairs {
+        push_length(&mut out, name.len());
+        push_length(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+/// Decode name-value pairs, e.g. the content of a GET_VALUES_RESULT record.
+/// Returns None if a length runs past the end of the data.
+pub fn decode_params(mut data: &[u8]) -> Option<HashMap<String, String>> {
+    fn take_length(data: &mut &[u8]) -> Option<usize> {
+        let first = *data.first()?;
+        if first < 128 {
+            *data = &data[1..];
+            return Some(first as usize);
+        }
+        let bytes = data.get(..4)?;
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff;
+        *data = &data[4..];
+        Some(len as usize)
+    }
+
+    let mut params = HashMap::new();
+    while !data.is_empty() {
+        let name_len = take_length(&mut data)?;
+        let value_len = take_length(&mut data)?;
+        let name = data.get(..name_len)?;
+        let value = data.get(name_len..name_len.checked_add(value_len)?)?;
+        params.insert(String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned());
+        data = &data[name_len + value_len..];
+    }
+    Some(params)
+}
+
+/// A GET_VALUES record asking the application about `names`.
+pub fn encode_get_values(names: &[&str]) -> Vec<u8> {
+    let content = encode_pairs(names.iter().map(|&name| (name, "")));
+    encode_record(FCGI_GET_VALUES, FCGI_NULL_REQUEST_ID, &content)
+}
+
+/// Everything the server sends for one Responder request: BEGIN_REQUEST, the
+/// PARAMS stream and the STDIN stream. The application is asked to keep the
+/// connection open for the next request once this one ends.
+pub fn encode_request(request_id: u16, params: &HashMap<String, String>, body: &[u8]) -> Vec<u8> {
+    let mut out = encode_request_head(request_id, params);
+    out.extend_from_slice(&encode_stream(FCGI_STDIN, request_id, body));
+    out
+}
+
+/// BEGIN_REQUEST and the PARAMS stream, for a request whose STDIN records are sent separately.
+pub fn encode_request_head(request_id: u16, params: &HashMap<String, String>) -> Vec<u8> {
+    let mut begin = Vec::with_capacity(8);
+    begin.extend_from_slice(&FCGI_RESPONDER.to_be_bytes());
+    begin.push(FCGI_KEEP_CONN);
+    begin.extend_from_slice(&[0; 5]);
+
+    let mut out = encode_record(FCGI_BEGIN_REQUEST, request_id, &begin);
+    out.extend_from_slice(&encode_stream(FCGI_PARAMS, request_id, &encode_params(params)));
+    out
+}
+
+/// Reassembles records from the bytes read off an application connection.
+#[derive(Debug, Default)]
+pub struct RecordReader {
+    buffer: Vec<u8>,
+}
+
+impl RecordReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next bytes read from the connection and return every complete record.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        self.buffer.extend_from_slice(data);
+        let mut records = Vec::new();
+        let mut pos = 0;
+
+        while self.buffer.len() - pos >= HEADER_LEN {
+            let header = &self.buffer[pos..pos + HEADER_LEN];
+            if header[0] != FCGI_VERSION_1 {
+                return Err(format!("Unsupported FastCGI version {}", header[0]).into());
+            }
+            let content_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let record_len = HEADER_LEN + content_len + header[6] as usize;
+            if self.buffer.len() - pos < record_len {
+                break;
+            }
+            records.push(Record {
+                record_type: header[1],
+                request_id: u16::from_be_bytes([header[2], header[3]]),
+                content: self.buffer[pos + HEADER_LEN..pos + HEADER_LEN + content_len].to_vec(),
+            });
+            pos += record_len;
+        }
+
+        self.buffer.drain(..pos);
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    /// A stand-in Responder application: reads one request off `stream` and answers with
+    /// a CGI response echoing what it received.
+    fn respond(mut stream: UnixStream) {
+        let mut reader = RecordReader::new();
+        let mut request_id = None;
+        let mut params = Vec::new();
+        let mut stdin = Vec::new();
+        let mut buf = [0u8; 4096];
+        'read: loop {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "client closed before ending STDIN");
+            for record in reader.feed(&buf[..n]).unwrap() {
+                match record.record_type {
+                    FCGI_BEGIN_REQUEST => {
+                        assert_eq!(u16::from_be_bytes([record.content[0], record.content[1]]), FCGI_RESPONDER);
+                        assert_eq!(record.content[2], FCGI_KEEP_CONN);
+                        request_id = Some(record.request_id);
+                    }
+                    FCGI_PARAMS => params.extend_from_slice(&record.content),
+                    FCGI_STDIN if record.content.is_empty() => break 'read,
+                    FCGI_STDIN => stdin.extend_from_slice(&record.content),
+                    other => panic!("unexpected record type {}", other),
+                }
+            }
+        }
+
+        let request_id = request_id.expect("no BEGIN_REQUEST");
+        let params = decode_params(&params).unwrap();
+        let mut body = format!("{} {}\n", params["REQUEST_METHOD"], params["SCRIPT_NAME"]).into_bytes();
+        body.extend_from_slice(&stdin);
+        let mut stdout = format!("Status: 201 Created\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        stdout.extend_from_slice(&body);
+
+        let mut out = encode_record(FCGI_STDERR, request_id, b"warning: stand-in responder\n");
+        out.extend_from_slice(&encode_stream(FCGI_STDOUT, request_id, &stdout));
+        out.extend_from_slice(&encode_record(FCGI_END_REQUEST, request_id, &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0]));
+        stream.write_all(&out).unwrap();
+    }
+
+    #[test]
+    fn request_round_trip_with_a_stand_in_responder() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let responder = thread::spawn(move || respond(server));
+
+        let mut params = HashMap::new();
+        params.insert("REQUEST_METHOD".to_string(), "POST".to_string());
+        params.insert("SCRIPT_NAME".to_string(), "/app.php".to_string());
+        params.insert("HTTP_COOKIE".to_string(), "c".repeat(300)); // Needs a four-byte length
+        // More than one STDIN record's worth
+        let body: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        client.write_all(&encode_request(7, &params, &body)).unwrap();
+
+        let mut reader = RecordReader::new();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut end = None;
+        let mut buf = [0u8; 1000]; // Small reads split records across feeds
+        while end.is_none() {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "responder closed before END_REQUEST");
+            for record in reader.feed(&buf[..n]).unwrap() {
+                assert_eq!(record.request_id, 7);
+                match record.record_type {
+                    FCGI_STDOUT => stdout.extend_from_slice(&record.content),
+                    FCGI_STDERR => stderr.extend_from_slice(&record.content),
+                    FCGI_END_REQUEST => end = EndRequest::parse(&record.content),
+                    other => panic!("unexpected record type {}", other),
+                }
+            }
+        }
+        responder.join().unwrap();
+
+        assert!(end.unwrap().is_success());
+        assert_eq!(stderr, b"warning: stand-in responder\n");
+        let head_end = stdout.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert!(stdout.starts_with(b"Status: 201 Created\r\n"));
+        let echoed = &stdout[head_end..];
+        assert!(echoed.starts_with(b"POST /app.php\n"));
+        assert_eq!(&echoed[b"POST /app.php\n".len()..], &body[..]);
+    }
+
+    #[test]
+    fn get_values_asks_for_names_with_empty_values() {
+        let record = encode_get_values(&[FCGI_MPXS_CONNS, FCGI_MAX_REQS]);
+        let records = RecordReader::new().feed(&record).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record_type, FCGI_GET_VALUES);
+        assert_eq!(records[0].request_id, FCGI_NULL_REQUEST_ID);
+        let names = decode_params(&records[0].content).unwrap();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[FCGI_MPXS_CONNS], "");
+        assert_eq!(names[FCGI_MAX_REQS], "");
+    }
+
+    #[test]
+    fn decode_params_rejects_lengths_past_the_end() {
+        let mut params = HashMap::new();
+        params.insert(FCGI_MPXS_CONNS.to_string(), "1".to_string());
+        let data = encode_params(&params);
+        assert_eq!(decode_params(&data).unwrap(), params);
+        assert!(decode_params(&data[..data.len() - 1]).is_none());
+        assert!(decode_params(&[0x80, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn reader_rejects_other_protocol_versions() {
+        let mut record = encode_record(FCGI_STDOUT, 1, b"x");
+        record[0] = 2;
+        assert!(RecordReader::new().feed(&record).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_BODY_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// A request body too large to keep in memory, spooled to a temporary file.
+/// The file is removed when the last request holding it is dropped.
+#[derive(Debug)]
+pub struct BodyFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl BodyFile {
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        loop {
+            let n = NEXT_BODY_FILE.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("webserv-body-{}-{}", std::process::id(), n));
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(Self { path, file, len: 0 }),
+                // Left behind by an earlier run with the same pid
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Read the body from `offset` into `buf`; returns 0 at the end.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    /// A separate handle positioned at the start, e.g. for a CGI script's stdin.
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+}
+
+impl Drop for BodyFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write as much of `body` from `offset` as a non-blocking socket accepts right now.
+/// Returns the number of bytes written.
+pub fn write_body_file<W: Write>(writer: &mut W, body: &BodyFile, offset: u64) -> io::Result<u64> {
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut written = 0;
+    while offset + written < body.len() {
+        let n = body.read_at(&mut chunk, offset + written)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let sent = crate::utils::net::write_available(writer, &chunk[..n])?;
+        written += sent as u64;
+        if sent < n {
+            break;
+        }
+    }
+    Ok(written)
+}
@@ -0,0 +1,88 @@
+#[derive(Debug)]
+enum ChunkState {
+    Size(Vec<u8>), // Collecting the chunk-size line
+    Data(usize),
+    DataEnd(usize), // CRLF after chunk data; bytes still expected
+    Trailer(Vec<u8>), // Collecting a trailer line; an empty one ends the body
+    Done,
+}
+
+/// Follows a chunked body (RFC 9112 section 7.1) as it arrives, to know where it ends.
+#[derive(Debug)]
+pub struct ChunkTracker {
+    state: ChunkState,
+}
+
+impl ChunkTracker {
+    const MAX_LINE: usize = 8 * 1024;
+
+    pub fn new() -> Self {
+        Self { state: ChunkState::Size(Vec::new()) }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done)
+    }
+
+    /// Returns how many bytes of `data` belong to the body; anything after the end is
+    /// dropped. Chunk contents are appended to `decoded` when given.
+    pub fn feed(&mut self, data: &[u8], mut decoded: Option<&mut Vec<u8>>) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut pos = 0;
+        while pos < data.len() {
+            match &mut self.state {
+                ChunkState::Done => break,
+                ChunkState::Size(line) | ChunkState::Trailer(line) => {
+                    let byte = data[pos];
+                    pos += 1;
+                    if byte != b'\n' {
+                        line.push(byte);
+                        if line.len() > Self::MAX_LINE {
+                            return Err("Chunk line too long".into());
+                        }
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(line).trim().to_string();
+                    self.state = match self.state {
+                        ChunkState::Size(_) => {
+                            let size_str = text.split(';').next().unwrap_or("").trim();
+                            let size = usize::from_str_radix(size_str, 16)
+                                .map_err(|_| format!("Invalid chunk size: {}", text))?;
+                            if size == 0 {
+                                ChunkState::Trailer(Vec::new())
+                            } else {
+                                ChunkState::Data(size)
+                            }
+                        }
+                        _ if text.is_empty() => ChunkState::Done,
+                        _ => ChunkState::Trailer(Vec::new()),
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    let take = (data.len() - pos).min(*remaining);
+                    if let Some(decoded) = decoded.as_deref_mut() {
+                        decoded.extend_from_slice(&data[pos..pos + take]);
+                    }
+                    pos += take;
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        self.state = ChunkState::DataEnd(2);
+                    }
+                }
+                ChunkState::DataEnd(remaining) => {
+                    // Accept a bare LF as well as CRLF
+                    let byte = data[pos];
+                    pos += 1;
+                    if byte == b'\n' {
+                        self.state = ChunkState::Size(Vec::new());
+                    } else {
+                        *remaining -= 1;
+                        if *remaining == 0 {
+                            return Err("Missing CRLF after chunk".into());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(pos)
+    }
+}
@@ -0,0 +1,239 @@
+use super::{HttpMethod, HttpRequest};
+use std::fmt;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An entity tag: `"opaque"`, or `W/"opaque"` for a weak one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub opaque: String,
+}
+
+impl EntityTag {
+    /// A strong tag for a file from its inode, size and modification time, so it
+    /// changes whenever the file is written or replaced.
+    pub fn for_file(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        EntityTag {
+            weak: false,
+            opaque: format!("{:x}-{:x}-{:x}", metadata.ino(), metadata.len(), modified.as_nanos()),
+        }
+    }
+
+    /// Both tags strong and equal (RFC 9110 section 8.8.3.2).
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.opaque == other.opaque
+    }
+
+    /// Equal once weakness is disregarded.
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.opaque == other.opaque
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.opaque)
+        } else {
+            write!(f, "\"{}\"", self.opaque)
+        }
+    }
+}
+
+/// Parse a comma-separated list of entity tags. Parsing stops at anything malformed.
+fn parse_tags(header: &str) -> Vec<EntityTag> {
+    let mut tags = Vec::new();
+    let mut rest = header;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return tags;
+        }
+        let weak = rest.starts_with("W/");
+        rest = rest.strip_prefix("W/").unwrap_or(rest);
+        let quoted = match rest.strip_prefix('"').and_then(|r| r.split_once('"')) {
+            Some(quoted) => quoted,
+            None => return tags,
+        };
+        tags.push(EntityTag { weak, opaque: quoted.0.to_string() });
+        rest = quoted.1;
+    }
+}
+
+/// Whether an `If-Match` or `If-None-Match` list names the current tag; `*` names any
+/// representation that exists.
+fn list_matches(header: &str, current: Option<&EntityTag>, eq: fn(&EntityTag, &EntityTag) -> bool) -> bool {
+    match current {
+        Some(_) if header.trim() == "*" => true,
+        Some(current) => parse_tags(header).iter().any(|tag| eq(tag, current)),
+        None => false,
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A date header in seconds, or `None` when absent or not a valid HTTP-date.
+fn header_date(request: &HttpRequest, name: &str) -> Option<u64> {
+    request.get_header(name).and_then(|value| httpdate::parse_http_date(value.trim()).ok()).map(seconds)
+}
+
+/// The validators of a target resource.
+pub struct Validators {
+    pub etag: EntityTag,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn for_file(metadata: &Metadata) -> Self {
+        Validators {
+            etag: EntityTag::for_file(metadata),
+            last_modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        }
+    }
+}
+
+/// The outcome of a request's preconditions.
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    Proceed,
+    NotModified, // 304, for GET and HEAD
+    Failed, // 412
+}
+
+/// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since`
+/// in the order of RFC 9110 section 13.2.2. `current` is `None` when the target does not exist.
+pub fn evaluate(request: &HttpRequest, current: Option<&Validators>) -> Precondition {
+    let etag = current.map(|c| &c.etag);
+    let safe = matches!(request.method, HttpMethod::GET | HttpMethod::HEAD);
+
+    if let Some(header) = request.get_header("if-match") {
+        if !list_matches(header, etag, EntityTag::strong_eq) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(date), Some(current)) = (header_date(request, "if-unmodified-since"), current) {
+        if seconds(current.last_modified) > date {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(header) = request.get_header("if-none-match") {
+        if list_matches(header, etag, EntityTag::weak_eq) {
+            return if safe { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if let (true, Some(date), Some(current)) = (safe, header_date(request, "if-modified-since"), current) {
+        if seconds(current.last_modified) <= date {
+            return Precondition::NotModified;
+        }
+    }
+    Precondition::Proceed
+}
+
+/// Whether a Range may be honoured: always without `If-Range`, otherwise only if it
+/// names the current representation exactly, by strong tag or by date.
+pub fn if_range_matches(request: &HttpRequest, current: &Validators) -> bool {
+    match request.get_header("if-range").map(|v| v.trim()) {
+        None => true,
+        Some(value) if value.starts_with('"') || value.starts_with("W/") => {
+            parse_tags(value).first().is_some_and(|tag| tag.strong_eq(&current.etag))
+        }
+        Some(value) => httpdate::parse_http_date(value).ok().map(seconds) == Some(seconds(current.last_modified)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: EntityTag { weak: false, opaque: "abc".to_string() },
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    fn date(offset: i64) -> String {
+        let secs = (1_700_000_000 + offset) as u64;
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn request(method: HttpMethod, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut request = HttpRequest::new();
+        request.method = method;
+        for (name, value) in headers {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        request
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Precondition {
+        evaluate(&request(HttpMethod::GET, headers), Some(&validators()))
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        assert_eq!(get(&[("if-none-match", "\"abc\"")]), Precondition::NotModified);
+        assert_eq!(get(&[("if-none-match", "W/\"abc\"")]), Precondition::NotModified);
+        assert_eq!(get(&[("if-none-match", "\"xyz\", W/\"abc\"")]), Precondition::NotModified);
+        assert_eq!(get(&[("if-none-match", "\"xyz\"")]), Precondition::Proceed);
+        assert_eq!(get(&[("if-none-match", "*")]), Precondition::NotModified);
+
+        // `*` only matches a representation that exists
+        let create = request(HttpMethod::PUT, &[("if-none-match", "*")]);
+        assert_eq!(evaluate(&create, None), Precondition::Proceed);
+        assert_eq!(evaluate(&create, Some(&validators())), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        assert_eq!(get(&[("if-match", "\"abc\"")]), Precondition::Proceed);
+        assert_eq!(get(&[("if-match", "*")]), Precondition::Proceed);
+        assert_eq!(get(&[("if-match", "W/\"abc\"")]), Precondition::Failed);
+        assert_eq!(get(&[("if-match", "\"xyz\"")]), Precondition::Failed);
+
+        let update = request(HttpMethod::PUT, &[("if-match", "*")]);
+        assert_eq!(evaluate(&update, None), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_unmodified_since_fails_for_a_newer_representation() {
+        assert_eq!(get(&[("if-unmodified-since", &date(0))]), Precondition::Proceed);
+        assert_eq!(get(&[("if-unmodified-since", &date(-60))]), Precondition::Failed);
+        // If-Match takes its place when both are sent
+        assert_eq!(get(&[("if-match", "\"abc\""), ("if-unmodified-since", &date(-60))]), Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_modified_since_is_ignored_with_if_none_match() {
+        assert_eq!(get(&[("if-modified-since", &date(0))]), Precondition::NotModified);
+        assert_eq!(get(&[("if-modified-since", &date(60))]), Precondition::NotModified);
+        assert_eq!(get(&[("if-modified-since", &date(-60))]), Precondition::Proceed);
+        assert_eq!(get(&[("if-modified-since", "yesterday")]), Precondition::Proceed);
+
+        let headers = [("if-none-match", "\"xyz\""), ("if-modified-since", &date(60)[..])];
+        assert_eq!(get(&headers), Precondition::Proceed);
+        // Only GET and HEAD are answered with 304
+        let post = request(HttpMethod::POST, &[("if-modified-since", &date(60))]);
+        assert_eq!(evaluate(&post, Some(&validators())), Precondition::Proceed);
+    }
+
+    #[test]
+    fn if_range_needs_an_exact_date_or_a_strong_tag() {
+        let current = validators();
+        let matches = |value: &str| if_range_matches(&request(HttpMethod::GET, &[("if-range", value)]), &current);
+
+        assert!(if_range_matches(&request(HttpMethod::GET, &[]), &current));
+        assert!(matches(&date(0)));
+        assert!(!matches(&date(60)));
+        assert!(!matches(&date(-60)));
+        assert!(matches("\"abc\""));
+        assert!(!matches("\"xyz\""));
+        // A weak tag never names a representation exactly
+        assert!(!matches("W/\"abc\""));
+    }
+}
@@ -2,14 +2,22 @@ use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
 
+pub mod body;
+pub mod chunked;
+pub mod conditional;
+pub mod range;
 pub mod request;
 pub mod response;
 pub mod status;
 
+pub use body::BodyFile;
+pub use chunked::ChunkTracker;
 pub use request::HttpRequest;
 pub use response::HttpResponse;
 pub use status::StatusCode;
 
+// Variants are spelled as the methods appear on the wire
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum HttpMethod {
     GET,
@@ -18,6 +26,13 @@ pub enum HttpMethod {
     HEAD,
     PUT,
     OPTIONS,
+    PATCH,
+    PROPFIND,
+    MKCOL,
+    COPY,
+    MOVE,
+    LOCK,
+    UNLOCK,
 }
 
 impl FromStr for HttpMethod {
@@ -31,11 +46,25 @@ impl FromStr for HttpMethod {
             "HEAD" => Ok(HttpMethod::HEAD),
             "PUT" => Ok(HttpMethod::PUT),
             "OPTIONS" => Ok(HttpMethod::OPTIONS),
+            "PATCH" => Ok(HttpMethod::PATCH),
+            "PROPFIND" => Ok(HttpMethod::PROPFIND),
+            "MKCOL" => Ok(HttpMethod::MKCOL),
+            "COPY" => Ok(HttpMethod::COPY),
+            "MOVE" => Ok(HttpMethod::MOVE),
+            "LOCK" => Ok(HttpMethod::LOCK),
+            "UNLOCK" => Ok(HttpMethod::UNLOCK),
             _ => Err(()),
         }
     }
 }
 
+impl HttpMethod {
+    /// Whether repeating the request has the same effect as sending it once (RFC 9110 9.2.2).
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, HttpMethod::POST | HttpMethod::PATCH | HttpMethod::LOCK)
+    }
+}
+
 impl fmt::Display for HttpMethod {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let method_str = match self {
@@ -45,6 +74,13 @@ impl fmt::Display for HttpMethod {
             HttpMethod::HEAD => "HEAD",
             HttpMethod::PUT => "PUT",
             HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::PROPFIND => "PROPFIND",
+            HttpMethod::MKCOL => "MKCOL",
+            HttpMethod::COPY => "COPY",
+            HttpMethod::MOVE => "MOVE",
+            HttpMethod::LOCK => "LOCK",
+            HttpMethod::UNLOCK => "UNLOCK",
         };
         write!(f, "{}", method_str)
     }
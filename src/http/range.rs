@@ -0,0 +1,171 @@
+/// One range of a representation, first and last byte inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `Content-Range` value for this range of a representation of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// What a `Range` header asks of a representation of a given length.
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Ranges(Vec<ByteRange>), // Sorted, with overlapping ranges merged
+    Unsatisfiable, // Well-formed, but no range overlaps the representation
+    Ignored, // Not a byte range we understand: the whole representation is sent
+}
+
+/// More ranges than this are not worth a multipart response and are ignored.
+const MAX_RANGES: usize = 32;
+
+/// Parse `Range: bytes=0-499,-500` (RFC 9110 section 14.1.2) against `len` bytes.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Ignored,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Ignored;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some(split) => split,
+            None => return RangeRequest::Ignored,
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // The last `last` bytes
+            let suffix: u64 = match last.parse() {
+                Ok(suffix) => suffix,
+                Err(_) => return RangeRequest::Ignored,
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            ByteRange { start: len.saturating_sub(suffix), end: len - 1 }
+        } else {
+            let start: u64 = match first.parse() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Ignored,
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Ignored,
+                }
+            };
+            if start >= len {
+                continue;
+            }
+            ByteRange { start, end: end.min(len - 1) }
+        };
+        ranges.push(range);
+    }
+    if count == 0 {
+        return RangeRequest::Ignored;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    RangeRequest::Ranges(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, len: u64) -> Vec<(u64, u64)> {
+        match parse_range(header, len) {
+            RangeRequest::Ranges(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            other => panic!("{} gave {:?}", header, other),
+        }
+    }
+
+    #[test]
+    fn suffix_ranges_count_from_the_end() {
+        assert_eq!(ranges("bytes=-500", 1000), vec![(500, 999)]);
+        // Longer than the representation: all of it
+        assert_eq!(ranges("bytes=-5000", 1000), vec![(0, 999)]);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn open_ended_and_overlong_ranges_stop_at_the_end() {
+        assert_eq!(ranges("bytes=900-", 1000), vec![(900, 999)]);
+        assert_eq!(ranges("bytes=0-", 1000), vec![(0, 999)]);
+        assert_eq!(ranges("bytes=10-5000", 1000), vec![(10, 999)]);
+        assert_eq!(ranges("Bytes = 0-0", 1000), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(ranges("bytes=0-99,50-149", 1000), vec![(0, 149)]);
+        assert_eq!(ranges("bytes=0-99,100-199", 1000), vec![(0, 199)]);
+        assert_eq!(ranges("bytes=100-199,0-99,150-", 1000), vec![(0, 999)]);
+        assert_eq!(ranges("bytes=-100,850-949", 1000), vec![(850, 999)]);
+    }
+
+    #[test]
+    fn ranges_starting_at_or_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1999", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5000-6000,1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        // Only the ranges that overlap are served
+        assert_eq!(ranges("bytes=2000-,10-19", 1000), vec![(10, 19)]);
+    }
+
+    #[test]
+    fn malformed_specs_are_ignored() {
+        for header in [
+            "items=0-99",
+            "bytes",
+            "bytes=",
+            "bytes=,",
+            "bytes=abc",
+            "bytes=10",
+            "bytes=x-10",
+            "bytes=10-x",
+            "bytes=-x",
+            "bytes=20-10",
+            "bytes=0-9,oops",
+        ] {
+            assert_eq!(parse_range(header, 1000), RangeRequest::Ignored, "{}", header);
+        }
+        let many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect();
+        assert_eq!(parse_range(&format!("bytes={}", many.join(",")), 1000), RangeRequest::Ignored);
+    }
+
+    #[test]
+    fn multiple_ranges_are_sorted() {
+        assert_eq!(ranges("bytes=500-599, 0-9, -1", 1000), vec![(0, 9), (500, 599), (999, 999)]);
+        let range = ByteRange { start: 500, end: 599 };
+        assert_eq!(range.len(), 100);
+        assert_eq!(range.content_range(1000), "bytes 500-599/1000");
+    }
+}
@@ -1,6 +1,7 @@
-use super::{HttpMethod, HttpVersion, Headers};
+use super::{BodyFile, HttpMethod, HttpVersion, Headers};
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
@@ -10,6 +11,7 @@ pub struct HttpRequest {
     pub version: HttpVersion,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    pub body_file: Option<Arc<BodyFile>>, // Set instead of `body` when the body was spooled to disk
     pub query_params: HashMap<String, String>,
     pub cookies: HashMap<String, String>,
 }
@@ -74,6 +76,7 @@ impl HttpRequest {
             version: HttpVersion::default(),
             headers: HashMap::new(),
             body: Vec::new(),
+            body_file: None,
             query_params: HashMap::new(),
             cookies: HashMap::new(),
             query_string: None,
@@ -81,15 +84,12 @@ impl HttpRequest {
     }
 
     pub fn parse(data: &[u8]) -> Result<Self, ParseError> {
-        let request_str = String::from_utf8_lossy(data);
-        let parts: Vec<&str> = request_str.splitn(2, "\r\n\r\n").collect();
-        
-        if parts.is_empty() {
-            return Err(ParseError::IncompleteRequest);
-        }
-
-        let header_part = parts[0];
-        let body_part = if parts.len() > 1 { parts[1].as_bytes() } else { &[] };
+        // Only the head is text: the body is kept byte for byte
+        let (head, body_part) = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => (&data[..end], &data[end + 4..]),
+            None => return Err(ParseError::IncompleteRequest),
+        };
+        let header_part = String::from_utf8_lossy(head);
 
         let mut lines = header_part.lines();
         
@@ -130,12 +130,35 @@ impl HttpRequest {
             version,
             headers,
             body,
+            body_file: None,
             query_params,
             cookies,
             query_string,
         })
     }
 
+    /// The GET a server issues for a CGI local redirect: same headers, new target, no body.
+    pub fn internal_redirect(&self, location: &str) -> Self {
+        let (path, query_string) = Self::parse_uri(location);
+        let query_params = query_string.as_deref().map(Self::parse_query_string).unwrap_or_default();
+        let mut headers = self.headers.clone();
+        for name in ["content-length", "content-type", "transfer-encoding", "expect"] {
+            headers.remove(name);
+        }
+
+        HttpRequest {
+            method: HttpMethod::GET,
+            uri: path,
+            version: self.version.clone(),
+            headers,
+            body: Vec::new(),
+            body_file: None,
+            query_params,
+            cookies: self.cookies.clone(),
+            query_string,
+        }
+    }
+
     fn parse_request_line(line: &str) -> Result<(HttpMethod, String, HttpVersion), ParseError> {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() != 3 {
@@ -241,6 +264,14 @@ impl HttpRequest {
             .and_then(|v| v.parse().ok())
     }
 
+    /// Length of the body, whether it is held in memory or spooled to disk.
+    pub fn body_len(&self) -> u64 {
+        match &self.body_file {
+            Some(file) => file.len(),
+            None => self.body.len() as u64,
+        }
+    }
+
     pub fn content_type(&self) -> Option<&String> {
         self.get_header("content-type")
     }
@@ -2,6 +2,7 @@ use super::{Headers, HttpVersion, StatusCode};
 use std::collections::HashMap;
 use std::fmt::Write;
 use std::fs;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct HttpResponse {
@@ -9,6 +10,31 @@ pub struct HttpResponse {
     pub status: StatusCode,
     pub headers: Headers,
     pub body: Vec<u8>,
+    pub body_parts: Vec<BodyPart>, // Sent after `body`, for bodies served from files
+}
+
+/// A region of an open file, sent with sendfile(2) instead of being read into memory.
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    pub file: Arc<fs::File>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A piece of a response body after `body`.
+#[derive(Debug, Clone)]
+pub enum BodyPart {
+    Bytes(Vec<u8>),
+    File(FileRegion),
+}
+
+impl BodyPart {
+    pub fn len(&self) -> u64 {
+        match self {
+            BodyPart::Bytes(bytes) => bytes.len() as u64,
+            BodyPart::File(region) => region.len,
+        }
+    }
 }
 
 impl HttpResponse {
@@ -22,6 +48,7 @@ impl HttpResponse {
             status,
             headers,
             body: Vec::new(),
+            body_parts: Vec::new(),
         }
     }
 
@@ -50,6 +77,20 @@ impl HttpResponse {
         response
     }
 
+    pub fn bad_gateway() -> Self {
+        let mut response = Self::new(StatusCode::BadGateway);
+        response.set_body(b"<html><body><h1>502 Bad Gateway</h1></body></html>");
+        response.set_header("content-type", "text/html");
+        response
+    }
+
+    pub fn gateway_timeout() -> Self {
+        let mut response = Self::new(StatusCode::GatewayTimeout);
+        response.set_body(b"<html><body><h1>504 Gateway Timeout</h1></body></html>");
+        response.set_header("content-type", "text/html");
+        response
+    }
+
     pub fn method_not_allowed_custom(error_page: Option<&str>) -> Self {
         if let Some(path) = error_page {
             if let Ok(metadata) = std::fs::metadata(path) {
@@ -76,6 +117,13 @@ impl HttpResponse {
         response
     }
 
+    pub fn conflict() -> Self {
+        let mut response = Self::new(StatusCode::Conflict);
+        response.set_body(b"<html><body><h1>409 Conflict</h1></body></html>");
+        response.set_header("content-type", "text/html");
+        response
+    }
+
     pub fn payload_too_large() -> Self {
         let mut response = Self::new(StatusCode::PayloadTooLarge);
         response.set_body(b"<html><body><h1>413 Payload Too Large</h1></body></html>");
@@ -104,6 +152,7 @@ impl HttpResponse {
 
     pub fn set_body(&mut self, body: &[u8]) {
         self.body = body.to_vec();
+        self.body_parts.clear();
         // Only set Content-Length if not chunked
         let chunked = self.headers.get("transfer-encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
         if !chunked {
@@ -113,6 +162,15 @@ impl HttpResponse {
         }
     }
 
+    /// Make the body a sequence of parts, file regions among them, sent as they are
+    /// without being joined in memory.
+    pub fn set_body_parts(&mut self, parts: Vec<BodyPart>) {
+        self.body.clear();
+        let len: u64 = parts.iter().map(BodyPart::len).sum();
+        self.body_parts = parts;
+        self.set_header("content-length", &len.to_string());
+    }
+
     pub fn set_body_string(&mut self, body: &str) {
         self.set_body(body.as_bytes());
     }
@@ -131,7 +189,8 @@ impl HttpResponse {
         self.headers.insert("set-cookie".to_string(), cookie);
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Status line and headers, for responses whose body is streamed separately.
+    pub fn head_bytes(&self) -> Vec<u8> {
         let mut response = Vec::new();
         // Status line
         let status_line = format!("{} {}\r\n", self.version, self.status);
@@ -143,6 +202,12 @@ impl HttpResponse {
         }
         // Empty line to separate headers from body
         response.extend_from_slice(b"\r\n");
+        response
+    }
+
+    /// Status line, headers and `body`; `body_parts` are left to the caller.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = self.head_bytes();
         // Body
         let chunked = self.headers.get("transfer-encoding").map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
         if chunked {
@@ -156,19 +221,26 @@ impl HttpResponse {
     /// Helper to encode a body as chunked transfer encoding
     fn encode_chunked_body(body: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
-        let mut pos = 0;
-        let len = body.len();
-        let chunk_size = 4096;
-        while pos < len {
-            let this_chunk = std::cmp::min(chunk_size, len - pos);
-            let size_line = format!("{:X}\r\n", this_chunk);
-            out.extend_from_slice(size_line.as_bytes());
-            out.extend_from_slice(&body[pos..pos + this_chunk]);
-            out.extend_from_slice(b"\r\n");
-            pos += this_chunk;
+        for chunk in body.chunks(4096) {
+            out.extend_from_slice(&Self::encode_chunk(chunk));
         }
         // Final zero-length chunk
-        out.extend_from_slice(b"0\r\n\r\n");
+        out.extend_from_slice(Self::LAST_CHUNK);
+        out
+    }
+
+    /// The zero-length chunk that ends a chunked body.
+    pub const LAST_CHUNK: &'static [u8] = b"0\r\n\r\n";
+
+    /// Encode one piece of a streamed body as a chunk. Empty input yields nothing,
+    /// since an empty chunk would end the body.
+    pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return Vec::new();
+        }
+        let mut out = format!("{:X}\r\n", data.len()).into_bytes();
+        out.extend_from_slice(data);
+        out.extend_from_slice(b"\r\n");
         out
     }
 
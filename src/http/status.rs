@@ -11,11 +11,16 @@ pub enum StatusCode {
     Created = 201,
     Accepted = 202,
     NoContent = 204,
+    PartialContent = 206,
+    MultiStatus = 207,
     
     // 3xx Redirection
     MovedPermanently = 301,
     Found = 302,
+    SeeOther = 303,
     NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
     
     // 4xx Client Error
     BadRequest = 400,
@@ -24,15 +29,21 @@ pub enum StatusCode {
     NotFound = 404,
     MethodNotAllowed = 405,
     RequestTimeout = 408,
+    Conflict = 409,
     LengthRequired = 411,
+    PreconditionFailed = 412,
     PayloadTooLarge = 413,
     UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    Locked = 423,
     
     // 5xx Server Error
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
     ServiceUnavailable = 503,
+    GatewayTimeout = 504,
     HttpVersionNotSupported = 505,
 }
 
@@ -45,22 +56,33 @@ impl StatusCode {
             StatusCode::Created => "Created",
             StatusCode::Accepted => "Accepted",
             StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MultiStatus => "Multi-Status",
             StatusCode::MovedPermanently => "Moved Permanently",
             StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
             StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
             StatusCode::BadRequest => "Bad Request",
             StatusCode::Unauthorized => "Unauthorized",
             StatusCode::Forbidden => "Forbidden",
             StatusCode::NotFound => "Not Found",
             StatusCode::MethodNotAllowed => "Method Not Allowed",
             StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
             StatusCode::LengthRequired => "Length Required",
+            StatusCode::PreconditionFailed => "Precondition Failed",
             StatusCode::PayloadTooLarge => "Payload Too Large",
             StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::Locked => "Locked",
             StatusCode::InternalServerError => "Internal Server Error",
             StatusCode::NotImplemented => "Not Implemented",
             StatusCode::BadGateway => "Bad Gateway",
             StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
             StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
         }
     }
@@ -101,22 +123,33 @@ impl From<u16> for StatusCode {
             201 => StatusCode::Created,
             202 => StatusCode::Accepted,
             204 => StatusCode::NoContent,
+            206 => StatusCode::PartialContent,
+            207 => StatusCode::MultiStatus,
             301 => StatusCode::MovedPermanently,
             302 => StatusCode::Found,
+            303 => StatusCode::SeeOther,
             304 => StatusCode::NotModified,
+            307 => StatusCode::TemporaryRedirect,
+            308 => StatusCode::PermanentRedirect,
             400 => StatusCode::BadRequest,
             401 => StatusCode::Unauthorized,
             403 => StatusCode::Forbidden,
             404 => StatusCode::NotFound,
             405 => StatusCode::MethodNotAllowed,
             408 => StatusCode::RequestTimeout,
+            409 => StatusCode::Conflict,
             411 => StatusCode::LengthRequired,
+            412 => StatusCode::PreconditionFailed,
             413 => StatusCode::PayloadTooLarge,
             414 => StatusCode::UriTooLong,
+            415 => StatusCode::UnsupportedMediaType,
+            416 => StatusCode::RangeNotSatisfiable,
+            423 => StatusCode::Locked,
             500 => StatusCode::InternalServerError,
             501 => StatusCode::NotImplemented,
             502 => StatusCode::BadGateway,
             503 => StatusCode::ServiceUnavailable,
+            504 => StatusCode::GatewayTimeout,
             505 => StatusCode::HttpVersionNotSupported,
             _ => StatusCode::InternalServerError,
         }
@@ -5,9 +5,15 @@ mod config;
 mod server;
 mod http;
 mod cgi;
+mod fastcgi;
+mod proxy;
+mod cache;
+mod compression;
 mod utils;
 mod static_handler;
 mod upload_handler;
+mod tus_handler;
+mod dav_handler;
 
 use config::Config;
 use server::WebServer;
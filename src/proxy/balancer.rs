@@ -0,0 +1,482 @@
+use crate::config::{BalanceMethod, HealthCheck, ListenAddr, UpstreamConfig};
+use crate::utils::hash::fnv1a;
+use crate::utils::json;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Runtime state of one server in an upstream group.
+#[derive(Debug)]
+struct Peer {
+    addr: ListenAddr,
+    weight: i64,
+    max_fails: u32,
+    fail_timeout: Duration,
+    current_weight: i64, // Smooth weighted round-robin state
+    active: usize, // Requests in flight
+    fails: u32,
+    failure_window: Option<Instant>, // When the first of `fails` happened
+    down_until: Option<Instant>,
+    healthy: bool, // Verdict of the active health checks; servers start out healthy
+    passes: u32, // Consecutive health check results
+    failures: u32,
+    last_check: Option<(Instant, String)>, // When the last check ended and its status or error
+}
+
+impl Peer {
+    fn is_up(&self, now: Instant) -> bool {
+        self.healthy && self.down_until.is_none_or(|until| now >= until)
+    }
+
+    fn state(&self, now: Instant) -> &'static str {
+        if !self.healthy {
+            "unhealthy"
+        } else if self.is_up(now) {
+            "up"
+        } else {
+            "down"
+        }
+    }
+}
+
+/// Picks servers from an `upstream` group and tracks their health from the outcome of
+/// proxied requests (passive health checks): after `max_fails` failures within
+/// `fail_timeout`, a server is left out of the rotation for `fail_timeout`. With a
+/// `health_check`, servers are also taken out after `fall` failed checks in a row and
+/// return after `rise` passed ones.
+#[derive(Debug)]
+pub struct UpstreamGroup {
+    name: String,
+    method: BalanceMethod,
+    peers: Vec<Peer>,
+    health_check: Option<HealthCheck>,
+    next_check: Instant,
+}
+
+impl UpstreamGroup {
+    /// How many times ip_hash rehashes before falling back to round-robin.
+    const MAX_REHASH: usize = 20;
+
+    pub fn new(config: &UpstreamConfig) -> Self {
+        let peers = config.servers.iter()
+            .map(|server| Peer {
+                addr: server.addr.clone(),
+                weight: server.weight as i64,
+                max_fails: server.max_fails,
+                fail_timeout: server.fail_timeout,
+                current_weight: 0,
+                active: 0,
+                fails: 0,
+                failure_window: None,
+                down_until: None,
+                healthy: true,
+                passes: 0,
+                failures: 0,
+                last_check: None,
+            })
+            .collect();
+        Self {
+            name: config.name.clone(),
+            method: config.method,
+            peers,
+            health_check: config.health_check.clone(),
+            next_check: Instant::now(),
+        }
+    }
+
+    pub fn addr(&self, peer: usize) -> &ListenAddr {
+        &self.peers[peer].addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    /// Whether the group's servers are due for a health check; if so the next round is
+    /// scheduled one interval from `now`.
+    pub fn checks_due(&mut self, now: Instant) -> bool {
+        let interval = match &self.health_check {
+            Some(check) => check.interval,
+            None => return false,
+        };
+        if now < self.next_check {
+            return false;
+        }
+        self.next_check = now + interval;
+        true
+    }
+
+    /// Record the outcome of an active check: the status code received, or why there
+    /// was none. Returns the server's new health when this result changed it.
+    pub fn record_check(&mut self, peer: usize, result: Result<u16, String>, now: Instant) -> Option<bool> {
+        let check = self.health_check.as_ref()?;
+        let peer = &mut self.peers[peer];
+        let passed = matches!(result, Ok(status) if check.accepts(status));
+        peer.last_check = Some((now, match result {
+            Ok(status) => status.to_string(),
+            Err(e) => e,
+        }));
+
+        if passed {
+            peer.passes += 1;
+            peer.failures = 0;
+            if !peer.healthy && peer.passes >= check.rise {
+                peer.healthy = true;
+                return Some(true);
+            }
+        } else {
+            peer.failures += 1;
+            peer.passes = 0;
+            if peer.healthy && peer.failures >= check.fall {
+                peer.healthy = false;
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    /// Choose a server for the next attempt at a request, skipping those in `tried`.
+    /// If every remaining server is marked down they are chosen from anyway: one may
+    /// have recovered, and refusing the request outright helps nobody.
+    pub fn select(&mut self, client_ip: Option<IpAddr>, tried: &[usize], now: Instant) -> Option<usize> {
+        let remaining: Vec<usize> = (0..self.peers.len()).filter(|peer| !tried.contains(peer)).collect();
+        if remaining.is_empty() {
+            return None;
+        }
+        let up: Vec<usize> = remaining.iter().copied().filter(|&peer| self.peers[peer].is_up(now)).collect();
+        let candidates = if up.is_empty() { remaining } else { up };
+
+        Some(match (self.method, client_ip) {
+            (BalanceMethod::IpHash, Some(ip)) => self.select_by_hash(ip, &candidates),
+            (BalanceMethod::LeastConn, _) => self.select_least_conn(&candidates),
+            // Unix socket clients have no address to hash
+            _ => self.select_round_robin(&candidates),
+        })
+    }
+
+    /// Smooth weighted round-robin (as in nginx): every candidate gains its weight, the
+    /// highest total wins and pays back the sum. Weights 5,1,1 give a a b a c a a rather
+    /// than a burst of five requests to the same server.
+    fn select_round_robin(&mut self, candidates: &[usize]) -> usize {
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &peer in candidates {
+            self.peers[peer].current_weight += self.peers[peer].weight;
+            total += self.peers[peer].weight;
+            if self.peers[peer].current_weight > self.peers[best].current_weight {
+                best = peer;
+            }
+        }
+        self.peers[best].current_weight -= total;
+        best
+    }
+
+    /// Fewest requests in flight relative to weight; ties go round-robin.
+    fn select_least_conn(&mut self, candidates: &[usize]) -> usize {
+        // Compare active/weight without dividing: a/wa < b/wb  <=>  a*wb < b*wa
+        let load = |peer: &Peer| (peer.active as i64, peer.weight);
+        let (active, weight) = candidates.iter()
+            .map(|&peer| load(&self.peers[peer]))
+            .min_by(|(a, wa), (b, wb)| (a * wb).cmp(&(b * wa)))
+            .unwrap_or((0, 1));
+        let least: Vec<usize> = candidates.iter().copied()
+            .filter(|&peer| {
+                let (a, wa) = load(&self.peers[peer]);
+                a * weight == active * wa
+            })
+            .collect();
+        self.select_round_robin(&least)
+    }
+
+    /// The same client address keeps going to the same server while it is up. The hash
+    /// covers every server, not just the candidates, so clients of the other servers stay
+    /// put when one goes down.
+    fn select_by_hash(&mut self, ip: IpAddr, candidates: &[usize]) -> usize {
+        let total_weight: i64 = self.peers.iter().map(|peer| peer.weight).sum();
+        let mut hash = match ip {
+            IpAddr::V4(v4) => fnv1a(&v4.octets()),
+            IpAddr::V6(v6) => fnv1a(&v6.octets()),
+        };
+        for _ in 0..Self::MAX_REHASH {
+            let mut point = (hash % total_weight as u64) as i64;
+            let peer = self.peers.iter().position(|peer| {
+                point -= peer.weight;
+                point < 0
+            });
+            if let Some(peer) = peer.filter(|peer| candidates.contains(peer)) {
+                return peer;
+            }
+            hash = fnv1a(&hash.to_be_bytes());
+        }
+        self.select_round_robin(candidates)
+    }
+
+    /// A request has been sent to `peer`.
+    pub fn acquire(&mut self, peer: usize) {
+        self.peers[peer].active += 1;
+    }
+
+    /// The request sent to `peer` has ended, whatever the outcome.
+    pub fn release(&mut self, peer: usize) {
+        self.peers[peer].active = self.peers[peer].active.saturating_sub(1);
+    }
+
+    /// `peer` answered: forget earlier failures and bring it back if it was down.
+    pub fn record_success(&mut self, peer: usize) {
+        let peer = &mut self.peers[peer];
+        peer.fails = 0;
+        peer.failure_window = None;
+        peer.down_until = None;
+    }
+
+    /// `peer` failed or timed out. Returns how long it is now marked down for, if this
+    /// failure was the one that reached `max_fails`.
+    pub fn record_failure(&mut self, peer: usize, now: Instant) -> Option<Duration> {
+        let peer = &mut self.peers[peer];
+        if peer.max_fails == 0 {
+            return None;
+        }
+        if peer.failure_window.is_none_or(|start| now.duration_since(start) >= peer.fail_timeout) {
+            peer.failure_window = Some(now);
+            peer.fails = 0;
+        }
+        peer.fails += 1;
+        if peer.fails < peer.max_fails {
+            return None;
+        }
+        peer.fails = 0;
+        peer.failure_window = None;
+        peer.down_until = Some(now + peer.fail_timeout);
+        Some(peer.fail_timeout)
+    }
+}
+
+impl UpstreamGroup {
+    /// The group and the state of each server, as a JSON object.
+    pub fn status_json(&self, now: Instant) -> String {
+        let method = match self.method {
+            BalanceMethod::RoundRobin => "round_robin",
+            BalanceMethod::LeastConn => "least_conn",
+            BalanceMethod::IpHash => "ip_hash",
+        };
+        let servers: Vec<String> = self.peers.iter()
+            .map(|peer| {
+                let down_for = peer.down_until
+                    .map(|until| until.saturating_duration_since(now).as_millis())
+                    .unwrap_or(0);
+                let mut server = format!(
+                    "{{\"addr\":{},\"weight\":{},\"state\":\"{}\",\"active\":{},\"fails\":{},\"down_for_ms\":{}",
+                    json::string(&peer.addr.to_string()), peer.weight, peer.state(now), peer.active, peer.fails, down_for,
+                );
+                if self.health_check.is_some() {
+                    let (last_result, checked_ago) = match &peer.last_check {
+                        Some((at, result)) => (json::string(result), now.saturating_duration_since(*at).as_millis().to_string()),
+                        None => ("null".to_string(), "null".to_string()),
+                    };
+                    server.push_str(&format!(
+                        ",\"health_check\":{{\"last_result\":{},\"checked_ago_ms\":{},\"passes\":{},\"failures\":{}}}",
+                        last_result, checked_ago, peer.passes, peer.failures,
+                    ));
+                }
+                server.push('}');
+                server
+            })
+            .collect();
+        format!(
+            "{{\"name\":{},\"method\":\"{}\",\"servers\":[{}]}}",
+            json::string(&self.name), method, servers.join(","),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamServer;
+    use std::net::SocketAddr;
+
+    fn group(method: BalanceMethod, weights: &[u32], max_fails: u32) -> UpstreamGroup {
+        let servers = weights.iter().enumerate()
+            .map(|(i, &weight)| UpstreamServer {
+                addr: ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 3000 + i as u16))),
+                weight,
+                max_fails,
+                fail_timeout: Duration::from_secs(10),
+            })
+            .collect();
+        UpstreamGroup::new(&UpstreamConfig {
+            name: "backend".to_string(),
+            method,
+            servers,
+            health_check: None,
+        })
+    }
+
+    fn checked_group(rise: u32, fall: u32) -> UpstreamGroup {
+        let mut group = group(BalanceMethod::RoundRobin, &[1, 1], 1);
+        group.health_check = Some(HealthCheck { rise, fall, ..HealthCheck::default() });
+        group
+    }
+
+    fn picks(group: &mut UpstreamGroup, client_ip: Option<IpAddr>, now: Instant, n: usize) -> Vec<usize> {
+        (0..n).map(|_| group.select(client_ip, &[], now).unwrap()).collect()
+    }
+
+    #[test]
+    fn round_robin_spreads_weighted_picks() {
+        let mut group = group(BalanceMethod::RoundRobin, &[5, 1, 1], 1);
+        let cycle = vec![0, 0, 1, 0, 2, 0, 0];
+        assert_eq!(picks(&mut group, None, Instant::now(), 7), cycle);
+        assert_eq!(picks(&mut group, None, Instant::now(), 7), cycle);
+    }
+
+    #[test]
+    fn select_skips_tried_servers() {
+        let mut group = group(BalanceMethod::RoundRobin, &[1, 1], 1);
+        let now = Instant::now();
+        assert_eq!(group.select(None, &[0], now), Some(1));
+        assert_eq!(group.select(None, &[0, 1], now), None);
+    }
+
+    #[test]
+    fn least_conn_follows_requests_in_flight() {
+        let mut group = group(BalanceMethod::LeastConn, &[1, 1], 1);
+        let now = Instant::now();
+        group.acquire(0);
+        assert_eq!(picks(&mut group, None, now, 3), vec![1, 1, 1]);
+        group.acquire(1);
+        group.acquire(1);
+        assert_eq!(picks(&mut group, None, now, 3), vec![0, 0, 0]);
+        group.release(1);
+        group.release(1);
+        assert_eq!(group.select(None, &[], now), Some(1));
+    }
+
+    #[test]
+    fn ip_hash_is_sticky_and_rehashes_past_a_down_server() {
+        let mut group = group(BalanceMethod::IpHash, &[1, 1, 1], 1);
+        let now = Instant::now();
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let home = group.select(Some(ip), &[], now).unwrap();
+        assert!(picks(&mut group, Some(ip), now, 10).iter().all(|&peer| peer == home));
+
+        group.record_failure(home, now);
+        let moved = group.select(Some(ip), &[], now).unwrap();
+        assert_ne!(moved, home);
+        assert!(picks(&mut group, Some(ip), now, 10).iter().all(|&peer| peer == moved));
+
+        // Back home once the server's fail_timeout has passed
+        let later = now + Duration::from_secs(11);
+        assert_eq!(group.select(Some(ip), &[], later), Some(home));
+    }
+
+    #[test]
+    fn max_fails_within_fail_timeout_marks_a_server_down() {
+        let mut group = group(BalanceMethod::RoundRobin, &[1, 1], 2);
+        let now = Instant::now();
+
+        // Failures further apart than fail_timeout don't add up
+        assert_eq!(group.record_failure(0, now), None);
+        assert_eq!(group.record_failure(0, now + Duration::from_secs(11)), None);
+
+        let failed_at = now + Duration::from_secs(12);
+        assert_eq!(group.record_failure(0, failed_at), Some(Duration::from_secs(10)));
+        assert!(picks(&mut group, None, failed_at, 4).iter().all(|&peer| peer == 1));
+        assert!(picks(&mut group, None, failed_at + Duration::from_secs(9), 4).iter().all(|&peer| peer == 1));
+
+        let recovered = failed_at + Duration::from_secs(10);
+        assert!(picks(&mut group, None, recovered, 4).contains(&0));
+    }
+
+    #[test]
+    fn down_servers_are_still_tried_when_all_are_down() {
+        let mut group = group(BalanceMethod::RoundRobin, &[1, 1], 1);
+        let now = Instant::now();
+        group.record_failure(0, now);
+        group.record_failure(1, now);
+        assert!(group.select(None, &[], now).is_some());
+
+        // A success brings a server back right away
+        group.record_success(0);
+        assert!(picks(&mut group, None, now, 4).iter().all(|&peer| peer == 0));
+    }
+
+    #[test]
+    fn failed_checks_take_a_server_out_after_fall() {
+        let mut group = checked_group(2, 3);
+        let now = Instant::now();
+
+        assert_eq!(group.record_check(0, Err("connection refused".to_string()), now), None);
+        assert_eq!(group.record_check(0, Ok(500), now), None);
+        // A pass in between starts the count over
+        assert_eq!(group.record_check(0, Ok(200), now), None);
+        assert_eq!(group.record_check(0, Ok(503), now), None);
+        assert_eq!(group.record_check(0, Ok(503), now), None);
+        assert!(picks(&mut group, None, now, 4).contains(&0));
+
+        assert_eq!(group.record_check(0, Err("timed out".to_string()), now), Some(false));
+        assert!(picks(&mut group, None, now, 4).iter().all(|&peer| peer == 1));
+        // Further failures change nothing
+        assert_eq!(group.record_check(0, Ok(500), now), None);
+    }
+
+    #[test]
+    fn passed_checks_bring_a_server_back_after_rise() {
+        let mut group = checked_group(2, 1);
+        let now = Instant::now();
+        assert_eq!(group.record_check(0, Ok(404), now), Some(false));
+
+        assert_eq!(group.record_check(0, Ok(200), now), None);
+        assert_eq!(group.record_check(0, Ok(500), now), None);
+        assert_eq!(group.record_check(0, Ok(204), now), None);
+        assert!(picks(&mut group, None, now, 4).iter().all(|&peer| peer == 1));
+
+        assert_eq!(group.record_check(0, Ok(301), now), Some(true));
+        assert!(picks(&mut group, None, now, 4).contains(&0));
+        assert_eq!(group.record_check(0, Ok(200), now), None);
+    }
+
+    #[test]
+    fn checks_only_accept_the_configured_statuses() {
+        let mut checked = checked_group(1, 1);
+        checked.health_check.as_mut().unwrap().statuses = vec![204];
+        let now = Instant::now();
+        assert_eq!(checked.record_check(0, Ok(200), now), Some(false));
+        assert_eq!(checked.record_check(0, Ok(204), now), Some(true));
+
+        // Without a health_check results are not recorded
+        let mut unchecked = group(BalanceMethod::RoundRobin, &[1], 1);
+        assert_eq!(unchecked.record_check(0, Ok(500), now), None);
+    }
+
+    #[test]
+    fn status_json_reports_each_server() {
+        let mut checked = checked_group(2, 1);
+        checked.peers[0].weight = 2;
+        let now = Instant::now();
+        checked.record_check(0, Err("connection refused".to_string()), now);
+        checked.record_failure(1, now);
+        checked.acquire(1);
+
+        assert_eq!(
+            checked.status_json(now + Duration::from_millis(1500)),
+            concat!(
+                "{\"name\":\"backend\",\"method\":\"round_robin\",\"servers\":[",
+                "{\"addr\":\"127.0.0.1:3000\",\"weight\":2,\"state\":\"unhealthy\",\"active\":0,\"fails\":0,\"down_for_ms\":0,",
+                "\"health_check\":{\"last_result\":\"connection refused\",\"checked_ago_ms\":1500,\"passes\":0,\"failures\":1}},",
+                "{\"addr\":\"127.0.0.1:3001\",\"weight\":1,\"state\":\"down\",\"active\":1,\"fails\":0,\"down_for_ms\":8500,",
+                "\"health_check\":{\"last_result\":null,\"checked_ago_ms\":null,\"passes\":0,\"failures\":0}}]}",
+            ),
+        );
+
+        // Without a health_check there is nothing to report about checks
+        let unchecked = group(BalanceMethod::LeastConn, &[1], 1);
+        assert_eq!(
+            unchecked.status_json(now),
+            "{\"name\":\"backend\",\"method\":\"least_conn\",\"servers\":[{\"addr\":\"127.0.0.1:3000\",\"weight\":1,\"state\":\"up\",\"active\":0,\"fails\":0,\"down_for_ms\":0}]}",
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::http::{ChunkTracker, HttpMethod, HttpRequest, HttpResponse};
+use std::net::IpAddr;
+
+mod balancer;
+pub use balancer::UpstreamGroup;
+
+/// Headers that describe a single connection and must not be forwarded (RFC 9110 section 7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn is_hop_by_hop(name: &str, connection_tokens: &[String]) -> bool {
+    let name = name.to_ascii_lowercase();
+    HOP_BY_HOP.contains(&name.as_str()) || connection_tokens.contains(&name)
+}
+
+/// Header names listed in a `Connection` header are hop-by-hop as well.
+fn connection_tokens(value: Option<&str>) -> Vec<String> {
+    value
+        .map(|v| v.split(',').map(|t| t.trim().to_ascii_lowercase()).filter(|t| !t.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Serialize the request to send upstream: `path` replaces the request target, `host`
+/// the Host header, and X-Forwarded-For/-Proto record the original client. The upstream
+/// connection is used for this request only. A body spooled to disk is not included
+/// and has to be sent after these bytes.
+pub fn build_upstream_request(request: &HttpRequest, path: &str, host: &str, client_ip: Option<IpAddr>) -> Vec<u8> {
+    let target = match &request.query_string {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, host);
+
+    let tokens = connection_tokens(request.headers.get("connection").map(|v| v.as_str()));
+    for (name, value) in &request.headers {
+        // The body is forwarded in full, so there is nothing to continue
+        if is_hop_by_hop(name, &tokens)
+            || matches!(name.as_str(), "host" | "content-length" | "expect" | "x-forwarded-for" | "x-forwarded-proto")
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    let forwarded_for = match (request.headers.get("x-forwarded-for"), client_ip) {
+        (Some(previous), Some(ip)) => Some(format!("{}, {}", previous, ip)),
+        (Some(previous), None) => Some(previous.clone()),
+        (None, Some(ip)) => Some(ip.to_string()),
+        (None, None) => None,
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if request.body_len() > 0 || request.headers.contains_key("content-length") {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body_len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
+
+/// How the upstream delimits its response body.
+#[derive(Debug)]
+enum Framing {
+    None,
+    Length(usize), // Bytes still to come
+    Chunked(ChunkTracker),
+    UntilClose, // Re-encoded as chunked for the client
+}
+
+#[derive(Debug)]
+enum ParserState {
+    Head,
+    Body(Framing),
+}
+
+/// Reads an upstream HTTP/1.x response and produces what to send to the client:
+/// the status line and end-to-end headers, then the body. Length-delimited and chunked
+/// bodies pass through unchanged; a body delimited by connection close is re-chunked
+/// so the client connection can stay open.
+#[derive(Debug)]
+pub struct UpstreamResponseParser {
+    state: ParserState,
+    head_buffer: Vec<u8>,
+    head_request: bool,
+    status: Option<u16>,
+    headers: Vec<(String, String)>, // End-to-end headers of the response
+    extra_headers: Vec<(String, String)>, // Added to the head sent to the client
+    decoded: Option<Vec<u8>>, // Body without transfer coding, when asked for
+}
+
+impl UpstreamResponseParser {
+    const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+    pub fn new(method: &HttpMethod) -> Self {
+        Self {
+            state: ParserState::Head,
+            head_buffer: Vec::new(),
+            head_request: *method == HttpMethod::HEAD,
+            status: None,
+            headers: Vec::new(),
+            extra_headers: Vec::new(),
+            decoded: None,
+        }
+    }
+
+    /// Add a header to the response head sent to the client.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.extra_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Also collect the body with chunked coding removed, for `take_decoded`.
+    pub fn decode_body(&mut self) {
+        self.decoded = Some(Vec::new());
+    }
+
+    /// The body bytes decoded since the last call.
+    pub fn take_decoded(&mut self) -> Vec<u8> {
+        self.decoded.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Whether the response head has been passed to the client.
+    pub fn head_sent(&self) -> bool {
+        matches!(self.state, ParserState::Body(_))
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Whether the whole body has been received, without waiting for the connection to close.
+    pub fn is_complete(&self) -> bool {
+        match &self.state {
+            ParserState::Head => false,
+            ParserState::Body(Framing::None) => true,
+            ParserState::Body(Framing::Length(remaining)) => *remaining == 0,
+            ParserState::Body(Framing::Chunked(tracker)) => tracker.is_done(),
+            ParserState::Body(Framing::UntilClose) => false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut output = Vec::new();
+        let after_head;
+
+        let data = if let ParserState::Head = self.state {
+            self.head_buffer.extend_from_slice(data);
+            loop {
+                let end = match find_head_end(&self.head_buffer) {
+                    Some(end) => end,
+                    None if self.head_buffer.len() > Self::MAX_HEAD_SIZE => return Err("Upstream response head too large".into()),
+                    None => return Ok(output),
+                };
+                let rest = self.head_buffer.split_off(end);
+                let head = std::mem::replace(&mut self.head_buffer, rest);
+                // None for an interim response (100 Continue): the real one follows
+                if let Some(head_bytes) = self.parse_head(&head)? {
+                    output.extend_from_slice(&head_bytes);
+                    break;
+                }
+            }
+            // Whatever followed the head is the start of the body
+            after_head = std::mem::take(&mut self.head_buffer);
+            &after_head[..]
+        } else {
+            data
+        };
+
+        if let ParserState::Body(framing) = &mut self.state {
+            match framing {
+                Framing::None => {}
+                Framing::Length(remaining) => {
+                    let take = data.len().min(*remaining);
+                    output.extend_from_slice(&data[..take]);
+                    *remaining -= take;
+                    if let Some(decoded) = &mut self.decoded {
+                        decoded.extend_from_slice(&data[..take]);
+                    }
+                }
+                Framing::Chunked(tracker) => {
+                    let used = tracker.feed(data, self.decoded.as_mut())?;
+                    output.extend_from_slice(&data[..used]);
+                }
+                Framing::UntilClose => {
+                    output.extend_from_slice(&HttpResponse::encode_chunk(data));
+                    if let Some(decoded) = &mut self.decoded {
+                        decoded.extend_from_slice(data);
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    /// The upstream closed the connection: return the bytes that end the response,
+    /// or an error if it was cut short.
+    pub fn finish(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match &self.state {
+            ParserState::Head => Err("Upstream closed the connection before sending a response".into()),
+            ParserState::Body(Framing::UntilClose) => Ok(HttpResponse::LAST_CHUNK.to_vec()),
+            _ if self.is_complete() => Ok(Vec::new()),
+            _ => Err("Upstream closed the connection mid-response".into()),
+        }
+    }
+
+    /// Parse one response head and move on to its body. Returns the head to send to
+    /// the client, or `None` for interim 1xx responses.
+    fn parse_head(&mut self, head: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let text = String::from_utf8_lossy(head);
+        let mut lines = text.lines();
+        let status_line = lines.next().ok_or("Empty upstream response")?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!("Invalid upstream status line: {}", status_line).into());
+        }
+        let status: u16 = parts.next().and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("Invalid upstream status line: {}", status_line))?;
+        let reason = parts.next().unwrap_or("").trim();
+
+        if (100..200).contains(&status) {
+            return Ok(None);
+        }
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let header = |wanted: &str| headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(wanted)).map(|(_, v)| v.as_str());
+
+        let chunked = header("transfer-encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        let framing = if self.head_request || status == 204 || status == 304 {
+            Framing::None
+        } else if chunked {
+            Framing::Chunked(ChunkTracker::new())
+        } else if let Some(length) = header("content-length") {
+            Framing::Length(length.parse().map_err(|_| format!("Invalid upstream Content-Length: {}", length))?)
+        } else {
+            Framing::UntilClose
+        };
+
+        let tokens = connection_tokens(header("connection"));
+        let mut out = format!("HTTP/1.1 {} {}\r\n", status, reason);
+        let mut end_to_end = Vec::new();
+        for (name, value) in headers.iter().chain(self.extra_headers.iter()) {
+            if is_hop_by_hop(name, &tokens) {
+                continue;
+            }
+            // Framing is decided below; a length next to chunked coding is ignored (RFC 9112 6.3)
+            if chunked && name.eq_ignore_ascii_case("content-length") {
+                continue;
+            }
+            out.push_str(&format!("{}: {}\r\n", name, value));
+            end_to_end.push((name.clone(), value.clone()));
+        }
+        if matches!(framing, Framing::Chunked(_) | Framing::UntilClose) {
+            out.push_str("transfer-encoding: chunked\r\n");
+        }
+        out.push_str("\r\n");
+
+        self.status = Some(status);
+        self.headers = end_to_end;
+        self.state = ParserState::Body(framing);
+        Ok(Some(out.into_bytes()))
+    }
+}
+
+/// The status code of an HTTP/1.x status line such as `HTTP/1.1 200 OK`.
+pub fn parse_status_code(line: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        return Some(pos + 4);
+    }
+    buffer.windows(2).position(|w| w == b"\n\n").map(|pos| pos + 2)
+}
@@ -0,0 +1,126 @@
+use crate::http::{BodyFile, ChunkTracker, HttpRequest};
+use crate::tus_handler::{TusAppend, TusError};
+use crate::upload_handler::{MultipartUpload, PutUpload, UploadError};
+use std::io;
+
+/// Bodies up to this size are kept in memory; larger ones are spooled to a file.
+const MAX_MEMORY_BODY: usize = 64 * 1024;
+
+/// How the client delimits the request body.
+#[derive(Debug)]
+pub enum BodyFraming {
+    Length(u64), // Bytes still to come
+    Chunked(ChunkTracker),
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Malformed(String),
+}
+
+/// Where the body goes as it arrives.
+#[derive(Debug)]
+pub enum BodySink {
+    Buffer(Vec<u8>), // Given to the request once complete
+    Spool(BodyFile), // The same, for bodies over MAX_MEMORY_BODY
+    Upload(Result<MultipartUpload, UploadError>), // Stored as it arrives; after an error the rest is dropped
+    Put(Result<PutUpload, UploadError>), // The same, for the raw body of a PUT
+    Cgi(u32), // Piped to the stdin of the CGI script with this pid
+    Tus(Result<TusAppend, TusError>), // Appended to a resumable upload; kept even if the request breaks off
+}
+
+/// A request whose body is still being received.
+#[derive(Debug)]
+pub struct IncomingBody {
+    pub request: HttpRequest,
+    pub server_index: usize,
+    pub sink: BodySink,
+    framing: BodyFraming,
+    received: u64,
+    limit: u64, // client_max_body_size
+}
+
+impl IncomingBody {
+    pub fn new(request: HttpRequest, server_index: usize, framing: BodyFraming, limit: u64, sink: BodySink) -> Self {
+        Self { request, server_index, sink, framing, received: 0, limit }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match &self.framing {
+            BodyFraming::Length(remaining) => *remaining == 0,
+            BodyFraming::Chunked(tracker) => tracker.is_done(),
+        }
+    }
+
+    /// Whether the body goes to a CGI script, whose timeout then bounds the request.
+    pub fn is_piped(&self) -> bool {
+        matches!(self.sink, BodySink::Cgi(_))
+    }
+
+    /// Take the body bytes at the start of `data`. Returns how many bytes were used
+    /// and the content they carry, with chunked coding removed.
+    pub fn decode(&mut self, data: &[u8]) -> Result<(usize, Vec<u8>), BodyError> {
+        let (used, content) = match &mut self.framing {
+            BodyFraming::Length(remaining) => {
+                let take = (data.len() as u64).min(*remaining) as usize;
+                *remaining -= take as u64;
+                (take, data[..take].to_vec())
+            }
+            BodyFraming::Chunked(tracker) => {
+                let mut decoded = Vec::new();
+                let used = tracker.feed(data, Some(&mut decoded)).map_err(|e| BodyError::Malformed(e.to_string()))?;
+                (used, decoded)
+            }
+        };
+        self.received += content.len() as u64;
+        if self.received > self.limit {
+            return Err(BodyError::TooLarge);
+        }
+        Ok((used, content))
+    }
+
+    /// Add body content to a buffer, spool file or upload; CGI input is queued by the server.
+    pub fn store(&mut self, data: &[u8]) -> io::Result<()> {
+        let spooled = match &mut self.sink {
+            BodySink::Buffer(buffer) if buffer.len() + data.len() > MAX_MEMORY_BODY => {
+                let mut file = BodyFile::create(&std::env::temp_dir())?;
+                file.write_all(buffer)?;
+                file.write_all(data)?;
+                file
+            }
+            BodySink::Buffer(buffer) => {
+                buffer.extend_from_slice(data);
+                return Ok(());
+            }
+            BodySink::Spool(file) => return file.write_all(data),
+            BodySink::Upload(upload) => {
+                if let Ok(current) = upload {
+                    if let Err(e) = current.feed(data) {
+                        *upload = Err(e);
+                    }
+                }
+                return Ok(());
+            }
+            BodySink::Put(upload) => {
+                if let Ok(current) = upload {
+                    if let Err(e) = current.write(data) {
+                        *upload = Err(e);
+                    }
+                }
+                return Ok(());
+            }
+            BodySink::Tus(append) => {
+                if let Ok(current) = append {
+                    if let Err(e) = current.write(data) {
+                        *append = Err(e);
+                    }
+                }
+                return Ok(());
+            }
+            BodySink::Cgi(_) => return Ok(()),
+        };
+        self.sink = BodySink::Spool(spooled);
+        Ok(())
+    }
+}
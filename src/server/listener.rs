@@ -0,0 +1,198 @@
+use crate::config::ListenAddr;
+use crate::utils::net::{bind_tcp_listener, connect_tcp_nonblocking};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// A bound listening socket, TCP or Unix domain.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// An accepted client socket.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(socket_addr) => Ok(Listener::Tcp(bind_tcp_listener(socket_addr)?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Accept one connection. The peer address is `None` for Unix domain sockets.
+    pub fn accept(&self) -> io::Result<(ClientStream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((ClientStream::Tcp(stream), Some(addr)))
+            }
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((ClientStream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous run, but never a regular file, nor a socket
+/// that a running server still accepts connections on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path) {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ECONNREFUSED) | Some(libc::ENOENT)) => {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl ClientStream {
+    /// Open a non-blocking connection to a backend (e.g. a FastCGI application).
+    pub fn connect(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(socket_addr) => Ok(ClientStream::Tcp(connect_tcp_nonblocking(socket_addr)?)),
+            ListenAddr::Unix(path) => {
+                // Local connects complete (or fail) immediately
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(ClientStream::Unix(stream))
+            }
+        }
+    }
+
+    /// Send up to `len` bytes of `file` from `offset` with sendfile(2), so they go from
+    /// the page cache to the socket without a copy. Files sendfile cannot read are sent
+    /// through a buffer instead.
+    pub fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<usize> {
+        const MAX_SEND: u64 = 1 << 30;
+        let count = len.min(MAX_SEND) as usize;
+        let mut file_offset = offset as libc::off_t;
+        let sent = unsafe { libc::sendfile(self.as_raw_fd(), file.as_raw_fd(), &mut file_offset, count) };
+        if sent >= 0 {
+            return Ok(sent as usize);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+                let mut chunk = vec![0u8; count.min(64 * 1024)];
+                let n = file.read_at(&mut chunk, offset)?;
+                self.write(&chunk[..n])
+            }
+            _ => Err(e),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            ClientStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            ClientStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            ClientStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for ClientStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ClientStream::Tcp(stream) => stream.as_raw_fd(),
+            ClientStream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("webserv-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn unix_bind_replaces_a_stale_socket() {
+        let path = socket_path("stale");
+        let _ = std::fs::remove_file(&path);
+        // A std listener leaves its socket file behind when dropped
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone())).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unix_bind_leaves_a_live_socket_alone() {
+        let path = socket_path("live");
+        let _ = std::fs::remove_file(&path);
+        let running = Listener::bind(&ListenAddr::Unix(path.clone())).unwrap();
+
+        let err = Listener::bind(&ListenAddr::Unix(path.clone())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        // The running server still owns its socket
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(running);
+    }
+}
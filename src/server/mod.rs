@@ -1,32 +1,71 @@
-use crate::config::{Config, ServerConfig, RouteConfig};
-use crate::http::{HttpRequest, HttpResponse, StatusCode};
+use crate::cache::{self, CacheCapture, CacheZone};
+use crate::compression::{self, Encoder};
+use crate::config::{CompressionConfig, Config, HostMatch, ListenAddr, ProxyTarget, ServerConfig, RouteConfig};
+use crate::http::{BodyFile, ChunkTracker, HttpMethod, HttpRequest, HttpResponse, StatusCode};
+use crate::http::response::BodyPart;
+use crate::http::body::write_body_file;
 use crate::static_handler::StaticFileHandler;
-use crate::cgi::{CgiHandler, CgiRequest, CgiProcess};
+use crate::tus_handler::TusAppend;
+use crate::upload_handler::PutUpload;
+use crate::cgi::{CgiHandler, CgiOutput, CgiOutputParser, CgiRequest, CgiProcess, ScriptLocation};
+use crate::fastcgi::{self, EndRequest, RecordReader};
+use crate::proxy::{self, UpstreamGroup, UpstreamResponseParser};
 use crate::utils::epoll::EpollManager;
+use crate::utils::net::{read_available, read_up_to, write_available};
+mod body;
+mod listener;
 mod session;
-use session::get_or_create_session_id;
-use std::collections::HashMap;
+use body::{BodyError, BodyFraming, BodySink, IncomingBody};
+use listener::{ClientStream, Listener};
+use session::set_session_cookie;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::ops::Bound;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct WebServer {
     config: Config,
-    listeners: Vec<TcpListener>,
+    listeners: HashMap<RawFd, (ListenAddr, Listener)>, // One entry per bound socket, keyed by its fd
     epoll: EpollManager,
     clients: HashMap<RawFd, ClientConnection>,
-    server_map: HashMap<SocketAddr, usize>, // Maps socket addr to server config index
-    cgi_connections: HashMap<RawFd, CgiConnection>, // Map CGI fd to CgiConnection
+    server_map: HashMap<ListenAddr, Vec<usize>>, // Maps listen addr to the server configs (virtual hosts) sharing it
+    cgi_connections: HashMap<u32, CgiConnection>, // Map CGI child pid to CgiConnection
+    cgi_fds: HashMap<RawFd, u32>, // Map each CGI pipe fd (stdin, stdout, stderr) to its child pid
+    fastcgi_connections: HashMap<RawFd, FastCgiConnection>, // Keyed by the application socket fd
+    fastcgi_pools: HashMap<ListenAddr, FastCgiPool>, // Connections kept open to each application
+    proxy_connections: HashMap<RawFd, ProxyConnection>, // Keyed by the upstream socket fd
+    upstreams: HashMap<String, UpstreamGroup>, // Balancing and health state of each upstream block
+    health_checks: HashMap<RawFd, HealthCheckConnection>, // Keyed by the socket fd of the check
+    cache_zones: HashMap<String, CacheZone>,
+    next_request_id: u64, // Tags CGI log lines so they can be matched to a request
 }
 
+/// Response bytes queued for a client before its backend is left unread; files queued
+/// for sendfile stay on disk and don't count.
+const MAX_CLIENT_OUTPUT: usize = 1024 * 1024;
+
+/// Idle connections kept open to each FastCGI application, and for how long.
+const MAX_IDLE_FASTCGI: usize = 8;
+const FASTCGI_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 struct ClientConnection {
-    stream: TcpStream,
-    server_config_index: usize,
+    stream: ClientStream,
+    listen_addr: ListenAddr,
+    peer_addr: Option<SocketAddr>, // None for Unix domain socket clients
     buffer: Vec<u8>,
+    body: Option<IncomingBody>, // The request whose body is being received
     response_buffer: Vec<u8>,
+    response_parts: VecDeque<BodyPart>, // Queued behind response_buffer: file bodies and what follows them
+    close_after_write: bool, // Close once the output drains (nph output, failed streams)
+    stalled_backend: Option<(Backend, Instant)>, // Left unread since then, until the queued output drains
+    read_closed: bool, // The client shut down its sending side; close once it is answered
+    internal_redirects: u32, // CGI local redirects followed for the current request
     last_activity: Instant,
     state: ConnectionState,
 }
@@ -39,29 +78,422 @@ enum ConnectionState {
     KeepAlive,
 }
 
+/// A backend whose response is relayed to a client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    Cgi(u32), // By pid
+    FastCgi(RawFd),
+    Proxy(RawFd),
+}
+
+/// What the route's cache zone makes of a request to a backend.
+enum CacheDecision {
+    Serve(HttpResponse), // From the cache
+    Fetch(Option<CacheCapture>), // From the backend, capturing the response if it may be stored
+}
+
+/// The response side shared by CGI scripts and FastCGI applications: parses their
+/// output, relays it to the client and remembers what a local redirect needs.
+#[derive(Debug)]
+struct CgiRelay {
+    pub client_fd: Option<RawFd>, // None once the client has disconnected
+    pub kind: &'static str, // "cgi" or "fastcgi", for log lines
+    pub request_id: u64,
+    pub script_path: String,
+    pub parser: CgiOutputParser,
+    pub nph: bool,
+    pub chunked: bool, // Body relayed with chunked encoding (script sent no Content-Length)
+    pub until_close: bool, // Body ended by closing the connection instead, for an HTTP/1.0 client
+    pub request: HttpRequest, // The request that started the script, without its body
+    pub server_index: usize,
+    pub local_redirect: Option<String>,
+    pub error_buffer: Vec<u8>, // Unfinished stderr line
+    pub cache: Option<CacheCapture>, // Response being stored in a cache zone
+    pub compression: CompressionConfig,
+    pub encoder: Option<Encoder>, // Compressing the body for the client
+}
+
 #[derive(Debug)]
 struct CgiConnection {
     pub process: CgiProcess,
-    pub client_fd: RawFd,
-    pub output_buffer: Vec<u8>,
-    pub error_buffer: Vec<u8>,
+    pub relay: CgiRelay,
     pub stdin_done: bool,
     pub stdout_done: bool,
     pub stderr_done: bool,
     pub body_to_write: Vec<u8>,
     pub body_written: usize,
-    pub done: bool,
+    pub body_pending: bool, // More of the body is still to come from the client
+    pub deadline: Instant,
+    pub terminated_at: Option<Instant>, // When SIGTERM was sent to the script's process group
+}
+
+/// The open connections to one FastCGI application, and how many requests each may carry.
+#[derive(Debug)]
+struct FastCgiPool {
+    pub connections: Vec<RawFd>, // Busy and idle ones, all in fastcgi_connections
+    pub max_requests: usize, // Per connection: 1 unless the application multiplexes (FCGI_MPXS_CONNS=1)
+    pub probed: bool, // The application has been asked what it supports (FCGI_GET_VALUES)
+}
+
+/// A connection to a FastCGI application. Requests ask it to stay open
+/// (FCGI_KEEP_CONN), so it goes back to its pool once they have ended.
+#[derive(Debug)]
+struct FastCgiConnection {
+    pub stream: ClientStream,
+    pub addr: ListenAddr,
+    pub requests: BTreeMap<u16, FastCgiRequest>, // By FastCGI request id
+    pub outgoing: Vec<u8>, // Records being written
+    pub written: usize,
+    pub last_writer: u16, // Request whose records were queued last; the others take turns
+    pub reader: RecordReader,
+    pub reused: bool, // A request has ended on it, so the application may have closed it since
+    pub probe: bool, // Only asks FCGI_GET_VALUES and carries no requests
+    pub close_at: Option<Instant>, // While idle (or probing), closed at this time
+}
+
+/// A request to a FastCGI application, kept whole so it can be sent again on a new
+/// connection when a reused one turns out to have been closed.
+#[derive(Debug)]
+struct FastCgiRequest {
+    pub relay: CgiRelay,
+    pub params: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub body_file: Option<Arc<BodyFile>>, // Spooled body, read into STDIN records as the socket takes them
+    pub head_queued: bool, // BEGIN_REQUEST and PARAMS are on the connection
+    pub body_offset: u64, // How much of body_file is encoded
+    pub stdin_done: bool,
+    pub answered: bool, // A record has come back for it
+    pub aborted: bool, // Sent FCGI_ABORT_REQUEST; its client has been answered already
+    pub retried: bool,
+    pub deadline: Instant,
+}
+
+/// A proxied request, kept so it can be sent again if an upstream server fails.
+#[derive(Debug)]
+struct ProxyRequest {
+    pub client_fd: Option<RawFd>, // None once the client has disconnected
+    pub request_id: u64,
+    pub target: ProxyTarget,
+    pub method: HttpMethod,
+    pub client_ip: Option<IpAddr>,
+    pub outgoing: Vec<u8>,
+    pub body_file: Option<Arc<BodyFile>>, // Spooled body, sent after `outgoing`
+    pub timeout: Duration, // For each attempt
+    pub tried: Vec<usize>, // Servers of the upstream group attempted so far
+    pub cache: Option<CacheCapture>, // Response being stored in a cache zone
+}
+
+#[derive(Debug)]
+struct ProxyConnection {
+    pub stream: ClientStream,
+    pub request: ProxyRequest,
+    pub upstream: ListenAddr,
+    pub peer: Option<usize>, // Index in the upstream group, when the target is one
+    pub written: usize,
+    pub body_written: u64, // Of the spooled body
+    pub parser: UpstreamResponseParser,
+    pub deadline: Instant,
+}
+
+/// An active health check: a GET to one server of an upstream group.
+#[derive(Debug)]
+struct HealthCheckConnection {
+    pub stream: ClientStream,
+    pub upstream: String,
+    pub peer: usize,
+    pub outgoing: Vec<u8>,
+    pub written: usize,
+    pub response: Vec<u8>, // Only read up to the status line
+    pub deadline: Instant,
+}
+
+impl CgiRelay {
+    /// Whether any part of the script's response has gone to the client, after
+    /// which its status can no longer change.
+    fn response_started(&self) -> bool {
+        self.parser.headers_done() && self.local_redirect.is_none()
+    }
+
+    /// Parse the next piece of script output and return the bytes for the client.
+    fn relay(&mut self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let outputs = self.parser.feed(data)?;
+        self.encode(outputs)
+    }
+
+    /// The output has ended: return whatever completes the response.
+    fn finish(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        // Output that never completed a header block goes out as the body now
+        let tail = self.parser.finish();
+        let mut bytes = self.encode(tail)?;
+        if let Some(encoder) = self.encoder.take() {
+            bytes.extend_from_slice(&self.frame(encoder.finish()?));
+        }
+        if self.chunked {
+            bytes.extend_from_slice(HttpResponse::LAST_CHUNK);
+        }
+        Ok(bytes)
+    }
+
+    /// Turn parsed output into wire bytes. Without a Content-Length from the script, or
+    /// when it is compressed, the body is relayed with chunked transfer encoding, or to an
+    /// HTTP/1.0 client as is, ending with the connection.
+    fn encode(&mut self, outputs: Vec<CgiOutput>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut bytes = Vec::new();
+        for output in outputs {
+            match output {
+                CgiOutput::Headers(cgi_resp) => {
+                    let declared_length = cgi_resp.headers.get("content-length").cloned();
+                    if let Some(capture) = &mut self.cache {
+                        capture.headers(cgi_resp.status, cgi_resp.headers.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+                    }
+                    let mut response = HttpResponse::from_cgi_response(cgi_resp);
+                    response.headers.remove("transfer-encoding");
+                    if self.cache.is_some() {
+                        response.set_header("x-cache", "MISS");
+                    }
+                    match declared_length {
+                        Some(length) => response.set_header("content-length", &length),
+                        None => {
+                            response.headers.remove("content-length");
+                        }
+                    }
+                    let accept_encoding = self.request.get_header("accept-encoding").map(|v| v.as_str());
+                    if let Some(encoding) = compression::choose(accept_encoding, &self.compression, &mut response) {
+                        self.encoder = Some(Encoder::new(encoding)?);
+                        compression::mark_encoded(&mut response, encoding);
+                    }
+                    let version = &self.request.version;
+                    if !response.headers.contains_key("content-length") {
+                        if (version.major, version.minor) < (1, 1) {
+                            response.set_header("connection", "close");
+                            self.until_close = true;
+                        } else {
+                            response.set_header("transfer-encoding", "chunked");
+                            self.chunked = true;
+                        }
+                    }
+                    bytes.extend_from_slice(&response.head_bytes());
+                }
+                CgiOutput::Body(body) => {
+                    if let Some(capture) = &mut self.cache {
+                        capture.body(&body);
+                    }
+                    let body = match &mut self.encoder {
+                        // Flushed, so what the script has written reaches the client now
+                        Some(encoder) => encoder.write(&body, true)?,
+                        None => body,
+                    };
+                    bytes.extend_from_slice(&self.frame(body));
+                }
+                CgiOutput::Raw(body) => {
+                    // nph output is passed through unparsed, so there is nothing to store
+                    if let Some(capture) = &mut self.cache {
+                        capture.abandon();
+                    }
+                    bytes.extend_from_slice(&body);
+                }
+                CgiOutput::LocalRedirect(location) => {
+                    if let Some(capture) = &mut self.cache {
+                        capture.abandon();
+                    }
+                    self.local_redirect = Some(location);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Frame a piece of the body for the client.
+    fn frame(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.chunked {
+            HttpResponse::encode_chunk(&data)
+        } else {
+            data
+        }
+    }
+
+    /// Forward complete stderr lines to the log. A partial line waits for the rest
+    /// unless stderr is closed or it grows too long.
+    fn log_stderr(&mut self, eof: bool) {
+        const MAX_LINE: usize = 8 * 1024;
+
+        let mut start = 0;
+        while let Some(newline) = self.error_buffer[start..].iter().position(|&b| b == b'\n') {
+            self.log_stderr_line(start, start + newline);
+            start += newline + 1;
+        }
+        self.error_buffer.drain(..start);
+
+        if !self.error_buffer.is_empty() && (eof || self.error_buffer.len() > MAX_LINE) {
+            self.log_stderr_line(0, self.error_buffer.len());
+            self.error_buffer.clear();
+        }
+    }
+
+    fn log_stderr_line(&self, start: usize, end: usize) {
+        let line = String::from_utf8_lossy(&self.error_buffer[start..end]);
+        log::warn!("[{} #{} {}] {}", self.kind, self.request_id, self.script_path, line.trim_end_matches('\r'));
+    }
+}
+
+impl ClientConnection {
+    /// The last response of a connection marked `close_after_write`, or of a client that
+    /// stopped sending, has been sent.
+    fn is_done(&self) -> bool {
+        !self.has_output()
+            && self.state != ConnectionState::Processing
+            && (self.close_after_write || (self.read_closed && self.body.is_none()))
+    }
+
+    fn has_output(&self) -> bool {
+        !self.response_buffer.is_empty() || !self.response_parts.is_empty()
+    }
+
+    /// Bytes queued in memory, toward `MAX_CLIENT_OUTPUT`.
+    fn queued_bytes(&self) -> usize {
+        let parts = self.response_parts.iter().map(|part| match part {
+            BodyPart::Bytes(bytes) => bytes.len(),
+            BodyPart::File(_) => 0,
+        });
+        self.response_buffer.len() + parts.sum::<usize>()
+    }
+
+    /// Queue bytes behind everything already queued.
+    fn queue_bytes(&mut self, data: &[u8]) {
+        match self.response_parts.back_mut() {
+            None => self.response_buffer.extend_from_slice(data),
+            Some(BodyPart::Bytes(bytes)) => bytes.extend_from_slice(data),
+            Some(BodyPart::File(_)) => self.response_parts.push_back(BodyPart::Bytes(data.to_vec())),
+        }
+    }
+
+    /// Queue a whole response. File regions in its body stay on disk until they are sent.
+    fn queue_response(&mut self, response: HttpResponse) {
+        self.queue_bytes(&response.to_bytes());
+        for part in response.body_parts {
+            match part {
+                BodyPart::Bytes(bytes) => self.queue_bytes(&bytes),
+                BodyPart::File(region) => self.response_parts.push_back(BodyPart::File(region)),
+            }
+        }
+    }
+}
+
+impl FastCgiPool {
+    fn new() -> Self {
+        Self { connections: Vec::new(), max_requests: 1, probed: false }
+    }
+}
+
+impl FastCgiConnection {
+    fn new(stream: ClientStream, addr: ListenAddr) -> Self {
+        Self {
+            stream,
+            addr,
+            requests: BTreeMap::new(),
+            outgoing: Vec::new(),
+            written: 0,
+            last_writer: 0,
+            reader: RecordReader::new(),
+            reused: false,
+            probe: false,
+            close_at: None,
+        }
+    }
+
+    /// The lowest request id not in use on this connection; 0 is for management records.
+    fn free_request_id(&self) -> u16 {
+        let mut id = 1;
+        while self.requests.contains_key(&id) {
+            id += 1;
+        }
+        id
+    }
+
+    /// Once the queued records are written, queue the next ones, taking turns between
+    /// the requests with something left to send. Returns false if none has.
+    fn queue_next_records(&mut self) -> io::Result<bool> {
+        let turns: Vec<u16> = self.requests.range((Bound::Excluded(self.last_writer), Bound::Unbounded))
+            .chain(self.requests.range(..=self.last_writer))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in turns {
+            let records = match self.requests.get_mut(&id) {
+                Some(request) => request.next_records(id)?,
+                None => None,
+            };
+            if let Some(records) = records {
+                self.outgoing = records;
+                self.written = 0;
+                self.last_writer = id;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl FastCgiRequest {
+    /// The next records to send as request `id`: BEGIN_REQUEST and PARAMS first (with
+    /// STDIN, for a body in memory), then a spooled body one STDIN record at a time and
+    /// the empty record ending the stream. None once everything is queued.
+    fn next_records(&mut self, id: u16) -> io::Result<Option<Vec<u8>>> {
+        if self.stdin_done || self.aborted {
+            return Ok(None);
+        }
+        if !self.head_queued {
+            self.head_queued = true;
+            if self.body_file.is_some() {
+                return Ok(Some(fastcgi::encode_request_head(id, &self.params)));
+            }
+            self.stdin_done = true;
+            return Ok(Some(fastcgi::encode_request(id, &self.params, &self.body)));
+        }
+        let body = match &self.body_file {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        let mut chunk = vec![0u8; fastcgi::MAX_CONTENT_LEN.min(32 * 1024)];
+        let n = body.read_at(&mut chunk, self.body_offset)?;
+        self.body_offset += n as u64;
+        self.stdin_done = n == 0;
+        Ok(Some(fastcgi::encode_record(fastcgi::FCGI_STDIN, id, &chunk[..n])))
+    }
+
+    /// Start over, to send the request again on another connection.
+    fn rewind(&mut self) {
+        self.head_queued = false;
+        self.body_offset = 0;
+        self.stdin_done = false;
+        self.answered = false;
+        self.retried = true;
+    }
 }
 
 impl WebServer {
     pub fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cache_zones = HashMap::new();
+        for (name, zone) in &config.cache_zones {
+            let zone = CacheZone::new(zone).map_err(|e| format!("cache_zone {}: {}", name, e))?;
+            cache_zones.insert(name.clone(), zone);
+        }
         Ok(Self {
-            config,
-            listeners: Vec::new(),
+            listeners: HashMap::new(),
             epoll: EpollManager::new()?,
             clients: HashMap::new(),
             server_map: HashMap::new(),
             cgi_connections: HashMap::new(),
+            cgi_fds: HashMap::new(),
+            fastcgi_connections: HashMap::new(),
+            fastcgi_pools: HashMap::new(),
+            proxy_connections: HashMap::new(),
+            upstreams: config.upstreams.iter()
+                .map(|(name, upstream)| (name.clone(), UpstreamGroup::new(upstream)))
+                .collect(),
+            health_checks: HashMap::new(),
+            cache_zones,
+            next_request_id: 1,
+            config,
         })
     }
 
@@ -103,22 +535,30 @@ impl WebServer {
 
     fn setup_listeners(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         for (index, server_config) in self.config.servers.iter().enumerate() {
-            let addr = server_config.socket_addr()?;
-            
-            // Check for duplicate ports
-            if self.server_map.contains_key(&addr) {
-                return Err(format!("Port {} already in use", addr.port()).into());
+            for listen in &server_config.listen {
+                let addr = &listen.addr;
+
+                // Server blocks sharing an address are name-based virtual hosts on one listener
+                if let Some(indices) = self.server_map.get_mut(addr) {
+                    if listen.default_server && indices.iter().any(|&i| self.config.servers[i].is_default_for(addr)) {
+                        return Err(format!("Duplicate default_server for {}", addr).into());
+                    }
+                    if !indices.contains(&index) {
+                        indices.push(index);
+                    }
+                    continue;
+                }
+
+                let listener = Listener::bind(addr)
+                    .map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+
+                println!("Server listening on {}", addr);
+
+                let fd = listener.as_raw_fd();
+                self.epoll.add_listener(fd)?;
+                self.server_map.insert(addr.clone(), vec![index]);
+                self.listeners.insert(fd, (addr.clone(), listener));
             }
-            
-            let listener = TcpListener::bind(&addr)?;
-            listener.set_nonblocking(true)?;
-            
-            println!("Server listening on {}", addr);
-            
-            let fd = listener.as_raw_fd();
-            self.epoll.add_listener(fd)?;
-            self.server_map.insert(addr, index);
-            self.listeners.push(listener);
         }
         
         Ok(())
@@ -128,8 +568,16 @@ impl WebServer {
         // AUDIT NOTE: Only one epoll_wait call per event loop iteration, as required by project spec.
         // This ensures a single epoll (or equivalent) call per client/server communication step.
         let timeout = Duration::from_millis(1000);
+        // A script that closed its output is usually exiting; poll it soon rather than next second
+        let reap_timeout = Duration::from_millis(10);
         
         loop {
+            let timeout = if self.has_unreaped_cgi() { reap_timeout } else { timeout };
+            // Wake up for a backend timeout shorter than the tick
+            let timeout = match self.next_backend_deadline() {
+                Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+                None => timeout,
+            };
             let events = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.epoll.wait(timeout))) {
                 Ok(Ok(ev)) => ev,
                 Ok(Err(e)) => {
@@ -146,8 +594,14 @@ impl WebServer {
                 let handler_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
                     if self.is_listener_fd(event.fd) {
                         self.handle_new_connection(event.fd)
-                    } else if self.cgi_connections.get_mut(&event.fd).is_some() {
+                    } else if self.cgi_fds.contains_key(&event.fd) {
                         self.handle_cgi_event(event.fd, event.readable, event.writable)
+                    } else if self.fastcgi_connections.contains_key(&event.fd) {
+                        self.handle_fastcgi_event(event.fd, event.readable, event.writable)
+                    } else if self.proxy_connections.contains_key(&event.fd) {
+                        self.handle_proxy_event(event.fd, event.readable, event.writable)
+                    } else if self.health_checks.contains_key(&event.fd) {
+                        self.handle_health_check_event(event.fd, event.readable, event.writable)
                     } else {
                         self.handle_client_event(event.fd, event.readable, event.writable)
                     }
@@ -163,41 +617,95 @@ impl WebServer {
             
             // Clean up timed out connections
             self.cleanup_timeouts();
+
+            // Probe upstream servers whose health checks are due
+            self.run_health_checks();
+
+            // Finish CGI scripts that closed their output but had not exited yet
+            self.reap_cgi_processes();
+        }
+    }
+
+    /// Pick the server block for a request on `addr`: the best `server_name` match for the
+    /// Host header (exact, then longest wildcard), else the `default_server`, else the first block.
+    fn select_server(&self, addr: &ListenAddr, host: Option<&str>) -> usize {
+        let candidates = match self.server_map.get(addr) {
+            Some(candidates) if !candidates.is_empty() => candidates,
+            _ => return 0,
+        };
+
+        if let Some(host) = host.map(Self::normalize_host) {
+            let mut best: Option<(HostMatch, usize)> = None;
+            for &index in candidates {
+                if let Some(rank) = self.config.servers[index].match_host(&host) {
+                    if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+                        best = Some((rank, index));
+                    }
+                }
+            }
+            if let Some((_, index)) = best {
+                return index;
+            }
         }
+
+        candidates.iter()
+            .copied()
+            .find(|&i| self.config.servers[i].is_default_for(addr))
+            .unwrap_or(candidates[0])
+    }
+
+    /// Lowercase a Host header value and strip the port ("Example.com:8080" -> "example.com").
+    fn normalize_host(host: &str) -> String {
+        let host = host.trim();
+        let name = if host.starts_with('[') {
+            // IPv6 literal, e.g. "[::1]:8080"
+            match host.find(']') {
+                Some(end) => &host[..=end],
+                None => host,
+            }
+        } else {
+            host.split(':').next().unwrap_or(host)
+        };
+        name.trim_end_matches('.').to_lowercase()
     }
 
     fn is_listener_fd(&self, fd: RawFd) -> bool {
-        self.listeners.iter().any(|listener| listener.as_raw_fd() == fd)
+        self.listeners.contains_key(&fd)
     }
 
     fn handle_new_connection(&mut self, listener_fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = self.listeners.iter()
-            .find(|l| l.as_raw_fd() == listener_fd)
+        let (listen_addr, listener) = self.listeners.get(&listener_fd)
             .ok_or("Listener not found")?;
         
         match listener.accept() {
-            Ok((stream, addr)) => {
-                stream.set_nonblocking(true)?;
+            Ok((stream, peer_addr)) => {
                 let client_fd = stream.as_raw_fd();
                 
-                // Find server config for this listener
-                let server_config_index = self.listeners.iter()
-                    .position(|l| l.as_raw_fd() == listener_fd)
-                    .unwrap_or(0);
+                // The server block is chosen per request from the Host header
+                let listen_addr = listen_addr.clone();
+                match peer_addr {
+                    Some(addr) => println!("New connection from {}", addr),
+                    None => println!("New connection on {}", listen_addr),
+                }
                 
                 let client = ClientConnection {
                     stream,
-                    server_config_index,
+                    listen_addr,
+                    peer_addr,
                     buffer: Vec::new(),
+                    body: None,
                     response_buffer: Vec::new(),
+                    response_parts: VecDeque::new(),
+                    close_after_write: false,
+                    stalled_backend: None,
+                    read_closed: false,
+                    internal_redirects: 0,
                     last_activity: Instant::now(),
                     state: ConnectionState::Reading,
                 };
                 
                 self.epoll.add_client(client_fd)?;
                 self.clients.insert(client_fd, client);
-                
-                println!("New connection from {}", addr);
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 // No more connections to accept
@@ -230,6 +738,10 @@ impl WebServer {
                 should_close = true;
             }
         }
+
+        if self.clients.get(&fd).is_some_and(ClientConnection::is_done) {
+            should_close = true;
+        }
         
         if should_close {
             self.close_client_connection(fd);
@@ -239,250 +751,528 @@ impl WebServer {
     }
 
     fn handle_client_read(&mut self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
-        let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
+        // Input not yet handled is bounded (a body piped to a slow CGI script, pipelined
+        // requests); resume_client_input reads the rest once it has been consumed
+        const MAX_CLIENT_INPUT: usize = 1024 * 1024;
 
         let mut buffer = [0; 8192];
-        match client.stream.read(&mut buffer) {
-            Ok(0) => {
-                // Client closed connection
-                return Err("Client closed connection".into());
-            }
-            Ok(n) => {
-                client.buffer.extend_from_slice(&buffer[..n]);
-                client.last_activity = Instant::now();
-
-                // --- Content-Length vs client_max_body_size check (immediate 413) ---
-                // Only check if we have headers
-                if let Some(pos) = Self::find_header_end(&client.buffer) {
-                    let header_part = &client.buffer[..pos];
-                    if let Ok(header_str) = std::str::from_utf8(header_part) {
-                        if let Some(content_length) = Self::extract_content_length(header_str) {
-                            let server_config_index = client.server_config_index;
-                            let server_config = &self.config.servers[server_config_index];
-                            if content_length > server_config.client_max_body_size {
-                                // Prepare 413 response (custom page if configured)
-                                let mut response = if let Some(error_page_path) = server_config.error_pages.get(&413) {
-                                    if let Ok(content) = std::fs::read(error_page_path) {
-                                        let mut resp = HttpResponse::new(StatusCode::PayloadTooLarge);
-                                        resp.set_body(&content);
-                                        resp.set_header("Content-Type", "text/html");
-                                        resp
-                                    } else {
-                                        HttpResponse::payload_too_large()
-                                    }
-                                } else {
-                                    HttpResponse::payload_too_large()
-                                };
-                                client.response_buffer = response.to_bytes();
-                                client.state = ConnectionState::Writing;
-                                return Ok(()); // Don't process further, don't wait for body
-                            }
-                        }
+        loop {
+            let client = match self.clients.get_mut(&fd) {
+                Some(client) => client,
+                None => return Ok(()),
+            };
+            if client.read_closed || client.buffer.len() >= MAX_CLIENT_INPUT {
+                return Ok(());
+            }
+            match client.stream.read(&mut buffer) {
+                Ok(0) => {
+                    if client.body.is_some() {
+                        return Err("Client closed connection before sending the whole body".into());
                     }
+                    // A client may close its side once its requests are sent; answer them first
+                    client.read_closed = true;
+                    return Ok(());
                 }
+                Ok(n) => {
+                    client.buffer.extend_from_slice(&buffer[..n]);
+                    client.last_activity = Instant::now();
+                    self.handle_client_input(fd)?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-                // Check if we have a complete request
-                let buffer_copy = client.buffer.clone();
-                let is_complete = Self::is_complete_request(&buffer_copy);
+    /// Act on what the client has sent: feed the body of the request being received,
+    /// or start on the next request once its head is complete.
+    fn handle_client_input(&mut self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+        const MAX_HEAD_SIZE: usize = 64 * 1024;
 
-                if is_complete {
-                    self.process_request(fd)?;
+        loop {
+            let client = match self.clients.get_mut(&fd) {
+                Some(client) => client,
+                None => return Ok(()),
+            };
+            if client.close_after_write {
+                // The connection ends with the current response
+                client.buffer.clear();
+                return Ok(());
+            }
+            if client.body.is_some() {
+                if !self.receive_body(fd)? {
+                    return Ok(());
                 }
+                continue;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // No more data available right now
+            // A pipelined request waits until the current response is complete
+            if client.state == ConnectionState::Processing {
+                return Ok(());
             }
+            let head_end = match Self::find_header_end(&client.buffer) {
+                Some(pos) => pos + 4,
+                None if client.buffer.len() > MAX_HEAD_SIZE => {
+                    self.reject_request(fd, HttpResponse::bad_request());
+                    return Ok(());
+                }
+                None => return Ok(()),
+            };
+            let head: Vec<u8> = client.buffer.drain(..head_end).collect();
+            client.state = ConnectionState::Processing;
+            client.internal_redirects = 0;
+            self.begin_request(fd, &head)?;
+        }
+    }
+
+    /// Parse a request head. A request without a body is handled right away; otherwise
+    /// `client_max_body_size` is checked against its length and the body is received
+    /// into the sink its route calls for.
+    fn begin_request(&mut self, fd: RawFd, head: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let request = match HttpRequest::parse(head) {
+            Ok(request) => request,
             Err(e) => {
-                return Err(e.into());
+                eprintln!("Error parsing request: {}", e);
+                self.reject_request(fd, HttpResponse::bad_request());
+                return Ok(());
             }
-        }
+        };
+        let listen_addr = self.clients.get(&fd).ok_or("Client not found")?.listen_addr.clone();
+        let server_index = self.select_server(&listen_addr, request.host().map(|h| h.as_str()));
 
-        Ok(())
-    }
+        let framing = if request.is_chunked() {
+            BodyFraming::Chunked(ChunkTracker::new())
+        } else if request.has_header("content-length") {
+            match request.content_length() {
+                Some(0) => return self.handle_request_wrapper(fd, request, server_index),
+                Some(length) => BodyFraming::Length(length as u64),
+                None => {
+                    self.reject_request(fd, HttpResponse::bad_request());
+                    return Ok(());
+                }
+            }
+        } else {
+            return self.handle_request_wrapper(fd, request, server_index);
+        };
 
-    fn handle_client_write(&mut self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
-        let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
-        
-        if client.response_buffer.is_empty() {
+        let limit = self.config.servers[server_index].client_max_body_size as u64;
+        if matches!(framing, BodyFraming::Length(length) if length > limit) {
+            let response = self.handle_payload_too_large(&self.config.servers[server_index]);
+            self.reject_request(fd, response);
             return Ok(());
         }
-        
-        match client.stream.write(&client.response_buffer) {
-            Ok(n) => {
-                client.response_buffer.drain(..n);
-                client.last_activity = Instant::now();
-                
-                if client.response_buffer.is_empty() {
-                    client.state = ConnectionState::KeepAlive;
-                }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // Can't write more right now
-            }
-            Err(e) => {
-                return Err(e.into());
+
+        let sink = match self.body_sink(fd, &request, &framing, server_index) {
+            Ok(sink) => sink,
+            Err(response) => {
+                self.reject_request(fd, response);
+                return Ok(());
             }
+        };
+        let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
+        if request.expects_continue() && client.buffer.is_empty() {
+            client.queue_bytes(b"HTTP/1.1 100 Continue\r\n\r\n");
         }
-        
+        client.body = Some(IncomingBody::new(request, server_index, framing, limit, sink));
         Ok(())
     }
 
-    fn is_complete_request(buffer: &[u8]) -> bool {
-        // Look for end of headers
-        if let Some(pos) = Self::find_header_end(buffer) {
-            // Check if we have the complete body
-            let header_part = &buffer[..pos];
-            if let Ok(header_str) = std::str::from_utf8(header_part) {
-                if let Some(content_length) = Self::extract_content_length(header_str) {
-                    let body_start = pos + 4; // Skip \r\n\r\n
-                    let body_received = buffer.len() - body_start;
-                    return body_received >= content_length;
-                } else {
-                    // No content-length, assume complete
-                    return true;
-                }
-            }
+    /// Where the body of `request` goes as it arrives: piped to a CGI script started right
+    /// away, stored into the upload directory, or held for the handler (in memory, or
+    /// spooled to a temporary file when large).
+    fn body_sink(&mut self, fd: RawFd, request: &HttpRequest, framing: &BodyFraming, server_index: usize) -> Result<BodySink, HttpResponse> {
+        let server_config = &self.config.servers[server_index];
+        let route = match self.find_route_for_request(request, server_config) {
+            Some(route) if !route.upstream_status => route,
+            _ => return Ok(BodySink::Buffer(Vec::new())),
+        };
+
+        // Refused before any of the body is read or a backend is started
+        if route.proxy_pass.is_some() || route.is_fastcgi_request(&request.uri) || route.is_cgi_request(&request.uri) {
+            Self::check_route_method(request, route, server_config)?;
         }
-        false
-    }
 
-    fn find_header_end(buffer: &[u8]) -> Option<usize> {
-        for i in 0..buffer.len().saturating_sub(3) {
-            if &buffer[i..i+4] == b"\r\n\r\n" {
-                return Some(i);
-            }
+        // A chunked body is spooled first, as the script needs its length up front
+        let is_cgi = route.proxy_pass.is_none() && route.fastcgi_pass.is_none() && route.is_cgi_request(&request.uri);
+        if is_cgi && matches!(framing, BodyFraming::Length(_)) {
+            let route = route.clone();
+            self.start_backend(fd, true, request.clone(), &route, server_index, None)?;
+            return self.cgi_connections.iter()
+                .find(|(_, conn)| conn.relay.client_fd == Some(fd))
+                .map(|(&pid, _)| BodySink::Cgi(pid))
+                .ok_or_else(HttpResponse::internal_server_error);
         }
-        None
-    }
 
-    fn extract_content_length(headers: &str) -> Option<usize> {
-        for line in headers.lines() {
-            if line.to_lowercase().starts_with("content-length:") {
-                if let Some(value) = line.split(':').nth(1) {
-                    return value.trim().parse().ok();
-                }
+        if route.proxy_pass.is_some() || route.is_fastcgi_request(&request.uri) || is_cgi {
+            return Ok(BodySink::Buffer(Vec::new()));
+        }
+        // Only when handle_static_request would let the request through to the handler
+        let method = request.method.to_string();
+        let allowed = server_config.routes.iter()
+            .find(|route| Self::matches_route(&request.uri, &route.path))
+            .is_some_and(|route| route.methods.contains(&method));
+        let handler = StaticFileHandler::new(server_config);
+        if let Some((store, id)) = handler.tus_target(request, server_config).filter(|_| allowed) {
+            return StaticFileHandler::start_tus_append(request, &store, &id).map(|append| BodySink::Tus(Ok(append)));
+        }
+        if let Some(location) = handler.put_target(request, server_config).filter(|_| allowed) {
+            return StaticFileHandler::start_put(request, location).map(|upload| BodySink::Put(Ok(upload)));
+        }
+        match handler.upload_target(request, server_config) {
+            Some((location, boundary)) if allowed => {
+                let upload_dir = location.upload_store.as_deref().unwrap_or_default();
+                StaticFileHandler::start_upload(&boundary, upload_dir, location)
+                    .map(|upload| BodySink::Upload(Ok(upload)))
+                    .map_err(|e| StaticFileHandler::upload_result(Err(e), request))
             }
+            _ => Ok(BodySink::Buffer(Vec::new())),
         }
-        None
     }
 
-    fn process_request(&mut self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
-        // Extract the data we need before borrowing mutably
-        let (request_data, server_config_index) = {
-            let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
-            let request_data = client.buffer.clone();
-            client.buffer.clear();
-            client.state = ConnectionState::Processing;
-            (request_data, client.server_config_index)
+    /// Feed the client's input to the body being received. Returns whether the body is
+    /// complete, after which the request has been handed on.
+    fn receive_body(&mut self, fd: RawFd) -> Result<bool, Box<dyn std::error::Error>> {
+        // Body bytes queued for a script's stdin before the rest is left with the client
+        const MAX_CGI_STDIN_QUEUE: usize = 1024 * 1024;
+
+        let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
+        let mut body = match client.body.take() {
+            Some(body) => body,
+            None => return Ok(true),
         };
-        
-        match HttpRequest::parse(&request_data) {
-            Ok(request) => {
-                self.handle_request_wrapper(fd, request, server_config_index)?;
+        let room = match &body.sink {
+            BodySink::Cgi(pid) => match self.cgi_connections.get(pid) {
+                Some(conn) if !conn.stdin_done => MAX_CGI_STDIN_QUEUE.saturating_sub(conn.body_to_write.len() - conn.body_written),
+                _ => usize::MAX, // The script is gone or stopped reading: the rest is dropped
+            },
+            _ => usize::MAX,
+        };
+
+        let available = client.buffer.len().min(room);
+        let content = match body.decode(&client.buffer[..available]) {
+            Ok((used, content)) => {
+                client.buffer.drain(..used);
+                content
             }
-            Err(e) => {
-                eprintln!("Error parsing request: {}", e);
-                let response = HttpResponse::bad_request();
-                let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
-                client.response_buffer = response.to_bytes();
-                client.state = ConnectionState::Writing;
+            Err(BodyError::TooLarge) => {
+                let response = self.handle_payload_too_large(&self.config.servers[body.server_index]);
+                self.reject_request(fd, response);
+                return Ok(false);
+            }
+            Err(BodyError::Malformed(e)) => {
+                eprintln!("Error reading request body: {}", e);
+                self.reject_request(fd, HttpResponse::bad_request());
+                return Ok(false);
             }
         };
-        
-        Ok(())
-    }
 
-    fn handle_request_wrapper(&mut self, client_fd: RawFd, request: HttpRequest, server_config_index: usize) -> Result<(), Box<dyn std::error::Error>> {
-        let server_config = &self.config.servers[server_config_index];
-        
-        let response = if let Some(route) = self.find_route_for_request(&request, server_config) {
-            if route.is_cgi_request(&request.uri) {
-                println!("Handling as CGI request");
-                match self.create_cgi_request(&request, route) {
-                    Ok(cgi_request) => {
-                        let cgi_handler = CgiHandler::new();
-                        match cgi_handler.execute(cgi_request) {
-                            Ok(cgi_response) => {
-                                HttpResponse::from_cgi_response(cgi_response)
-                            }
-                            Err(e) => {
-                                eprintln!("Error executing CGI: {}", e);
-                                HttpResponse::internal_server_error()
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Error creating CGI request: {}", e);
-                        HttpResponse::internal_server_error()
-                    }
+        let complete = body.is_complete();
+        match body.sink {
+            BodySink::Cgi(pid) => self.feed_cgi_stdin(pid, &content, complete),
+            _ => {
+                if let Err(e) = body.store(&content) {
+                    eprintln!("Error storing request body: {}", e);
+                    self.reject_request(fd, HttpResponse::internal_server_error());
+                    return Ok(false);
                 }
-            } else {
-                // Use the new static request handler with proper 403 handling
-                Self::handle_static_request(request, server_config)
             }
-        } else {
-            self.handle_not_found(server_config)
-        };
+        }
+        if !complete {
+            if let Some(client) = self.clients.get_mut(&fd) {
+                client.body = Some(body);
+            }
+            return Ok(false);
+        }
 
-        if let Some(client) = self.clients.get_mut(&client_fd) {
-            client.response_buffer = response.to_bytes();
-            client.state = ConnectionState::Writing;
+        let IncomingBody { mut request, server_index, sink, .. } = body;
+        match sink {
+            // The script's response completes the request
+            BodySink::Cgi(_) => {}
+            BodySink::Buffer(data) => {
+                request.body = data;
+                self.handle_request_wrapper(fd, request, server_index)?;
+            }
+            BodySink::Spool(file) => {
+                request.body_file = Some(Arc::new(file));
+                self.handle_request_wrapper(fd, request, server_index)?;
+            }
+            BodySink::Upload(upload) => {
+                let result = upload.and_then(StaticFileHandler::finish_upload);
+                let response = StaticFileHandler::upload_result(result, &request);
+                self.queue_handled_response(fd, &request, response);
+            }
+            BodySink::Put(upload) => {
+                let response = StaticFileHandler::put_result(upload.and_then(PutUpload::finish), &request);
+                self.queue_handled_response(fd, &request, response);
+            }
+            BodySink::Tus(append) => {
+                let response = StaticFileHandler::tus_append_result(append.and_then(TusAppend::finish));
+                self.queue_handled_response(fd, &request, response);
+            }
         }
-        
-        Ok(())
+        Ok(true)
     }
 
-    fn create_cgi_request(
-        &self,
-        request: &HttpRequest,
-        route_config: &RouteConfig,
-    ) -> Result<CgiRequest, anyhow::Error> {
-        let root = route_config.root.as_deref().unwrap_or("./");
-
-        // We need to map this to a filesystem path like "./www/cgi-bin/test.py".
-        let script_path = PathBuf::from(root).join(request.uri.trim_start_matches('/'));
+    /// Queue the response to a request whose body the static handler took as it arrived.
+    fn queue_handled_response(&mut self, fd: RawFd, request: &HttpRequest, mut response: HttpResponse) {
+        set_session_cookie(request, &mut response);
+        if let Some(client) = self.clients.get_mut(&fd) {
+            client.queue_response(response);
+            client.state = ConnectionState::Writing;
+        }
+    }
 
-        if !script_path.exists() {
-            return Err(anyhow::anyhow!("CGI script not found at: {:?}", script_path));
+    /// Answer a request whose body will not be read, closing the connection afterwards.
+    fn reject_request(&mut self, fd: RawFd, response: HttpResponse) {
+        if let Some(client) = self.clients.get_mut(&fd) {
+            client.body = None;
+            client.buffer.clear();
+            client.queue_response(response);
+            client.state = ConnectionState::Writing;
+            client.close_after_write = true;
         }
+    }
 
-        Ok(CgiRequest {
-            script_path: script_path.to_str().unwrap().to_string(),
+    /// Continue with client input that was left waiting: more of a body once its CGI script
+    /// has room for it, or requests pipelined behind a response that has just finished.
+    fn resume_client_input(&mut self, fd: RawFd) {
+        match self.clients.get_mut(&fd) {
+            Some(client) => client.last_activity = Instant::now(),
+            None => return,
+        }
+        if let Err(e) = self.handle_client_input(fd).and_then(|_| self.handle_client_read(fd)) {
+            eprintln!("Error reading from client {}: {}", fd, e);
+            self.close_client_connection(fd);
+            return;
+        }
+        self.deliver_response(fd, Vec::new());
+    }
+
+    /// Send queued output until the socket is full: bytes with write(2), file regions
+    /// with sendfile(2). Client sockets are edge-triggered, so stopping any earlier
+    /// would leave output waiting for an EPOLLOUT that never comes.
+    fn handle_client_write(&mut self, fd: RawFd) -> Result<(), Box<dyn std::error::Error>> {
+        let client = self.clients.get_mut(&fd).ok_or("Client not found")?;
+
+        loop {
+            let result = if !client.response_buffer.is_empty() {
+                client.stream.write(&client.response_buffer).map(|n| {
+                    client.response_buffer.drain(..n);
+                })
+            } else {
+                match client.response_parts.front_mut() {
+                    None => break,
+                    Some(BodyPart::Bytes(bytes)) => {
+                        client.response_buffer = std::mem::take(bytes);
+                        client.response_parts.pop_front();
+                        continue;
+                    }
+                    Some(BodyPart::File(region)) if region.len == 0 => {
+                        client.response_parts.pop_front();
+                        continue;
+                    }
+                    Some(BodyPart::File(region)) => match client.stream.send_file(&region.file, region.offset, region.len) {
+                        // The file shrank since its length was sent; the response cannot be completed
+                        Ok(0) => return Err("File ended before its Content-Length".into()),
+                        Ok(n) => {
+                            region.offset += n as u64;
+                            region.len -= n as u64;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    },
+                }
+            };
+            match result {
+                Ok(()) => client.last_activity = Instant::now(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // A streamed CGI response may drain before the script is done
+        if !client.has_output() && client.state == ConnectionState::Writing {
+            client.state = ConnectionState::KeepAlive;
+        }
+        let resume = match client.stalled_backend {
+            Some(_) if client.queued_bytes() < MAX_CLIENT_OUTPUT => client.stalled_backend.take(),
+            _ => None,
+        };
+        if let Some((backend, since)) = resume {
+            self.resume_backend(fd, backend, since);
+        }
+        Ok(())
+    }
+
+    fn find_header_end(buffer: &[u8]) -> Option<usize> {
+        for i in 0..buffer.len().saturating_sub(3) {
+            if &buffer[i..i+4] == b"\r\n\r\n" {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    fn handle_payload_too_large(&self, server_config: &ServerConfig) -> HttpResponse {
+        if let Some(error_page_path) = server_config.error_pages.get(&413) {
+            if let Ok(content) = std::fs::read(error_page_path) {
+                let mut response = HttpResponse::new(StatusCode::PayloadTooLarge);
+                response.set_body(&content);
+                response.set_header("Content-Type", "text/html");
+                return response;
+            }
+        }
+        HttpResponse::payload_too_large()
+    }
+
+    fn handle_request_wrapper(&mut self, client_fd: RawFd, request: HttpRequest, server_config_index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let server_config = &self.config.servers[server_config_index];
+        let accept_encoding = request.get_header("accept-encoding").cloned();
+        // Responses of proxied servers are passed on as they are
+        let compression = self.find_route_for_request(&request, server_config)
+            .filter(|route| route.proxy_pass.is_none())
+            .map(|route| route.compression.clone())
+            .unwrap_or_default();
+
+        let mut response = if let Some(route) = self.find_route_for_request(&request, server_config) {
+            if route.upstream_status {
+                self.upstream_status_response()
+            } else if route.proxy_pass.is_some() || route.is_fastcgi_request(&request.uri) || route.is_cgi_request(&request.uri) {
+                let route = route.clone();
+                match Self::check_route_method(&request, &route, server_config) {
+                    Err(response) => response,
+                    Ok(()) => match self.consult_cache(client_fd, &request, &route, server_config_index) {
+                        CacheDecision::Serve(response) => response,
+                        CacheDecision::Fetch(cache) => match self.start_backend(client_fd, true, request, &route, server_config_index, cache) {
+                            // The response is relayed from the backend's event handler
+                            Ok(()) => return Ok(()),
+                            Err(response) => response,
+                        },
+                    },
+                }
+            } else {
+                // Use the new static request handler with proper 403 handling
+                Self::handle_static_request(request, server_config)
+            }
+        } else {
+            self.handle_not_found(server_config)
+        };
+
+        compression::compress_response(accept_encoding.as_deref(), &compression, &mut response);
+        if let Some(client) = self.clients.get_mut(&client_fd) {
+            client.queue_response(response);
+            client.state = ConnectionState::Writing;
+        }
+        
+        Ok(())
+    }
+
+    fn create_cgi_request(
+        &self,
+        client_fd: RawFd,
+        request: &HttpRequest,
+        server_config: &ServerConfig,
+        route_config: &RouteConfig,
+    ) -> Result<CgiRequest, anyhow::Error> {
+        let client = self.clients.get(&client_fd)
+            .ok_or_else(|| anyhow::anyhow!("Client {} not found", client_fd))?;
+        let root = PathBuf::from(route_config.root.as_deref().unwrap_or("./"));
+
+        // Map "/cgi-bin/app.py/users/42" to "./www/cgi-bin/app.py" plus PATH_INFO "/users/42"
+        let location = match CgiHandler::resolve_script(&root, &request.uri, route_config.cgi_extension.as_deref()) {
+            Some(location) => location,
+            // A FastCGI application may route paths that are not files here
+            None if route_config.fastcgi_pass.is_some() && !request.uri.split('/').any(|segment| segment == "..") => ScriptLocation {
+                script_path: root.join(request.uri.trim_start_matches('/')),
+                script_name: request.uri.clone(),
+                path_info: String::new(),
+            },
+            None => return Err(anyhow::anyhow!("CGI script not found for: {}", request.uri)),
+        };
+        let document_root = root.canonicalize().unwrap_or(root);
+
+        // SERVER_NAME/SERVER_PORT describe the URL the client used
+        let host = request.host().map(|h| h.trim().to_string());
+        let server_name = host.as_deref()
+            .map(Self::normalize_host)
+            .filter(|name| !name.is_empty())
+            .or_else(|| server_config.server_names.first().cloned())
+            .unwrap_or_else(|| "localhost".to_string());
+        let host_port = host.as_deref()
+            .and_then(|h| h.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse::<u16>().ok());
+        let server_port = client.listen_addr.port().or(host_port).unwrap_or(80);
+
+        let (remote_addr, remote_port) = match client.peer_addr {
+            Some(addr) => (addr.ip().to_string(), Some(addr.port())),
+            None => ("unix:".to_string(), None),
+        };
+
+        // A body piped to the script as it arrives is not part of the request yet
+        let content_length = match request.body_len() {
+            0 => request.content_length().unwrap_or(0) as u64,
+            len => len,
+        };
+
+        Ok(CgiRequest {
+            script_path: location.script_path.to_string_lossy().into_owned(),
+            script_name: location.script_name,
+            path_info: location.path_info,
+            document_root: document_root.to_string_lossy().into_owned(),
             method: request.method.to_string(),
             uri: request.uri.clone(),
             query_string: request.query_string.clone().unwrap_or_default(),
             headers: request.headers.clone(),
             body: request.body.clone(),
-            remote_addr: "127.0.0.1".to_string(), // Placeholder, could be improved
+            body_file: request.body_file.clone(),
+            content_length,
+            server_name,
+            server_port: server_port.to_string(),
+            remote_addr,
+            remote_port,
             cgi_pass: route_config.cgi_pass.clone(),
         })
     }
 
     fn handle_static_request(request: HttpRequest, server_config: &ServerConfig) -> HttpResponse {
+        let mut response = Self::serve_static_request(&request, server_config);
+        set_session_cookie(&request, &mut response);
+        response
+    }
+
+    /// Refuse a method the route doesn't allow: 403 if it allows none, 405 otherwise.
+    fn check_route_method(request: &HttpRequest, route: &RouteConfig, server_config: &ServerConfig) -> Result<(), HttpResponse> {
+        if route.methods.is_empty() {
+            println!("Empty methods for route {}, returning 403 Forbidden", route.path);
+            // Try to serve custom 403 error page
+            if let Some(error_page_path) = server_config.error_pages.get(&403) {
+                if let Ok(content) = std::fs::read(error_page_path) {
+                    let mut response = HttpResponse::new(StatusCode::Forbidden);
+                    response.set_body(&content);
+                    response.set_header("Content-Type", "text/html");
+                    return Err(response);
+                }
+            }
+            // Fallback to default 403 response
+            return Err(HttpResponse::forbidden());
+        }
+        if !route.methods.contains(&request.method.to_string()) {
+            let error_page = server_config.error_pages.get(&405).map(|s| s.as_str());
+            return Err(HttpResponse::method_not_allowed_custom(error_page));
+        }
+        Ok(())
+    }
+
+    fn serve_static_request(request: &HttpRequest, server_config: &ServerConfig) -> HttpResponse {
         println!("[DEBUG] All route configs:");
         for route in &server_config.routes {
             println!("  path: {}, methods: {:?}, root: {:?}, cgi_pass: {:?}, cgi_extension: {:?}", route.path, route.methods, route.root, route.cgi_pass, route.cgi_extension);
         }
         println!("Handling {} request for {}", request.method, request.uri);
         
-        // --- SESSION HANDLING LOGIC START ---
-        let cookie_header = request.get_header("cookie");
-        let session_id = get_or_create_session_id(cookie_header);
-        let mut set_cookie_needed = true;
-        if let Some(cookie_header) = cookie_header {
-            if cookie_header.contains(&format!("SESSIONID={}", session_id)) {
-                set_cookie_needed = false;
-            }
-        }
-        // --- SESSION HANDLING LOGIC END ---
-
         // Check if body size exceeds limit
         if request.body.len() > server_config.client_max_body_size {
-            let mut resp = HttpResponse::payload_too_large();
-            if set_cookie_needed {
-                resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-            }
-            return resp;
+            return HttpResponse::payload_too_large();
         }
         
         // Special case: if the request is GET /redirect, serve www/redirect.html directly
@@ -493,62 +1283,24 @@ impl WebServer {
                     let mut resp = HttpResponse::new(StatusCode::Ok);
                     resp.set_body(&contents);
                     resp.set_header("Content-Type", "text/html");
-                    if set_cookie_needed {
-                        resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                    }
                     return resp;
                 }
             }
-            let mut resp = HttpResponse::not_found();
-            if set_cookie_needed {
-                resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-            }
-            return resp;
+            return HttpResponse::not_found();
         }
         // Find matching route
         for route in &server_config.routes {
             if Self::matches_route(&request.uri, &route.path) {
                 
-                // CHECK FOR EMPTY METHODS FIRST - before any file system operations
-                if route.methods.is_empty() {
-                    println!("Empty methods for route {}, returning 403 Forbidden", route.path);
-                    // Try to serve custom 403 error page
-                    if let Some(error_page_path) = server_config.error_pages.get(&403) {
-                        if let Ok(content) = std::fs::read(error_page_path) {
-                            let mut response = HttpResponse::new(StatusCode::Forbidden);
-                            response.set_body(&content);
-                            response.set_header("Content-Type", "text/html");
-                            if set_cookie_needed {
-                                response.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                            }
-                            return response;
-                        }
-                    }
-                    // Fallback to default 403 response
-                    let mut resp = HttpResponse::forbidden();
-                    if set_cookie_needed {
-                        resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                    }
-                    return resp;
-                }
-                
-                // Check if method is allowed
-                if !route.methods.contains(&request.method.to_string()) {
-                    let error_page = server_config.error_pages.get(&405).map(|s| s.as_str());
-                    let mut resp = HttpResponse::method_not_allowed_custom(error_page);
-                    if set_cookie_needed {
-                        resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                    }
-                    return resp;
+                // CHECK METHODS FIRST - before any file system operations
+                if let Err(response) = Self::check_route_method(request, route, server_config) {
+                    return response;
                 }
                 
                 // Use static file handler for this route
                 let static_handler = StaticFileHandler::new(server_config);
-                let mut response = static_handler.handle_request(&request, server_config);
-                if set_cookie_needed {
-                    response.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                }
-                
+                let response = static_handler.handle_request(request, server_config);
+
                 // If we got a 404 and there's a custom error page for it, try to serve that
                 if response.status == StatusCode::NotFound {
                     if let Some(error_page) = server_config.error_pages.get(&404) {
@@ -559,9 +1311,6 @@ impl WebServer {
                                     let mut custom_response = HttpResponse::new(StatusCode::NotFound);
                                     custom_response.set_body(&content);
                                     custom_response.set_header("content-type", "text/html");
-                                    if set_cookie_needed {
-                                        custom_response.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-                                    }
                                     return custom_response;
                                 }
                             }
@@ -573,11 +1322,7 @@ impl WebServer {
         }
         
         // No matching route found
-        let mut resp = HttpResponse::not_found();
-        if set_cookie_needed {
-            resp.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
-        }
-        resp
+        HttpResponse::not_found()
     }
 
     fn matches_route(uri: &str, route_path: &str) -> bool {
@@ -594,6 +1339,11 @@ impl WebServer {
         let mut to_remove = Vec::new();
         
         for (&fd, client) in &self.clients {
+            // Clients waiting on a CGI script are bounded by cgi_timeout instead; one
+            // still sending a body is not, unless the body goes to the script
+            if client.state == ConnectionState::Processing && client.body.as_ref().is_none_or(IncomingBody::is_piped) {
+                continue;
+            }
             if now.duration_since(client.last_activity) > timeout_duration {
                 to_remove.push(fd);
             }
@@ -603,6 +1353,107 @@ impl WebServer {
             println!("Client {} timed out", fd);
             self.close_client_connection(fd);
         }
+
+        self.cleanup_cgi_timeouts();
+    }
+
+    /// Enforce `cgi_timeout`: SIGTERM the script's process group and answer 504, then
+    /// SIGKILL it after a grace period. Terminated scripts are reaped here even if
+    /// something in the group still holds their pipes open. FastCGI and proxied requests
+    /// that run past their timeout are abandoned with a 504.
+    fn cleanup_cgi_timeouts(&mut self) {
+        const KILL_GRACE: Duration = Duration::from_secs(3);
+        let now = Instant::now();
+        let stalled = self.stalled_backends();
+
+        let mut timed_out = Vec::new();
+        let mut reaped = Vec::new();
+        for (&pid, conn) in self.cgi_connections.iter_mut() {
+            match conn.terminated_at {
+                None if now >= conn.deadline && !stalled.contains(&Backend::Cgi(pid)) => timed_out.push(pid),
+                None => {}
+                Some(terminated_at) => {
+                    if let Ok(Some(_)) = conn.process.child.try_wait() {
+                        reaped.push(pid);
+                    } else if now.duration_since(terminated_at) >= KILL_GRACE {
+                        conn.process.signal_group(libc::SIGKILL);
+                    }
+                }
+            }
+        }
+
+        for pid in timed_out {
+            log::warn!("CGI process {} exceeded its timeout, terminating", pid);
+            if let Some((client_fd, headers_sent)) = self.terminate_cgi(pid) {
+                self.fail_relayed_response(client_fd, headers_sent, HttpResponse::gateway_timeout());
+            }
+        }
+        for pid in reaped {
+            self.remove_cgi(pid);
+        }
+
+        let idle: Vec<RawFd> = self.fastcgi_connections.iter()
+            .filter(|(_, conn)| conn.requests.is_empty() && conn.close_at.is_some_and(|at| now >= at))
+            .map(|(&fd, _)| fd)
+            .collect();
+        for fd in idle {
+            self.remove_fastcgi(fd);
+        }
+        let expired: Vec<(RawFd, u16)> = self.fastcgi_connections.iter()
+            .filter(|(&fd, _)| !stalled.contains(&Backend::FastCgi(fd)))
+            .flat_map(|(&fd, conn)| conn.requests.iter()
+                .filter(|(_, request)| now >= request.deadline)
+                .map(move |(&id, _)| (fd, id)))
+            .collect();
+        for (fd, id) in expired {
+            let request = match self.fastcgi_connections.get_mut(&fd).and_then(|conn| conn.requests.get_mut(&id)) {
+                Some(request) if now >= request.deadline => request,
+                _ => continue,
+            };
+            if request.aborted {
+                // Not even aborting it ended the request
+                self.close_fastcgi(fd, Some("application did not end an aborted request".to_string()));
+                continue;
+            }
+            log::warn!("[fastcgi #{}] application exceeded its timeout", request.relay.request_id);
+            request.deadline = now + KILL_GRACE;
+            if let Some((client_fd, headers_sent)) = self.abort_fastcgi_request(fd, id) {
+                self.fail_relayed_response(client_fd, headers_sent, HttpResponse::gateway_timeout());
+            }
+        }
+
+        let expired: Vec<RawFd> = self.proxy_connections.iter()
+            .filter(|(&fd, conn)| now >= conn.deadline && !stalled.contains(&Backend::Proxy(fd)))
+            .map(|(&fd, _)| fd)
+            .collect();
+        for fd in expired {
+            if let Some(conn) = self.remove_proxy(fd) {
+                self.proxy_failed(conn, "timed out", HttpResponse::gateway_timeout());
+            }
+        }
+    }
+
+    /// Backends left unread for a slow client. Waiting on the client does not count
+    /// toward their timeout; `resume_backend` extends the deadline instead.
+    fn stalled_backends(&self) -> Vec<Backend> {
+        self.clients.values()
+            .filter_map(|client| client.stalled_backend.map(|(backend, _)| backend))
+            .collect()
+    }
+
+    /// The earliest deadline `cleanup_cgi_timeouts` will act on.
+    fn next_backend_deadline(&self) -> Option<Instant> {
+        let stalled = self.stalled_backends();
+        let cgi = self.cgi_connections.iter()
+            .filter(|(&pid, conn)| conn.terminated_at.is_none() && !stalled.contains(&Backend::Cgi(pid)))
+            .map(|(_, conn)| conn.deadline);
+        let fastcgi = self.fastcgi_connections.iter()
+            .filter(|(&fd, _)| !stalled.contains(&Backend::FastCgi(fd)))
+            .flat_map(|(_, conn)| conn.requests.values().map(|request| request.deadline).chain(conn.close_at));
+        let proxy = self.proxy_connections.iter()
+            .filter(|(&fd, _)| !stalled.contains(&Backend::Proxy(fd)))
+            .map(|(_, conn)| conn.deadline);
+        cgi.chain(fastcgi).chain(proxy).min()
     }
 
     fn close_client_connection(&mut self, fd: RawFd) {
@@ -610,162 +1461,1734 @@ impl WebServer {
             let _ = self.epoll.remove_client(fd);
             drop(client); // This will close the stream
             println!("Closed connection: {}", fd);
+
+            // Nobody is waiting for these scripts any more
+            let orphaned: Vec<u32> = self.cgi_connections.iter()
+                .filter(|(_, conn)| conn.relay.client_fd == Some(fd))
+                .map(|(&pid, _)| pid)
+                .collect();
+            for pid in orphaned {
+                self.terminate_cgi(pid);
+            }
+            let orphaned: Vec<(RawFd, u16)> = self.fastcgi_connections.iter()
+                .flat_map(|(&app_fd, conn)| conn.requests.iter()
+                    .filter(|(_, request)| request.relay.client_fd == Some(fd))
+                    .map(move |(&id, _)| (app_fd, id)))
+                .collect();
+            for (app_fd, id) in orphaned {
+                self.abort_fastcgi_request(app_fd, id);
+            }
+            let orphaned: Vec<RawFd> = self.proxy_connections.iter()
+                .filter(|(_, conn)| conn.request.client_fd == Some(fd))
+                .map(|(&upstream_fd, _)| upstream_fd)
+                .collect();
+            for upstream_fd in orphaned {
+                self.remove_proxy(upstream_fd);
+            }
         }
     }
 
-    fn start_cgi_for_client(&mut self, client_fd: RawFd, cgi_req: CgiRequest) -> Result<(), Box<dyn std::error::Error>> {
-        let handler = CgiHandler::new();
-        let process = handler.start_nonblocking(cgi_req.clone())?;
-        let stdout_fd = process.stdout_fd;
-        let stderr_fd = process.stderr_fd;
-        let stdin_fd = process.stdin_fd;
-        let cgi_conn = CgiConnection {
-            process,
+    /// Queue response bytes that were produced outside the client's own event (e.g. by CGI)
+    /// and try to send them right away: client sockets are edge-triggered, so no EPOLLOUT
+    /// arrives for a socket that was already writable.
+    fn deliver_response(&mut self, client_fd: RawFd, data: Vec<u8>) {
+        match self.clients.get_mut(&client_fd) {
+            Some(client) => client.queue_bytes(&data),
+            None => return,
+        }
+        if let Err(e) = self.handle_client_write(client_fd) {
+            eprintln!("Error writing to client {}: {}", client_fd, e);
+            self.close_client_connection(client_fd);
+            return;
+        }
+        if self.clients.get(&client_fd).is_some_and(ClientConnection::is_done) {
+            self.close_client_connection(client_fd);
+        }
+    }
+
+    /// How much more output may be queued for a client. Once it is gone there is no
+    /// limit, as the response only goes to the cache.
+    fn output_room(&self, client_fd: Option<RawFd>) -> usize {
+        match client_fd.and_then(|fd| self.clients.get(&fd)) {
+            Some(client) => MAX_CLIENT_OUTPUT.saturating_sub(client.queued_bytes()),
+            None => usize::MAX,
+        }
+    }
+
+    /// Leave `backend` unread until the client's queued output drains.
+    fn stall(&mut self, client_fd: Option<RawFd>, backend: Backend) {
+        if let Some(client) = client_fd.and_then(|fd| self.clients.get_mut(&fd)) {
+            client.stalled_backend.get_or_insert((backend, Instant::now()));
+        }
+    }
+
+    /// Read on from a backend that was left unread while the client's output queue was
+    /// full. The time spent waiting on the client does not count against its timeout.
+    fn resume_backend(&mut self, client_fd: RawFd, backend: Backend, since: Instant) {
+        let stalled = since.elapsed();
+        let result = match backend {
+            Backend::Cgi(pid) => match self.cgi_connections.get_mut(&pid) {
+                Some(conn) if conn.relay.client_fd == Some(client_fd) => {
+                    conn.deadline += stalled;
+                    match conn.process.stdout_fd {
+                        Some(stdout_fd) => self.handle_cgi_event(stdout_fd, true, false),
+                        None => Ok(()),
+                    }
+                }
+                _ => Ok(()),
+            },
+            Backend::FastCgi(fd) => match self.fastcgi_connections.get_mut(&fd) {
+                Some(conn) if conn.requests.values().any(|request| request.relay.client_fd == Some(client_fd)) => {
+                    // Every request on the connection waited
+                    for request in conn.requests.values_mut() {
+                        request.deadline += stalled;
+                    }
+                    self.handle_fastcgi_event(fd, true, false)
+                }
+                _ => Ok(()),
+            },
+            Backend::Proxy(fd) => match self.proxy_connections.get_mut(&fd) {
+                Some(conn) if conn.request.client_fd == Some(client_fd) => {
+                    conn.deadline += stalled;
+                    self.handle_proxy_event(fd, true, false)
+                }
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            log::error!("Error resuming {:?} for client {}: {}", backend, client_fd, e);
+        }
+    }
+
+    /// Mark the current response as complete and pick up the rest of the request body,
+    /// or a request the client pipelined while the response was being produced.
+    fn finish_response(&mut self, client_fd: RawFd, data: Vec<u8>, close_after_write: bool) {
+        match self.clients.get_mut(&client_fd) {
+            Some(client) => {
+                client.state = ConnectionState::Writing;
+                client.close_after_write |= close_after_write;
+                client.queue_bytes(&data);
+            }
+            None => return,
+        }
+        self.resume_client_input(client_fd);
+    }
+
+    fn allocate_request_id(&mut self) -> u64 {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        request_id
+    }
+
+    fn new_relay(&mut self, client_fd: Option<RawFd>, kind: &'static str, script_path: &str, request: HttpRequest, server_index: usize) -> CgiRelay {
+        let nph = CgiHandler::is_nph_script(script_path);
+        CgiRelay {
             client_fd,
-            output_buffer: Vec::new(),
+            kind,
+            request_id: self.allocate_request_id(),
+            script_path: script_path.to_string(),
+            parser: CgiOutputParser::new(nph),
+            nph,
+            chunked: false,
+            until_close: false,
+            request,
+            server_index,
+            local_redirect: None,
             error_buffer: Vec::new(),
-            stdin_done: false,
+            cache: None,
+            compression: CompressionConfig::default(),
+            encoder: None,
+        }
+    }
+
+    fn start_cgi_for_client(&mut self, relay: CgiRelay, handler: &CgiHandler, cgi_req: CgiRequest) -> Result<(), Box<dyn std::error::Error>> {
+        let mut process = handler.start_nonblocking(&cgi_req)?;
+        let pid = process.child.id();
+
+        // A body the client is still sending is fed in by receive_body
+        let body_pending = cgi_req.body_file.is_none() && cgi_req.content_length > cgi_req.body.len() as u64;
+        let stdin_done = cgi_req.body.is_empty() && !body_pending;
+        if stdin_done {
+            // Nothing to send: close stdin so the script sees EOF immediately
+            if let Some(fd) = process.stdin_fd {
+                process.close_pipe(fd);
+            }
+        }
+
+        log::info!("[cgi #{} {}] {} {} (pid {})", relay.request_id, cgi_req.script_path, cgi_req.method, cgi_req.uri, pid);
+
+        let fds = process.open_fds();
+        let cgi_conn = CgiConnection {
+            process,
+            relay,
+            stdin_done,
             stdout_done: false,
             stderr_done: false,
-            body_to_write: cgi_req.body.clone(),
+            body_to_write: cgi_req.body,
             body_written: 0,
-            done: false,
+            body_pending,
+            deadline: Instant::now() + handler.timeout(),
+            terminated_at: None,
         };
-        if let Some(fd) = stdout_fd {
-            self.epoll.add_client(fd)?;
-            self.cgi_connections.insert(fd, cgi_conn);
-        }
-        if let Some(fd) = stderr_fd {
-            self.epoll.add_client(fd)?;
-        }
-        if let Some(fd) = stdin_fd {
-            self.epoll.add_client(fd)?;
+        self.cgi_connections.insert(pid, cgi_conn);
+
+        for fd in fds {
+            if let Err(e) = self.epoll.add_client(fd) {
+                self.abort_cgi(pid);
+                return Err(e);
+            }
+            self.cgi_fds.insert(fd, pid);
         }
         Ok(())
     }
 
     fn handle_cgi_event(&mut self, fd: RawFd, readable: bool, writable: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let mut fds_to_remove = Vec::new();
-        if let Some(conn) = self.cgi_connections.get_mut(&fd) {
-            // Handle stdin (write request body)
-            if let Some(stdin_fd) = conn.process.stdin_fd {
-                if fd == stdin_fd && writable && !conn.stdin_done {
-                    let body = &conn.body_to_write.clone();
-                    let written = &mut conn.body_written;
-                    if *written < body.len() {
-                        match conn.process.child.stdin.as_mut().unwrap().write(&body[*written..]) {
-                            Ok(n) => {
-                                *written += n;
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                // Can't write more now, will try again
-                            }
-                            Err(e) => {
-                                log::error!("Error writing to CGI stdin: {}", e);
-                                conn.stdin_done = true; // Stop trying
-                            }
-                        }
-                    }
-                    if *written >= body.len() {
-                        conn.stdin_done = true;
-                        // Close stdin to signal end of input
-                        drop(conn.process.child.stdin.take()); 
-                        fds_to_remove.push(fd);
-                    }
-                }
+        let pid = match self.cgi_fds.get(&fd) {
+            Some(&pid) => pid,
+            None => return Ok(()),
+        };
+        let stdin_fd = match self.cgi_connections.get(&pid) {
+            Some(conn) => conn.process.stdin_fd,
+            None => {
+                self.cgi_fds.remove(&fd);
+                return Ok(());
             }
+        };
 
-            // Handle stdout (read script output)
-            if let Some(stdout_fd) = conn.process.stdout_fd {
-                if fd == stdout_fd && readable && !conn.stdout_done {
-                    let mut buf = [0; 4096];
-                    match conn.process.child.stdout.as_mut().unwrap().read(&mut buf) {
-                        Ok(0) => {
-                            conn.stdout_done = true;
-                            fds_to_remove.push(fd);
-                        }
-                        Ok(n) => {
-                            conn.output_buffer.extend_from_slice(&buf[..n]);
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            // No more data for now
-                        }
-                        Err(e) => {
-                            log::error!("Error reading from CGI stdout: {}", e);
-                            conn.stdout_done = true; // Stop trying
-                            fds_to_remove.push(fd);
-                        }
-                    }
-                }
+        // Handle stdin (write request body)
+        if stdin_fd == Some(fd) && writable {
+            self.write_cgi_stdin(pid);
+            // There is room for more of the body again
+            let waiting_client = self.cgi_connections.get(&pid)
+                .filter(|conn| conn.body_pending)
+                .and_then(|conn| conn.relay.client_fd);
+            if let Some(client_fd) = waiting_client {
+                self.resume_client_input(client_fd);
             }
+        }
 
-            // Handle stderr (read script error)
-            if let Some(stderr_fd) = conn.process.stderr_fd {
-                if fd == stderr_fd && readable && !conn.stderr_done {
-                    let mut buf = [0; 4096];
-                    match conn.process.child.stderr.as_mut().unwrap().read(&mut buf) {
-                        Ok(0) => {
-                            conn.stderr_done = true;
-                            fds_to_remove.push(fd);
-                        }
-                        Ok(n) => {
-                            conn.error_buffer.extend_from_slice(&buf[..n]);
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            // No more data for now
-                        }
-                        Err(e) => {
-                            log::error!("Error reading from CGI stderr: {}", e);
-                            conn.stderr_done = true; // Stop trying
-                            fds_to_remove.push(fd);
-                        }
-                    }
+        // Handle stdout (read script output)
+        let stdout_fd = self.cgi_connections.get(&pid).and_then(|conn| conn.process.stdout_fd);
+        if stdout_fd == Some(fd) && readable {
+            self.read_cgi_stdout(pid);
+        }
+
+        // Handle stderr (read script error)
+        let conn = match self.cgi_connections.get_mut(&pid) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        if conn.process.stderr_fd == Some(fd) && readable && !conn.stderr_done {
+            match conn.process.read_stderr(&mut conn.relay.error_buffer) {
+                Ok(eof) => conn.stderr_done = eof,
+                Err(e) => {
+                    log::error!("Error reading from CGI stderr: {}", e);
+                    conn.stderr_done = true; // Stop trying
                 }
             }
+            conn.relay.log_stderr(conn.stderr_done);
+            if conn.stderr_done {
+                self.close_cgi_fd(pid, fd);
+            }
+        }
 
-            // Check if CGI process is finished
-            if conn.stdout_done && conn.stderr_done && !conn.done {
-                conn.done = true;
-                let cgi_handler = CgiHandler::new();
-                let response = if !conn.error_buffer.is_empty() {
-                    log::error!("CGI Error: {}", String::from_utf8_lossy(&conn.error_buffer));
-                    HttpResponse::internal_server_error()
-                } else {
-                    match cgi_handler.parse_cgi_output(&conn.output_buffer) {
-                        Ok(cgi_resp) => HttpResponse::from_cgi_response(cgi_resp),
-                        Err(e) => {
-                            log::error!("Failed to parse CGI output: {}", e);
-                            HttpResponse::internal_server_error()
-                        }
-                    }
-                };
+        self.try_finish_cgi(pid);
+        Ok(())
+    }
 
-                // Send response to the original client
-                if let Some(client) = self.clients.get_mut(&conn.client_fd) {
-                    client.response_buffer = response.to_bytes();
-                    client.state = ConnectionState::Writing;
+    /// Relay what the script has written to stdout. While the client has a full queue of
+    /// output the rest stays in the pipe, and the script blocks on writing it, until
+    /// `handle_client_write` drains the queue.
+    fn read_cgi_stdout(&mut self, pid: u32) {
+        loop {
+            let client_fd = match self.cgi_connections.get(&pid) {
+                Some(conn) if !conn.stdout_done => conn.relay.client_fd,
+                _ => return,
+            };
+            let room = self.output_room(client_fd);
+            if room == 0 {
+                self.stall(client_fd, Backend::Cgi(pid));
+                return;
+            }
+            let conn = match self.cgi_connections.get_mut(&pid) {
+                Some(conn) => conn,
+                None => return,
+            };
+            let mut data = Vec::new();
+            match conn.process.read_stdout(&mut data, room) {
+                Ok(eof) => conn.stdout_done = eof,
+                Err(e) => {
+                    log::error!("Error reading from CGI stdout: {}", e);
+                    conn.stdout_done = true; // Stop trying
                 }
+            }
+            let more = !conn.stdout_done && data.len() >= room;
+            if let (true, Some(stdout_fd)) = (conn.stdout_done, conn.process.stdout_fd) {
+                self.close_cgi_fd(pid, stdout_fd);
+            }
+            if !data.is_empty() {
+                self.relay_cgi_output(pid, &data);
+            }
+            if !more {
+                return;
+            }
+        }
+    }
+
+    /// Queue body bytes for a script's stdin as they arrive from the client; `end` once the
+    /// body is complete. The pipe is edge-triggered, so writing starts right away.
+    fn feed_cgi_stdin(&mut self, pid: u32, data: &[u8], end: bool) {
+        let conn = match self.cgi_connections.get_mut(&pid) {
+            Some(conn) if !conn.stdin_done => conn,
+            _ => return,
+        };
+        conn.body_to_write.drain(..conn.body_written);
+        conn.body_written = 0;
+        conn.body_to_write.extend_from_slice(data);
+        conn.body_pending = !end;
+        self.write_cgi_stdin(pid);
+    }
 
-                // Clean up this CGI connection
-                if let Some(process_fd) = conn.process.stdout_fd { fds_to_remove.push(process_fd); }
-                if let Some(process_fd) = conn.process.stderr_fd { fds_to_remove.push(process_fd); }
+    /// Write queued body bytes to the script's stdin and close it once the whole body
+    /// is written, which signals the end of input.
+    fn write_cgi_stdin(&mut self, pid: u32) {
+        let conn = match self.cgi_connections.get_mut(&pid) {
+            Some(conn) if !conn.stdin_done => conn,
+            _ => return,
+        };
+        match conn.process.write_stdin(&conn.body_to_write[conn.body_written..]) {
+            Ok(n) => conn.body_written += n,
+            Err(e) => {
+                // Typically EPIPE: the script exited without reading its input
+                log::error!("Error writing to CGI stdin: {}", e);
+                conn.body_written = conn.body_to_write.len();
+                conn.body_pending = false;
             }
         }
-        for process_fd in fds_to_remove {
-            self.epoll.remove_client(process_fd)?;
-            self.cgi_connections.remove(&process_fd);
+        if conn.body_written < conn.body_to_write.len() || conn.body_pending {
+            return;
+        }
+        conn.stdin_done = true;
+        conn.body_to_write = Vec::new();
+        conn.body_written = 0;
+        if let Some(fd) = conn.process.stdin_fd {
+            self.close_cgi_fd(pid, fd);
         }
+    }
 
-        Ok(())
+    /// Send script output to the client as soon as it arrives: headers once the
+    /// header block is complete, then the body as it is written.
+    fn relay_cgi_output(&mut self, pid: u32, data: &[u8]) {
+        let conn = match self.cgi_connections.get_mut(&pid) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let client_fd = conn.relay.client_fd;
+        match conn.relay.relay(data) {
+            Ok(bytes) => {
+                if let Some(client_fd) = client_fd {
+                    if !bytes.is_empty() {
+                        self.deliver_response(client_fd, bytes);
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to parse CGI output: {}", e);
+                self.abort_cgi(pid);
+            }
+        }
     }
-}
 
-impl Drop for WebServer {
-    fn drop(&mut self) {
-        // Clean up all connections
-        let fds: Vec<RawFd> = self.clients.keys().cloned().collect();
-        for fd in fds {
-            self.close_client_connection(fd);
+    /// Answer a client whose backend (CGI script, FastCGI application, proxied server) failed.
+    /// Once streaming has started the status can no longer change, so the connection is
+    /// closed to mark the response as incomplete.
+    fn fail_relayed_response(&mut self, client_fd: RawFd, headers_sent: bool, response: HttpResponse) {
+        if headers_sent {
+            self.close_client_connection(client_fd);
+        } else {
+            self.finish_response(client_fd, response.to_bytes(), false);
         }
     }
-}
\ No newline at end of file
+
+    /// Finish the client's side of a CGI or FastCGI response once the output has ended.
+    /// `failure` is the error response to send if the script did not succeed.
+    fn complete_cgi_response(&mut self, mut relay: CgiRelay, failure: Option<HttpResponse>) {
+        if let Some(response) = failure {
+            if let Some(client_fd) = relay.client_fd {
+                self.fail_relayed_response(client_fd, relay.response_started(), response);
+            }
+            return;
+        }
+
+        if let Some(location) = relay.local_redirect.take() {
+            if let Some(client_fd) = relay.client_fd {
+                self.follow_local_redirect(client_fd, &relay, &location);
+            }
+            return;
+        }
+
+        let bytes = relay.finish();
+        if let Some(capture) = relay.cache.take() {
+            self.store_in_cache(capture);
+        }
+        match (relay.client_fd, bytes) {
+            // nph output carries its own framing, which we don't track
+            (Some(client_fd), Ok(bytes)) => self.finish_response(client_fd, bytes, relay.nph || relay.until_close),
+            (Some(client_fd), Err(e)) => {
+                log::error!("Failed to complete response for {}: {}", relay.request.uri, e);
+                self.close_client_connection(client_fd);
+            }
+            (None, _) => {}
+        }
+    }
+
+    /// Deregister one CGI pipe from epoll, then close it.
+    fn close_cgi_fd(&mut self, pid: u32, fd: RawFd) {
+        let _ = self.epoll.remove_client(fd);
+        self.cgi_fds.remove(&fd);
+        if let Some(conn) = self.cgi_connections.get_mut(&pid) {
+            conn.process.close_pipe(fd);
+        }
+    }
+
+    /// Once stdout and stderr are closed, reap the script and complete its response.
+    /// If the process has not exited yet, `reap_cgi_processes` retries on the next tick.
+    fn try_finish_cgi(&mut self, pid: u32) {
+        let status = match self.cgi_connections.get_mut(&pid) {
+            Some(conn) if conn.stdout_done && conn.stderr_done => match conn.process.child.try_wait() {
+                Ok(Some(status)) => Some(status),
+                Ok(None) => return,
+                Err(e) => {
+                    log::error!("Failed to reap CGI process {}: {}", pid, e);
+                    None
+                }
+            },
+            _ => return,
+        };
+
+        let conn = match self.remove_cgi(pid) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let failure = if Self::cgi_succeeded(&conn.relay, status) {
+            None
+        } else {
+            Some(HttpResponse::internal_server_error())
+        };
+        self.complete_cgi_response(conn.relay, failure);
+    }
+
+    /// Serve the path named by a CGI local redirect as a fresh GET (RFC 3875 section 6.2.2).
+    fn follow_local_redirect(&mut self, client_fd: RawFd, relay: &CgiRelay, location: &str) {
+        const MAX_INTERNAL_REDIRECTS: u32 = 10;
+
+        let redirects = match self.clients.get_mut(&client_fd) {
+            Some(client) => {
+                client.internal_redirects += 1;
+                client.internal_redirects
+            }
+            None => return,
+        };
+        if redirects > MAX_INTERNAL_REDIRECTS {
+            log::error!("Too many CGI local redirects for {}, last to {}", relay.request.uri, location);
+            self.finish_response(client_fd, HttpResponse::internal_server_error().to_bytes(), false);
+            return;
+        }
+
+        log::info!("CGI local redirect from {} to {}", relay.request.uri, location);
+        let request = relay.request.internal_redirect(location);
+        if let Err(e) = self.handle_request_wrapper(client_fd, request, relay.server_index) {
+            eprintln!("Error processing request from client {}: {}", client_fd, e);
+            self.close_client_connection(client_fd);
+            return;
+        }
+        // A static target was answered right away; a CGI target responds on its own
+        if self.clients.get(&client_fd).is_some_and(|c| c.state != ConnectionState::Processing) {
+            self.finish_response(client_fd, Vec::new(), false);
+        }
+    }
+
+    /// Only the exit status decides; stderr output is logged but is not an error.
+    fn cgi_succeeded(relay: &CgiRelay, status: Option<ExitStatus>) -> bool {
+        match status {
+            Some(status) if status.success() => true,
+            Some(status) => {
+                log::error!("[cgi #{} {}] script failed ({})", relay.request_id, relay.script_path, status);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Drop a CGI connection, deregistering and closing any pipes still open.
+    fn remove_cgi(&mut self, pid: u32) -> Option<CgiConnection> {
+        let mut conn = self.cgi_connections.remove(&pid)?;
+        conn.relay.log_stderr(true);
+        for fd in conn.process.open_fds() {
+            let _ = self.epoll.remove_client(fd);
+            self.cgi_fds.remove(&fd);
+            conn.process.close_pipe(fd);
+        }
+        Some(conn)
+    }
+
+    /// SIGTERM a script's process group and detach it from its client (the fd may be
+    /// reused by the next accept). `cleanup_cgi_timeouts` escalates to SIGKILL and reaps it.
+    /// Returns the client that was waiting, if any, and whether its response has started.
+    fn terminate_cgi(&mut self, pid: u32) -> Option<(RawFd, bool)> {
+        let conn = self.cgi_connections.get_mut(&pid)?;
+        if conn.terminated_at.is_none() {
+            conn.process.signal_group(libc::SIGTERM);
+            conn.terminated_at = Some(Instant::now());
+        }
+        let headers_sent = conn.relay.response_started();
+        conn.relay.client_fd.take().map(|fd| (fd, headers_sent))
+    }
+
+    /// Kill a CGI script that can no longer be served and answer its client with 500.
+    fn abort_cgi(&mut self, pid: u32) {
+        if let Some(mut conn) = self.remove_cgi(pid) {
+            conn.process.signal_group(libc::SIGKILL);
+            let _ = conn.process.child.wait();
+            if let Some(client_fd) = conn.relay.client_fd {
+                self.fail_relayed_response(client_fd, conn.relay.response_started(), HttpResponse::internal_server_error());
+            }
+        }
+    }
+
+    fn has_unreaped_cgi(&self) -> bool {
+        self.cgi_connections.values().any(|conn| conn.stdout_done && conn.stderr_done)
+    }
+
+    fn reap_cgi_processes(&mut self) {
+        let pending: Vec<u32> = self.cgi_connections.iter()
+            .filter(|(_, conn)| conn.stdout_done && conn.stderr_done)
+            .map(|(&pid, _)| pid)
+            .collect();
+        for pid in pending {
+            self.try_finish_cgi(pid);
+        }
+    }
+
+    /// Send a request to a FastCGI application: on a pooled connection with room for it,
+    /// or else on a new one. It is written out from `handle_fastcgi_event` as the socket
+    /// accepts it. Requests share a connection only once the application has said it
+    /// multiplexes them; applications such as php-fpm take one at a time.
+    fn start_fastcgi_for_client(
+        &mut self,
+        relay: CgiRelay,
+        addr: &ListenAddr,
+        handler: &CgiHandler,
+        cgi_req: CgiRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("[fastcgi #{} {}] {} {} via {}", relay.request_id, cgi_req.script_path, cgi_req.method, cgi_req.uri, addr);
+        let request = FastCgiRequest {
+            relay,
+            params: handler.build_environment(&cgi_req),
+            body: cgi_req.body,
+            body_file: cgi_req.body_file,
+            head_queued: false,
+            body_offset: 0,
+            stdin_done: false,
+            answered: false,
+            aborted: false,
+            retried: false,
+            deadline: Instant::now() + handler.timeout(),
+        };
+
+        if !self.fastcgi_pools.get(addr).is_some_and(|pool| pool.probed) {
+            self.probe_fastcgi(addr);
+        }
+        match self.pooled_fastcgi(addr) {
+            Some(fd) => {
+                self.queue_fastcgi_request(fd, request);
+                // The socket is writable already, so no EPOLLOUT comes for the new records
+                self.handle_fastcgi_event(fd, false, true)
+            }
+            None => {
+                let fd = self.connect_fastcgi(addr)?;
+                self.queue_fastcgi_request(fd, request);
+                Ok(())
+            }
+        }
+    }
+
+    fn fastcgi_pool(&mut self, addr: &ListenAddr) -> &mut FastCgiPool {
+        self.fastcgi_pools.entry(addr.clone()).or_insert_with(FastCgiPool::new)
+    }
+
+    /// A pooled connection to `addr` with room for another request, the least busy first.
+    fn pooled_fastcgi(&self, addr: &ListenAddr) -> Option<RawFd> {
+        let pool = self.fastcgi_pools.get(addr)?;
+        pool.connections.iter()
+            .filter_map(|&fd| self.fastcgi_connections.get(&fd).map(|conn| (fd, conn.requests.len())))
+            .filter(|&(_, busy)| busy < pool.max_requests)
+            .min_by_key(|&(_, busy)| busy)
+            .map(|(fd, _)| fd)
+    }
+
+    /// Open a new connection to the application at `addr` and add it to the pool.
+    fn connect_fastcgi(&mut self, addr: &ListenAddr) -> Result<RawFd, Box<dyn std::error::Error>> {
+        let stream = ClientStream::connect(addr)?;
+        let fd = stream.as_raw_fd();
+        self.epoll.add_client(fd)?;
+        self.fastcgi_connections.insert(fd, FastCgiConnection::new(stream, addr.clone()));
+        self.fastcgi_pool(addr).connections.push(fd);
+        Ok(fd)
+    }
+
+    fn queue_fastcgi_request(&mut self, fd: RawFd, request: FastCgiRequest) {
+        if let Some(conn) = self.fastcgi_connections.get_mut(&fd) {
+            let id = conn.free_request_id();
+            conn.requests.insert(id, request);
+            conn.close_at = None;
+        }
+    }
+
+    /// Ask the application at `addr` whether it multiplexes requests (FCGI_GET_VALUES),
+    /// on a connection of its own: some applications, php-fpm among them, close the
+    /// connection once they have answered.
+    fn probe_fastcgi(&mut self, addr: &ListenAddr) {
+        const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+        // If the application can't be reached, the next request asks again
+        let stream = match ClientStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let fd = stream.as_raw_fd();
+        if self.epoll.add_client(fd).is_err() {
+            return;
+        }
+        let mut conn = FastCgiConnection::new(stream, addr.clone());
+        conn.outgoing = fastcgi::encode_get_values(&[fastcgi::FCGI_MPXS_CONNS, fastcgi::FCGI_MAX_REQS]);
+        conn.probe = true;
+        conn.close_at = Some(Instant::now() + PROBE_TIMEOUT);
+        self.fastcgi_connections.insert(fd, conn);
+        self.fastcgi_pool(addr).probed = true;
+    }
+
+    /// Take in the application's answer to FCGI_GET_VALUES: requests share its
+    /// connections only if it multiplexes them (FCGI_MPXS_CONNS=1), up to FCGI_MAX_REQS.
+    fn set_fastcgi_values(&mut self, addr: &ListenAddr, content: &[u8]) {
+        const MAX_MULTIPLEXED: usize = 32;
+        let values = fastcgi::decode_params(content).unwrap_or_default();
+        if values.get(fastcgi::FCGI_MPXS_CONNS).map(String::as_str) != Some("1") {
+            return;
+        }
+        let max_requests = values.get(fastcgi::FCGI_MAX_REQS)
+            .and_then(|max| max.parse::<usize>().ok())
+            .filter(|&max| max > 0)
+            .map_or(MAX_MULTIPLEXED, |max| max.min(MAX_MULTIPLEXED));
+        log::info!("FastCGI application {} multiplexes up to {} requests per connection", addr, max_requests);
+        self.fastcgi_pool(addr).max_requests = max_requests;
+    }
+
+    fn handle_fastcgi_event(&mut self, fd: RawFd, readable: bool, writable: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = match self.fastcgi_connections.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let mut failure = None;
+        let mut eof = false;
+
+        // Send what is queued (this also surfaces a failed connect)
+        let mut sending = writable;
+        while sending {
+            if conn.written == conn.outgoing.len() {
+                match conn.queue_next_records() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        failure = Some(format!("reading request body failed: {}", e));
+                        break;
+                    }
+                }
+            }
+            match write_available(&mut conn.stream, &conn.outgoing[conn.written..]) {
+                Ok(n) => conn.written += n,
+                Err(e) => {
+                    failure = Some(format!("write failed: {}", e));
+                    break;
+                }
+            }
+            sending = conn.written == conn.outgoing.len();
+        }
+
+        // Read the responses, at most as much as the fullest client output queue has room for
+        let mut reading = readable && failure.is_none();
+        loop {
+            let (room, client_fd) = match self.fastcgi_connections.get(&fd) {
+                Some(conn) => conn.requests.values()
+                    .map(|request| (self.output_room(request.relay.client_fd), request.relay.client_fd))
+                    .min_by_key(|&(room, _)| room)
+                    .unwrap_or((usize::MAX, None)),
+                None => return Ok(()),
+            };
+            if reading && room == 0 {
+                self.stall(client_fd, Backend::FastCgi(fd));
+                reading = false;
+            }
+            let conn = match self.fastcgi_connections.get_mut(&fd) {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+
+            let mut data = Vec::new();
+            if reading {
+                match read_up_to(&mut conn.stream, &mut data, room) {
+                    Ok(closed) => eof = closed,
+                    Err(e) => failure = Some(format!("read failed: {}", e)),
+                }
+            }
+            reading = reading && !eof && failure.is_none() && data.len() >= room;
+
+            match conn.reader.feed(&data) {
+                Ok(records) => self.handle_fastcgi_records(fd, records),
+                Err(e) => failure = Some(e.to_string()),
+            }
+            if failure.is_some() || eof {
+                break;
+            }
+            if !reading {
+                return Ok(());
+            }
+        }
+
+        self.close_fastcgi(fd, failure);
+        Ok(())
+    }
+
+    /// Hand the records read off a connection to the requests they belong to.
+    fn handle_fastcgi_records(&mut self, fd: RawFd, records: Vec<fastcgi::Record>) {
+        for record in records {
+            let conn = match self.fastcgi_connections.get_mut(&fd) {
+                Some(conn) => conn,
+                None => return,
+            };
+            if conn.probe {
+                if record.record_type == fastcgi::FCGI_GET_VALUES_RESULT {
+                    let addr = conn.addr.clone();
+                    self.set_fastcgi_values(&addr, &record.content);
+                }
+                // An application that doesn't know GET_VALUES answers FCGI_UNKNOWN_TYPE
+                if matches!(record.record_type, fastcgi::FCGI_GET_VALUES_RESULT | fastcgi::FCGI_UNKNOWN_TYPE) {
+                    self.remove_fastcgi(fd);
+                    return;
+                }
+                continue;
+            }
+
+            let id = record.request_id;
+            // Records for other request ids are not ours to handle
+            let request = match conn.requests.get_mut(&id) {
+                Some(request) => request,
+                None => continue,
+            };
+            request.answered = true;
+            match record.record_type {
+                fastcgi::FCGI_STDOUT if !request.aborted && !record.content.is_empty() => {
+                    let client_fd = request.relay.client_fd;
+                    match request.relay.relay(&record.content) {
+                        Ok(bytes) => {
+                            if let Some(client_fd) = client_fd.filter(|_| !bytes.is_empty()) {
+                                self.deliver_response(client_fd, bytes);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed to parse FastCGI output: {}", e);
+                            if let Some((client_fd, headers_sent)) = self.abort_fastcgi_request(fd, id) {
+                                self.fail_relayed_response(client_fd, headers_sent, HttpResponse::bad_gateway());
+                            }
+                        }
+                    }
+                }
+                fastcgi::FCGI_STDERR => {
+                    request.relay.error_buffer.extend_from_slice(&record.content);
+                    request.relay.log_stderr(false);
+                }
+                fastcgi::FCGI_END_REQUEST => self.end_fastcgi_request(fd, id, EndRequest::parse(&record.content)),
+                _ => {}
+            }
+        }
+    }
+
+    /// The application ended a request: finish its response and leave the connection
+    /// to the next request.
+    fn end_fastcgi_request(&mut self, fd: RawFd, id: u16, end: Option<EndRequest>) {
+        let conn = match self.fastcgi_connections.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut request = match conn.requests.remove(&id) {
+            Some(request) => request,
+            None => return,
+        };
+        conn.reused = true;
+        let addr = conn.addr.clone();
+        if conn.requests.is_empty() {
+            self.release_fastcgi(fd);
+        }
+        request.relay.log_stderr(true);
+        if request.aborted {
+            return;
+        }
+
+        let failure = match end {
+            Some(end) if end.is_success() => None,
+            Some(end) if end.protocol_status == fastcgi::FCGI_CANT_MPX_CONN && !request.retried => {
+                log::warn!("[fastcgi #{}] {} does not multiplex requests after all, retrying", request.relay.request_id, addr);
+                self.fastcgi_pool(&addr).max_requests = 1;
+                return self.retry_fastcgi_request(&addr, request);
+            }
+            Some(end) => {
+                log::error!(
+                    "[fastcgi #{} {}] request failed (app status {}, protocol status {})",
+                    request.relay.request_id, request.relay.script_path, end.app_status, end.protocol_status
+                );
+                Some(HttpResponse::internal_server_error())
+            }
+            None => {
+                log::error!("[fastcgi #{}] invalid END_REQUEST record", request.relay.request_id);
+                Some(HttpResponse::bad_gateway())
+            }
+        };
+        self.complete_cgi_response(request.relay, failure);
+    }
+
+    /// Keep a connection whose requests have all ended open for the next one, unless
+    /// enough connections to its application are idle already.
+    fn release_fastcgi(&mut self, fd: RawFd) {
+        let addr = match self.fastcgi_connections.get(&fd) {
+            Some(conn) => conn.addr.clone(),
+            None => return,
+        };
+        let idle = self.fastcgi_pools.get(&addr).map_or(0, |pool| {
+            pool.connections.iter()
+                .filter(|pooled| self.fastcgi_connections.get(pooled).is_some_and(|conn| conn.requests.is_empty()))
+                .count()
+        });
+        if idle > MAX_IDLE_FASTCGI {
+            self.remove_fastcgi(fd);
+        } else if let Some(conn) = self.fastcgi_connections.get_mut(&fd) {
+            conn.close_at = Some(Instant::now() + FASTCGI_IDLE_TIMEOUT);
+        }
+    }
+
+    /// Give up on one request. A connection carrying nothing else is closed, which
+    /// aborts the request in the application; otherwise the application is sent
+    /// FCGI_ABORT_REQUEST and the request's output is dropped until it ends. Returns the
+    /// client that was waiting, if any, and whether its response has started.
+    fn abort_fastcgi_request(&mut self, fd: RawFd, id: u16) -> Option<(RawFd, bool)> {
+        let conn = self.fastcgi_connections.get_mut(&fd)?;
+        let alone = conn.requests.len() == 1;
+        let request = conn.requests.get_mut(&id)?;
+        let headers_sent = request.relay.response_started();
+        let client = request.relay.client_fd.take().map(|client_fd| (client_fd, headers_sent));
+        if alone {
+            self.remove_fastcgi(fd);
+            return client;
+        }
+        if !request.head_queued {
+            // The application has not heard of it yet
+            conn.requests.remove(&id);
+            return client;
+        }
+        request.aborted = true;
+        request.relay.cache = None;
+        conn.outgoing.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_ABORT_REQUEST, id, &[]));
+        if let Err(e) = self.handle_fastcgi_event(fd, false, true) {
+            log::error!("Error aborting FastCGI request {} on {}: {}", id, fd, e);
+        }
+        client
+    }
+
+    /// Close a connection the application closed or that failed. Requests still on it
+    /// are sent again on a new connection if it was a reused one that never answered
+    /// them, as the application may have closed it while it sat idle; the others fail.
+    fn close_fastcgi(&mut self, fd: RawFd, failure: Option<String>) {
+        let FastCgiConnection { addr, requests, reused, .. } = match self.remove_fastcgi(fd) {
+            Some(conn) => conn,
+            None => return,
+        };
+        for request in requests.into_values() {
+            if request.aborted {
+                continue;
+            }
+            if reused && !request.answered && !request.retried {
+                log::warn!("[fastcgi #{}] {} closed a kept-alive connection, sending the request again", request.relay.request_id, addr);
+                self.retry_fastcgi_request(&addr, request);
+                continue;
+            }
+            match &failure {
+                Some(e) => log::error!("[fastcgi #{}] {}", request.relay.request_id, e),
+                None => log::error!("[fastcgi #{}] application closed the connection before ending the request", request.relay.request_id),
+            }
+            self.complete_cgi_response(request.relay, Some(HttpResponse::bad_gateway()));
+        }
+    }
+
+    /// Send a request again from the start, on a new connection.
+    fn retry_fastcgi_request(&mut self, addr: &ListenAddr, mut request: FastCgiRequest) {
+        request.rewind();
+        match self.connect_fastcgi(addr) {
+            Ok(fd) => self.queue_fastcgi_request(fd, request),
+            Err(e) => {
+                log::error!("[fastcgi #{}] connecting to {} failed: {}", request.relay.request_id, addr, e);
+                self.complete_cgi_response(request.relay, Some(HttpResponse::bad_gateway()));
+            }
+        }
+    }
+
+    /// Look the request up in the route's cache zone. A fresh entry is served as a HIT;
+    /// a stale one still within its stale window is served as STALE while a background
+    /// request refreshes it. Otherwise the backend's response is captured for the zone.
+    fn consult_cache(&mut self, client_fd: RawFd, request: &HttpRequest, route: &RouteConfig, server_index: usize) -> CacheDecision {
+        let zone_name = if route.proxy_pass.is_some() { &route.proxy_cache } else { &route.cgi_cache };
+        let zone = match zone_name.as_ref().and_then(|name| self.cache_zones.get_mut(name)) {
+            Some(zone) if cache::is_cacheable_request(request) => zone,
+            _ => return CacheDecision::Fetch(None),
+        };
+        let capture = CacheCapture::new(zone, request, route.cache_valid, route.cache_stale.unwrap_or_default());
+        if cache::wants_fresh(request) {
+            return CacheDecision::Fetch(Some(capture));
+        }
+
+        let now = SystemTime::now();
+        let entry = match zone.lookup(capture.primary_key(), &request.headers) {
+            Some(entry) if entry.is_usable(now) => entry,
+            _ => return CacheDecision::Fetch(Some(capture)),
+        };
+        if entry.is_fresh(now) {
+            return CacheDecision::Serve(entry.to_response("HIT", now));
+        }
+
+        if zone.begin_update(capture.primary_key()) {
+            log::info!("Refreshing stale cache entry {}", capture.primary_key());
+            // The refresh must bring back a full response to replace the entry
+            let mut refresh = HttpRequest { body: Vec::new(), body_file: None, ..request.clone() };
+            refresh.headers.remove("if-none-match");
+            refresh.headers.remove("if-modified-since");
+            // Errors are logged by start_backend; the entry is retried once the update claim lapses
+            let _ = self.start_backend(client_fd, false, refresh, route, server_index, Some(capture));
+        }
+        CacheDecision::Serve(entry.to_response("STALE", now))
+    }
+
+    /// Hand the request to the route's backend: a proxied server, a FastCGI application
+    /// or a CGI script, whose response is relayed from their event handlers. Without
+    /// `deliver` the response only goes to the cache (a background refresh).
+    fn start_backend(
+        &mut self,
+        client_fd: RawFd,
+        deliver: bool,
+        request: HttpRequest,
+        route: &RouteConfig,
+        server_index: usize,
+        cache: Option<CacheCapture>,
+    ) -> Result<(), HttpResponse> {
+        let deliver_to = deliver.then_some(client_fd);
+
+        if let Some(proxy_pass) = &route.proxy_pass {
+            let path = proxy_pass.upstream_path(&route.path, &request.uri);
+            let client_ip = self.clients.get(&client_fd).and_then(|c| c.peer_addr).map(|addr| addr.ip());
+            let request_id = self.allocate_request_id();
+            log::info!("[proxy #{}] {} {} -> {}{}", request_id, request.method, request.uri, proxy_pass.host, path);
+
+            // The request is written out from handle_proxy_event as the socket accepts it
+            return self.connect_proxy(ProxyRequest {
+                client_fd: deliver_to,
+                request_id,
+                target: proxy_pass.target.clone(),
+                method: request.method.clone(),
+                client_ip,
+                outgoing: proxy::build_upstream_request(&request, &path, &proxy_pass.host, client_ip),
+                body_file: request.body_file.clone(),
+                timeout: route.proxy_timeout.unwrap_or(Duration::from_secs(60)),
+                tried: Vec::new(),
+                cache,
+            }).map_err(|e| {
+                log::error!("Error connecting to upstream {}: {}", proxy_pass.host, e);
+                HttpResponse::bad_gateway()
+            });
+        }
+
+        println!("Handling as CGI request");
+        let server_config = &self.config.servers[server_index];
+        let cgi_request = self.create_cgi_request(client_fd, &request, server_config, route).map_err(|e| {
+            eprintln!("Error creating CGI request: {}", e);
+            HttpResponse::internal_server_error()
+        })?;
+        let handler = CgiHandler::with_timeout(route.cgi_timeout);
+        let kind = if route.fastcgi_pass.is_some() { "fastcgi" } else { "cgi" };
+        let origin = HttpRequest { body: Vec::new(), body_file: None, ..request };
+        let mut relay = self.new_relay(deliver_to, kind, &cgi_request.script_path, origin, server_index);
+        relay.cache = cache;
+        relay.compression = route.compression.clone();
+
+        let started = match &route.fastcgi_pass {
+            Some(addr) => self.start_fastcgi_for_client(relay, addr, &handler, cgi_request)
+                .map_err(|e| (e, HttpResponse::bad_gateway())),
+            None => self.start_cgi_for_client(relay, &handler, cgi_request)
+                .map_err(|e| (e, HttpResponse::internal_server_error())),
+        };
+        started.map_err(|(e, response)| {
+            eprintln!("Error executing CGI: {}", e);
+            response
+        })
+    }
+
+    fn store_in_cache(&mut self, capture: CacheCapture) {
+        if let Some(zone) = self.cache_zones.get_mut(capture.zone()) {
+            zone.store(capture);
+        }
+    }
+
+    /// Connect to the next server for `request`. Servers of an upstream group that
+    /// refuse the connection outright count as failed and the next one is tried.
+    fn connect_proxy(&mut self, mut request: ProxyRequest) -> Result<(), String> {
+        let mut last_error = String::new();
+        loop {
+            let (addr, peer) = match &request.target {
+                ProxyTarget::Addr(addr) if request.tried.is_empty() => (addr.clone(), None),
+                ProxyTarget::Addr(_) => return Err(last_error),
+                ProxyTarget::Upstream(name) => {
+                    let group = self.upstreams.get_mut(name).ok_or_else(|| format!("unknown upstream '{}'", name))?;
+                    match group.select(request.client_ip, &request.tried, Instant::now()) {
+                        Some(peer) => (group.addr(peer).clone(), Some(peer)),
+                        None => return Err(format!("no servers left to try in upstream '{}'", name)),
+                    }
+                }
+            };
+            request.tried.push(peer.unwrap_or(0));
+
+            let stream = match ClientStream::connect(&addr) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!("[proxy #{}] connecting to {} failed: {}", request.request_id, addr, e);
+                    last_error = e.to_string();
+                    self.upstream_failed(&request.target, peer);
+                    continue;
+                }
+            };
+            let fd = stream.as_raw_fd();
+            self.epoll.add_client(fd).map_err(|e| e.to_string())?;
+            if let (Some(group), Some(peer)) = (self.upstream_group(&request.target), peer) {
+                group.acquire(peer);
+            }
+            log::info!("[proxy #{}] sending to {}", request.request_id, addr);
+
+            let mut parser = UpstreamResponseParser::new(&request.method);
+            if request.cache.is_some() {
+                parser.decode_body();
+                parser.add_header("X-Cache", "MISS");
+            }
+            let conn = ProxyConnection {
+                stream,
+                upstream: addr,
+                peer,
+                written: 0,
+                body_written: 0,
+                parser,
+                deadline: Instant::now() + request.timeout,
+                request,
+            };
+            self.proxy_connections.insert(fd, conn);
+            return Ok(());
+        }
+    }
+
+    /// The upstream server failed or timed out. As long as none of its response has
+    /// reached the client, the request moves on to the next server of an upstream group,
+    /// unless it is not idempotent and the failed server may already have acted on it.
+    fn proxy_failed(&mut self, conn: ProxyConnection, error: &str, response: HttpResponse) {
+        log::error!("[proxy #{}] upstream {}: {}", conn.request.request_id, conn.upstream, error);
+        self.upstream_failed(&conn.request.target, conn.peer);
+
+        let client_fd = conn.request.client_fd;
+        let head_sent = conn.parser.head_sent();
+        let retry = !head_sent
+            && matches!(conn.request.target, ProxyTarget::Upstream(_))
+            && (conn.written == 0 || conn.request.method.is_idempotent());
+        if retry {
+            let request_id = conn.request.request_id;
+            match self.connect_proxy(conn.request) {
+                Ok(()) => return,
+                Err(e) => log::error!("[proxy #{}] {}", request_id, e),
+            }
+        }
+        if let Some(client_fd) = client_fd {
+            self.fail_relayed_response(client_fd, head_sent, response);
+        }
+    }
+
+    fn upstream_group(&mut self, target: &ProxyTarget) -> Option<&mut UpstreamGroup> {
+        match target {
+            ProxyTarget::Upstream(name) => self.upstreams.get_mut(name),
+            ProxyTarget::Addr(_) => None,
+        }
+    }
+
+    /// Count a failure against `peer`, which may take it out of the rotation for a while.
+    fn upstream_failed(&mut self, target: &ProxyTarget, peer: Option<usize>) {
+        if let (Some(group), Some(peer)) = (self.upstream_group(target), peer) {
+            if let Some(down_for) = group.record_failure(peer, Instant::now()) {
+                log::warn!("Upstream server {} marked down for {:?}", group.addr(peer), down_for);
+            }
+        }
+    }
+
+    fn handle_proxy_event(&mut self, fd: RawFd, readable: bool, writable: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = match self.proxy_connections.get_mut(&fd) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let mut error = None;
+        let mut eof = false;
+
+        // Send the request (this also surfaces a failed connect)
+        if writable && conn.written < conn.request.outgoing.len() {
+            match write_available(&mut conn.stream, &conn.request.outgoing[conn.written..]) {
+                Ok(n) => conn.written += n,
+                Err(e) => error = Some(format!("write failed: {}", e)),
+            }
+        }
+        if let Some(body) = &conn.request.body_file {
+            if writable && error.is_none() && conn.written == conn.request.outgoing.len() {
+                match write_body_file(&mut conn.stream, body, conn.body_written) {
+                    Ok(n) => conn.body_written += n,
+                    Err(e) => error = Some(format!("write failed: {}", e)),
+                }
+            }
+        }
+
+        // Read the response, at most as much as the client's output queue has room for
+        let mut reading = readable && error.is_none();
+        loop {
+            let client_fd = match self.proxy_connections.get(&fd) {
+                Some(conn) => conn.request.client_fd,
+                None => return Ok(()),
+            };
+            let room = self.output_room(client_fd);
+            if reading && room == 0 {
+                self.stall(client_fd, Backend::Proxy(fd));
+                reading = false;
+            }
+            let conn = match self.proxy_connections.get_mut(&fd) {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+
+            let mut data = Vec::new();
+            if reading {
+                match read_up_to(&mut conn.stream, &mut data, room) {
+                    Ok(closed) => eof = closed,
+                    Err(e) => error = Some(format!("read failed: {}", e)),
+                }
+            }
+            reading = reading && !eof && error.is_none() && data.len() >= room;
+
+            let mut bytes = Vec::new();
+            if error.is_none() {
+                match conn.parser.feed(&data) {
+                    Ok(output) => bytes = output,
+                    Err(e) => error = Some(e.to_string()),
+                }
+                if let Some(capture) = &mut conn.request.cache {
+                    capture.body(&conn.parser.take_decoded());
+                }
+            }
+            let done = error.is_some() || eof || conn.parser.is_complete();
+            if let Some(client_fd) = client_fd {
+                if !bytes.is_empty() {
+                    self.deliver_response(client_fd, bytes);
+                }
+            }
+            if done {
+                break;
+            }
+            if !reading {
+                return Ok(());
+            }
+        }
+
+        let mut conn = match self.remove_proxy(fd) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let tail = match error {
+            Some(e) => Err(e),
+            None => conn.parser.finish().map_err(|e| e.to_string()),
+        };
+        match tail {
+            Ok(bytes) => {
+                if let (Some(group), Some(peer)) = (self.upstream_group(&conn.request.target), conn.peer) {
+                    group.record_success(peer);
+                }
+                log::info!("[proxy #{}] {} from {}", conn.request.request_id, conn.parser.status().unwrap_or(0), conn.upstream);
+                if let (Some(mut capture), Some(status)) = (conn.request.cache.take(), conn.parser.status()) {
+                    capture.body(&conn.parser.take_decoded());
+                    capture.headers(status, conn.parser.headers().iter().map(|(n, v)| (n.as_str(), v.as_str())));
+                    self.store_in_cache(capture);
+                }
+                if let Some(client_fd) = conn.request.client_fd {
+                    self.finish_response(client_fd, bytes, false);
+                }
+            }
+            Err(e) => self.proxy_failed(conn, &e, HttpResponse::bad_gateway()),
+        }
+        Ok(())
+    }
+
+    /// Start a round of checks for each upstream group that is due, and fail the checks
+    /// that ran past their timeout. Runs on every event loop tick, so intervals are
+    /// honoured to within the one second epoll timeout.
+    fn run_health_checks(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<RawFd> = self.health_checks.iter()
+            .filter(|(_, check)| now >= check.deadline)
+            .map(|(&fd, _)| fd)
+            .collect();
+        for fd in expired {
+            if let Some(check) = self.remove_health_check(fd) {
+                self.record_health_check(&check.upstream, check.peer, Err("timed out".to_string()));
+            }
+        }
+
+        let due: Vec<String> = self.upstreams.iter_mut()
+            .filter(|(_, group)| group.health_check().is_some())
+            .filter_map(|(name, group)| group.checks_due(now).then(|| name.clone()))
+            .collect();
+        for name in due {
+            for peer in 0..self.upstreams[&name].len() {
+                // A check still waiting on a slow server is not doubled up
+                if !self.health_checks.values().any(|check| check.upstream == name && check.peer == peer) {
+                    self.start_health_check(&name, peer);
+                }
+            }
+        }
+    }
+
+    fn start_health_check(&mut self, name: &str, peer: usize) {
+        let group = &self.upstreams[name];
+        let (uri, timeout) = match group.health_check() {
+            Some(check) => (check.uri.clone(), check.timeout),
+            None => return,
+        };
+        let addr = group.addr(peer).clone();
+
+        let stream = match ClientStream::connect(&addr) {
+            Ok(stream) => stream,
+            Err(e) => return self.record_health_check(name, peer, Err(e.to_string())),
+        };
+        let fd = stream.as_raw_fd();
+        if let Err(e) = self.epoll.add_client(fd) {
+            return self.record_health_check(name, peer, Err(e.to_string()));
+        }
+        let outgoing = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: webserv-health-check\r\nConnection: close\r\n\r\n",
+            uri, name,
+        );
+        self.health_checks.insert(fd, HealthCheckConnection {
+            stream,
+            upstream: name.to_string(),
+            peer,
+            outgoing: outgoing.into_bytes(),
+            written: 0,
+            response: Vec::new(),
+            deadline: Instant::now() + timeout,
+        });
+    }
+
+    fn handle_health_check_event(&mut self, fd: RawFd, readable: bool, writable: bool) -> Result<(), Box<dyn std::error::Error>> {
+        const MAX_STATUS_LINE: usize = 8 * 1024;
+        let check = match self.health_checks.get_mut(&fd) {
+            Some(check) => check,
+            None => return Ok(()),
+        };
+
+        let mut result = None;
+        if writable && check.written < check.outgoing.len() {
+            match write_available(&mut check.stream, &check.outgoing[check.written..]) {
+                Ok(n) => check.written += n,
+                Err(e) => result = Some(Err(e.to_string())),
+            }
+        }
+        if readable && result.is_none() {
+            match read_available(&mut check.stream, &mut check.response) {
+                Ok(eof) => {
+                    if let Some(end) = check.response.iter().position(|&b| b == b'\n') {
+                        result = Some(proxy::parse_status_code(&check.response[..end])
+                            .ok_or_else(|| "invalid status line".to_string()));
+                    } else if eof {
+                        result = Some(Err("closed without a response".to_string()));
+                    } else if check.response.len() > MAX_STATUS_LINE {
+                        result = Some(Err("invalid status line".to_string()));
+                    }
+                }
+                Err(e) => result = Some(Err(e.to_string())),
+            }
+        }
+
+        if let Some(result) = result {
+            if let Some(check) = self.remove_health_check(fd) {
+                self.record_health_check(&check.upstream, check.peer, result);
+            }
+        }
+        Ok(())
+    }
+
+    fn record_health_check(&mut self, name: &str, peer: usize, result: Result<u16, String>) {
+        let group = match self.upstreams.get_mut(name) {
+            Some(group) => group,
+            None => return,
+        };
+        log::debug!("Health check of {} in upstream '{}': {:?}", group.addr(peer), name, result);
+        match group.record_check(peer, result, Instant::now()) {
+            Some(true) => log::info!("Upstream server {} passed its health checks, back in rotation", group.addr(peer)),
+            Some(false) => log::warn!("Upstream server {} failed its health checks, taken out of rotation", group.addr(peer)),
+            None => {}
+        }
+    }
+
+    fn remove_health_check(&mut self, fd: RawFd) -> Option<HealthCheckConnection> {
+        let check = self.health_checks.remove(&fd)?;
+        let _ = self.epoll.remove_client(fd);
+        Some(check)
+    }
+
+    /// The state of every upstream group as JSON, for a location with `upstream_status on`.
+    fn upstream_status_response(&self) -> HttpResponse {
+        let now = Instant::now();
+        let mut names: Vec<&String> = self.upstreams.keys().collect();
+        names.sort();
+        let groups: Vec<String> = names.iter().map(|name| self.upstreams[*name].status_json(now)).collect();
+
+        let mut response = HttpResponse::ok();
+        response.set_header("Content-Type", "application/json");
+        response.set_header("Cache-Control", "no-store");
+        response.set_body_string(&format!("{{\"upstreams\":[{}]}}\n", groups.join(",")));
+        response
+    }
+
+    fn remove_proxy(&mut self, fd: RawFd) -> Option<ProxyConnection> {
+        let conn = self.proxy_connections.remove(&fd)?;
+        let _ = self.epoll.remove_client(fd);
+        if let (Some(group), Some(peer)) = (self.upstream_group(&conn.request.target), conn.peer) {
+            group.release(peer);
+        }
+        Some(conn)
+    }
+
+    /// Drop a FastCGI connection; closing the socket also aborts its requests in the application.
+    fn remove_fastcgi(&mut self, fd: RawFd) -> Option<FastCgiConnection> {
+        let mut conn = self.fastcgi_connections.remove(&fd)?;
+        let _ = self.epoll.remove_client(fd);
+        if let Some(pool) = self.fastcgi_pools.get_mut(&conn.addr) {
+            pool.connections.retain(|&pooled| pooled != fd);
+        }
+        for request in conn.requests.values_mut() {
+            request.relay.log_stderr(true);
+        }
+        Some(conn)
+    }
+}
+
+impl Drop for WebServer {
+    fn drop(&mut self) {
+        // Clean up all connections
+        let fds: Vec<RawFd> = self.clients.keys().cloned().collect();
+        for fd in fds {
+            self.close_client_connection(fd);
+        }
+
+        // Don't leave CGI scripts running after the server is gone
+        let pids: Vec<u32> = self.cgi_connections.keys().cloned().collect();
+        for pid in pids {
+            if let Some(mut conn) = self.remove_cgi(pid) {
+                conn.process.signal_group(libc::SIGKILL);
+                let _ = conn.process.child.wait();
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BalanceMethod, ProxyPass, UpstreamConfig, UpstreamServer};
+    use crate::fastcgi::Record;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    /// A stand-in upstream server: answers every request with its own name as the body
+    /// and reports the name on `served`.
+    fn stand_in(listener: TcpListener, name: &'static str, served: mpsc::Sender<&'static str>) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", name.len(), name);
+                let _ = stream.write_all(response.as_bytes());
+                if served.send(name).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    fn upstream_server(addr: SocketAddr, fail_timeout: Duration) -> UpstreamServer {
+        UpstreamServer { addr: ListenAddr::Tcp(addr), weight: 1, max_fails: 1, fail_timeout }
+    }
+
+    fn proxy_request(request_id: u64) -> ProxyRequest {
+        ProxyRequest {
+            client_fd: None,
+            request_id,
+            target: ProxyTarget::Upstream("backend".to_string()),
+            method: HttpMethod::GET,
+            client_ip: None,
+            outgoing: b"GET / HTTP/1.1\r\nHost: backend\r\nConnection: close\r\n\r\n".to_vec(),
+            body_file: None,
+            timeout: Duration::from_secs(5),
+            tried: Vec::new(),
+            cache: None,
+        }
+    }
+
+    /// Proxy one request through the event handlers and return the stand-in that served it.
+    fn proxy_one(server: &mut WebServer, served: &Receiver<&'static str>, request_id: u64) -> &'static str {
+        server.connect_proxy(proxy_request(request_id)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.proxy_connections.is_empty() {
+            assert!(Instant::now() < deadline, "proxied request did not complete");
+            for event in server.epoll.wait(Duration::from_millis(100)).unwrap() {
+                server.handle_proxy_event(event.fd, event.readable, event.writable).unwrap();
+            }
+        }
+        served.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn proxied_requests_rotate_and_skip_a_refusing_server() {
+        let fail_timeout = Duration::from_millis(500);
+        let (served_tx, served) = mpsc::channel();
+        let a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let b = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Nothing listens on c's port until it is bound again below
+        let c_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let servers = vec![
+            upstream_server(a.local_addr().unwrap(), fail_timeout),
+            upstream_server(b.local_addr().unwrap(), fail_timeout),
+            upstream_server(c_addr, fail_timeout),
+        ];
+        stand_in(a, "a", served_tx.clone());
+        stand_in(b, "b", served_tx.clone());
+
+        let upstream = UpstreamConfig {
+            name: "backend".to_string(),
+            method: BalanceMethod::RoundRobin,
+            servers,
+            health_check: None,
+        };
+        let config = Config {
+            servers: Vec::new(),
+            upstreams: HashMap::from([("backend".to_string(), upstream)]),
+            cache_zones: HashMap::new(),
+        };
+        let mut server = WebServer::new(config).unwrap();
+
+        // The third request goes to c, is refused and fails over to a; c then stays out
+        let order: Vec<&str> = (1..=7).map(|id| proxy_one(&mut server, &served, id)).collect();
+        assert_eq!(order, ["a", "b", "a", "b", "a", "b", "a"]);
+        let failed_at = Instant::now();
+        let status = server.upstreams["backend"].status_json(failed_at);
+        assert!(status.contains(&format!("\"addr\":\"{}\",\"weight\":1,\"state\":\"down\"", c_addr)), "{}", status);
+
+        // Back in the rotation once fail_timeout has passed
+        stand_in(TcpListener::bind(c_addr).unwrap(), "c", served_tx);
+        thread::sleep(fail_timeout.saturating_sub(failed_at.elapsed()) + Duration::from_millis(50));
+        let order: Vec<&str> = (8..=10).map(|id| proxy_one(&mut server, &served, id)).collect();
+        assert!(order.contains(&"c"), "{:?}", order);
+    }
+
+    /// Read records off `stream` until `wanted` holds one of them, returning all read so far.
+    fn read_records(stream: &mut UnixStream, reader: &mut RecordReader, wanted: impl Fn(&Record) -> bool) -> Vec<Record> {
+        let mut records = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !records.iter().any(&wanted) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "server closed the connection");
+            records.extend(reader.feed(&buf[..n]).unwrap());
+        }
+        records
+    }
+
+    /// A stand-in FastCGI application that multiplexes. It answers FCGI_GET_VALUES on a
+    /// connection of its own, then takes `count` requests on a single connection: the
+    /// first two are answered together, their records interleaved. Returns the request
+    /// ids they came with.
+    fn multiplexing_responder(listener: UnixListener, count: usize) -> Vec<u16> {
+        let (mut probe, _) = listener.accept().unwrap();
+        let query = read_records(&mut probe, &mut RecordReader::new(), |_| true);
+        assert_eq!(query[0].record_type, fastcgi::FCGI_GET_VALUES);
+        let values = HashMap::from([
+            (fastcgi::FCGI_MPXS_CONNS.to_string(), "1".to_string()),
+            (fastcgi::FCGI_MAX_REQS.to_string(), "4".to_string()),
+        ]);
+        let answer = fastcgi::encode_record(fastcgi::FCGI_GET_VALUES_RESULT, fastcgi::FCGI_NULL_REQUEST_ID, &fastcgi::encode_params(&values));
+        probe.write_all(&answer).unwrap();
+        drop(probe);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = RecordReader::new();
+        let mut params: HashMap<u16, Vec<u8>> = HashMap::new();
+        let mut answered = Vec::new();
+        while answered.len() < count {
+            let batch = if answered.is_empty() { 2 } else { 1 };
+            let mut ready = Vec::new();
+            while ready.len() < batch {
+                let records = read_records(&mut stream, &mut reader, |record| record.record_type == fastcgi::FCGI_STDIN && record.content.is_empty());
+                for record in records {
+                    match record.record_type {
+                        fastcgi::FCGI_BEGIN_REQUEST => {
+                            assert_eq!(record.content[2], fastcgi::FCGI_KEEP_CONN);
+                            params.insert(record.request_id, Vec::new());
+                        }
+                        fastcgi::FCGI_PARAMS => params.get_mut(&record.request_id).unwrap().extend_from_slice(&record.content),
+                        fastcgi::FCGI_STDIN if record.content.is_empty() => ready.push(record.request_id),
+                        _ => {}
+                    }
+                }
+            }
+
+            // Headers in order, bodies in reverse, then the ends of both streams
+            let mut out = Vec::new();
+            let bodies: Vec<String> = ready.iter()
+                .map(|id| format!("{}\n", fastcgi::decode_params(&params[id]).unwrap()["REQUEST_URI"]))
+                .collect();
+            for (id, body) in ready.iter().zip(&bodies) {
+                let head = format!("Content-Type: text/plain\r\nContent-Length: {}\r\n\r\n", body.len());
+                out.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_STDOUT, *id, head.as_bytes()));
+            }
+            for (id, body) in ready.iter().zip(&bodies).rev() {
+                out.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_STDOUT, *id, body.as_bytes()));
+            }
+            for id in &ready {
+                out.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_STDOUT, *id, &[]));
+                out.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_END_REQUEST, *id, &[0, 0, 0, 0, fastcgi::FCGI_REQUEST_COMPLETE, 0, 0, 0]));
+            }
+            stream.write_all(&out).unwrap();
+            answered.extend(ready);
+        }
+        answered
+    }
+
+    /// A client whose request is being processed: its fd, and the client's end of the connection.
+    fn test_client(server: &mut WebServer) -> (RawFd, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let fd = stream.as_raw_fd();
+        server.clients.insert(fd, ClientConnection {
+            stream: ClientStream::Unix(stream),
+            listen_addr: ListenAddr::Unix(PathBuf::from("/tmp/webserv-test.sock")),
+            peer_addr: None,
+            buffer: Vec::new(),
+            body: None,
+            response_buffer: Vec::new(),
+            response_parts: VecDeque::new(),
+            close_after_write: false,
+            stalled_backend: None,
+            read_closed: false,
+            internal_redirects: 0,
+            last_activity: Instant::now(),
+            state: ConnectionState::Processing,
+        });
+        (fd, peer)
+    }
+
+    /// Hand a GET for `uri` to the FastCGI route from a new client, returning the
+    /// client's end of the connection.
+    fn fastcgi_client(server: &mut WebServer, route: &RouteConfig, uri: &str) -> UnixStream {
+        let (fd, peer) = test_client(server);
+        let request = HttpRequest::parse(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", uri).as_bytes()).unwrap();
+        server.start_backend(fd, true, request, route, 0, None).unwrap();
+        peer
+    }
+
+    /// Run the FastCGI event handlers until `done` holds.
+    fn drive_fastcgi(server: &mut WebServer, done: impl Fn(&WebServer) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(server) {
+            assert!(Instant::now() < deadline, "FastCGI requests did not complete");
+            for event in server.epoll.wait(Duration::from_millis(100)).unwrap() {
+                server.handle_fastcgi_event(event.fd, event.readable, event.writable).unwrap();
+            }
+        }
+    }
+
+    fn read_response(peer: &mut UnixStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        // The connection is kept alive, so read until nothing more comes
+        while let Ok(n) = peer.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn fastcgi_requests_share_a_multiplexed_kept_alive_connection() {
+        let path = std::env::temp_dir().join(format!("webserv-fastcgi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let responder = thread::spawn(move || multiplexing_responder(listener, 3));
+
+        let config = Config {
+            servers: vec![ServerConfig::default()],
+            upstreams: HashMap::new(),
+            cache_zones: HashMap::new(),
+        };
+        let mut server = WebServer::new(config).unwrap();
+        let addr = ListenAddr::Unix(path.clone());
+        let mut route = RouteConfig::new("/".to_string());
+        route.fastcgi_pass = Some(addr.clone());
+        let idle = |server: &WebServer| server.fastcgi_connections.values().all(|conn| conn.requests.is_empty());
+
+        // The second request joins the first on its connection once the application
+        // has said it multiplexes; the stand-in answers neither until both are in
+        let mut one = fastcgi_client(&mut server, &route, "/one");
+        drive_fastcgi(&mut server, |server| server.fastcgi_pools[&addr].max_requests > 1);
+        assert_eq!(server.fastcgi_pools[&addr].max_requests, 4);
+        let mut two = fastcgi_client(&mut server, &route, "/two");
+        drive_fastcgi(&mut server, idle);
+        assert_eq!(server.fastcgi_pools[&addr].connections.len(), 1);
+
+        // The connection was kept open for the next request
+        let mut three = fastcgi_client(&mut server, &route, "/three");
+        drive_fastcgi(&mut server, idle);
+        assert_eq!(responder.join().unwrap(), [1, 2, 1]);
+        let _ = std::fs::remove_file(&path);
+
+        for (peer, uri) in [(&mut one, "/one"), (&mut two, "/two"), (&mut three, "/three")] {
+            let response = read_response(peer);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with(&format!("\r\n\r\n{}\n", uri)), "{}", response);
+        }
+    }
+
+    /// Read one request off `stream` and answer it with its REQUEST_URI.
+    fn respond_to_one(stream: &mut UnixStream, reader: &mut RecordReader) {
+        let records = read_records(stream, reader, |record| record.record_type == fastcgi::FCGI_STDIN && record.content.is_empty());
+        let id = records[0].request_id;
+        let params: Vec<u8> = records.iter()
+            .filter(|record| record.record_type == fastcgi::FCGI_PARAMS)
+            .flat_map(|record| record.content.clone())
+            .collect();
+        let body = format!("{}\n", fastcgi::decode_params(&params).unwrap()["REQUEST_URI"]);
+        let stdout = format!("Content-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut out = fastcgi::encode_stream(fastcgi::FCGI_STDOUT, id, stdout.as_bytes());
+        out.extend_from_slice(&fastcgi::encode_record(fastcgi::FCGI_END_REQUEST, id, &[0, 0, 0, 0, fastcgi::FCGI_REQUEST_COMPLETE, 0, 0, 0]));
+        stream.write_all(&out).unwrap();
+    }
+
+    #[test]
+    fn fastcgi_request_is_sent_again_when_a_kept_alive_connection_was_closed() {
+        let path = std::env::temp_dir().join(format!("webserv-fastcgi-retry-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let responder = thread::spawn(move || {
+            // An application that doesn't know FCGI_GET_VALUES
+            let (mut probe, _) = listener.accept().unwrap();
+            read_records(&mut probe, &mut RecordReader::new(), |_| true);
+            let unknown = [fastcgi::FCGI_GET_VALUES, 0, 0, 0, 0, 0, 0, 0];
+            probe.write_all(&fastcgi::encode_record(fastcgi::FCGI_UNKNOWN_TYPE, fastcgi::FCGI_NULL_REQUEST_ID, &unknown)).unwrap();
+            drop(probe);
+
+            // Answers the first request, then closes the connection under the second
+            let (mut first, _) = listener.accept().unwrap();
+            let mut reader = RecordReader::new();
+            respond_to_one(&mut first, &mut reader);
+            read_records(&mut first, &mut reader, |record| record.record_type == fastcgi::FCGI_STDIN && record.content.is_empty());
+            drop(first);
+
+            let (mut second, _) = listener.accept().unwrap();
+            respond_to_one(&mut second, &mut RecordReader::new());
+        });
+
+        let config = Config {
+            servers: vec![ServerConfig::default()],
+            upstreams: HashMap::new(),
+            cache_zones: HashMap::new(),
+        };
+        let mut server = WebServer::new(config).unwrap();
+        let addr = ListenAddr::Unix(path.clone());
+        let mut route = RouteConfig::new("/".to_string());
+        route.fastcgi_pass = Some(addr.clone());
+        let idle = |server: &WebServer| server.fastcgi_connections.values().all(|conn| conn.requests.is_empty());
+
+        let mut one = fastcgi_client(&mut server, &route, "/one");
+        drive_fastcgi(&mut server, idle);
+        let mut two = fastcgi_client(&mut server, &route, "/two");
+        drive_fastcgi(&mut server, idle);
+        responder.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(server.fastcgi_pools[&addr].max_requests, 1);
+        for (peer, uri) in [(&mut one, "/one"), (&mut two, "/two")] {
+            let response = read_response(peer);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with(&format!("\r\n\r\n{}\n", uri)), "{}", response);
+        }
+    }
+
+    #[test]
+    fn backend_routes_refuse_methods_they_do_not_allow() {
+        let mut fastcgi = RouteConfig::new("/app".to_string());
+        let socket = std::env::temp_dir().join(format!("webserv-methods-{}.sock", std::process::id()));
+        fastcgi.fastcgi_pass = Some(ListenAddr::Unix(socket));
+        fastcgi.methods = vec!["GET".to_string()];
+        // No methods at all
+        let mut proxied = RouteConfig::new("/api".to_string());
+        let upstream = ListenAddr::Tcp(TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        proxied.proxy_pass = Some(ProxyPass { target: ProxyTarget::Addr(upstream), host: "backend".to_string(), path: None });
+        let mut cgi = RouteConfig::new("/cgi-bin".to_string());
+        cgi.cgi_pass = Some("/bin/sh".to_string());
+        cgi.methods = vec!["GET".to_string()];
+        let server_config = ServerConfig { routes: vec![fastcgi, proxied, cgi], ..ServerConfig::default() };
+        let config = Config {
+            servers: vec![server_config],
+            upstreams: HashMap::new(),
+            cache_zones: HashMap::new(),
+        };
+        let mut server = WebServer::new(config).unwrap();
+
+        for (request, status) in [("POST /app/index.php", "405"), ("GET /api/users", "403"), ("DELETE /cgi-bin/run.sh", "405")] {
+            let (fd, _peer) = test_client(&mut server);
+            let request = HttpRequest::parse(format!("{} HTTP/1.1\r\nHost: localhost\r\n\r\n", request).as_bytes()).unwrap();
+            server.handle_request_wrapper(fd, request, 0).unwrap();
+            let response = String::from_utf8_lossy(&server.clients[&fd].response_buffer).into_owned();
+            assert!(response.starts_with(&format!("HTTP/1.1 {} ", status)), "{}", response);
+        }
+        assert!(server.fastcgi_connections.is_empty() && server.proxy_connections.is_empty() && server.cgi_connections.is_empty());
+
+        // Refused before the body is read, rather than starting the script to stream it to
+        let (fd, _peer) = test_client(&mut server);
+        let request = HttpRequest::parse(b"POST /cgi-bin/run.sh HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\n").unwrap();
+        let refused = server.body_sink(fd, &request, &BodyFraming::Length(5), 0).expect_err("body accepted");
+        assert_eq!(refused.status, StatusCode::MethodNotAllowed);
+        assert!(server.cgi_connections.is_empty());
+    }
+}
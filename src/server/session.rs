@@ -1,3 +1,4 @@
+use crate::http::{HttpRequest, HttpResponse};
 use std::collections::HashMap;
 use std::sync::Mutex;
 use rand::Rng;
@@ -27,3 +28,12 @@ pub fn get_or_create_session_id(cookie_header: Option<&String>) -> String {
     store.insert(session_id.clone(), String::new());
     session_id
 }
+
+/// Give the response a `SESSIONID` cookie, unless the request already carries its session.
+pub fn set_session_cookie(request: &HttpRequest, response: &mut HttpResponse) {
+    let cookie_header = request.get_header("cookie");
+    let session_id = get_or_create_session_id(cookie_header);
+    if !cookie_header.is_some_and(|cookie| cookie.contains(&format!("SESSIONID={}", session_id))) {
+        response.set_cookie("SESSIONID", &session_id, Some(3600), Some("/"));
+    }
+}
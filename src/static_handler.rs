@@ -1,9 +1,16 @@
+use crate::compression::{self, Encoding};
 use crate::config::{RouteConfig, ServerConfig};
-use crate::http::{HttpRequest, HttpResponse};
+use crate::dav_handler::{self, DavRequest};
+use crate::http::conditional::{self, Precondition, Validators};
+use crate::http::range::{self, ByteRange, RangeRequest};
+use crate::http::response::{BodyPart, FileRegion};
+use crate::http::{HttpMethod, HttpRequest, HttpResponse, StatusCode};
+use crate::tus_handler::{TusAppend, TusError, TusProgress, TusStore, TUS_VERSION};
+use crate::upload_handler::{self, MultipartUpload, PutUpload, UploadError, UploadSummary};
+use crate::utils::{html, json};
 use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 use std::env;
 use log::debug;
@@ -53,8 +60,6 @@ impl StaticFileHandler {
     ///   - /normal-demo returns a Content-Length response
     pub fn handle_request(&self, request: &crate::http::HttpRequest, server_config: &crate::config::ServerConfig) -> crate::http::HttpResponse {
 
-        use crate::http::HttpMethod;
-
         // --- Body size check: reject too-large requests with 413 ---
         if let Some(content_length) = request.content_length() {
             if content_length > server_config.client_max_body_size {
@@ -156,15 +161,42 @@ impl StaticFileHandler {
             return HttpResponse::method_not_allowed_custom(error_page);
         }
 
+        // Resumable uploads: the location accepts tus requests instead of forms
+        if let Some(store) = Self::tus_store(location) {
+            if matches!(request.method, HttpMethod::POST | HttpMethod::HEAD | HttpMethod::PATCH | HttpMethod::DELETE | HttpMethod::OPTIONS) {
+                return Self::handle_tus(request, server_config, location, &store, path);
+            }
+        }
+
+        // WebDAV: upload_store is shared as a collection
+        if location.dav && location.upload_store.is_some() {
+            match request.method {
+                HttpMethod::GET | HttpMethod::HEAD => {
+                    let fs_path = match dav_handler::resolve(location, path) {
+                        Some(fs_path) => fs_path,
+                        None => return HttpResponse::not_found(),
+                    };
+                    return match fs::metadata(&fs_path) {
+                        Ok(metadata) if metadata.is_dir() => self.generate_directory_listing(&fs_path),
+                        Ok(metadata) => self.serve_file(&fs_path, request, &metadata, location),
+                        Err(_) => HttpResponse::not_found(),
+                    };
+                }
+                HttpMethod::POST | HttpMethod::PUT => {}
+                _ => return DavRequest::new(request, location).handle(path),
+            }
+        }
+
         // Serve upload form on GET if upload_store is set
         if request.method == HttpMethod::GET {
             if let Some(_upload_dir) = &location.upload_store {
                 // Serve a simple HTML upload form
                 let html = format!(r#"
                     <html><body>
-                    <h1>Upload a file</h1>
+                    <h1>Upload files</h1>
                     <form method="POST" enctype="multipart/form-data" action="{}">
-                        <input type="file" name="file" required />
+                        <input type="file" name="file" multiple required />
+                        <input type="text" name="description" placeholder="Description" />
                         <button type="submit">Upload</button>
                     </form>
                     </body></html>
@@ -182,32 +214,11 @@ impl StaticFileHandler {
                 // Only accept multipart/form-data
                 let content_type = request.headers.get("content-type").map(|s| s.as_str()).unwrap_or("");
                 if let Some(boundary) = Self::extract_boundary(content_type) {
-                    match Self::save_multipart_file(&request.body, &boundary, upload_dir) {
-                        Ok(Some(filename)) => {
-                            // Show a link or image preview
-                            let file_url = format!("{}/{}", path.trim_end_matches('/'), filename);
-                            let mut html = String::from("<html><body><h1>Upload successful!</h1>");
-                            if filename.ends_with(".png") || filename.ends_with(".jpg") || filename.ends_with(".jpeg") || filename.ends_with(".gif") {
-                                html.push_str(&format!("<img src='{}' style='max-width:400px;'/><br>", file_url));
-                            }
-                            html.push_str(&format!("<a href='{}'>View uploaded file</a>", file_url));
-                            html.push_str("</body></html>");
-                            let mut resp = HttpResponse::ok();
-                            resp.set_header("Content-Type", "text/html");
-                            resp.set_body(html.as_bytes());
-                            return resp;
-                        }
-                        Ok(None) => {
-                            let mut resp = HttpResponse::bad_request();
-                            resp.set_body(b"No file found in upload");
-                            return resp;
-                        }
-                        Err(e) => {
-                            let mut resp = HttpResponse::internal_server_error();
-                            resp.set_body(format!("Upload error: {}", e).as_bytes());
-                            return resp;
-                        }
-                    }
+                    let result = Self::start_upload(&boundary, upload_dir, location).and_then(|mut upload| {
+                        upload.feed(&request.body)?;
+                        Self::finish_upload(upload)
+                    });
+                    return Self::upload_result(result, request);
                 } else {
                     let mut resp = HttpResponse::bad_request();
                     resp.set_body(b"Missing or invalid Content-Type: multipart/form-data");
@@ -216,6 +227,16 @@ impl StaticFileHandler {
             }
         }
 
+        // PUT writes the raw body to a file in upload_store
+        if request.method == HttpMethod::PUT && location.upload_store.is_some() {
+            let mut upload = match Self::start_put(request, location) {
+                Ok(upload) => upload,
+                Err(resp) => return resp,
+            };
+            let result = Self::copy_body(request, |data| upload.write(data)).and_then(|_| upload.finish());
+            return Self::put_result(result, request);
+        }
+
         // Handle DELETE method for file deletion
         if request.method == HttpMethod::DELETE {
             if let Some(_upload_dir) = &location.upload_store {
@@ -248,6 +269,9 @@ impl StaticFileHandler {
         if !fs_path.starts_with(&self.server_root) {
             return HttpResponse::forbidden();
         }
+        if TusStore::is_staging_path(&fs_path) {
+            return HttpResponse::not_found();
+        }
         
         // Check if the file exists and is accessible
         match fs::metadata(&fs_path) {
@@ -255,7 +279,7 @@ impl StaticFileHandler {
                 if metadata.is_dir() {
                     self.handle_directory(&fs_path, &location, request)
                 } else {
-                    self.serve_file(&fs_path, &request, &metadata)
+                    self.serve_file(&fs_path, &request, &metadata, location)
                 }
             }
             Err(_) => {
@@ -278,42 +302,371 @@ impl StaticFileHandler {
             })
     }
 
-    fn save_multipart_file(body: &[u8], boundary: &str, upload_dir: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        use std::fs;
-        use std::path::Path;
-        let boundary_marker = format!("--{}", boundary);
-        let body_str = String::from_utf8_lossy(body);
-        let mut filename = None;
-        let mut filedata = None;
-        for part in body_str.split(&boundary_marker) {
-            // Look for Content-Disposition with filename
-            if let Some(disposition) = part.find("Content-Disposition:") {
-                if let Some(fname_start) = part.find("filename=\"") {
-                    let fname_end = part[fname_start+10..].find('"').map(|i| fname_start+10+i).unwrap_or(part.len());
-                    let fname = &part[fname_start+10..fname_end];
-                    if !fname.is_empty() {
-                        filename = Some(fname.to_string());
-                        // Find start of file data (after double CRLF)
-                        if let Some(data_start) = part.find("\r\n\r\n") {
-                            let data = &part[data_start+4..];
-                            // Remove trailing CRLF-- if present
-                            let data = data.trim_end_matches(|c| c == '\r' || c == '\n' || c == '-').as_bytes();
-                            filedata = Some(data.to_vec());
-                        }
+    /// The location and multipart boundary of a POST this handler would store as an
+    /// upload, so the server can stream its body into the upload directory as it arrives.
+    pub fn upload_target<'a>(&self, request: &HttpRequest, server_config: &'a ServerConfig) -> Option<(&'a RouteConfig, String)> {
+        let location = self.find_best_location(request.uri.split('?').next().unwrap_or(""), server_config);
+        if request.method != HttpMethod::POST || location.redirect.is_some() || !location.methods.iter().any(|m| m == "POST") {
+            return None;
+        }
+        location.upload_store.as_ref()?;
+        let boundary = Self::extract_boundary(request.headers.get("content-type")?)?;
+        Some((location, boundary))
+    }
+
+    pub fn start_upload(boundary: &str, upload_dir: &str, location: &RouteConfig) -> Result<MultipartUpload, UploadError> {
+        MultipartUpload::new(boundary, Path::new(upload_dir), location.upload_conflict, location.upload_uuid_names)
+    }
+
+    pub fn finish_upload(upload: MultipartUpload) -> Result<UploadSummary, UploadError> {
+        let summary = upload.finish()?;
+        for file in &summary.files {
+            log::info!("Stored upload {} ({} bytes, field {})", file.path.display(), file.size, file.field.as_deref().unwrap_or("-"));
+        }
+        Ok(summary)
+    }
+
+    /// The response to an upload: the summary of what was stored, or why nothing was.
+    pub fn upload_result(result: Result<UploadSummary, UploadError>, request: &HttpRequest) -> HttpResponse {
+        match result {
+            Ok(summary) if summary.files.is_empty() => {
+                let mut resp = HttpResponse::bad_request();
+                resp.set_body(b"No file found in upload");
+                resp
+            }
+            Ok(summary) => {
+                let wants_json = request.headers.get("accept").is_some_and(|accept| accept.contains("application/json"));
+                Self::upload_response(&summary, request.uri.split('?').next().unwrap_or(""), wants_json)
+            }
+            Err(e) => {
+                let mut resp = match e {
+                    UploadError::Malformed(_) | UploadError::InvalidFilename(_) => HttpResponse::bad_request(),
+                    UploadError::Conflict(_) => HttpResponse::conflict(),
+                    UploadError::Io(_) => HttpResponse::internal_server_error(),
+                };
+                resp.set_body(format!("Upload error: {}", e).as_bytes());
+                resp
+            }
+        }
+    }
+
+    /// The location of a PUT this handler would store, so the server can write its
+    /// body to disk as it arrives.
+    pub fn put_target<'a>(&self, request: &HttpRequest, server_config: &'a ServerConfig) -> Option<&'a RouteConfig> {
+        let location = self.find_best_location(request.uri.split('?').next().unwrap_or(""), server_config);
+        if request.method != HttpMethod::PUT || location.redirect.is_some() || !location.methods.iter().any(|m| m == "PUT") {
+            return None;
+        }
+        location.upload_store.as_ref()?;
+        Some(location)
+    }
+
+    /// Check a PUT and open the temporary file for its body. The file is named by the
+    /// last path segment, e.g. `PUT /uploads/app.tar.gz` stores `upload_store/app.tar.gz`.
+    pub fn start_put(request: &HttpRequest, location: &RouteConfig) -> Result<PutUpload, HttpResponse> {
+        let upload_dir = location.upload_store.as_deref().unwrap_or_default();
+        if request.content_length().is_none() && !request.is_chunked() {
+            let mut resp = HttpResponse::new(StatusCode::LengthRequired);
+            resp.set_body(b"PUT needs a Content-Length or a chunked body");
+            return Err(resp);
+        }
+        let path = request.uri.split('?').next().unwrap_or("");
+        let target = if location.dav {
+            // Anywhere in the shared tree, as long as the collection exists and is not locked
+            let target = dav_handler::resolve(location, path).ok_or_else(HttpResponse::forbidden)?;
+            let _ = fs::create_dir_all(upload_dir);
+            dav_handler::check_parent(&target)?;
+            dav_handler::check_locks(&target, request, false)?;
+            target
+        } else {
+            let name = path.strip_prefix(location.path.trim_end_matches('/')).unwrap_or("").trim_start_matches('/');
+            let name = match dav_handler::percent_decode(name) {
+                None => Err(UploadError::InvalidFilename("malformed percent-encoding")),
+                Some(name) if name.contains('/') => Err(UploadError::InvalidFilename("subdirectories are not supported")),
+                Some(name) => upload_handler::sanitize_filename(&name),
+            };
+            match name {
+                Ok(name) => Path::new(upload_dir).join(name),
+                Err(e) => return Err(Self::put_result(Err(e), request)),
+            }
+        };
+
+        let existing = match fs::metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => return Err(HttpResponse::conflict()),
+            Ok(metadata) => Some(metadata),
+            Err(_) => None,
+        };
+        let current = existing.as_ref().map(Validators::for_file);
+        if conditional::evaluate(request, current.as_ref()) != Precondition::Proceed {
+            let mut resp = HttpResponse::new(StatusCode::PreconditionFailed);
+            resp.set_body(b"Precondition failed");
+            return Err(resp);
+        }
+
+        let create_only = request.get_header("if-none-match").is_some_and(|v| v.trim() == "*");
+        PutUpload::new(&target, create_only).map_err(|e| Self::put_result(Err(e), request))
+    }
+
+    /// The response to a PUT: 201 for a new file, 204 for a replaced one.
+    pub fn put_result(result: Result<bool, UploadError>, request: &HttpRequest) -> HttpResponse {
+        match result {
+            Ok(true) => {
+                log::info!("Created {} by PUT", request.uri);
+                let mut resp = HttpResponse::new(StatusCode::Created);
+                resp.set_header("Location", request.uri.split('?').next().unwrap_or(""));
+                resp.set_body(b"");
+                resp
+            }
+            Ok(false) => {
+                log::info!("Replaced {} by PUT", request.uri);
+                HttpResponse::new(StatusCode::NoContent)
+            }
+            Err(e) => {
+                let mut resp = match e {
+                    UploadError::Malformed(_) | UploadError::InvalidFilename(_) => HttpResponse::bad_request(),
+                    // Only an If-None-Match: * upload refuses to replace a file
+                    UploadError::Conflict(_) => HttpResponse::new(StatusCode::PreconditionFailed),
+                    UploadError::Io(_) => HttpResponse::internal_server_error(),
+                };
+                resp.set_body(format!("Upload error: {}", e).as_bytes());
+                resp
+            }
+        }
+    }
+
+    /// Pass a body the server received whole, from memory or its spool file, to `write`.
+    fn copy_body<E: From<std::io::Error>>(request: &HttpRequest, mut write: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        write(&request.body)?;
+        if let Some(body_file) = &request.body_file {
+            let mut chunk = vec![0u8; 64 * 1024];
+            let mut offset = 0;
+            while offset < body_file.len() {
+                let n = body_file.read_at(&mut chunk, offset)?;
+                if n == 0 {
+                    break;
+                }
+                write(&chunk[..n])?;
+                offset += n as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn tus_store(location: &RouteConfig) -> Option<TusStore> {
+        let upload_dir = location.upload_store.as_ref().filter(|_| location.tus)?;
+        Some(TusStore::new(Path::new(upload_dir), location.upload_conflict, location.upload_uuid_names))
+    }
+
+    /// The upload id in a tus URL: the path segment after the location, e.g. `/files/<id>`.
+    fn tus_id<'a>(path: &'a str, location: &RouteConfig) -> &'a str {
+        path.strip_prefix(location.path.trim_end_matches('/')).unwrap_or("").trim_matches('/')
+    }
+
+    /// The store and upload id of a PATCH this handler would append to an upload, so
+    /// the server can write its body to the upload as it arrives.
+    pub fn tus_target(&self, request: &HttpRequest, server_config: &ServerConfig) -> Option<(TusStore, String)> {
+        let path = request.uri.split('?').next().unwrap_or("");
+        let location = self.find_best_location(path, server_config);
+        if request.method != HttpMethod::PATCH || location.redirect.is_some() || !location.methods.iter().any(|m| m == "PATCH") {
+            return None;
+        }
+        let store = Self::tus_store(location)?;
+        Some((store, Self::tus_id(path, location).to_string()))
+    }
+
+    /// Check a PATCH and take the upload for appending its body.
+    pub fn start_tus_append(request: &HttpRequest, store: &TusStore, id: &str) -> Result<TusAppend, HttpResponse> {
+        if let Some(resp) = Self::tus_version_mismatch(request) {
+            return Err(resp);
+        }
+        let content_type = request.headers.get("content-type").map(|s| s.as_str()).unwrap_or("");
+        if content_type.split(';').next().unwrap_or("").trim() != "application/offset+octet-stream" {
+            return Err(Self::tus_error(StatusCode::UnsupportedMediaType, "Content-Type must be application/offset+octet-stream"));
+        }
+        let offset = match request.headers.get("upload-offset").and_then(|v| v.trim().parse::<u64>().ok()) {
+            Some(offset) => offset,
+            None => return Err(Self::tus_error(StatusCode::BadRequest, "Missing or invalid Upload-Offset")),
+        };
+        let append = store.append(id, offset).map_err(Self::tus_error_response)?;
+        if request.content_length().is_some_and(|len| offset + len as u64 > append.length()) {
+            return Err(Self::tus_error_response(TusError::TooLarge));
+        }
+        Ok(append)
+    }
+
+    /// The response to a PATCH once its body is in.
+    pub fn tus_append_result(result: Result<TusProgress, TusError>) -> HttpResponse {
+        match result {
+            Ok(progress) => {
+                if let Some(path) = &progress.stored {
+                    log::info!("Stored upload {} ({} bytes, tus)", path.display(), progress.offset);
+                }
+                let mut resp = Self::tus_response(StatusCode::NoContent);
+                resp.set_header("Upload-Offset", &progress.offset.to_string());
+                resp
+            }
+            Err(e) => Self::tus_error_response(e),
+        }
+    }
+
+    /// Resumable uploads (tus 1.0.0): OPTIONS describes the server, POST creates an
+    /// upload, HEAD reports its offset, PATCH appends to it and DELETE drops it.
+    fn handle_tus(request: &HttpRequest, server_config: &ServerConfig, location: &RouteConfig, store: &TusStore, path: &str) -> HttpResponse {
+        if request.method == HttpMethod::OPTIONS {
+            let mut resp = Self::tus_response(StatusCode::NoContent);
+            resp.set_header("Tus-Version", TUS_VERSION);
+            resp.set_header("Tus-Extension", "creation,termination");
+            resp.set_header("Tus-Max-Size", &server_config.client_max_body_size.to_string());
+            return resp;
+        }
+        if let Some(resp) = Self::tus_version_mismatch(request) {
+            return resp;
+        }
+
+        let id = Self::tus_id(path, location);
+        match request.method {
+            HttpMethod::POST if id.is_empty() => {
+                if request.headers.contains_key("upload-defer-length") {
+                    return Self::tus_error(StatusCode::BadRequest, "Upload-Defer-Length is not supported");
+                }
+                let length = match request.headers.get("upload-length").and_then(|v| v.trim().parse::<u64>().ok()) {
+                    Some(length) => length,
+                    None => return Self::tus_error(StatusCode::BadRequest, "Missing or invalid Upload-Length"),
+                };
+                if length > server_config.client_max_body_size as u64 {
+                    return Self::tus_error(StatusCode::PayloadTooLarge, "Upload-Length exceeds Tus-Max-Size");
+                }
+                match store.create(length, request.headers.get("upload-metadata").map(|s| s.as_str())) {
+                    Ok(id) => {
+                        let mut resp = Self::tus_response(StatusCode::Created);
+                        resp.set_header("Location", &format!("{}/{}", location.path.trim_end_matches('/'), id));
+                        resp.set_body(b"");
+                        resp
+                    }
+                    Err(e) => Self::tus_error_response(e),
+                }
+            }
+            HttpMethod::HEAD => match store.info(id) {
+                Ok((info, offset)) => {
+                    let mut resp = Self::tus_response(StatusCode::Ok);
+                    resp.set_header("Upload-Offset", &offset.to_string());
+                    resp.set_header("Upload-Length", &info.length.to_string());
+                    if let Some(metadata) = &info.metadata {
+                        resp.set_header("Upload-Metadata", metadata);
                     }
+                    resp.set_header("Cache-Control", "no-store");
+                    resp
                 }
+                Err(e) => Self::tus_error_response(e),
+            },
+            HttpMethod::PATCH => {
+                let mut append = match Self::start_tus_append(request, store, id) {
+                    Ok(append) => append,
+                    Err(resp) => return resp,
+                };
+                let result = Self::copy_body(request, |data| append.write(data)).and_then(|_| append.finish());
+                Self::tus_append_result(result)
+            }
+            HttpMethod::DELETE => match store.terminate(id) {
+                Ok(()) => Self::tus_response(StatusCode::NoContent),
+                Err(e) => Self::tus_error_response(e),
+            },
+            _ => {
+                let error_page = server_config.error_pages.get(&405).map(|s| s.as_str());
+                HttpResponse::method_not_allowed_custom(error_page)
             }
         }
-        if let (Some(fname), Some(data)) = (filename, filedata) {
-            let dir = Path::new(upload_dir);
-            fs::create_dir_all(dir)?;
-            let save_path = dir.join(&fname);
-            let mut file = File::create(&save_path)?;
-            file.write_all(&data)?;
-            Ok(Some(fname))
-        } else {
-            Ok(None)
+    }
+
+    /// Every request but OPTIONS must say which protocol version it speaks.
+    fn tus_version_mismatch(request: &HttpRequest) -> Option<HttpResponse> {
+        if request.headers.get("tus-resumable").map(|v| v.trim()) == Some(TUS_VERSION) {
+            return None;
+        }
+        let mut resp = Self::tus_error(StatusCode::PreconditionFailed, "Unsupported or missing Tus-Resumable");
+        resp.set_header("Tus-Version", TUS_VERSION);
+        Some(resp)
+    }
+
+    fn tus_response(status: StatusCode) -> HttpResponse {
+        let mut resp = HttpResponse::new(status);
+        resp.set_header("Tus-Resumable", TUS_VERSION);
+        resp
+    }
+
+    fn tus_error(status: StatusCode, message: &str) -> HttpResponse {
+        let mut resp = Self::tus_response(status);
+        resp.set_header("Content-Type", "text/plain");
+        resp.set_body_string(message);
+        resp
+    }
+
+    fn tus_error_response(e: TusError) -> HttpResponse {
+        let status = match &e {
+            TusError::NotFound => StatusCode::NotFound,
+            TusError::OffsetMismatch(_) => StatusCode::Conflict,
+            TusError::Locked => StatusCode::Locked,
+            TusError::TooLarge => StatusCode::PayloadTooLarge,
+            TusError::Invalid(_) => StatusCode::BadRequest,
+            TusError::Upload(UploadError::Malformed(_) | UploadError::InvalidFilename(_)) => StatusCode::BadRequest,
+            TusError::Upload(UploadError::Conflict(_)) => StatusCode::Conflict,
+            TusError::Upload(UploadError::Io(_)) => StatusCode::InternalServerError,
+        };
+        if status == StatusCode::InternalServerError {
+            log::error!("tus upload failed: {}", e);
+        }
+        Self::tus_error(status, &format!("Upload error: {}", e))
+    }
+
+    /// List the stored files and the other form fields, as JSON if the client asked
+    /// for it and as an HTML page otherwise.
+    fn upload_response(summary: &UploadSummary, path: &str, wants_json: bool) -> HttpResponse {
+        let file_url = |filename: &str| format!("{}/{}", path.trim_end_matches('/'), filename);
+        let mut resp = HttpResponse::ok();
+
+        if wants_json {
+            let files: Vec<String> = summary.files.iter()
+                .map(|file| format!(
+                    "{{\"field\":{},\"filename\":{},\"original_filename\":{},\"size\":{},\"path\":{},\"url\":{}}}",
+                    file.field.as_deref().map(json::string).unwrap_or_else(|| "null".to_string()),
+                    json::string(&file.filename),
+                    json::string(&file.original_filename),
+                    file.size,
+                    json::string(&file.path.to_string_lossy()),
+                    json::string(&file_url(&file.filename)),
+                ))
+                .collect();
+            let fields: Vec<String> = summary.fields.iter()
+                .map(|(name, value)| format!("{{\"name\":{},\"value\":{}}}", json::string(name), json::string(value)))
+                .collect();
+            resp.set_header("Content-Type", "application/json");
+            resp.set_body_string(&format!("{{\"files\":[{}],\"fields\":[{}]}}", files.join(","), fields.join(",")));
+            return resp;
+        }
+
+        let mut page = String::from("<html><body><h1>Upload successful!</h1><ul>");
+        for file in &summary.files {
+            let url = html::escape(&file_url(&file.filename));
+            page.push_str("<li>");
+            // Show an image preview
+            let lower = file.filename.to_ascii_lowercase();
+            if [".png", ".jpg", ".jpeg", ".gif"].iter().any(|ext| lower.ends_with(ext)) {
+                page.push_str(&format!("<img src='{}' style='max-width:400px;'/><br>", url));
+            }
+            page.push_str(&format!("<a href='{}'>{}</a> ({} bytes)</li>", url, html::escape(&file.filename), file.size));
+        }
+        page.push_str("</ul>");
+        if !summary.fields.is_empty() {
+            page.push_str("<h2>Fields</h2><dl>");
+            for (name, value) in &summary.fields {
+                page.push_str(&format!("<dt>{}</dt><dd>{}</dd>", html::escape(name), html::escape(value)));
+            }
+            page.push_str("</dl>");
         }
+        page.push_str("</body></html>");
+        resp.set_header("Content-Type", "text/html");
+        resp.set_body(page.as_bytes());
+        resp
     }
 
 
@@ -385,47 +738,150 @@ impl StaticFileHandler {
         normalized
     }
 
-    fn serve_file(&self, path: &Path, _request: &HttpRequest, metadata: &std::fs::Metadata) -> HttpResponse {
-        match fs::read(path) {
-            Ok(content) => {
-                let mut response = HttpResponse::ok();
-                
-                // Set Content-Type based on file extension
-                let mime_type = path.extension()
-                    .and_then(|ext| ext.to_str())
-                    .map(|ext| ext.to_lowercase())
-                    .as_deref()
-                    .map(|ext| match ext {
-                        "html" | "htm" => "text/html",
-                        "css" => "text/css",
-                        "js" => "application/javascript",
-                        "json" => "application/json",
-                        "jpg" | "jpeg" => "image/jpeg",
-                        "png" => "image/png",
-                        "gif" => "image/gif",
-                        "svg" => "image/svg+xml",
-                        "pdf" => "application/pdf",
-                        "txt" => "text/plain",
-                        _ => "application/octet-stream",
-                    })
-                    .unwrap_or("application/octet-stream");
-                
-                response.set_header("Content-Type", mime_type);
-                response.set_header("Content-Length", &content.len().to_string());
-                response.set_header("Last-Modified", &self.http_date(metadata.modified().unwrap_or_else(|_| SystemTime::now())));
-                response.set_body(&content);
-                response
+    fn serve_file(&self, path: &Path, request: &HttpRequest, metadata: &std::fs::Metadata, location: &RouteConfig) -> HttpResponse {
+        // Set Content-Type based on file extension
+        let mime_type = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+            .map(|ext| match ext {
+                "html" | "htm" => "text/html",
+                "css" => "text/css",
+                "js" => "application/javascript",
+                "json" => "application/json",
+                "jpg" | "jpeg" => "image/jpeg",
+                "png" => "image/png",
+                "gif" => "image/gif",
+                "svg" => "image/svg+xml",
+                "pdf" => "application/pdf",
+                "txt" => "text/plain",
+                _ => "application/octet-stream",
+            })
+            .unwrap_or("application/octet-stream");
+
+        // A precompressed sibling the client accepts (app.js.br for app.js) is sent in its place
+        let siblings = if location.compression.static_files == Some(true) { Self::precompressed_siblings(path) } else { Vec::new() };
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, ..)| *encoding).collect();
+        let encoding = request.get_header("accept-encoding").and_then(|accept| compression::negotiate(accept, &available));
+        let (path, metadata) = match siblings.iter().find(|(sibling_encoding, ..)| Some(*sibling_encoding) == encoding) {
+            Some((_, sibling, sibling_metadata)) => (sibling.as_path(), sibling_metadata),
+            None => (path, metadata),
+        };
+
+        let validators = Validators::for_file(metadata);
+        let last_modified = self.http_date(validators.last_modified);
+        let etag = validators.etag.to_string();
+
+        match conditional::evaluate(request, Some(&validators)) {
+            Precondition::Proceed => {}
+            Precondition::NotModified => {
+                let mut response = HttpResponse::new(StatusCode::NotModified);
+                response.set_header("ETag", &etag);
+                response.set_header("Last-Modified", &last_modified);
+                if !siblings.is_empty() {
+                    compression::vary_by_encoding(&mut response);
+                }
+                return response;
             }
-            Err(e) => {
-                use std::io::ErrorKind;
-                debug!("Failed to read file: {}: {}", path.display(), e);
-                if e.kind() == ErrorKind::PermissionDenied {
-                    HttpResponse::forbidden()
-                } else {
-                    HttpResponse::not_found()
+            Precondition::Failed => {
+                let mut response = HttpResponse::new(StatusCode::PreconditionFailed);
+                response.set_body(b"Precondition failed");
+                return response;
+            }
+        }
+
+        // Only a GET takes a Range, and only for the version of the file the client has part of
+        let ranges = match request.get_header("range") {
+            Some(range) if request.method == HttpMethod::GET && conditional::if_range_matches(request, &validators) => {
+                range::parse_range(range, metadata.len())
+            }
+            _ => RangeRequest::Ignored,
+        };
+        let mut response = match ranges {
+            RangeRequest::Ignored => match fs::File::open(path) {
+                Ok(file) => {
+                    let mut response = HttpResponse::ok();
+                    response.set_header("Content-Type", mime_type);
+                    let region = FileRegion { file: Arc::new(file), offset: 0, len: metadata.len() };
+                    response.set_body_parts(vec![BodyPart::File(region)]);
+                    response
                 }
+                Err(e) => return Self::read_error(path, e),
+            },
+            RangeRequest::Unsatisfiable => {
+                let mut response = HttpResponse::new(StatusCode::RangeNotSatisfiable);
+                response.set_header("Content-Range", &format!("bytes */{}", metadata.len()));
+                response.set_body(b"");
+                response
             }
+            RangeRequest::Ranges(ranges) => match Self::read_ranges(path, &ranges, metadata.len(), mime_type) {
+                Ok(response) => response,
+                Err(e) => return Self::read_error(path, e),
+            },
+        };
+        response.set_header("Accept-Ranges", "bytes");
+        response.set_header("ETag", &etag);
+        response.set_header("Last-Modified", &last_modified);
+        if let Some(encoding) = encoding {
+            response.set_header("Content-Encoding", encoding.token());
+        }
+        if !siblings.is_empty() {
+            compression::vary_by_encoding(&mut response);
         }
+        response
+    }
+
+    /// The precompressed versions of a file next to it, e.g. `app.js.br` and `app.js.gz`.
+    fn precompressed_siblings(path: &Path) -> Vec<(Encoding, PathBuf, fs::Metadata)> {
+        Encoding::PREFERRED
+            .iter()
+            .filter_map(|&encoding| {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(".");
+                sibling.push(encoding.extension());
+                let sibling = PathBuf::from(sibling);
+                let metadata = fs::metadata(&sibling).ok().filter(|metadata| metadata.is_file())?;
+                Some((encoding, sibling, metadata))
+            })
+            .collect()
+    }
+
+    fn read_error(path: &Path, e: std::io::Error) -> HttpResponse {
+        use std::io::ErrorKind;
+        debug!("Failed to read file: {}: {}", path.display(), e);
+        if e.kind() == ErrorKind::PermissionDenied {
+            HttpResponse::forbidden()
+        } else {
+            HttpResponse::not_found()
+        }
+    }
+
+    /// A 206 response with the requested ranges of the file: the range itself when there
+    /// is one, a multipart/byteranges body when there are several.
+    fn read_ranges(path: &Path, ranges: &[ByteRange], total: u64, mime_type: &str) -> std::io::Result<HttpResponse> {
+        let file = Arc::new(fs::File::open(path)?);
+        let region = |range: &ByteRange| BodyPart::File(FileRegion { file: file.clone(), offset: range.start, len: range.len() });
+
+        let mut response = HttpResponse::new(StatusCode::PartialContent);
+        if let [range] = ranges {
+            response.set_header("Content-Type", mime_type);
+            response.set_header("Content-Range", &range.content_range(total));
+            response.set_body_parts(vec![region(range)]);
+            return Ok(response);
+        }
+        let boundary: String = rand::random::<[u8; 12]>().iter().map(|b| format!("{:02x}", b)).collect();
+        let mut parts = Vec::new();
+        for (i, range) in ranges.iter().enumerate() {
+            // Each part starts on a new line, which ends the part before it
+            let separator = if i == 0 { "" } else { "\r\n" };
+            let head = format!("{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n", separator, boundary, mime_type, range.content_range(total));
+            parts.push(BodyPart::Bytes(head.into_bytes()));
+            parts.push(region(range));
+        }
+        parts.push(BodyPart::Bytes(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+        response.set_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary));
+        response.set_body_parts(parts);
+        Ok(response)
     }
 
     fn handle_directory(&self, path: &Path, location: &RouteConfig, _request: &HttpRequest) -> HttpResponse {
@@ -434,7 +890,7 @@ impl StaticFileHandler {
             let index_path = path.join(index);
             if let Ok(metadata) = fs::metadata(&index_path) {
                 if metadata.is_file() {
-                    return self.serve_file(&index_path, _request, &metadata);
+                    return self.serve_file(&index_path, _request, &metadata, location);
                 }
             }
         }
@@ -527,14 +983,7 @@ impl StaticFileHandler {
     }
 
     fn http_date(&self, time: SystemTime) -> String {
-        use std::time::UNIX_EPOCH;
-        
-        // Convert SystemTime to seconds since epoch
-        let duration = time.duration_since(UNIX_EPOCH).unwrap_or_else(|_| std::time::Duration::new(0, 0));
-        
-        // Simple timestamp - for a production server, use a proper date formatting library
-        let secs = duration.as_secs();
-        format!("{}", secs)
+        httpdate::fmt_http_date(time)
     }
 
     fn handle_delete_request(&self, path: &str, location: &RouteConfig) -> HttpResponse {
@@ -544,6 +993,9 @@ impl StaticFileHandler {
         if !fs_path.starts_with(&self.server_root) {
             return HttpResponse::forbidden();
         }
+        if TusStore::is_staging_path(&fs_path) {
+            return HttpResponse::not_found();
+        }
         
         // Check if file exists
         match std::fs::metadata(&fs_path) {
@@ -576,3 +1028,194 @@ impl StaticFileHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CompressionConfig;
+    use std::os::unix::fs::FileExt;
+    use std::time::Duration;
+
+    const PLAIN: &[u8] = b"console.log('the plain file, longer than either precompressed one');\n";
+    const BROTLI: &[u8] = b"brotli bytes, not really compressed";
+    const GZIP: &[u8] = b"gzip bytes, not really compressed";
+
+    /// A server rooted at a new directory holding `app.js` with `.br` and `.gz` siblings,
+    /// each modified at a different time.
+    fn precompressed_server(name: &str) -> (PathBuf, ServerConfig) {
+        let dir = std::env::temp_dir().join(format!("webserv-static-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (i, (file, content)) in [("app.js", PLAIN), ("app.js.br", BROTLI), ("app.js.gz", GZIP)].iter().enumerate() {
+            fs::write(dir.join(file), content).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + 1000 * i as u64);
+            fs::File::options().write(true).open(dir.join(file)).unwrap().set_modified(modified).unwrap();
+        }
+        let mut route = RouteConfig::new("/".to_string());
+        route.methods = vec!["GET".to_string(), "HEAD".to_string()];
+        route.root = Some(dir.to_string_lossy().into_owned());
+        route.compression = CompressionConfig { static_files: Some(true), ..CompressionConfig::default() };
+        (dir, ServerConfig { routes: vec![route], ..ServerConfig::default() })
+    }
+
+    fn get(server: &ServerConfig, headers: &[(&str, &str)]) -> HttpResponse {
+        let mut request = HttpRequest::new();
+        request.method = HttpMethod::GET;
+        request.uri = "/app.js".to_string();
+        for (name, value) in headers {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+        StaticFileHandler::new(server).handle_request(&request, server)
+    }
+
+    /// The whole body, files included.
+    fn body(response: &HttpResponse) -> Vec<u8> {
+        let mut body = response.body.clone();
+        for part in &response.body_parts {
+            match part {
+                BodyPart::Bytes(bytes) => body.extend_from_slice(bytes),
+                BodyPart::File(region) => {
+                    let mut buf = vec![0u8; region.len as usize];
+                    region.file.read_exact_at(&mut buf, region.offset).unwrap();
+                    body.extend_from_slice(&buf);
+                }
+            }
+        }
+        body
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response.get_header(name).map(String::as_str)
+    }
+
+    /// The ETag and Last-Modified a file is served with.
+    fn validators(path: &Path) -> (String, String) {
+        let validators = Validators::for_file(&fs::metadata(path).unwrap());
+        (validators.etag.to_string(), httpdate::fmt_http_date(validators.last_modified))
+    }
+
+    #[test]
+    fn precompressed_sibling_is_chosen_by_accept_encoding() {
+        let (dir, server) = precompressed_server("select");
+        let cases: [(Option<&str>, &[u8], Option<&str>); 5] = [
+            (Some("gzip, br"), BROTLI, Some("br")),
+            (Some("br;q=0.5, gzip"), GZIP, Some("gzip")),
+            (Some("zstd"), PLAIN, None),
+            (Some("br;q=0, gzip;q=0"), PLAIN, None),
+            (None, PLAIN, None),
+        ];
+        for (accept, expected, coding) in cases {
+            let headers: Vec<(&str, &str)> = accept.map(|accept| ("accept-encoding", accept)).into_iter().collect();
+            let response = get(&server, &headers);
+            assert_eq!(response.status, StatusCode::Ok, "{:?}", accept);
+            assert_eq!(body(&response), expected, "{:?}", accept);
+            assert_eq!(header(&response, "content-encoding"), coding, "{:?}", accept);
+            assert_eq!(header(&response, "content-type"), Some("application/javascript"), "{:?}", accept);
+            assert_eq!(header(&response, "content-length"), Some(expected.len().to_string().as_str()), "{:?}", accept);
+        }
+
+        // Without compression_static the siblings are ignored
+        let mut plain_server = server.clone();
+        plain_server.routes[0].compression.static_files = None;
+        let response = get(&plain_server, &[("accept-encoding", "br")]);
+        assert_eq!(body(&response), PLAIN);
+        assert!(header(&response, "vary").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn precompressed_sibling_has_its_own_validators_and_varies() {
+        let (dir, server) = precompressed_server("validators");
+        let (br_etag, br_modified) = validators(&dir.join("app.js.br"));
+        let (plain_etag, plain_modified) = validators(&dir.join("app.js"));
+        assert_ne!(br_etag, plain_etag);
+        assert_ne!(br_modified, plain_modified);
+
+        let response = get(&server, &[("accept-encoding", "br")]);
+        assert_eq!(header(&response, "etag"), Some(br_etag.as_str()));
+        assert_eq!(header(&response, "last-modified"), Some(br_modified.as_str()));
+        assert_eq!(header(&response, "vary"), Some("Accept-Encoding"));
+
+        let response = get(&server, &[]);
+        assert_eq!(header(&response, "etag"), Some(plain_etag.as_str()));
+        assert_eq!(header(&response, "last-modified"), Some(plain_modified.as_str()));
+        assert_eq!(header(&response, "vary"), Some("Accept-Encoding"));
+
+        // A 304 for the sibling's tag varies too, and the plain file's tag does not match it
+        let response = get(&server, &[("accept-encoding", "br"), ("if-none-match", &br_etag)]);
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert_eq!(header(&response, "etag"), Some(br_etag.as_str()));
+        assert_eq!(header(&response, "vary"), Some("Accept-Encoding"));
+        let response = get(&server, &[("accept-encoding", "br"), ("if-none-match", &plain_etag)]);
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(body(&response), BROTLI);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn range_applies_to_the_precompressed_sibling() {
+        let (dir, server) = precompressed_server("range");
+        let response = get(&server, &[("accept-encoding", "gzip"), ("range", "bytes=0-3")]);
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(body(&response), &GZIP[..4]);
+        assert_eq!(header(&response, "content-encoding"), Some("gzip"));
+        assert_eq!(header(&response, "content-range"), Some(format!("bytes 0-3/{}", GZIP.len()).as_str()));
+        assert_eq!(header(&response, "vary"), Some("Accept-Encoding"));
+
+        // If-Range names the sibling's version, not the plain file's
+        let (gz_etag, _) = validators(&dir.join("app.js.gz"));
+        let (plain_etag, _) = validators(&dir.join("app.js"));
+        let response = get(&server, &[("accept-encoding", "gzip"), ("range", "bytes=-4"), ("if-range", &gz_etag)]);
+        assert_eq!(response.status, StatusCode::PartialContent);
+        assert_eq!(body(&response), &GZIP[GZIP.len() - 4..]);
+        let response = get(&server, &[("accept-encoding", "gzip"), ("range", "bytes=-4"), ("if-range", &plain_etag)]);
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(body(&response), GZIP);
+
+        // Past the end of the sibling, though within the plain file
+        let response = get(&server, &[("accept-encoding", "br"), ("range", &format!("bytes={}-", BROTLI.len()))]);
+        assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+        assert_eq!(header(&response, "content-range"), Some(format!("bytes */{}", BROTLI.len()).as_str()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn partial_tus_uploads_are_neither_served_nor_deleted() {
+        let dir = std::env::temp_dir().join(format!("webserv-static-tus-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let store = TusStore::new(&dir, crate::config::UploadConflict::Rename, false);
+        let id = store.create(100, None).unwrap();
+        let staged: Vec<String> = fs::read_dir(dir.join(crate::tus_handler::STAGING_DIR)).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(!staged.is_empty());
+
+        let mut files = RouteConfig::new("/".to_string());
+        files.methods = vec!["GET".to_string()];
+        files.root = Some(dir.to_string_lossy().into_owned());
+        let mut uploads = RouteConfig::new("/up".to_string());
+        uploads.methods = vec!["DELETE".to_string()];
+        uploads.root = files.root.clone();
+        uploads.upload_store = files.root.clone();
+        let server = ServerConfig { routes: vec![files, uploads], ..ServerConfig::default() };
+        let handler = StaticFileHandler::new(&server);
+        let send = |method: HttpMethod, uri: String| {
+            let mut request = HttpRequest::new();
+            request.method = method;
+            request.uri = uri;
+            handler.handle_request(&request, &server)
+        };
+
+        for name in &staged {
+            for uri in [format!("/.tus/{}", name), format!("/sub/../.tus/{}", name)] {
+                assert_eq!(send(HttpMethod::GET, uri.clone()).status, StatusCode::NotFound, "{}", uri);
+            }
+            assert_eq!(send(HttpMethod::DELETE, format!("/up/.tus/{}", name)).status, StatusCode::NotFound);
+            assert!(dir.join(".tus").join(name).exists());
+        }
+        assert_eq!(send(HttpMethod::GET, "/.tus".to_string()).status, StatusCode::NotFound);
+        assert_eq!(store.info(&id).unwrap().1, 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use crate::config::UploadConflict;
+use crate::upload_handler::{self, UploadError};
+use crate::utils::base64;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// The protocol version spoken, sent as `Tus-Resumable` and `Tus-Version`.
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// The subdirectory of an upload directory holding partial uploads.
+pub const STAGING_DIR: &str = ".tus";
+
+/// Why a tus request failed.
+#[derive(Debug)]
+pub enum TusError {
+    NotFound,
+    OffsetMismatch(u64), // The upload is at this offset
+    Locked, // Another request is appending to the upload
+    TooLarge, // The data would run past Upload-Length
+    Invalid(&'static str),
+    Upload(UploadError), // Storing the data or the finished file failed
+}
+
+impl fmt::Display for TusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TusError::NotFound => write!(f, "No such upload"),
+            TusError::OffsetMismatch(offset) => write!(f, "The upload is at offset {}", offset),
+            TusError::Locked => write!(f, "The upload is being written by another request"),
+            TusError::TooLarge => write!(f, "The data runs past Upload-Length"),
+            TusError::Invalid(what) => write!(f, "Invalid request: {}", what),
+            TusError::Upload(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TusError {}
+
+impl From<io::Error> for TusError {
+    fn from(e: io::Error) -> Self {
+        TusError::Upload(UploadError::Io(e))
+    }
+}
+
+impl From<UploadError> for TusError {
+    fn from(e: UploadError) -> Self {
+        TusError::Upload(e)
+    }
+}
+
+/// What is known about an upload, kept in `<id>.info` beside its data so it
+/// survives a restart. The offset is the size of the data file.
+#[derive(Debug, Clone)]
+pub struct TusInfo {
+    pub length: u64, // Upload-Length
+    pub metadata: Option<String>, // Upload-Metadata as sent
+    pub stored: Option<String>, // The name in upload_store once complete
+}
+
+impl TusInfo {
+    fn parse(text: &str) -> Option<Self> {
+        let mut info = TusInfo { length: 0, metadata: None, stored: None };
+        let mut has_length = false;
+        for line in text.lines() {
+            match line.split_once(' ') {
+                Some(("length", value)) => {
+                    info.length = value.parse().ok()?;
+                    has_length = true;
+                }
+                Some(("metadata", value)) => info.metadata = Some(value.to_string()),
+                Some(("stored", value)) => info.stored = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        has_length.then_some(info)
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("length {}\n", self.length);
+        if let Some(metadata) = &self.metadata {
+            text.push_str(&format!("metadata {}\n", metadata));
+        }
+        if let Some(stored) = &self.stored {
+            text.push_str(&format!("stored {}\n", stored));
+        }
+        text
+    }
+
+    /// The sanitized `filename` (or `name`) from the metadata, if the client sent one.
+    fn filename(&self) -> Result<Option<String>, TusError> {
+        match &self.metadata {
+            Some(metadata) => metadata_filename(metadata),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How far an upload got after a request appended to it.
+#[derive(Debug)]
+pub struct TusProgress {
+    pub offset: u64,
+    pub stored: Option<PathBuf>, // Where the file went, when this request completed it
+}
+
+/// Resumable uploads for one upload directory. Partial uploads live in its `.tus`
+/// subdirectory and are moved into the directory itself once complete.
+#[derive(Debug, Clone)]
+pub struct TusStore {
+    upload_dir: PathBuf,
+    dir: PathBuf,
+    conflict: UploadConflict,
+    uuid_names: bool,
+}
+
+impl TusStore {
+    pub fn new(upload_dir: &Path, conflict: UploadConflict, uuid_names: bool) -> Self {
+        Self {
+            upload_dir: upload_dir.to_path_buf(),
+            dir: upload_dir.join(STAGING_DIR),
+            conflict,
+            uuid_names,
+        }
+    }
+
+    /// Whether `path` lies in some upload directory's staging area. Partial uploads
+    /// are never served or deleted as files, even where a location's root shows
+    /// the upload directory.
+    pub fn is_staging_path(path: &Path) -> bool {
+        path.components().any(|component| component.as_os_str() == STAGING_DIR)
+    }
+
+    /// Start an upload of `length` bytes. Returns its id.
+    pub fn create(&self, length: u64, metadata: Option<&str>) -> Result<String, TusError> {
+        let info = TusInfo { length, metadata: metadata.map(str::to_string), stored: None };
+        // Refuse a bad or taken name now rather than after the data has been sent
+        if let Some(name) = info.filename()? {
+            if self.conflict == UploadConflict::Reject && !self.uuid_names && self.upload_dir.join(&name).exists() {
+                return Err(UploadError::Conflict(name).into());
+            }
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let id = loop {
+            let id: String = rand::random::<[u8; 16]>().iter().map(|b| format!("{:02x}", b)).collect();
+            match OpenOptions::new().write(true).create_new(true).open(self.data_path(&id)) {
+                Ok(_) => break id,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if let Err(e) = self.write_info(&id, &info) {
+            let _ = fs::remove_file(self.data_path(&id));
+            return Err(e.into());
+        }
+        if length == 0 {
+            self.append(&id, 0)?.finish()?;
+        }
+        Ok(id)
+    }
+
+    /// The upload's state and current offset.
+    pub fn info(&self, id: &str) -> Result<(TusInfo, u64), TusError> {
+        let info = self.read_info(id)?;
+        if info.stored.is_some() {
+            let length = info.length;
+            return Ok((info, length));
+        }
+        match fs::metadata(self.data_path(id)) {
+            Ok(metadata) => Ok((info, metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(TusError::NotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Take the upload for appending at `offset`, which must be where it is now.
+    /// Only one request may append to an upload at a time.
+    pub fn append(&self, id: &str, offset: u64) -> Result<TusAppend, TusError> {
+        let info = self.read_info(id)?;
+        if info.stored.is_some() {
+            if offset != info.length {
+                return Err(TusError::OffsetMismatch(info.length));
+            }
+            return Ok(TusAppend { store: self.clone(), id: id.to_string(), info, file: None, offset });
+        }
+
+        let file = match OpenOptions::new().append(true).open(self.data_path(id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(TusError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        lock(&file)?;
+        let current = file.metadata()?.len();
+        if offset != current {
+            return Err(TusError::OffsetMismatch(current));
+        }
+        Ok(TusAppend { store: self.clone(), id: id.to_string(), info, file: Some(file), offset })
+    }
+
+    /// Drop an upload and whatever was received of it.
+    pub fn terminate(&self, id: &str) -> Result<(), TusError> {
+        let info = self.read_info(id)?;
+        if info.stored.is_none() {
+            if let Ok(file) = File::open(self.data_path(id)) {
+                lock(&file)?;
+            }
+            let _ = fs::remove_file(self.data_path(id));
+        }
+        fs::remove_file(self.info_path(id))?;
+        Ok(())
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.info", id))
+    }
+
+    fn read_info(&self, id: &str) -> Result<TusInfo, TusError> {
+        // Ids are generated here, so anything else cannot name an upload
+        if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(TusError::NotFound);
+        }
+        match fs::read_to_string(self.info_path(id)) {
+            Ok(text) => TusInfo::parse(&text).ok_or(TusError::Invalid("unreadable upload state")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(TusError::NotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the info file in one step, so a crash never leaves half of it.
+    fn write_info(&self, id: &str, info: &TusInfo) -> io::Result<()> {
+        let temp_path = self.dir.join(format!("{}.info.tmp", id));
+        fs::write(&temp_path, info.to_text())?;
+        fs::rename(&temp_path, self.info_path(id))
+    }
+}
+
+/// A request appending to an upload. The data file stays locked until it is dropped.
+#[derive(Debug)]
+pub struct TusAppend {
+    store: TusStore,
+    id: String,
+    info: TusInfo,
+    file: Option<File>, // None when the upload is already complete
+    offset: u64,
+}
+
+impl TusAppend {
+    pub fn length(&self) -> u64 {
+        self.info.length
+    }
+
+    /// Add the next bytes. What was written stays even if the request breaks off later.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), TusError> {
+        if self.offset + data.len() as u64 > self.info.length {
+            return Err(TusError::TooLarge);
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(data)?;
+        }
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// The request is over: once every byte is in, move the file into the upload directory.
+    pub fn finish(mut self) -> Result<TusProgress, TusError> {
+        if self.file.is_none() || self.offset < self.info.length {
+            return Ok(TusProgress { offset: self.offset, stored: None });
+        }
+        let name = self.info.filename()?.unwrap_or_else(|| self.id.clone());
+        let name = if self.store.uuid_names { upload_handler::uuid_name(&name) } else { name };
+        let path = upload_handler::place_file(&self.store.data_path(&self.id), &self.store.upload_dir, &name, self.store.conflict)?;
+        self.info.stored = Some(path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(name));
+        self.store.write_info(&self.id, &self.info)?;
+        Ok(TusProgress { offset: self.offset, stored: Some(path) })
+    }
+}
+
+/// Take an exclusive lock on an upload's data file without waiting for it.
+fn lock(file: &File) -> Result<(), TusError> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        return Err(TusError::Locked);
+    }
+    Err(e.into())
+}
+
+/// The file name from `Upload-Metadata`: comma-separated keys, each followed by a
+/// space and its base64 value.
+fn metadata_filename(metadata: &str) -> Result<Option<String>, TusError> {
+    for pair in metadata.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let key = parts.next().unwrap_or("");
+        let value = base64::decode(parts.next().unwrap_or("").trim()).ok_or(TusError::Invalid("Upload-Metadata value is not base64"))?;
+        if key == "filename" || key == "name" {
+            let value = String::from_utf8(value).map_err(|_| TusError::Invalid("filename is not UTF-8"))?;
+            return Ok(Some(upload_handler::sanitize_filename(&value)?));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,772 @@
+use crate::config::UploadConflict;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why an upload failed: the body was not valid multipart, or storing it failed.
+#[derive(Debug)]
+pub enum UploadError {
+    Malformed(&'static str),
+    InvalidFilename(&'static str),
+    Conflict(String), // The name is taken and the location's policy is `reject`
+    Io(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Malformed(what) => write!(f, "Malformed multipart body: {}", what),
+            UploadError::InvalidFilename(why) => write!(f, "Invalid filename: {}", why),
+            UploadError::Conflict(name) => write!(f, "A file named '{}' already exists", name),
+            UploadError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+/// The headers of one part (RFC 7578 section 4).
+#[derive(Debug, Default, Clone)]
+pub struct PartHeaders {
+    pub name: Option<String>, // The form field
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MultipartEvent {
+    Part(PartHeaders), // A new part begins
+    Data(Vec<u8>), // The next bytes of the current part's body
+    PartEnd,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Preamble,
+    Delimiter, // Just past a boundary: either the close delimiter or a new part follows
+    Headers,
+    Body,
+    Epilogue,
+}
+
+/// Incremental multipart/form-data parser. Bodies are binary-safe and handed out as
+/// they arrive, so a part never has to be held in memory whole.
+#[derive(Debug)]
+pub struct MultipartParser {
+    delimiter: Vec<u8>, // CRLF "--" boundary (RFC 2046 section 5.1.1)
+    state: State,
+    buffer: Vec<u8>,
+}
+
+impl MultipartParser {
+    const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+    pub fn new(boundary: &str) -> Result<Self, UploadError> {
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(UploadError::Malformed("boundary must be 1 to 70 characters"));
+        }
+        Ok(Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+            // The first boundary may open the body without a CRLF before it
+            buffer: b"\r\n".to_vec(),
+        })
+    }
+
+    /// Feed the next bytes of the body.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<MultipartEvent>, UploadError> {
+        let mut events = Vec::new();
+        if self.state == State::Epilogue {
+            return Ok(events);
+        }
+        self.buffer.extend_from_slice(data);
+
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buffer, &self.delimiter) {
+                    Some(at) => {
+                        self.buffer.drain(..at + self.delimiter.len());
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        self.keep_partial_delimiter();
+                        return Ok(events);
+                    }
+                },
+                State::Delimiter => {
+                    if self.buffer.len() < 2 {
+                        return Ok(events);
+                    }
+                    if self.buffer.starts_with(b"--") {
+                        // Anything after the close delimiter is ignored
+                        self.state = State::Epilogue;
+                        self.buffer = Vec::new();
+                        return Ok(events);
+                    }
+                    // Transport padding may follow a boundary before its CRLF
+                    let padding = self.buffer.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+                    if self.buffer.len() < padding + 2 {
+                        if padding > Self::MAX_HEADER_SIZE {
+                            return Err(UploadError::Malformed("missing CRLF after boundary"));
+                        }
+                        return Ok(events);
+                    }
+                    if &self.buffer[padding..padding + 2] != b"\r\n" {
+                        return Err(UploadError::Malformed("missing CRLF after boundary"));
+                    }
+                    self.buffer.drain(..padding + 2);
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    // A part without headers starts with the blank line right away
+                    let end = if self.buffer.starts_with(b"\r\n") {
+                        Some((0, 2))
+                    } else {
+                        find(&self.buffer, b"\r\n\r\n").map(|at| (at, at + 4))
+                    };
+                    match end {
+                        Some((header_len, body_start)) => {
+                            let headers = parse_part_headers(&self.buffer[..header_len])?;
+                            self.buffer.drain(..body_start);
+                            events.push(MultipartEvent::Part(headers));
+                            self.state = State::Body;
+                        }
+                        None if self.buffer.len() > Self::MAX_HEADER_SIZE => {
+                            return Err(UploadError::Malformed("part header block too large"));
+                        }
+                        None => return Ok(events),
+                    }
+                }
+                State::Body => match find(&self.buffer, &self.delimiter) {
+                    Some(at) => {
+                        if at > 0 {
+                            events.push(MultipartEvent::Data(self.buffer[..at].to_vec()));
+                        }
+                        self.buffer.drain(..at + self.delimiter.len());
+                        events.push(MultipartEvent::PartEnd);
+                        self.state = State::Delimiter;
+                    }
+                    None => {
+                        let end = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                        if end > 0 {
+                            events.push(MultipartEvent::Data(self.buffer.drain(..end).collect()));
+                        }
+                        return Ok(events);
+                    }
+                },
+                State::Epilogue => return Ok(events),
+            }
+        }
+    }
+
+    /// The body has ended: it must have been closed by the final boundary.
+    pub fn finish(&self) -> Result<(), UploadError> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            State::Preamble => Err(UploadError::Malformed("no boundary found")),
+            _ => Err(UploadError::Malformed("body ended before the closing boundary")),
+        }
+    }
+
+    /// Drop what cannot be part of a delimiter, keeping a tail that might be the start
+    /// of one split across reads.
+    fn keep_partial_delimiter(&mut self) {
+        let end = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+        self.buffer.drain(..end);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn parse_part_headers(block: &[u8]) -> Result<PartHeaders, UploadError> {
+    // Filenames are sent as raw UTF-8 by browsers (RFC 7578 section 4.2)
+    let text = String::from_utf8_lossy(block);
+    let mut headers = PartHeaders::default();
+    for line in text.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or(UploadError::Malformed("invalid part header"))?;
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-disposition" => {
+                for (param, value) in disposition_params(value) {
+                    match param.as_str() {
+                        "name" => headers.name = Some(value),
+                        "filename" => headers.filename = Some(value),
+                        _ => {}
+                    }
+                }
+            }
+            "content-type" => headers.content_type = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    Ok(headers)
+}
+
+/// The parameters of `form-data; name="file"; filename="a b.png"`, with quoted values
+/// unquoted. Only `\"` and `\\` are treated as escapes: some clients send Windows
+/// paths with bare backslashes.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value.split_once(';').map(|(_, params)| params).unwrap_or("");
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        let (name, after) = match rest.split_once('=') {
+            Some(split) => split,
+            None => break,
+        };
+        let after = after.trim_start();
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = quoted.len();
+                let mut chars = quoted.char_indices().peekable();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' if matches!(chars.peek(), Some((_, '"' | '\\'))) => {
+                            if let Some((_, escaped)) = chars.next() {
+                                value.push(escaped);
+                            }
+                        }
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        params.push((name.trim().to_ascii_lowercase(), value));
+        rest = remaining;
+    }
+    params
+}
+
+/// Reduce a client-supplied filename to a bare name for the upload directory.
+/// Directory components of either slash style are dropped; names that are empty,
+/// hidden, too long or contain control characters are refused.
+pub fn sanitize_filename(raw: &str) -> Result<String, UploadError> {
+    const MAX_NAME_LEN: usize = 255;
+
+    if raw.chars().any(char::is_control) {
+        return Err(UploadError::InvalidFilename("control characters are not allowed"));
+    }
+    let name = raw.rsplit(['/', '\\']).next().unwrap_or("").trim();
+    if name.is_empty() {
+        return Err(UploadError::InvalidFilename("no name left after removing directories"));
+    }
+    // Also covers "." and ".."
+    if name.starts_with('.') {
+        return Err(UploadError::InvalidFilename("names may not start with a dot"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(UploadError::InvalidFilename("name is too long"));
+    }
+    Ok(name.to_string())
+}
+
+/// A random (version 4) UUID followed by the extension of `name`, if it has a plain one.
+pub fn uuid_name(name: &str) -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let uuid = format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]);
+    match name.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() && ext.len() <= 10 && ext.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            format!("{}.{}", uuid, ext.to_ascii_lowercase())
+        }
+        _ => uuid,
+    }
+}
+
+/// "report.tar.gz" becomes "report-2.tar.gz".
+fn numbered_name(name: &str, n: u32) -> String {
+    match name.find('.') {
+        Some(dot) => format!("{}-{}{}", &name[..dot], n, &name[dot..]),
+        None => format!("{}-{}", name, n),
+    }
+}
+
+/// Move a complete file from `temp_path` into `dir` as `name`, according to the
+/// conflict policy. Returns where it was stored.
+pub fn place_file(temp_path: &Path, dir: &Path, name: &str, conflict: UploadConflict) -> Result<PathBuf, UploadError> {
+    // How many numbered names `rename` tries before giving up
+    const MAX_RENAMES: u32 = 1000;
+
+    let path = dir.join(name);
+    if conflict == UploadConflict::Overwrite {
+        fs::rename(temp_path, &path)?;
+        return Ok(path);
+    }
+
+    // Unlike rename, a hard link fails when the name is taken, so claiming the
+    // name cannot race with another upload
+    for n in 0..=MAX_RENAMES {
+        let candidate = if n == 0 { path.clone() } else { dir.join(numbered_name(name, n)) };
+        match fs::hard_link(temp_path, &candidate) {
+            Ok(()) => {
+                let _ = fs::remove_file(temp_path);
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if conflict == UploadConflict::Reject {
+                    break;
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(UploadError::Conflict(name.to_string()))
+}
+
+/// A file part stored in the upload directory.
+#[derive(Debug)]
+pub struct SavedFile {
+    pub field: Option<String>,
+    pub filename: String, // The name it was stored under
+    pub original_filename: String, // As sent by the client
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Everything a multipart upload carried: the stored files and the other form fields.
+#[derive(Debug, Default)]
+pub struct UploadSummary {
+    pub files: Vec<SavedFile>,
+    pub fields: Vec<(String, String)>,
+}
+
+/// A file part being written to a temporary file.
+#[derive(Debug)]
+struct PendingFile {
+    field: Option<String>,
+    original_filename: String,
+    name: String, // Sanitized or generated
+    temp_path: PathBuf,
+    file: Option<File>, // Closed once the part is complete
+    size: u64,
+}
+
+#[derive(Debug)]
+enum CurrentPart {
+    File, // The last entry of `files`
+    Field { name: String, value: Vec<u8> },
+}
+
+/// Stores the file parts of a multipart body in the upload directory as the body is
+/// parsed. Files are written under temporary names and only moved into place once the
+/// whole body has arrived intact, so a failed upload leaves nothing behind.
+#[derive(Debug)]
+pub struct MultipartUpload {
+    parser: MultipartParser,
+    dir: PathBuf,
+    conflict: UploadConflict,
+    uuid_names: bool,
+    files: Vec<PendingFile>,
+    fields: Vec<(String, String)>,
+    fields_size: usize,
+    current: Option<CurrentPart>, // None between parts and for parts that are skipped
+}
+
+impl MultipartUpload {
+    /// Form fields are kept in memory, so their combined size is bounded.
+    const MAX_FIELDS_SIZE: usize = 1024 * 1024;
+
+    pub fn new(boundary: &str, dir: &Path, conflict: UploadConflict, uuid_names: bool) -> Result<Self, UploadError> {
+        let parser = MultipartParser::new(boundary)?;
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            parser,
+            dir: dir.to_path_buf(),
+            conflict,
+            uuid_names,
+            files: Vec::new(),
+            fields: Vec::new(),
+            fields_size: 0,
+            current: None,
+        })
+    }
+
+    /// Feed the next bytes of the request body.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), UploadError> {
+        for event in self.parser.feed(data)? {
+            match event {
+                MultipartEvent::Part(headers) => {
+                    self.current = match (headers.filename, headers.name) {
+                        // Browsers send an empty filename for a file input left blank
+                        (Some(filename), _) if filename.is_empty() => None,
+                        (Some(filename), field) => {
+                            let name = sanitize_filename(&filename)?;
+                            let name = if self.uuid_names { uuid_name(&name) } else { name };
+                            let temp_path = self.dir.join(temp_name());
+                            let file = File::create(&temp_path)?;
+                            self.files.push(PendingFile {
+                                field,
+                                original_filename: filename,
+                                name,
+                                temp_path,
+                                file: Some(file),
+                                size: 0,
+                            });
+                            Some(CurrentPart::File)
+                        }
+                        (None, Some(name)) => Some(CurrentPart::Field { name, value: Vec::new() }),
+                        (None, None) => None,
+                    };
+                }
+                MultipartEvent::Data(data) => match &mut self.current {
+                    Some(CurrentPart::File) => {
+                        if let Some(pending) = self.files.last_mut() {
+                            if let Some(file) = &mut pending.file {
+                                file.write_all(&data)?;
+                            }
+                            pending.size += data.len() as u64;
+                        }
+                    }
+                    Some(CurrentPart::Field { value, .. }) => {
+                        self.fields_size += data.len();
+                        if self.fields_size > Self::MAX_FIELDS_SIZE {
+                            return Err(UploadError::Malformed("form fields too large"));
+                        }
+                        value.extend_from_slice(&data);
+                    }
+                    None => {}
+                },
+                MultipartEvent::PartEnd => match self.current.take() {
+                    Some(CurrentPart::File) => {
+                        if let Some(pending) = self.files.last_mut() {
+                            pending.file = None;
+                        }
+                    }
+                    Some(CurrentPart::Field { name, value }) => {
+                        self.fields.push((name, String::from_utf8_lossy(&value).into_owned()));
+                    }
+                    None => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// The body is complete: move every file into place.
+    pub fn finish(mut self) -> Result<UploadSummary, UploadError> {
+        self.parser.finish()?;
+        if self.conflict == UploadConflict::Reject {
+            // Refuse the whole upload before storing any of it
+            let mut names = HashSet::new();
+            for pending in &self.files {
+                if !names.insert(&pending.name) || self.dir.join(&pending.name).exists() {
+                    return Err(UploadError::Conflict(pending.name.clone()));
+                }
+            }
+        }
+
+        let mut saved = Vec::new();
+        while !self.files.is_empty() {
+            // Files still listed are removed by drop if this fails
+            let path = place_file(&self.files[0].temp_path, &self.dir, &self.files[0].name, self.conflict)?;
+            let pending = self.files.remove(0);
+            saved.push(SavedFile {
+                field: pending.field,
+                filename: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(pending.name),
+                original_filename: pending.original_filename,
+                path,
+                size: pending.size,
+            });
+        }
+        Ok(UploadSummary {
+            files: saved,
+            fields: std::mem::take(&mut self.fields),
+        })
+    }
+}
+
+impl Drop for MultipartUpload {
+    fn drop(&mut self) {
+        for pending in &self.files {
+            let _ = fs::remove_file(&pending.temp_path);
+        }
+    }
+}
+
+/// The body of a PUT, written to a temporary file beside its target. Once the body
+/// is complete the file replaces the target in one step, so readers never see part of it.
+#[derive(Debug)]
+pub struct PutUpload {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+    create_only: bool, // Fail rather than replace an existing file
+}
+
+impl PutUpload {
+    pub fn new(path: &Path, create_only: bool) -> Result<Self, UploadError> {
+        let dir = path.parent().ok_or(UploadError::InvalidFilename("no directory"))?;
+        fs::create_dir_all(dir)?;
+        let temp_path = dir.join(temp_name());
+        let file = File::create(&temp_path)?;
+        Ok(Self { path: path.to_path_buf(), temp_path, file, create_only })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), UploadError> {
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Put the file in place. Returns whether it is new rather than a replacement.
+    pub fn finish(self) -> Result<bool, UploadError> {
+        if self.create_only {
+            // Unlike rename, a hard link fails when the name is taken
+            return match fs::hard_link(&self.temp_path, &self.path) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    Err(UploadError::Conflict(self.path.file_name().unwrap_or_default().to_string_lossy().into_owned()))
+                }
+                Err(e) => Err(e.into()),
+            };
+        }
+        let existed = self.path.exists();
+        fs::rename(&self.temp_path, &self.path)?;
+        Ok(!existed)
+    }
+}
+
+impl Drop for PutUpload {
+    fn drop(&mut self) {
+        // Gone already once renamed into place
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// A name no client file can clash with: dot-prefixed, unique within this process.
+fn temp_name() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!(".upload-{}-{}.part", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each part's headers and body as parsed.
+    type Parts = Vec<(PartHeaders, Vec<u8>)>;
+
+    /// Parse a body fed in the given pieces: its parts, and whether it was closed properly.
+    fn parse(boundary: &str, pieces: &[&[u8]]) -> (Parts, Result<(), UploadError>) {
+        let mut parser = MultipartParser::new(boundary).unwrap();
+        let mut parts: Parts = Vec::new();
+        for piece in pieces {
+            let events = match parser.feed(piece) {
+                Ok(events) => events,
+                Err(e) => return (parts, Err(e)),
+            };
+            for event in events {
+                match event {
+                    MultipartEvent::Part(headers) => parts.push((headers, Vec::new())),
+                    MultipartEvent::Data(data) => parts.last_mut().unwrap().1.extend_from_slice(&data),
+                    MultipartEvent::PartEnd => {}
+                }
+            }
+        }
+        (parts, parser.finish())
+    }
+
+    /// A body of one file part holding `content`.
+    fn file_body(content: &[u8]) -> Vec<u8> {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n".to_vec();
+        body.extend_from_slice(content);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        body
+    }
+
+    /// A new, empty directory for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webserv-upload-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A finished upload waiting under its temporary name.
+    fn temp_file(dir: &Path, content: &str) -> PathBuf {
+        let path = dir.join(temp_name());
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_only_the_last_path_segment() {
+        assert_eq!(sanitize_filename("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize_filename("/abs/path").unwrap(), "path");
+        assert_eq!(sanitize_filename("..\\win\\path").unwrap(), "path");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\report final.pdf").unwrap(), "report final.pdf");
+        assert_eq!(sanitize_filename("  spaced.txt ").unwrap(), "spaced.txt");
+        assert!(matches!(sanitize_filename("uploads/"), Err(UploadError::InvalidFilename(_))));
+    }
+
+    #[test]
+    fn sanitize_filename_refuses_dot_names_and_control_characters() {
+        for raw in [".", "..", ".hidden", "dir/..", "dir\\.htaccess", "a\0b.txt", "a\rb.txt", "a\nb.txt", "tab\t.txt", "\u{7f}.txt"] {
+            assert!(matches!(sanitize_filename(raw), Err(UploadError::InvalidFilename(_))), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn sanitize_filename_allows_names_up_to_255_bytes() {
+        let longest = format!("{}.txt", "a".repeat(251));
+        assert_eq!(sanitize_filename(&longest).unwrap(), longest);
+        let too_long = format!("{}.txt", "a".repeat(252));
+        assert!(matches!(sanitize_filename(&too_long), Err(UploadError::InvalidFilename(_))));
+        // Counted in bytes, not characters
+        assert!(sanitize_filename(&"é".repeat(128)).is_err());
+    }
+
+    #[test]
+    fn uuid_name_is_a_version_4_uuid_keeping_a_plain_extension() {
+        let name = uuid_name("Photo.JPG");
+        let (uuid, ext) = name.split_once('.').unwrap();
+        assert_eq!(ext, "jpg");
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(uuid.split('-').map(str::len).collect::<Vec<_>>(), [8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!("89ab".contains(&uuid[19..20]), "{}", uuid);
+        assert_ne!(uuid_name("Photo.JPG"), name);
+
+        assert!(uuid_name("archive.tar.gz").ends_with(".gz"));
+        for name in ["README", "trailing.", "odd.ex-t", "long.extension12"] {
+            assert_eq!(uuid_name(name).len(), 36, "{}", name);
+        }
+    }
+
+    #[test]
+    fn place_file_renames_around_an_existing_file() {
+        let dir = test_dir("rename");
+        fs::write(dir.join("report.tar.gz"), "old").unwrap();
+
+        let first = place_file(&temp_file(&dir, "one"), &dir, "report.tar.gz", UploadConflict::Rename).unwrap();
+        assert_eq!(first, dir.join("report-1.tar.gz"));
+        let second = place_file(&temp_file(&dir, "two"), &dir, "report.tar.gz", UploadConflict::Rename).unwrap();
+        assert_eq!(second, dir.join("report-2.tar.gz"));
+        assert_eq!(fs::read_to_string(dir.join("report.tar.gz")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&second).unwrap(), "two");
+        // The temporary files are gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn place_file_overwrites_or_rejects_an_existing_file() {
+        let dir = test_dir("conflict");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+
+        let temp = temp_file(&dir, "rejected");
+        let err = place_file(&temp, &dir, "a.txt", UploadConflict::Reject).unwrap_err();
+        assert!(matches!(err, UploadError::Conflict(ref name) if name == "a.txt"), "{}", err);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+        // Left for the caller to clean up
+        assert!(temp.exists());
+        fs::remove_file(temp).unwrap();
+
+        let path = place_file(&temp_file(&dir, "new"), &dir, "a.txt", UploadConflict::Overwrite).unwrap();
+        assert_eq!(path, dir.join("a.txt"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Without a conflict every mode stores the name as it is
+        for conflict in [UploadConflict::Rename, UploadConflict::Overwrite, UploadConflict::Reject] {
+            let name = format!("{:?}.txt", conflict);
+            assert_eq!(place_file(&temp_file(&dir, "x"), &dir, &name, conflict).unwrap(), dir.join(&name));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn multipart_bodies_are_binary_safe() {
+        let content = b"\0\xff\xfe not utf-8 \xc3\x28 \r\n\0";
+        let (parts, end) = parse("XyZ", &[&file_body(content)]);
+        assert!(end.is_ok());
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0.filename.as_deref(), Some("a.bin"));
+        assert_eq!(parts[0].1, content);
+    }
+
+    #[test]
+    fn multipart_content_may_end_like_a_delimiter() {
+        for content in [&b"data\r\n--"[..], b"data--", b"-----", b"\r\n--Xy", b"--XyZ"] {
+            let (parts, end) = parse("XyZ", &[&file_body(content)]);
+            assert!(end.is_ok(), "{:?}", content);
+            assert_eq!(parts[0].1, content);
+        }
+    }
+
+    #[test]
+    fn multipart_delimiters_may_be_split_at_any_offset() {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n".to_vec();
+        body.extend_from_slice(&file_body(b"line\r\n--Xy not a boundary\r\n")[..]);
+        let whole = parse("XyZ", &[&body]);
+        assert!(whole.1.is_ok());
+        assert_eq!(whole.0.len(), 2);
+        assert_eq!(whole.0[0].1, b"hello");
+        assert_eq!(whole.0[1].1, b"line\r\n--Xy not a boundary\r\n");
+
+        for at in 0..=body.len() {
+            let (parts, end) = parse("XyZ", &[&body[..at], &body[at..]]);
+            assert!(end.is_ok(), "split at {}", at);
+            let bodies: Vec<&[u8]> = parts.iter().map(|(_, body)| &body[..]).collect();
+            assert_eq!(bodies, [&whole.0[0].1[..], &whole.0[1].1[..]], "split at {}", at);
+        }
+        let bytes: Vec<&[u8]> = body.chunks(1).collect();
+        let (parts, end) = parse("XyZ", &bytes);
+        assert!(end.is_ok());
+        assert_eq!(parts[1].1, whole.0[1].1);
+    }
+
+    #[test]
+    fn multipart_preamble_and_epilogue_are_ignored() {
+        let body = b"This is the preamble.\r\n--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\none\r\n--XyZ--\r\nThis is the epilogue.\r\n--XyZ\r\n\r\nnot a part";
+        let (parts, end) = parse("XyZ", &[body]);
+        assert!(end.is_ok());
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0.name.as_deref(), Some("a"));
+        assert_eq!(parts[0].1, b"one");
+
+        // A preamble that merely contains the boundary text is not a delimiter
+        let (parts, _) = parse("XyZ", &[b"x--XyZ\r\n\r\nno\r\n--XyZ\r\n\r\nyes\r\n--XyZ--"]);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].1, b"yes");
+    }
+
+    #[test]
+    fn multipart_body_without_its_closing_delimiter_is_an_error() {
+        let body = file_body(b"complete?");
+        let cut = &body[..body.len() - b"--\r\n".len()];
+        for truncated in [&body[..body.len() - 12], cut, &b"--XyZ\r\nContent-Disposition: form-data"[..], b"no boundary at all"] {
+            let (_, end) = parse("XyZ", &[truncated]);
+            assert!(matches!(end, Err(UploadError::Malformed(_))), "{:?}", String::from_utf8_lossy(truncated));
+        }
+
+        // Nothing of a truncated upload is stored
+        let dir = test_dir("truncated");
+        let mut upload = MultipartUpload::new("XyZ", &dir, UploadConflict::Rename, false).unwrap();
+        upload.feed(&body[..body.len() - 12]).unwrap();
+        assert!(matches!(upload.finish(), Err(UploadError::Malformed(_))));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -83,8 +83,11 @@ impl EpollManager {
         for i in 0..num_events as usize {
             let event = &events[i];
             let fd = event.u64 as RawFd;
-            let readable = (event.events & libc::EPOLLIN as u32) != 0;
-            let writable = (event.events & libc::EPOLLOUT as u32) != 0;
+            // Hang-ups and errors are reported as readiness so the next read/write
+            // observes EOF or the error (a closed pipe may signal EPOLLHUP without EPOLLIN)
+            let hangup = (event.events & (libc::EPOLLHUP | libc::EPOLLERR) as u32) != 0;
+            let readable = hangup || (event.events & libc::EPOLLIN as u32) != 0;
+            let writable = (event.events & (libc::EPOLLOUT | libc::EPOLLERR) as u32) != 0;
             
             result.push(EpollEvent {
                 fd,
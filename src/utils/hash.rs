@@ -0,0 +1,10 @@
+/// 64-bit FNV-1a. Unlike the std hasher it gives the same value in every run, so it
+/// can name files and pick servers consistently across restarts.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
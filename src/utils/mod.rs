@@ -1 +1,6 @@
-pub mod epoll;
\ No newline at end of file
+pub mod base64;
+pub mod epoll;
+pub mod hash;
+pub mod html;
+pub mod json;
+pub mod net;
\ No newline at end of file
@@ -0,0 +1,136 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::FromRawFd;
+
+const LISTEN_BACKLOG: i32 = 511;
+
+/// Convert a std socket address into the raw form expected by bind/connect.
+pub fn to_raw_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: v4.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(v4.ip().octets()) },
+                sin_zero: [0; 8],
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: v6.port().to_be(),
+                sin6_flowinfo: v6.flowinfo(),
+                sin6_addr: libc::in6_addr { s6_addr: v6.ip().octets() },
+                sin6_scope_id: v6.scope_id(),
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn set_int_option(fd: i32, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Bind a non-blocking TCP listener. IPv6 sockets are made v6-only so that
+/// `listen [::]:8080;` and `listen 0.0.0.0:8080;` can coexist, as in nginx.
+pub fn bind_tcp_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let domain = if addr.is_ipv6() { libc::AF_INET6 } else { libc::AF_INET };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Hand the fd to TcpListener right away so it is closed on every error path
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if addr.is_ipv6() {
+        set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+    }
+
+    let (storage, len) = to_raw_sockaddr(addr);
+    if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, LISTEN_BACKLOG) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+/// Write as much of `data` as a non-blocking socket accepts right now.
+pub fn write_available<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match writer.write(&data[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(written)
+}
+
+/// Read everything a non-blocking socket has buffered into `buf`.
+/// Returns `Ok(true)` once the peer has closed its side.
+pub fn read_available<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    read_up_to(reader, buf, usize::MAX)
+}
+
+/// Like `read_available`, but stop once `buf` holds `limit` bytes or more; anything
+/// left unread is waiting for the caller to come back without a new readiness event.
+pub fn read_up_to<R: Read>(reader: &mut R, buf: &mut Vec<u8>, limit: usize) -> io::Result<bool> {
+    let mut chunk = [0u8; 8192];
+    while buf.len() < limit {
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Start a non-blocking TCP connect. The connection may still be in progress when
+/// this returns; the socket becomes writable once it completes, and a failure shows
+/// up as an error on the first read or write.
+pub fn connect_tcp_nonblocking(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let domain = if addr.is_ipv6() { libc::AF_INET6 } else { libc::AF_INET };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let (storage, len) = to_raw_sockaddr(addr);
+    if unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+    Ok(stream)
+}
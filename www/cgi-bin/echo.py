@@ -34,7 +34,7 @@ if os.environ.get('REQUEST_METHOD') == 'POST':
 print("<h2>All Environment Variables:</h2>")
 print("<ul>")
 for key, value in sorted(os.environ.items()):
-    if key.startswith(('HTTP_', 'REQUEST_', 'CONTENT_', 'QUERY_', 'SERVER_', 'GATEWAY_')):
+    if key.startswith(('HTTP_', 'REQUEST_', 'CONTENT_', 'QUERY_', 'SERVER_', 'GATEWAY_', 'SCRIPT_', 'PATH_', 'REMOTE_', 'DOCUMENT_')):
         print(f"<li><strong>{key}:</strong> {value}</li>")
 print("</ul>")
 